@@ -0,0 +1,27 @@
+use rumqtt::{
+    actor::{self, Message},
+    MqttOptions, QoS,
+};
+use std::{thread, time::Duration};
+
+fn main() {
+    pretty_env_logger::init();
+    let mqtt_options = MqttOptions::new("test-actor", "127.0.0.1", 1883).set_keep_alive(10);
+    let (addr, notifications) = actor::start(mqtt_options).unwrap();
+
+    addr.send(Message::Subscribe("hello/world".to_owned(), QoS::AtLeastOnce)).unwrap();
+
+    thread::spawn(move || {
+        for i in 0..100 {
+            let payload = format!("publish {}", i).into_bytes();
+            thread::sleep(Duration::from_secs(1));
+            addr.send(Message::Publish("hello/world".to_owned(), QoS::AtLeastOnce, false, payload)).unwrap();
+        }
+
+        addr.send(Message::Disconnect).unwrap();
+    });
+
+    for notification in notifications {
+        println!("{:?}", notification)
+    }
+}
@@ -1,16 +1,18 @@
 use rumqtt::{ConnectionMethod, MqttClient, MqttOptions, QoS, SecurityOptions};
 use serde_derive::Deserialize;
-use std::{thread, time::Duration};
+use std::{fs, thread, time::Duration};
 // NOTES:
 // ---------
 // Proive necessary stuff from environment variables
-// RUST_LOG=rumqtt=debug PROJECT=ABC ID=DEF REGISTRY=GHI cargo run --example gcloud
+// RUST_LOG=rumqtt=debug PROJECT=ABC ID=DEF REGISTRY=GHI KEY=rsa_private.der CA=roots.pem cargo run --example gcloud
 
 #[derive(Deserialize, Debug)]
 struct Config {
     project: String,
     id: String,
     registry: String,
+    key: String,
+    ca: String,
 }
 
 fn main() {
@@ -24,9 +26,10 @@ fn main() {
         + "/devices/"
         + &config.id;
 
-    let security_options = SecurityOptions::GcloudIot(config.project, include_bytes!("../../certs/rsa_private.der").to_vec(), 60);
+    let key = fs::read(&config.key).unwrap();
+    let security_options = SecurityOptions::GcloudIot(config.project, key, 60);
 
-    let ca = include_bytes!("../../certs/roots.pem").to_vec();
+    let ca = fs::read(&config.ca).unwrap();
     let connection_method = ConnectionMethod::Tls(ca, None);
 
     let mqtt_options = MqttOptions::new(client_id, "mqtt.googleapis.com", 8883)
@@ -3,7 +3,6 @@ use std::{thread, time::Duration};
 
 fn main() {
     pretty_env_logger::init();
-    let broker = "prod-mqtt-broker.atherengineering.in";
     let port = 1883;
 
     let reconnection_options = ReconnectOptions::Always(10);
@@ -1,7 +1,5 @@
 extern crate pretty_env_logger;
 extern crate rumqtt;
-use rumqtt::{MqttClient, MqttOptions};
-use std::thread;
 
 fn main() {
     // pretty_env_logger::init();
@@ -22,7 +22,7 @@ fn main() {
         for i in 0..100 {
             let payload = format!("publish {}", i);
             thread::sleep(Duration::from_secs(1));
-            mqtt_client.publish(topic.clone(), QoS::AtLeastOnce, false, payload).unwrap();
+            mqtt_client.publish(topic, QoS::AtLeastOnce, false, payload).unwrap();
         }
     });
 
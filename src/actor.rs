@@ -0,0 +1,108 @@
+//! Actor style handle to the mqtt eventloop. Requests are sent as messages to a
+//! mailbox which is drained by a thread owning the [client]
+//!
+//! [client]: ../client/struct.MqttClient.html
+use crate::client::{MqttClient, Notification};
+use crate::error::{ClientError, ConnectError};
+use crate::mqttoptions::MqttOptions;
+use crossbeam_channel::{self, Receiver, Sender};
+use mqtt311::{Publish, QoS};
+use std::thread;
+
+/// Messages understood by the mqtt actor
+#[derive(Debug)]
+pub enum Message {
+    /// (topic, qos, retain, payload)
+    Publish(String, QoS, bool, Vec<u8>),
+    /// (topic, qos)
+    Subscribe(String, QoS),
+    Unsubscribe(String),
+    /// Ack of an incoming publish in manual ack mode
+    Ack(Publish),
+    /// Gracefully disconnect and stop the actor
+    Disconnect,
+}
+
+/// Address of the mqtt actor. Cheap to clone and send across threads
+#[derive(Clone, Debug)]
+pub struct Addr {
+    tx: Sender<Message>,
+}
+
+impl Addr {
+    /// Sends a message to the actor's mailbox. Blocks when the mailbox is full
+    pub fn send(&self, message: Message) -> Result<(), ClientError> {
+        self.tx.send(message)?;
+        Ok(())
+    }
+}
+
+/// Starts the mqtt eventloop and an actor to drive it. Returns the actor's
+/// address and the receiver of incoming notifications
+pub fn start(opts: MqttOptions) -> Result<(Addr, Receiver<Notification>), ConnectError> {
+    let (tx, rx) = crossbeam_channel::bounded(opts.request_channel_capacity());
+    let (client, notifications) = MqttClient::start(opts)?;
+
+    thread::Builder::new().name("rumqtt-actor".to_owned()).spawn(move || run(client, rx))?;
+    Ok((Addr { tx }, notifications))
+}
+
+fn run(mut client: MqttClient, mailbox: Receiver<Message>) {
+    for message in mailbox {
+        let out = match message {
+            Message::Publish(topic, qos, retain, payload) => client.publish(topic, qos, retain, payload),
+            Message::Subscribe(topic, qos) => client.subscribe(topic, qos),
+            Message::Unsubscribe(topic) => client.unsubscribe(topic),
+            Message::Ack(publish) => client.ack(&publish),
+            Message::Disconnect => {
+                if let Err(e) = client.shutdown() {
+                    error!("Actor disconnect failed. Error = {:?}", e);
+                }
+                break;
+            }
+        };
+
+        if let Err(e) = out {
+            error!("Actor request failed. Error = {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{start, Message};
+    use crate::client::test::recording_broker;
+    use crate::mqttoptions::MqttOptions;
+    use mqtt311::{Packet, QoS};
+    use std::time::Duration;
+
+    #[test]
+    fn messages_should_reach_the_broker_in_order() {
+        let (port, packets_rx) = recording_broker();
+        let (addr, _notifications) = start(MqttOptions::new("actor", "127.0.0.1", port)).unwrap();
+        addr.send(Message::Publish("hello/world".to_owned(), QoS::AtMostOnce, false, vec![1, 2, 3])).unwrap();
+        addr.send(Message::Subscribe("hello/#".to_owned(), QoS::AtLeastOnce)).unwrap();
+        addr.send(Message::Disconnect).unwrap();
+
+        match packets_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            (0, Packet::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "hello/world");
+                assert_eq!(*publish.payload, vec![1, 2, 3]);
+            }
+            packet => panic!("Expecting a publish. Received = {:?}", packet),
+        }
+
+        match packets_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            (0, Packet::Subscribe(subscribe)) => {
+                assert_eq!(subscribe.topics[0].topic_path, "hello/#");
+                assert_eq!(subscribe.topics[0].qos, QoS::AtLeastOnce);
+            }
+            packet => panic!("Expecting a subscribe. Received = {:?}", packet),
+        }
+
+        match packets_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            (0, Packet::Disconnect) => (),
+            packet => panic!("Expecting a disconnect. Received = {:?}", packet),
+        }
+    }
+}
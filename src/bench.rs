@@ -0,0 +1,156 @@
+//! Ready made measurements to compare releases. Latency is measured from the
+//! time a publish is written for the network till its ack with a [LatencyProbe]
+//! and throughput by publishing a batch and waiting for all of it to be acked
+//!
+//! [LatencyProbe]: struct.LatencyProbe.html
+use crate::client::{BatchStatus, MqttClient};
+use crate::error::ClientError;
+use crate::probe::Probe;
+use mqtt311::{PacketIdentifier, QoS};
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Records publish to ack latencies. Share it with the options as an `Arc`
+///
+/// ```ignore
+/// let probe = Arc::new(LatencyProbe::new());
+/// let opts = MqttOptions::new("bench", "localhost", 1883).set_probe(probe.clone());
+/// ```
+#[derive(Debug, Default)]
+pub struct LatencyProbe {
+    sent: Mutex<BTreeMap<PacketIdentifier, Instant>>,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl LatencyProbe {
+    pub fn new() -> LatencyProbe {
+        LatencyProbe::default()
+    }
+
+    /// Summary of the latencies recorded so far. None if nothing is acked yet
+    pub fn latencies(&self) -> Option<Latencies> {
+        let mut latencies = self.latencies.lock().unwrap().clone();
+        Latencies::from(&mut latencies)
+    }
+
+    /// Forgets the recorded latencies, e.g after a warm up run
+    pub fn reset(&self) {
+        self.sent.lock().unwrap().clear();
+        self.latencies.lock().unwrap().clear();
+    }
+}
+
+impl Probe for LatencyProbe {
+    // retransmissions don't restart the measurement
+    fn sent(&self, pkid: PacketIdentifier, at: Instant) {
+        self.sent.lock().unwrap().entry(pkid).or_insert(at);
+    }
+
+    fn acked(&self, pkid: PacketIdentifier, at: Instant) {
+        if let Some(sent) = self.sent.lock().unwrap().remove(&pkid) {
+            self.latencies.lock().unwrap().push(at.duration_since(sent));
+        }
+    }
+}
+
+/// Publish to ack latency summary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latencies {
+    pub count: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Latencies {
+    fn from(latencies: &mut [Duration]) -> Option<Latencies> {
+        if latencies.is_empty() {
+            return None;
+        }
+
+        latencies.sort();
+        let count = latencies.len();
+        let total: Duration = latencies.iter().sum();
+        let percentile = |p: usize| latencies[(count - 1) * p / 100];
+
+        Some(Latencies {
+            count,
+            min: latencies[0],
+            mean: total / count as u32,
+            p50: percentile(50),
+            p99: percentile(99),
+            max: latencies[count - 1],
+        })
+    }
+}
+
+/// Result of a throughput run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    /// Lost when the publishes were dropped with the session before all the acks
+    pub status: BatchStatus,
+    pub messages: usize,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn messages_per_sec(&self) -> f64 {
+        self.messages as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Publishes `count` messages of `size` bytes and waits till the broker acks all
+/// of them. Use qos 1 or 2 as qos 0 publishes aren't acked
+pub fn throughput(client: &mut MqttClient, topic: &str, qos: QoS, size: usize, count: usize) -> Result<Throughput, ClientError> {
+    let payload = vec![0xAB; size];
+    let start = Instant::now();
+    let batch = (0..count).map(|_| (topic, qos, false, payload.clone()));
+    let status = client.publish_all(batch)?.recv().unwrap_or(BatchStatus::Lost);
+
+    Ok(Throughput {
+        status,
+        messages: count,
+        bytes: count * size,
+        elapsed: start.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::LatencyProbe;
+    use crate::probe::Probe;
+    use mqtt311::PacketIdentifier;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn latencies_should_be_measured_from_first_send() {
+        let probe = LatencyProbe::new();
+        let start = Instant::now();
+        for pkid in 1..=100 {
+            probe.sent(PacketIdentifier(pkid), start);
+            // retransmission
+            probe.sent(PacketIdentifier(pkid), start + Duration::from_millis(500));
+            probe.acked(PacketIdentifier(pkid), start + Duration::from_millis(u64::from(pkid)));
+        }
+
+        let latencies = probe.latencies().unwrap();
+        assert_eq!(latencies.count, 100);
+        assert_eq!(latencies.min, Duration::from_millis(1));
+        assert_eq!(latencies.p50, Duration::from_millis(50));
+        assert_eq!(latencies.p99, Duration::from_millis(99));
+        assert_eq!(latencies.max, Duration::from_millis(100));
+
+        probe.reset();
+        assert!(probe.latencies().is_none());
+    }
+}
@@ -0,0 +1,325 @@
+//! Typed topic bindings. A binding pairs a topic with a payload type and yields a
+//! typed sender and a typed receiver so that application code deals with its own
+//! types instead of raw bytes and topic strings
+use crate::client::{MqttClient, Notification};
+use crate::error::ClientError;
+use crate::topic::TopicTree;
+use crossbeam_channel::{self, Receiver, RecvError, Sender, TryRecvError};
+use mqtt311::{Publish, QoS};
+use std::{collections::HashMap, fmt, marker::PhantomData};
+
+/// Conversion of a type to and from mqtt payload
+pub trait Payload: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(payload: &[u8]) -> Result<Self, String>;
+}
+
+impl Payload for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, String> {
+        Ok(payload.to_vec())
+    }
+}
+
+impl Payload for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, String> {
+        String::from_utf8(payload.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+/// Publishes values of type `T` to the bound topic
+pub struct TypedSender<T> {
+    client: MqttClient,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    marker: PhantomData<fn(&T)>,
+}
+
+impl<T: Payload> TypedSender<T> {
+    pub fn send(&mut self, value: &T) -> Result<(), ClientError> {
+        self.client.publish(self.topic.clone(), self.qos, self.retain, value.encode())
+    }
+
+    /// Retain flag of the publishes sent by this sender
+    pub fn set_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+}
+
+/// Receives values of type `T` from the topics matching the bound filter
+pub struct TypedReceiver<T> {
+    rx: Receiver<(String, T)>,
+}
+
+impl<T> TypedReceiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.rx.recv().map(|(_, value)| value)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.rx.try_recv().map(|(_, value)| value)
+    }
+
+    /// Receives the value along with the topic it's published on. Useful with wildcard filters
+    pub fn recv_with_topic(&self) -> Result<(String, T), RecvError> {
+        self.rx.recv()
+    }
+}
+
+/// Topic with named parameters in place of levels. E.g `devices/{id}/telemetry`
+#[derive(Clone, Debug)]
+pub struct TopicTemplate {
+    template: String,
+}
+
+impl TopicTemplate {
+    pub fn new<S: Into<String>>(template: S) -> TopicTemplate {
+        TopicTemplate { template: template.into() }
+    }
+
+    /// Topic with all the parameters replaced by their values
+    pub fn render(&self, params: &[(&str, &str)]) -> Result<String, ClientError> {
+        let levels: Result<Vec<&str>, ClientError> = self
+            .template
+            .split('/')
+            .map(|level| match param_name(level) {
+                Some(name) => params
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| *value)
+                    .ok_or_else(|| ClientError::MissingTopicParam(name.to_owned())),
+                None => Ok(level),
+            })
+            .collect();
+
+        Ok(levels?.join("/"))
+    }
+
+    /// Subscription filter matching all the topics of this template
+    pub fn filter(&self) -> String {
+        let levels: Vec<&str> = self
+            .template
+            .split('/')
+            .map(|level| if param_name(level).is_some() { "+" } else { level })
+            .collect();
+
+        levels.join("/")
+    }
+
+    /// Parameter values of a topic matching the template
+    pub fn extract(&self, topic: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut levels = topic.split('/');
+
+        for template_level in self.template.split('/') {
+            let level = levels.next()?;
+            match param_name(template_level) {
+                Some(name) => {
+                    params.insert(name.to_owned(), level.to_owned());
+                }
+                None if template_level == level => (),
+                None => return None,
+            }
+        }
+
+        match levels.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+fn param_name(level: &str) -> Option<&str> {
+    if level.len() > 2 && level.starts_with('{') && level.ends_with('}') {
+        Some(&level[1..level.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Message type mapped to a topic template. Implementing this (along with [Payload])
+/// generates the publish and subscribe glue for the type
+///
+/// [Payload]: trait.Payload.html
+pub trait TopicMessage: Payload + Send + 'static {
+    /// Topic template. E.g `devices/{id}/telemetry`
+    const TOPIC: &'static str;
+    const QOS: QoS = QoS::AtLeastOnce;
+
+    fn template() -> TopicTemplate {
+        TopicTemplate::new(Self::TOPIC)
+    }
+
+    /// Publishes the message to the topic rendered with the parameters
+    fn publish(&self, client: &mut MqttClient, params: &[(&str, &str)]) -> Result<(), ClientError> {
+        let topic = Self::template().render(params)?;
+        client.publish(topic, Self::QOS, false, self.encode())
+    }
+
+    /// Subscribes to all the topics of the template and returns a typed receiver
+    fn subscribe(client: &mut MqttClient, bindings: &mut Bindings) -> Result<TypedReceiver<Self>, ClientError> {
+        let filter = Self::template().filter();
+        client.subscribe(filter.clone(), Self::QOS)?;
+        Ok(bindings.route(filter))
+    }
+}
+
+type Route = Box<dyn Fn(&Publish) + Send>;
+
+/// Routes incoming publishes to typed receivers. Feed it the notifications of the
+/// client with [dispatch]
+///
+/// [dispatch]: struct.Bindings.html#method.dispatch
+#[derive(Default)]
+pub struct Bindings {
+    routes: Vec<(String, Route)>,
+    // position of the first route of each filter
+    index: TopicTree<usize>,
+}
+
+impl Bindings {
+    pub fn new() -> Bindings {
+        Bindings::default()
+    }
+
+    /// Subscribes to the topic and returns a typed sender and receiver for it. Sender
+    /// publishes to the topic as is and hence isn't usable with wildcard topics
+    pub fn bind<T, S>(&mut self, client: &mut MqttClient, topic: S, qos: QoS) -> Result<(TypedSender<T>, TypedReceiver<T>), ClientError>
+    where
+        T: Payload + Send + 'static,
+        S: Into<String>,
+    {
+        let topic = topic.into();
+        client.subscribe(topic.clone(), qos)?;
+
+        let receiver = self.route(topic.clone());
+        let sender = TypedSender {
+            client: client.clone(),
+            topic,
+            qos,
+            retain: false,
+            marker: PhantomData,
+        };
+
+        Ok((sender, receiver))
+    }
+
+    /// Typed receiver for publishes matching the filter without subscribing
+    pub fn route<T, S>(&mut self, filter: S) -> TypedReceiver<T>
+    where
+        T: Payload + Send + 'static,
+        S: Into<String>,
+    {
+        let (tx, rx): (Sender<(String, T)>, _) = crossbeam_channel::unbounded();
+        let route = move |publish: &Publish| match T::decode(&publish.payload) {
+            Ok(value) => {
+                if tx.send((publish.topic_name.clone(), value)).is_err() {
+                    debug!("Typed receiver dropped. Topic = {}", publish.topic_name);
+                }
+            }
+            Err(e) => error!("Payload decode failed. Topic = {}, Error = {}", publish.topic_name, e),
+        };
+
+        let filter = filter.into();
+        if self.index.get(&filter).is_none() {
+            self.index.insert(filter.clone(), self.routes.len());
+        }
+
+        self.routes.push((filter, Box::new(route)));
+        TypedReceiver { rx }
+    }
+
+    /// Hands the publish over to the first binding whose filter matches the topic.
+    /// Returns the notification back if no binding consumes it
+    pub fn dispatch(&self, notification: Notification) -> Option<Notification> {
+        let publish = match notification {
+            Notification::Publish(publish) => publish,
+            notification => return Some(notification),
+        };
+
+        match self.index.matches(&publish.topic_name).into_iter().min().map(|&i| &self.routes[i]) {
+            Some((_, route)) => {
+                route(&publish);
+                None
+            }
+            None => Some(Notification::Publish(publish)),
+        }
+    }
+}
+
+impl fmt::Debug for Bindings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filters: Vec<&String> = self.routes.iter().map(|(filter, _)| filter).collect();
+        write!(f, "Bindings({:?})", filters)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bindings, Payload, TopicTemplate};
+    use crate::client::Notification;
+    use mqtt311::{Publish, QoS};
+    use std::sync::Arc;
+
+    struct Temperature(u8);
+
+    impl Payload for Temperature {
+        fn encode(&self) -> Vec<u8> {
+            vec![self.0]
+        }
+
+        fn decode(payload: &[u8]) -> Result<Self, String> {
+            match payload {
+                [t] => Ok(Temperature(*t)),
+                _ => Err("expecting one byte".to_owned()),
+            }
+        }
+    }
+
+    fn publish(topic: &str, payload: Vec<u8>) -> Notification {
+        Notification::Publish(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: topic.to_owned(),
+            pkid: None,
+            payload: Arc::new(payload),
+        })
+    }
+
+    #[test]
+    fn matching_publishes_should_be_decoded_to_typed_receiver() {
+        let mut bindings = Bindings::new();
+        let temperatures = bindings.route::<Temperature, _>("sensors/+/temperature");
+
+        assert!(bindings.dispatch(publish("sensors/1/temperature", vec![25])).is_none());
+        assert!(bindings.dispatch(publish("sensors/1/humidity", vec![60])).is_some());
+
+        let (topic, temperature) = temperatures.recv_with_topic().unwrap();
+        assert_eq!(topic, "sensors/1/temperature");
+        assert_eq!(temperature.0, 25);
+        assert!(temperatures.try_recv().is_err());
+    }
+
+    #[test]
+    fn topic_template_should_render_filter_and_extract_params() {
+        let template = TopicTemplate::new("devices/{id}/telemetry");
+
+        assert_eq!(template.render(&[("id", "d1")]).unwrap(), "devices/d1/telemetry");
+        assert!(template.render(&[]).is_err());
+        assert_eq!(template.filter(), "devices/+/telemetry");
+
+        let params = template.extract("devices/d1/telemetry").unwrap();
+        assert_eq!(params["id"], "d1");
+        assert!(template.extract("devices/d1/status").is_none());
+    }
+}
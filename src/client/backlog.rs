@@ -0,0 +1,346 @@
+use crate::client::{notifier::GenerationSender, Notification, NotificationSender};
+use crate::error::NotificationError;
+use crate::mqttoptions::QueuePolicy;
+use crate::persistence::{decode, encode};
+use mqtt311::{Packet, Publish};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+/// Sender of all the eventloop notifications. With an incoming spill, publishes
+/// which don't fit in the user's channel are held back in memory up to the
+/// threshold and in a temporary file beyond that. They are fed back in order as
+/// the channel frees up, before any newer publish. Without a spill, the queue
+/// policy decides what happens to them. Other notifications aren't held back
+pub(crate) struct BacklogSender {
+    inner: GenerationSender,
+    policy: QueuePolicy,
+    backlog: Option<Backlog>,
+}
+
+impl BacklogSender {
+    pub(crate) fn new(inner: GenerationSender, spill: Option<(usize, PathBuf)>, policy: QueuePolicy, capacity: usize) -> BacklogSender {
+        let backlog = match (spill, policy) {
+            (Some((threshold, dir)), _) => Some(Backlog::new(threshold, Some(dir), None)),
+            (None, QueuePolicy::DropOldest) => Some(Backlog::new(usize::MAX, None, Some(capacity))),
+            (None, _) => None,
+        };
+
+        BacklogSender { inner, policy, backlog }
+    }
+
+    /// Hands the held back publishes over till the channel is full again
+    pub(crate) fn drain(&mut self) {
+        let backlog = match &mut self.backlog {
+            Some(backlog) => backlog,
+            None => return,
+        };
+
+        loop {
+            let publish = match backlog.front() {
+                Ok(Some(publish)) => publish,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Failed to read incoming spill. Dropping it. Error = {:?}", e);
+                    backlog.clear();
+                    return;
+                }
+            };
+
+            match self.inner.try_notify(Notification::Publish(publish)) {
+                Ok(()) => backlog.pop(),
+                Err(NotificationError::Full) => return,
+                Err(NotificationError::Disconnected) => {
+                    backlog.clear();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl NotificationSender for BacklogSender {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.drain();
+        let (publish, backlog) = match (notification, &mut self.backlog) {
+            (Notification::Publish(publish), Some(backlog)) => (publish, backlog),
+            (notification @ Notification::Publish(_), None) if self.policy == QueuePolicy::Block => {
+                return self.inner.notify(notification)
+            }
+            (notification, _) => return self.inner.try_notify(notification),
+        };
+
+        if backlog.is_empty() {
+            match self.inner.try_notify(Notification::Publish(publish.clone())) {
+                Err(NotificationError::Full) => (),
+                out => return out,
+            }
+        }
+
+        backlog.push(publish).map_err(|e| {
+            error!("Failed to spill incoming publish. Error = {:?}", e);
+            NotificationError::Full
+        })
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        if let Some(backlog) = &mut self.backlog {
+            while let Ok(Some(publish)) = backlog.front() {
+                self.inner.notify(Notification::Publish(publish))?;
+                backlog.pop();
+            }
+        }
+
+        self.inner.notify(notification)
+    }
+}
+
+/// Held back publishes. Memory is used till the threshold and the file after
+/// that. Publishes go to the file as long as it isn't empty so that order is kept.
+/// Without a directory, publishes stay in memory and the oldest ones are dropped
+/// beyond the limit
+struct Backlog {
+    threshold: usize,
+    dir: Option<PathBuf>,
+    limit: Option<usize>,
+    memory: VecDeque<Publish>,
+    memory_bytes: usize,
+    file: Option<SpillFile>,
+}
+
+impl Backlog {
+    fn new(threshold: usize, dir: Option<PathBuf>, limit: Option<usize>) -> Backlog {
+        Backlog {
+            threshold,
+            dir,
+            limit,
+            memory: VecDeque::new(),
+            memory_bytes: 0,
+            file: None,
+        }
+    }
+
+    fn push(&mut self, publish: Publish) -> io::Result<()> {
+        if let Some(limit) = self.limit {
+            if self.memory.len() >= limit {
+                if let Some(dropped) = self.memory.pop_front() {
+                    warn!("Notification channel full. Dropping oldest publish. Topic = {}", dropped.topic_name);
+                    self.memory_bytes -= dropped.payload.len();
+                }
+            }
+        }
+
+        let len = publish.payload.len();
+        let spilling = self.file.as_ref().is_some_and(|file| !file.is_empty());
+        let dir = match &self.dir {
+            Some(dir) if spilling || self.memory_bytes + len > self.threshold => dir,
+            _ => {
+                self.memory_bytes += len;
+                self.memory.push_back(publish);
+                return Ok(());
+            }
+        };
+
+        if self.file.is_none() {
+            self.file = Some(SpillFile::create(dir)?);
+        }
+
+        self.file.as_mut().unwrap().push(&publish)
+    }
+
+    fn front(&mut self) -> io::Result<Option<Publish>> {
+        match (self.memory.front(), &mut self.file) {
+            (Some(publish), _) => Ok(Some(publish.clone())),
+            (None, Some(file)) => file.front(),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn pop(&mut self) {
+        match self.memory.pop_front() {
+            Some(publish) => self.memory_bytes -= publish.payload.len(),
+            None => {
+                if let Some(file) = &mut self.file {
+                    file.pop();
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.memory.clear();
+        self.memory_bytes = 0;
+        self.file = None;
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len() + self.file.as_ref().map_or(0, |file| file.count)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Temporary file queue of `| len (u32 be) | mqtt encoded publish |` records.
+/// File is truncated once everything is read and deleted on drop
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    read_offset: u64,
+    write_offset: u64,
+    count: usize,
+    head: Option<(Publish, u64)>,
+}
+
+impl SpillFile {
+    fn create(dir: &Path) -> io::Result<SpillFile> {
+        let path = dir.join(format!("{}.spill", Uuid::new_v4()));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(SpillFile {
+            path,
+            file,
+            read_offset: 0,
+            write_offset: 0,
+            count: 0,
+            head: None,
+        })
+    }
+
+    fn push(&mut self, publish: &Publish) -> io::Result<()> {
+        let body = encode(&Packet::Publish(publish.clone()))?;
+        let mut record = Vec::with_capacity(4 + body.len());
+        record.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        record.extend_from_slice(&body);
+
+        self.file.seek(SeekFrom::Start(self.write_offset))?;
+        self.file.write_all(&record)?;
+        self.write_offset += record.len() as u64;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn front(&mut self) -> io::Result<Option<Publish>> {
+        if self.count == 0 {
+            return Ok(None);
+        }
+
+        if let Some((publish, _)) = &self.head {
+            return Ok(Some(publish.clone()));
+        }
+
+        let mut len = [0; 4];
+        self.file.seek(SeekFrom::Start(self.read_offset))?;
+        self.file.read_exact(&mut len)?;
+        let mut body = vec![0; u32::from_be_bytes(len) as usize];
+        self.file.read_exact(&mut body)?;
+
+        match decode(&body)? {
+            Packet::Publish(publish) => {
+                self.head = Some((publish.clone(), 4 + body.len() as u64));
+                Ok(Some(publish))
+            }
+            packet => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected record {:?}", packet))),
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((_, len)) = self.head.take() {
+            self.read_offset += len;
+            self.count -= 1;
+        }
+
+        if self.count == 0 {
+            self.read_offset = 0;
+            self.write_offset = 0;
+            if let Err(e) = self.file.set_len(0) {
+                error!("Failed to truncate incoming spill. Error = {:?}", e);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BacklogSender;
+    use crate::client::{notifier::GenerationSender, ConnectionStats, Notification, NotificationSender};
+    use crate::mqttoptions::QueuePolicy;
+    use mqtt311::{Publish, QoS};
+    use std::{env, fs, sync::Arc};
+
+    fn publish(i: u8) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(vec![i; 10]),
+        }
+    }
+
+    #[test]
+    fn publishes_should_spill_to_disk_and_come_back_in_order() {
+        let dir = env::temp_dir().join(format!("rumqtt-backlog-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (tx, rx) = crossbeam_channel::bounded::<Notification>(2);
+        let inner = GenerationSender::new(Box::new(tx), Arc::new(ConnectionStats::default()));
+        let mut sender = BacklogSender::new(inner, Some((20, dir.clone())), QueuePolicy::DropNewest, 2);
+
+        // 2 in the channel, 2 in memory and the rest in the file
+        for i in 0..8 {
+            sender.try_notify(Notification::Publish(publish(i))).unwrap();
+        }
+
+        assert_eq!(sender.backlog.as_ref().unwrap().len(), 6);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut received = Vec::new();
+        while received.len() < 8 {
+            match rx.try_recv() {
+                Ok(Notification::Publish(publish)) => received.push(publish.payload[0]),
+                Ok(notification) => panic!("Expecting publish. Received = {:?}", notification),
+                Err(_) => sender.drain(),
+            }
+        }
+
+        assert_eq!(received, (0..8).collect::<Vec<u8>>());
+        assert_eq!(sender.backlog.as_ref().unwrap().len(), 0);
+        drop(sender);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drop_oldest_policy_should_keep_the_latest_publishes() {
+        let (tx, rx) = crossbeam_channel::bounded::<Notification>(2);
+        let inner = GenerationSender::new(Box::new(tx), Arc::new(ConnectionStats::default()));
+        let mut sender = BacklogSender::new(inner, None, QueuePolicy::DropOldest, 2);
+        for i in 0..6 {
+            sender.try_notify(Notification::Publish(publish(i))).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Ok(Notification::Publish(publish)) = rx.try_recv() {
+            received.push(publish.payload[0]);
+            sender.drain();
+        }
+
+        // channel keeps the first ones and the held back ones are the latest
+        assert_eq!(received, vec![0, 1, 4, 5]);
+    }
+}
@@ -0,0 +1,91 @@
+use futures::{task, Async, Poll, Stream};
+
+pub trait BudgetExt: Stream {
+    fn budget(self, max: usize) -> Budget<Self>
+    where
+        Self: Sized,
+    {
+        Budget { stream: self, max, used: 0 }
+    }
+}
+
+impl<T: ?Sized> BudgetExt for T where T: Stream {}
+
+/// An adapter which yields back to the runtime after `max` items in a row.
+///
+/// A stream which is always ready (e.g a flood of incoming publishes) otherwise
+/// keeps the task busy forever and starves the timers and the other streams
+/// which are only polled again after the runtime gets control back
+#[must_use = "streams do nothing unless polled"]
+pub struct Budget<S> {
+    stream: S,
+    max: usize,
+    used: usize,
+}
+
+impl<S> Stream for Budget<S>
+where
+    S: Stream,
+{
+    type Item = <S as Stream>::Item;
+    type Error = <S as Stream>::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.used >= self.max {
+            self.used = 0;
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+
+        match self.stream.poll()? {
+            Async::Ready(item) => {
+                self.used += 1;
+                Ok(Async::Ready(item))
+            }
+            Async::NotReady => {
+                self.used = 0;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BudgetExt;
+    use futures::{
+        executor::{self, Notify, NotifyHandle},
+        stream, Async,
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct Counter(AtomicUsize);
+
+    impl Notify for Counter {
+        fn notify(&self, _id: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn ready_stream_should_yield_after_budget_and_wake_itself() {
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let handle = NotifyHandle::from(counter.clone());
+        let mut stream = executor::spawn(stream::iter_ok::<_, ()>(0..5).budget(2));
+
+        let mut polls = Vec::new();
+        loop {
+            match stream.poll_stream_notify(&handle, 0).unwrap() {
+                Async::Ready(Some(item)) => polls.push(Some(item)),
+                Async::Ready(None) => break,
+                Async::NotReady => polls.push(None),
+            }
+        }
+
+        assert_eq!(polls, vec![Some(0), Some(1), None, Some(2), Some(3), None, Some(4)]);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,24 +1,47 @@
 use crate::client::{
+    backlog::BacklogSender,
+    budget::BudgetExt,
     mqttstate::MqttState,
     network::stream::NetworkStream,
+    notifier::GenerationSender,
+    offline::OfflineBuffer,
     prepend::{Prepend, StreamExt},
-    Command, Notification, Request, UserHandle,
+    priority::PriorityExt,
+    Command, ConnectionState, ConnectionStats, DisconnectReason, Notification, NotificationSender, PublishFile, QueueStats, Request,
+    StateChange, UserHandle,
 };
 use crate::codec::MqttCodec;
+#[cfg(feature = "compression")]
+use crate::compression;
 use crate::error::{ConnectError, NetworkError};
-use crate::mqttoptions::{ConnectionMethod, MqttOptions, Proxy, ReconnectOptions, SecurityOptions};
+use crate::mqttoptions::{MqttOptions, PayloadSinkHandle, Proxy, QueuePolicy, ReconnectOptions, SecurityOptions, TakeoverAction};
+use crate::reconnect::Attempt;
+use crate::sampling::Sampling;
+use crate::sequence::SequenceTracking;
+use crate::transform::Transformers;
+use crate::validation::{Authorization, Validators};
 use crossbeam_channel::{self, Sender};
 use futures::{
     future::{self, Either},
     stream::{self, SplitStream},
     sync::mpsc::{self, Receiver},
-    Future, Sink, Stream,
+    try_ready, Async, Future, Sink, Stream,
+};
+use mqtt311::{Packet, PacketIdentifier, Publish, QoS};
+use std::{
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    io::{self, Write},
+    panic,
+    path::PathBuf,
+    rc::Rc,
+    sync::{Arc, Mutex, Once},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use mqtt311::Packet;
-use std::{cell::RefCell, rc::Rc, thread, time::Duration};
 use tokio::runtime::current_thread::Runtime;
 use tokio_codec::Framed;
-use tokio_timer::{timeout, Timeout};
+use tokio_timer::{Delay, Interval, Timeout};
 
 //  NOTES: Don't use `wait` in eventloop thread even if you
 //         are ok with blocking code. It might cause deadlocks
@@ -26,47 +49,130 @@ use tokio_timer::{timeout, Timeout};
 
 pub struct Connection {
     mqtt_state: Rc<RefCell<MqttState>>,
-    notification_tx: Sender<Notification>,
+    notification_tx: Rc<RefCell<BacklogSender>>,
     connection_tx: Option<Sender<Result<(), ConnectError>>>,
     connection_count: u32,
     mqttoptions: MqttOptions,
     is_network_enabled: bool,
+    connection_stats: Arc<ConnectionStats>,
+    queue_stats: Arc<QueueStats>,
+    sampling: Rc<RefCell<Sampling>>,
+    /// last sequence numbers of the tracked topics
+    sequence_tracking: Rc<RefCell<SequenceTracking>>,
+    /// opening of the first power saving window
+    started: Instant,
+    /// connack time of the current connection
+    connected_at: Option<Instant>,
+    /// connections in a row which the broker closed right after connack
+    flaps: u32,
+    /// eventloop stopped on a client id takeover
+    taken_over: bool,
+    /// reconnection attempts since the last successful connection
+    reconnect_attempts: u32,
+    /// a connection succeeded in the lifetime of the client
+    ever_connected: bool,
+    /// publishes made by the clients while disconnected
+    offline_buffer: Option<Arc<OfflineBuffer>>,
 }
 
 impl Connection {
     /// Takes mqtt options and tries to create initial connection on current thread and handles
     /// connection events in a new thread if the initial connection is successful
-    pub fn run(mqttoptions: MqttOptions) -> Result<UserHandle, ConnectError> {
-        let (notification_tx, notification_rx) = crossbeam_channel::bounded(mqttoptions.notification_channel_capacity());
+    pub fn run(mqttoptions: MqttOptions, notification_tx: Box<dyn NotificationSender>) -> Result<UserHandle, ConnectError> {
         let (request_tx, request_rx) = mpsc::channel::<Request>(mqttoptions.request_channel_capacity());
         let (command_tx, command_rx) = mpsc::channel::<Command>(5);
 
         let (connection_tx, connection_rx) = crossbeam_channel::bounded(1);
         let reconnect_option = mqttoptions.reconnect_opts();
+        let has_reconnect_policy = mqttoptions.reconnect_policy().is_some();
+        let connection_stats = Arc::new(ConnectionStats::default());
+        let eventloop_connection_stats = connection_stats.clone();
+        let queue_stats = Arc::new(QueueStats::default());
+        let eventloop_queue_stats = queue_stats.clone();
+        let offline_buffer = mqttoptions.offline_buffer().map(|(messages, bytes)| Arc::new(OfflineBuffer::new(messages, bytes)));
+        let eventloop_offline_buffer = offline_buffer.clone();
+        let claim = match mqttoptions.duplicate_connect_protection() {
+            true => Some(ClientIdClaim::acquire(&mqttoptions)?),
+            false => None,
+        };
 
         // start the network thread to handle all mqtt network io
-        thread::spawn(move || {
+        let thread = thread::Builder::new().name(mqttoptions.thread_name());
+        let eventloop = thread.spawn(move || {
+            let _claim = claim;
+            if let Some(hook) = mqttoptions.thread_hook() {
+                hook.run();
+            }
+
             let mqtt_state = Rc::new(RefCell::new(MqttState::new(mqttoptions.clone())));
+            let sampling = Rc::new(RefCell::new(mqttoptions.sampling()));
+            let sequence_tracking = Rc::new(RefCell::new(mqttoptions.sequence_tracking()));
+            let notification_tx = GenerationSender::new(notification_tx, eventloop_connection_stats.clone());
+            let notification_tx = BacklogSender::new(
+                notification_tx,
+                mqttoptions.incoming_spill(),
+                mqttoptions.incoming_queue_policy(),
+                mqttoptions.notification_channel_capacity(),
+            );
             let mut connection = Connection {
                 mqtt_state,
-                notification_tx,
+                notification_tx: Rc::new(RefCell::new(notification_tx)),
                 connection_tx: Some(connection_tx),
                 connection_count: 0,
                 mqttoptions,
                 is_network_enabled: true,
+                connection_stats: eventloop_connection_stats,
+                queue_stats: eventloop_queue_stats,
+                sampling,
+                sequence_tracking,
+                started: Instant::now(),
+                connected_at: None,
+                flaps: 0,
+                taken_over: false,
+                reconnect_attempts: 0,
+                ever_connected: false,
+                offline_buffer: eventloop_offline_buffer,
             };
 
-            connection.mqtt_eventloop(request_rx, command_rx)
-        });
+            let (mut request_rx, mut command_rx) = (request_rx, command_rx);
+            capture_eventloop_panics();
+            loop {
+                let eventloop = panic::AssertUnwindSafe(|| connection.mqtt_eventloop(&mut request_rx, &mut command_rx));
+                if panic::catch_unwind(eventloop).is_ok() {
+                    break;
+                }
+
+                let report = LAST_PANIC.with(|last| last.borrow_mut().take()).unwrap_or_else(|| "Unknown panic".to_owned());
+                error!("Eventloop panicked. Restarting with the inflight state. {}", report);
+                connection.handle_disconnection();
+                handle_notification(Notification::Panicked(report), &connection.notification_tx);
+                if !connection.should_reconnect_again() {
+                    break;
+                }
+
+                // start over with a fresh state which carries the inflight publishes and
+                // subscriptions of the old one. they are resent and renewed on connection
+                let session = connection.mqtt_state.borrow().export_session();
+                let mqttoptions = connection.mqttoptions.clone().set_session(session);
+                connection.mqtt_state = Rc::new(RefCell::new(MqttState::new(mqttoptions)));
+            }
+        })?;
 
         // return user handle to client to send requests and handle notifications
         let user_handle = UserHandle {
             request_tx,
             command_tx,
-            notification_rx,
+            connection_stats,
+            queue_stats,
+            offline_buffer,
+            eventloop: Arc::new(Mutex::new(Some(eventloop))),
         };
 
         match reconnect_option {
+            // policy decides about retrying the first connection too
+            _ if has_reconnect_policy => {
+                let _ = connection_rx.recv()?;
+            }
             ReconnectOptions::AfterFirstSuccess(_) => connection_rx.recv()??,
             ReconnectOptions::Never => connection_rx.recv()??,
             ReconnectOptions::Always(_) => {
@@ -81,22 +187,34 @@ impl Connection {
     // NOTE: We need to use same reactor across threads because io resources (framed) will
     //       bind to reactor lazily.
     //       You'll face `reactor gone` error if `framed` is used again with a new recator
-    fn mqtt_eventloop(&mut self, mut request_rx: Receiver<Request>, mut command_rx: Receiver<Command>) {
+    fn mqtt_eventloop(&mut self, request_rx: &mut Receiver<Request>, command_rx: &mut Receiver<Command>) {
         let mut prepended_request_stream = self.prepend_stream(request_rx.by_ref());
         let mut command_stream = self.command_stream(command_rx.by_ref());
 
         'reconnection: loop {
+            if self.connection_count > 0 {
+                self.notify_state(ConnectionState::Reconnecting);
+            }
+
+            if let Some(limiter) = self.mqttoptions.reconnect_limiter() {
+                limiter.acquire();
+            }
+
+            if let Some(hook) = self.mqttoptions.before_reconnect() {
+                if self.reconnect_attempts > 0 {
+                    hook.run(&self.attempt());
+                }
+            }
 
             let mqtt_connect_future = self.mqtt_connect();
-            let timeout = Duration::from_secs(30);
-            let (runtime, framed) = match self.connect_timeout(mqtt_connect_future, timeout) {
+            let (runtime, framed) = match self.connect_timeout(mqtt_connect_future) {
                 Ok(f) => f,
                 Err(true) => continue 'reconnection,
                 Err(false) => break 'reconnection,
             };
 
             let (network_sink, network_stream) = framed.split();
-            let network_sink = network_sink.sink_map_err(|e| NetworkError::Io(e));
+            let network_sink = network_sink.sink_map_err(NetworkError::Io);
             let network_reply_stream = self.network_reply_stream(network_stream);
             let prepended_request_stream = &mut prepended_request_stream;
             let command_stream = &mut command_stream;
@@ -112,7 +230,16 @@ impl Connection {
                                                network_sink);
 
             // let mqtt_future = network_stream.select(command_stream).forward(network_sink);
-            match self.mqtt_io(runtime, mqtt_future) {
+            let io = self.mqtt_io(runtime, mqtt_future);
+            self.handle_disconnection();
+            if self.taken_over {
+                break 'reconnection;
+            }
+
+            // pick up options changed while the eventloop is running
+            self.mqttoptions = self.mqtt_state.borrow().opts.clone();
+
+            match io {
                 Err(true) => continue 'reconnection,
                 Err(false) => (),
                 Ok(_v) => ()
@@ -126,16 +253,11 @@ impl Connection {
     }
 
     /// Makes a blocking mqtt connection an returns framed and reactor
-    fn connect_timeout(
-        &mut self,
-        mqtt_connect_future: impl FramedFuture,
-        timeout: Duration,
-    ) -> Result<(Runtime, MqttFramed), bool> {
-        // mqtt connection
+    fn connect_timeout(&mut self, mqtt_connect_future: impl FramedFuture) -> Result<(Runtime, MqttFramed), bool> {
+        // mqtt connection. connect and connack timeouts are part of the future
         let mut rt = Runtime::new().unwrap();
-        let mqtt_connect_deadline = Timeout::new(mqtt_connect_future, timeout);
 
-        let framed = match rt.block_on(mqtt_connect_deadline) {
+        let framed = match rt.block_on(mqtt_connect_future) {
             Ok(framed) => {
                 info!("Mqtt connection successful!!");
                 self.handle_connection_success();
@@ -151,11 +273,38 @@ impl Connection {
         Ok((rt, framed))
     }
 
-    fn should_reconnect_again(&self) -> bool {
+    fn should_reconnect_again(&mut self) -> bool {
         let reconnect_options = self.mqttoptions.reconnect_opts();
         let is_disconnecting = self.mqtt_state.clone().borrow().is_disconnecting();
+        if is_disconnecting {
+            return false;
+        }
+
+        // first attempt follows a lost connection or a failed first connection
+        self.reconnect_attempts += 1;
+        if let Some(max) = self.mqttoptions.max_reconnect_attempts() {
+            if self.reconnect_attempts > max {
+                self.give_up();
+                return false;
+            }
+        }
+
+        if let Some(policy) = self.mqttoptions.reconnect_policy() {
+            let attempt = self.attempt();
+            return match policy.next_attempt(&attempt) {
+                Some(delay) => {
+                    thread::sleep(delay);
+                    true
+                }
+                None => {
+                    info!("Reconnect policy gave up. Attempts = {}", attempt.attempt);
+                    self.give_up();
+                    false
+                }
+            };
+        }
 
-        let reconn_policy_action = match reconnect_options {
+        match reconnect_options {
             ReconnectOptions::AfterFirstSuccess(time) => {
                 let time = Duration::from_secs(time);
                 thread::sleep(time);
@@ -167,13 +316,36 @@ impl Connection {
                 true
             }
             ReconnectOptions::Never => false,
-        };
+        }
+    }
 
-        reconn_policy_action && !is_disconnecting
+    /// Current connection attempt. The first connection is attempt 1 as well
+    fn attempt(&self) -> Attempt {
+        Attempt {
+            attempt: self.reconnect_attempts.max(1),
+            connected_before: self.ever_connected,
+        }
+    }
+
+    fn give_up(&self) {
+        let failed = self.reconnect_attempts - 1;
+        error!("Giving up reconnecting. Failed attempts = {}", failed);
+        // last notification. wait for room instead of dropping it
+        if let Err(e) = self.notification_tx.borrow_mut().notify(Notification::ReconnectExhausted(failed)) {
+            error!("Notification send failed. Error = {:?}", e);
+        }
     }
 
     fn mqtt_io(&mut self, mut runtime: Runtime, mqtt_future: impl Future<Item = (), Error = NetworkError>) -> Result<(), bool> {
-        match runtime.block_on(mqtt_future) {
+        let out = runtime.block_on(mqtt_future);
+
+        let is_disconnecting = self.mqtt_state.borrow().is_disconnecting();
+        let reason = disconnect_reason(&out, is_disconnecting);
+        handle_notification(Notification::Disconnected(reason), &self.notification_tx);
+        self.notify_state(ConnectionState::Disconnected(reason));
+        self.detect_takeover(reason);
+
+        match out {
             Err(NetworkError::UserDisconnect) => {
                 info!("User commanded for network disconnect");
                 self.is_network_enabled = false;
@@ -185,7 +357,7 @@ impl Connection {
                 Err(true)
             }
             Err(NetworkError::NetworkStreamClosed) => {
-                let mqtt_state = self.mqtt_state.clone().borrow();
+                let mqtt_state = self.mqtt_state.borrow();
                 if mqtt_state.is_disconnecting() {
                     info!("Shutting down gracefully");
                 }
@@ -210,57 +382,251 @@ impl Connection {
         network_sink: impl PacketSink)
         -> impl Future<Item = (), Error = NetworkError> {
         // check if the network is enabled and create a future
-        let mqtt_state = self.mqtt_state.clone();
-        let keep_alive = self.mqttoptions.keep_alive();
+        let max_packets_per_turn = self.mqttoptions.max_packets_per_turn();
 
         // convert a reply request stream to reply packet stream after filtering
         // unnecessary requests
-        let network_reply_stream = Timeout::new(network_reply_stream, keep_alive)
-                                        .or_else(move |e| {
-                                            let mut mqtt_state = mqtt_state.borrow_mut();
-                                            handle_stream_timeout_error(e, &mut mqtt_state)
-                                        })
-                                        .filter(|reply| should_forward_packet(reply))
+        let network_reply_stream = network_reply_stream
+                                        .filter(should_forward_packet)
                                         .and_then(move |packet| future::ok(packet.into()));
 
         // convert a request request stream to request packet stream after filtering
         // unnecessary requests
         let network_request_stream = network_request_stream
+                                        .filter(should_forward_packet)
                                         .and_then(move |packet| future::ok(packet.into()));
-        let network_stream = network_reply_stream.select(network_request_stream);
+        // acks, pings and user commands always go ahead of the publish backlog
+        let control_stream = command_stream
+            .select(self.ping_stream())
+            .select(self.radio_window_stream());
+        let background_stream = self
+            .retransmission_stream()
+            .select(self.presence_stream())
+            .select(self.backlog_stream());
 
         if self.is_network_enabled {
-            Either::A(command_stream
-                    .select(network_stream)
+            Either::A(network_reply_stream
+                    .select(control_stream)
+                    .prioritize(network_request_stream.select(background_stream))
+                    .budget(max_packets_per_turn)
                     .forward(network_sink)
                     .map(|(_selct, _splitsink)| ()))
         } else {
-            Either::B(command_stream.forward(network_sink).map(|(_selct, _splitsink)| ()))
+            Either::B(control_stream.select(background_stream).forward(network_sink).map(|(_selct, _splitsink)| ()))
+        }
+    }
+
+
+
+    /// Notifies the user when power saving transmission windows open and close.
+    /// Never yields a packet
+    fn radio_window_stream(&self) -> impl PacketStream {
+        let power_saving = match self.mqttoptions.power_saving() {
+            Some(power_saving) => power_saving,
+            None => return Either::A(stream::empty()),
+        };
+
+        let notification_tx = self.notification_tx.clone();
+        let first_window = Instant::now() + power_saving.next_window(self.started.elapsed());
+        let windows = Interval::new(first_window, power_saving.interval)
+            .map_err(NetworkError::from)
+            .and_then(move |_| {
+                handle_notification(Notification::RadioActive, &notification_tx);
+                let notification_tx = notification_tx.clone();
+                tokio_timer::sleep(power_saving.window)
+                    .map_err(NetworkError::from)
+                    .map(move |_| {
+                        handle_notification(Notification::RadioIdle, &notification_tx);
+                    })
+            })
+            .filter_map(|_| None);
+
+        Either::B(windows)
+    }
+
+    /// Resends publishes and pubrels which aren't acknowledged within the retry interval
+    /// Pings once nothing was sent for a keep alive and fails when the ping response
+    /// doesn't arrive within 1.5 keep alives. Outgoing packets push the ping further
+    fn ping_stream(&self) -> impl PacketStream {
+        let mqtt_state = self.mqtt_state.clone();
+        let mut delay = Delay::new(mqtt_state.borrow().keep_alive_deadline());
+
+        stream::poll_fn(move || loop {
+            let deadline = mqtt_state.borrow().keep_alive_deadline();
+            if delay.deadline() != deadline {
+                delay.reset(deadline);
+            }
+
+            try_ready!(delay.poll());
+            if let Request::Ping = mqtt_state.borrow_mut().handle_outgoing_mqtt_packet(Packet::Pingreq)? {
+                return Ok(Async::Ready(Some(Packet::Pingreq)));
+            }
+        })
+    }
+
+    fn retransmission_stream(&self) -> impl PacketStream {
+        let interval = match self.mqttoptions.retry_interval() {
+            Some(interval) => interval,
+            None => return Either::A(stream::empty()),
+        };
+
+        // publishes expire between ticks. tick often enough to resend them close to the interval
+        let tick = interval.min(Duration::from_secs(1));
+        let mqtt_state = self.mqtt_state.clone();
+        let retransmissions = Interval::new_interval(tick)
+            .map_err(NetworkError::from)
+            .map(move |_| stream::iter_ok(mqtt_state.borrow_mut().handle_retransmissions()))
+            .flatten();
+
+        Either::B(retransmissions)
+    }
+
+    /// Feeds publishes held back by the incoming spill (or the drop oldest queue
+    /// policy) to the user as the notification channel frees up. Never yields a packet
+    fn backlog_stream(&self) -> impl PacketStream {
+        if self.mqttoptions.incoming_spill().is_none() && self.mqttoptions.incoming_queue_policy() != QueuePolicy::DropOldest {
+            return Either::A(stream::empty());
         }
+
+        let notification_tx = self.notification_tx.clone();
+        let drains = Interval::new_interval(Duration::from_millis(100))
+            .map_err(NetworkError::from)
+            .filter_map(move |_| {
+                notification_tx.borrow_mut().drain();
+                None
+            });
+
+        Either::B(drains)
+    }
+
+    fn presence_stream(&self) -> impl PacketStream {
+        let presence = match self.mqttoptions.presence() {
+            Some(presence) => presence,
+            None => return Either::A(stream::empty()),
+        };
+
+        let mqtt_state = self.mqtt_state.clone();
+        let updates = Interval::new(Instant::now(), presence.interval)
+            .map_err(NetworkError::from)
+            .and_then(move |_| mqtt_state.borrow_mut().handle_outgoing_mqtt_packet(Packet::Publish(presence.publish())))
+            .filter(should_forward_packet)
+            .map(Packet::from);
+
+        Either::B(updates)
     }
 
+    /// Counts connections closed by the broker right after connack and notifies a
+    /// takeover once they are in a row
+    fn detect_takeover(&mut self, reason: DisconnectReason) {
+        let detection = match self.mqttoptions.takeover_detection() {
+            Some(detection) => detection,
+            None => return,
+        };
+
+        let lived = self.connected_at.take().map(|at| at.elapsed()).unwrap_or_default();
+        if !detection.is_flap(lived, reason) {
+            self.flaps = 0;
+            return;
+        }
 
+        self.flaps += 1;
+        if self.flaps < detection.flaps {
+            return;
+        }
+
+        warn!("Client id taken over by another client. Flaps = {}", self.flaps);
+        self.flaps = 0;
+        handle_notification(Notification::TakenOver, &self.notification_tx);
+        self.taken_over = detection.action == TakeoverAction::Stop;
+    }
 
     fn handle_connection_success(&mut self) {
+        if let Some(hook) = self.mqttoptions.after_connect() {
+            hook.run(&self.attempt());
+        }
+
+        self.connected_at = Some(Instant::now());
         self.connection_count += 1;
+        self.reconnect_attempts = 0;
+        self.ever_connected = true;
+        let keep_alive = self.mqtt_state.borrow().opts.keep_alive();
+        self.connection_stats.set_connected(keep_alive);
+        self.notify_state(ConnectionState::Connected);
 
         if self.connection_count == 1 {
             let connection_tx = self.connection_tx.take().unwrap();
             connection_tx.send(Ok(())).unwrap();
+            self.redeliver_stored_publishes();
         }
     }
 
-    fn handle_connection_error(&mut self, error: timeout::Error<ConnectError>) {
-        self.connection_count += 1;
+    /// Marks the connection down. Clients buffer their publishes from now on
+    fn handle_disconnection(&self) {
+        self.connection_stats.set_disconnected();
+        if let Some(buffer) = &self.offline_buffer {
+            buffer.go_offline();
+        }
+    }
 
-        let error = match error.into_inner() {
-            Some(e) => Err(e),
-            None => Err(ConnectError::Timeout),
-        };
+    /// Hands over persisted publishes of the previous run to the user. Blocks till
+    /// there is space in the notification channel so that nothing is dropped
+    fn redeliver_stored_publishes(&mut self) {
+        let publishes = self.mqtt_state.borrow_mut().handle_stored_incoming();
+
+        let payload_spill = self.mqttoptions.payload_spill();
+        #[cfg(feature = "compression")]
+        let decompression_limit = self.mqttoptions.max_packet_size();
+        let transformers = self.mqttoptions.transformers();
+        let validators = self.mqttoptions.validators();
+        let authorizer = self.mqttoptions.incoming_authorizer();
+        for publish in publishes {
+            debug!("Redelivering stored publish. Pkid = {:?}", publish.pkid);
+            let pkid = publish.pkid;
+            let notification = match authorize_incoming(Notification::Publish(publish), &authorizer) {
+                Ok(notification) => notification,
+                Err(publish) => {
+                    self.mqtt_state.borrow_mut().handle_incoming_rejected(&publish, Request::None);
+                    continue;
+                }
+            };
+
+            let notification = transform_incoming(notification, &transformers);
+            #[cfg(feature = "compression")]
+            let notification = decompress_incoming(notification, decompression_limit);
+            let notification = validate_incoming(notification, &validators);
+            let notification = spill_large_payload(notification, &payload_spill);
+            if let Err(e) = self.notification_tx.borrow_mut().notify(notification) {
+                error!("Notification send failed. Error = {:?}", e);
+                break;
+            }
+
+            if let Some(pkid) = pkid {
+                self.mqtt_state.borrow_mut().handle_incoming_delivered(pkid);
+            }
+        }
+    }
+
+    fn handle_connection_error(&mut self, error: ConnectError) {
+        self.connection_count += 1;
+        self.connection_stats.add_connect_failure(&error);
+        self.notify_state(ConnectionState::ConnectFailed(error.to_string()));
 
         if self.connection_count == 1 {
             let connection_tx = self.connection_tx.take().unwrap();
-            connection_tx.send(error).unwrap();
+            connection_tx.send(Err(error)).unwrap();
+        }
+    }
+
+    /// Sends the state transition to the state channel, if any, without blocking
+    fn notify_state(&self, state: ConnectionState) {
+        let tx = match self.mqttoptions.state_channel() {
+            Some(tx) => tx,
+            None => return,
+        };
+
+        let change = StateChange { state, at: SystemTime::now() };
+        if tx.try_send(change).is_err() {
+            debug!("State channel full or closed");
         }
     }
 
@@ -274,10 +640,23 @@ impl Connection {
 
         let builder = NetworkStream::builder();
 
-        let builder = match connection_method {
-            ConnectionMethod::Tls(ca, Some((cert, key))) => builder.add_certificate_authority(&ca).add_client_auth(&cert, &key),
-            ConnectionMethod::Tls(ca, None) => builder.add_certificate_authority(&ca),
-            ConnectionMethod::Tcp => builder,
+        let tls_options = connection_method.tls_options();
+        #[cfg(feature = "rustls")]
+        let is_tls = tls_options.is_some();
+        let builder = match tls_options {
+            Some(tls_options) => builder.set_tls_options(tls_options),
+            None => builder,
+        };
+
+        #[cfg(feature = "rustls")]
+        let builder = match self.mqttoptions.client_auth_signer() {
+            Some((cert, signer)) if is_tls => builder.add_client_auth_signer(&cert, signer),
+            _ => builder,
+        };
+
+        let builder = match self.mqttoptions.resolver() {
+            Some(resolver) => builder.set_resolver(resolver),
+            None => builder,
         };
 
         let builder = match proxy {
@@ -288,46 +667,96 @@ impl Connection {
             }
         };
 
-        builder.connect(&host, port)
+        let max_packet_size = self.mqttoptions.max_packet_size();
+        let will_message = self.mqttoptions.last_will_bytes();
+        builder.connect(&host, port).map(move |mut framed| {
+            framed.codec_mut().set_max_packet_size(max_packet_size);
+            framed.codec_mut().set_will_message(will_message);
+            framed
+        })
     }
 
     /// Composes a new future which is a combination of tcp connect + mqtt handshake
     fn mqtt_connect(&self) -> impl Future<Item = MqttFramed, Error = ConnectError> {
         let mqtt_state = self.mqtt_state.clone();
-        let tcp_connect_future = self.tcp_connect_future();
+        let tcp_connect_future = Timeout::new(self.tcp_connect_future(), self.mqttoptions.connect_timeout())
+            .map_err(|e| e.into_inner().unwrap_or(ConnectError::Timeout));
+        let connack_timeout = self.mqttoptions.connack_timeout();
         let connect_packet = self.mqtt_state.borrow_mut().handle_outgoing_connect().unwrap();
 
-        tcp_connect_future
-            .and_then(move |framed| {
-                let packet = Packet::Connect(connect_packet);
-                framed.send(packet).map_err(ConnectError::Io)
-            })
-            .and_then(|framed| framed.into_future().map_err(|(err, _framed)| ConnectError::Io(err)))
-            .and_then(move |(response, framed)| {
-                info!("Mqtt connect response = {:?}", response);
-                let mut mqtt_state = mqtt_state.borrow_mut();
-                check_and_validate_connack(response, framed, &mut mqtt_state)
-            })
+        tcp_connect_future.and_then(move |framed| {
+            let packet = Packet::Connect(connect_packet);
+            let connack = framed
+                .send(packet)
+                .map_err(ConnectError::Io)
+                .and_then(|framed| framed.into_future().map_err(|(err, _framed)| ConnectError::Io(err)));
+
+            Timeout::new(connack, connack_timeout)
+                .map_err(|e| e.into_inner().unwrap_or(ConnectError::ConnackTimeout))
+                .and_then(move |(response, framed)| {
+                    info!("Mqtt connect response = {:?}", response);
+                    let mut mqtt_state = mqtt_state.borrow_mut();
+                    check_and_validate_connack(response, framed, &mut mqtt_state)
+                })
+        })
     }
 
     /// Handles all incoming network packets (including sending notifications to user over crossbeam
     /// channel) and creates a stream of packets to send on network
     fn network_reply_stream(&self, network_stream: SplitStream<MqttFramed>) -> impl RequestStream {
         let mqtt_state = self.mqtt_state.clone();
+        let delivery_state = self.mqtt_state.clone();
         let notification_tx = self.notification_tx.clone();
+        let payload_spill = self.mqttoptions.payload_spill();
+        #[cfg(feature = "compression")]
+        let decompression_limit = self.mqttoptions.max_packet_size();
+        let transformers = self.mqttoptions.transformers();
+        let validators = self.mqttoptions.validators();
+        let authorizer = self.mqttoptions.incoming_authorizer();
+        let sampling = self.sampling.clone();
+        let sequence_tracking = self.sequence_tracking.clone();
+        let topic_stats = self.mqttoptions.topic_stats();
+        let connection_stats = self.connection_stats.clone();
         let network_stream = network_stream
             .map_err(NetworkError::Io)
             .and_then(move |packet| {
                 debug!("Incoming packet = {:?}", packet_info(&packet));
-                let reply = mqtt_state.borrow_mut().handle_incoming_mqtt_packet(packet);
+                let mut mqtt_state = mqtt_state.borrow_mut();
+                let reply = mqtt_state.handle_incoming_mqtt_packet(packet);
+                connection_stats.set_pkid_exhausted(mqtt_state.is_pkid_exhausted());
                 future::result(reply)
             })
             .and_then(move |(notification, reply)| {
-                handle_notification(notification, &notification_tx);
+                if let Notification::Publish(ref publish) = notification {
+                    sampling.borrow_mut().observe(publish, true);
+                    topic_stats.observe(&publish.topic_name, publish.payload.len(), true);
+                }
+
+                let (notification, reply) = match authorize_incoming(notification, &authorizer) {
+                    Ok(notification) => (notification, reply),
+                    Err(publish) => (Notification::None, delivery_state.borrow_mut().handle_incoming_rejected(&publish, reply)),
+                };
+
+                let pkid = persisted_pkid(&notification);
+                let notification = transform_incoming(notification, &transformers);
+                #[cfg(feature = "compression")]
+                let notification = decompress_incoming(notification, decompression_limit);
+                if let Notification::Publish(ref publish) = notification {
+                    if let Some(gap) = sequence_tracking.borrow_mut().observe(&publish.topic_name, &publish.payload) {
+                        warn!("Sequence gap. Topic = {}, Expected = {}, Received = {}", gap.topic, gap.expected, gap.received);
+                        handle_notification(Notification::SequenceGap(gap), &notification_tx);
+                    }
+                }
+
+                let notification = validate_incoming(notification, &validators);
+                let notification = spill_large_payload(notification, &payload_spill);
+                if let (true, Some(pkid)) = (handle_notification(notification, &notification_tx), pkid) {
+                    delivery_state.borrow_mut().handle_incoming_delivered(pkid);
+                }
+
                 future::ok(reply)
             })
-            .filter(|reply| should_forward_packet(reply))
-            .and_then(move |packet| future::ok(packet));
+            .filter(should_forward_packet);
 
         network_stream.chain(stream::once(Err(NetworkError::NetworkStreamClosed)))
     }
@@ -336,6 +765,12 @@ impl Connection {
         let mqtt_state = self.mqtt_state.clone();
         let last_session_publishes = mqtt_state.borrow_mut().handle_reconnection();
         previous_request_stream.merge_session(last_session_publishes);
+
+        // publishes buffered while disconnected follow the unacked ones
+        if let Some(buffer) = &self.offline_buffer {
+            let buffered = buffer.go_online().into_iter().map(Request::Publish).collect();
+            previous_request_stream.merge_session(buffered);
+        }
     }
 
     /// Handles all incoming user and session requests and creates a stream of packets to send
@@ -365,20 +800,102 @@ impl Connection {
     fn request_stream(&mut self, request: impl RequestStream) -> impl RequestStream {
         // process user requests and convert them to network packets
         let mqtt_state = self.mqtt_state.clone();
+        let control_state = self.mqtt_state.clone();
+        let notification_tx = self.notification_tx.clone();
+        let queue_stats = self.queue_stats.clone();
+        let sampling = self.sampling.clone();
+        let topic_stats = self.mqttoptions.topic_stats();
+        let connection_stats = self.connection_stats.clone();
+        let scoped_state = self.mqtt_state.clone();
+
+        // a full outgoing queue stops taking requests. acks of the reply stream, which
+        // are polled in the same task, open it again
+        let gate_state = self.mqtt_state.clone();
+        let mut request = request;
+        let request = stream::poll_fn(move || {
+            if gate_state.borrow().is_outgoing_queue_blocked() {
+                return Ok(Async::NotReady);
+            }
+
+            request.poll()
+        });
+
         let request_stream = request
             .map_err(|e| {
                 error!("User request error = {:?}", e);
                 NetworkError::Blah
             })
+            .filter(move |userrequest| match userrequest {
+                Request::Reconfigure(reconfigure) => {
+                    control_state.borrow_mut().handle_reconfigure(reconfigure.clone());
+                    handle_notification(Notification::Reconfigured(reconfigure.clone()), &notification_tx);
+                    false
+                }
+                Request::Flush(flush_tx) => {
+                    let _ = flush_tx.try_send(());
+                    false
+                }
+                Request::Watch(watch_tx) => {
+                    control_state.borrow_mut().handle_watch(watch_tx.clone());
+                    false
+                }
+                Request::Inflight(inflight_tx) => {
+                    let _ = inflight_tx.try_send(control_state.borrow().inflight());
+                    false
+                }
+                Request::ExportSession(session_tx) => {
+                    let _ = session_tx.try_send(control_state.borrow().export_session());
+                    false
+                }
+                Request::SelfTest(topic, self_test_tx) => {
+                    control_state.borrow_mut().handle_self_test(topic.clone(), self_test_tx.clone());
+                    false
+                }
+                Request::SelfTestDone(topic) => {
+                    control_state.borrow_mut().handle_self_test_done(topic);
+                    false
+                }
+                // user publishes don't have a pkid yet. session replays do
+                Request::Publish(publish) if publish.pkid.is_none() => {
+                    queue_stats.remove(publish.payload.len());
+                    sampling.borrow_mut().observe(publish, false);
+                    topic_stats.observe(&publish.topic_name, publish.payload.len(), false);
+                    true
+                }
+                Request::ConnectionPublish(publish, generation) => {
+                    queue_stats.remove(publish.payload.len());
+                    if *generation != connection_stats.generation() {
+                        warn!("Dropping publish of a dead connection. Topic = {}", publish.topic_name);
+                        return false;
+                    }
+
+                    sampling.borrow_mut().observe(publish, false);
+                    topic_stats.observe(&publish.topic_name, publish.payload.len(), false);
+                    true
+                }
+                _ => true,
+            })
+            .map(move |userrequest| match userrequest {
+                Request::ConnectionPublish(publish, _) => {
+                    Request::Publish(scoped_state.borrow_mut().handle_connection_scoped_publish(publish))
+                }
+                Request::ShutdownPending(pending_tx) => {
+                    let _ = pending_tx.try_send(scoped_state.borrow().pending_work());
+                    Request::Disconnect
+                }
+                userrequest => userrequest,
+            })
             .and_then(move |userrequest| {
                 let mut mqtt_state = mqtt_state.borrow_mut();
                 validate_userrequest(userrequest, &mut mqtt_state)
             });
 
         let mqtt_state = self.mqtt_state.clone();
+        let connection_stats = self.connection_stats.clone();
         request_stream.and_then(move |packet: Packet| {
             let mut mqtt_state = mqtt_state.borrow_mut();
             let o = mqtt_state.handle_outgoing_mqtt_packet(packet);
+            connection_stats.set_pkid_exhausted(mqtt_state.is_pkid_exhausted());
             future::result(o)
         })
     }
@@ -392,10 +909,12 @@ impl Connection {
                                     .mqttoptions
                                     .outgoing_queuelimit();
 
+        let power_saving = self.mqttoptions.power_saving();
+        let started = self.started;
+
         let mqtt_state = self.mqtt_state.clone();
 
         stream.and_then(move |request| {
-            let request = request;
             let mqtt_state = mqtt_state.borrow();
             let len = mqtt_state.publish_queue_len();
             debug!("Outgoing request = {:?}", request_info(&request));
@@ -407,6 +926,13 @@ impl Connection {
                 Either::B(nonthrottled_request(queuedelay, len, limit, request))
             }
         })
+        .and_then(move |request| {
+            // hold requests till the next transmission window in power saving mode
+            match power_saving.and_then(|power_saving| power_saving.time_to_window(started.elapsed())) {
+                Some(delay) => Either::A(tokio_timer::sleep(delay).map_err(NetworkError::from).map(|_| request)),
+                None => Either::B(future::ok(request)),
+            }
+        })
     }
 
     fn command_stream<'a>(&mut self, commands: &'a mut mpsc::Receiver<Command>) -> impl PacketStream + 'a {
@@ -460,41 +986,212 @@ fn nonthrottled_request(
     }
 }
 
-fn handle_stream_timeout_error(
-    error: timeout::Error<NetworkError>,
-    mqtt_state: &mut MqttState)
-    -> impl Future<Item = Request, Error = NetworkError> {
-    // check if a ping to the broker is necessary
-    let out = mqtt_state.handle_outgoing_mqtt_packet(Packet::Pingreq);
-    future::err(error).or_else(move |e| {
-        if e.is_elapsed() {
-            match out {
-                Ok(packet) => future::ok(packet),
-                Err(e) => future::err(e),
-            }
-        } else {
-            future::err(e.into_inner().unwrap())
-        }
-    })
-}
-
 fn validate_userrequest(userrequest: Request, mqtt_state: &mut MqttState) -> impl PacketFuture {
     match userrequest {
         Request::Reconnect(mqttoptions) => {
-            mqtt_state.opts = mqttoptions;
+            mqtt_state.opts = *mqttoptions;
+            future::err(NetworkError::UserReconnect)
+        }
+        Request::SetCredentials(username, password) => {
+            info!("Reconnecting with new credentials. Username = {}", username);
+            let security = SecurityOptions::UsernamePassword(username, password);
+            mqtt_state.opts = mqtt_state.opts.clone().set_security_opts(security);
             future::err(NetworkError::UserReconnect)
         }
         _ => future::ok(userrequest.into()),
     }
 }
 
-fn handle_notification(notification: Notification, notification_tx: &Sender<Notification>) {
+/// Classifies the error which ended the eventloop
+fn disconnect_reason(out: &Result<(), NetworkError>, is_disconnecting: bool) -> DisconnectReason {
+    match out {
+        _ if is_disconnecting => DisconnectReason::User,
+        Err(NetworkError::UserDisconnect) | Err(NetworkError::UserReconnect) => DisconnectReason::User,
+        Err(NetworkError::NetworkStreamClosed) => DisconnectReason::BrokerClosed,
+        Err(NetworkError::Io(e)) => match e.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => DisconnectReason::Reset,
+            // broker closed the socket while a packet was being written
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe => DisconnectReason::BrokerClosed,
+            io::ErrorKind::TimedOut => DisconnectReason::Timeout,
+            _ => DisconnectReason::Io,
+        },
+        Err(NetworkError::AwaitPingResp) | Err(NetworkError::Timeout) | Err(NetworkError::TimeOut(_)) => DisconnectReason::Timeout,
+        _ => DisconnectReason::Io,
+    }
+}
+
+/// Forwards the notification to the user. Returns `true` if it's handed over
+fn handle_notification(notification: Notification, notification_tx: &RefCell<BacklogSender>) -> bool {
+    match notification {
+        Notification::None => false,
+        _ => match notification_tx.borrow_mut().try_notify(notification) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Notification send failed. Error = {:?}", e);
+                false
+            }
+        },
+    }
+}
+
+/// Returns publishes rejected by the authorizer as error
+fn authorize_incoming(notification: Notification, authorizer: &Option<Authorization>) -> Result<Notification, Publish> {
+    let (publish, authorizer) = match (notification, authorizer) {
+        (Notification::Publish(publish), Some(authorizer)) => (publish, authorizer),
+        (notification, _) => return Ok(notification),
+    };
+
+    match authorizer.authorize(&publish.topic_name, publish.payload.len(), publish.qos) {
+        Ok(()) => Ok(Notification::Publish(publish)),
+        Err(reason) => {
+            warn!("Incoming publish rejected. Topic = {}, Reason = {}", publish.topic_name, reason);
+            Err(publish)
+        }
+    }
+}
+
+/// Reverts the outgoing transformations of the topic. Publishes failing the
+/// inverse chain are converted to invalid notifications with the payload as received
+fn transform_incoming(notification: Notification, transformers: &Transformers) -> Notification {
     match notification {
-        Notification::None => (),
-        _ => match notification_tx.try_send(notification) {
-            Ok(()) => (),
-            Err(e) => error!("Notification send failed. Error = {:?}", e),
+        Notification::Publish(mut publish) if transformers.applies(&publish.topic_name) => {
+            match transformers.decode(&publish.topic_name, publish.payload.to_vec()) {
+                Ok(payload) => {
+                    publish.payload = Arc::new(payload);
+                    Notification::Publish(publish)
+                }
+                Err(reason) => {
+                    warn!("Failed to transform incoming publish. Topic = {}, Reason = {}", publish.topic_name, reason);
+                    Notification::Invalid(publish, reason)
+                }
+            }
+        }
+        notification => notification,
+    }
+}
+
+/// Converts publishes failing validation to invalid notifications
+fn validate_incoming(notification: Notification, validators: &Validators) -> Notification {
+    match notification {
+        Notification::Publish(publish) => match validators.validate(&publish.topic_name, &publish.payload) {
+            Ok(()) => Notification::Publish(publish),
+            Err(reason) => {
+                warn!("Invalid incoming publish. Topic = {}, Reason = {}", publish.topic_name, reason);
+                Notification::Invalid(publish, reason)
+            }
         },
+        notification => notification,
+    }
+}
+
+thread_local! {
+    // eventloop threads keep the message and backtrace of their last panic
+    static CAPTURE_PANICS: Cell<bool> = const { Cell::new(false) };
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Client ids (along with the broker) of the eventloops of this process
+/// which claimed them
+static CLAIMED_CLIENT_IDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Claim on a client id by an eventloop. Released on drop
+struct ClientIdClaim(String);
+
+impl ClientIdClaim {
+    fn acquire(mqttoptions: &MqttOptions) -> Result<ClientIdClaim, ConnectError> {
+        let (host, port) = mqttoptions.broker_address();
+        let key = format!("{}@{}:{}", mqttoptions.client_id(), host, port);
+        let mut claimed = CLAIMED_CLIENT_IDS.lock().unwrap_or_else(|e| e.into_inner());
+        if claimed.contains(&key) {
+            return Err(ConnectError::AlreadyConnecting(mqttoptions.client_id()));
+        }
+
+        claimed.push(key.clone());
+        Ok(ClientIdClaim(key))
+    }
+}
+
+impl Drop for ClientIdClaim {
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED_CLIENT_IDS.lock().unwrap_or_else(|e| e.into_inner());
+        claimed.retain(|key| *key != self.0);
+    }
+}
+
+/// Chains a panic hook which captures the backtrace of panics on this thread
+fn capture_eventloop_panics() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CAPTURE_PANICS.with(Cell::get) {
+                let report = format!("{}\n{}", info, Backtrace::force_capture());
+                LAST_PANIC.with(|last| *last.borrow_mut() = Some(report));
+            }
+
+            previous(info)
+        }));
+    });
+
+    CAPTURE_PANICS.with(|capture| capture.set(true));
+}
+
+/// Decompresses marked payloads. Publish is delivered as is if decompression fails
+#[cfg(feature = "compression")]
+fn decompress_incoming(notification: Notification, limit: usize) -> Notification {
+    match notification {
+        Notification::Publish(mut publish) if compression::is_compressed(&publish.payload) => {
+            match compression::decompress(&publish.payload, limit) {
+                Ok(payload) => publish.payload = Arc::new(payload),
+                Err(e) => warn!("Failed to decompress payload. Topic = {}, Error = {}", publish.topic_name, e),
+            }
+
+            Notification::Publish(publish)
+        }
+        notification => notification,
+    }
+}
+
+/// Writes payloads above the spill threshold to the sink and converts the publish
+/// notification to a file notification. Publish is delivered as is if the write fails
+fn spill_large_payload(notification: Notification, payload_spill: &Option<(usize, PayloadSinkHandle)>) -> Notification {
+    match (notification, payload_spill) {
+        (Notification::Publish(publish), Some((threshold, sink))) if publish.payload.len() > *threshold => {
+            match write_payload(sink, &publish.topic_name, &publish.payload) {
+                Ok(path) => Notification::PublishFile(PublishFile {
+                    topic_name: publish.topic_name,
+                    qos: publish.qos,
+                    retain: publish.retain,
+                    pkid: publish.pkid,
+                    path,
+                    len: publish.payload.len(),
+                }),
+                Err(e) => {
+                    error!("Failed to write payload to sink. Error = {:?}", e);
+                    Notification::Publish(publish)
+                }
+            }
+        }
+        (notification, _) => notification,
+    }
+}
+
+/// Blocks the eventloop till the whole payload is written. Payload is fully
+/// received by then and its memory is released only after the write
+fn write_payload(sink: &PayloadSinkHandle, topic: &str, payload: &[u8]) -> io::Result<PathBuf> {
+    let (mut writer, path) = sink.open(topic, payload.len())?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(path)
+}
+
+/// Packet id of the incoming publishes which are persisted until handed over
+fn persisted_pkid(notification: &Notification) -> Option<PacketIdentifier> {
+    match notification {
+        Notification::Publish(publish) if publish.qos != QoS::AtMostOnce => publish.pkid,
+        Notification::PublishFile(publish) if publish.qos != QoS::AtMostOnce => publish.pkid,
+        _ => None,
     }
 }
 
@@ -506,16 +1203,13 @@ fn check_and_validate_connack(packet: Option<Packet>, framed: MqttFramed, mqtt_s
             Err(err) => future::err(err),
             _ => future::ok(framed),
         },
-        Some(packet) => future::err(ConnectError::NotConnackPacket(packet)),
+        Some(packet) => future::err(ConnectError::NotConnackPacket(Box::new(packet))),
         None => future::err(ConnectError::NoResponse),
     }
 }
 
 fn should_forward_packet(reply: &Request) -> bool {
-    match reply {
-        Request::None => false,
-        _ => true,
-    }
+    !matches!(reply, Request::None)
 }
 
 fn packet_info(packet: &Packet) -> String {
@@ -578,18 +1272,12 @@ impl<T> PacketStream for T where T: Stream<Item = Packet, Error = NetworkError>
 trait PacketSink: Sink<SinkItem = Packet, SinkError = NetworkError> {}
 impl<T> PacketSink for T where T: Sink<SinkItem = Packet, SinkError = NetworkError> {}
 
-trait CommandStream: Stream<Item = Command, Error = NetworkError> {}
-impl<T> CommandStream for T where T: Stream<Item = Command, Error = NetworkError> {}
-
 trait RequestStream: Stream<Item = Request, Error = NetworkError> {}
 impl<T> RequestStream for T where T: Stream<Item = Request, Error = NetworkError> {}
 
 trait PacketFuture: Future<Item = Packet, Error = NetworkError> {}
 impl<T> PacketFuture for T where T: Future<Item = Packet, Error = NetworkError> {}
 
-trait RequestFuture: Future<Item = Request, Error = NetworkError> {}
-impl<T> RequestFuture for T where T: Future<Item = Request, Error = NetworkError> {}
-
 trait FramedFuture: Future<Item = MqttFramed, Error = ConnectError> {}
 impl<T> FramedFuture for T where T: Future<Item = MqttFramed, Error = ConnectError> {}
 
@@ -607,3 +1295,82 @@ impl<T> FramedFuture for T where T: Future<Item = MqttFramed, Error = ConnectErr
 //         debug!("{:?}", last_session_data);
 //         debug!("{:?}", state.publish_queue_len())
 // }
+
+#[cfg(test)]
+mod test {
+    use super::{persisted_pkid, spill_large_payload};
+    use crate::client::Notification;
+    use crate::mqttoptions::MqttOptions;
+    use mqtt311::{PacketIdentifier, Publish, QoS};
+    use std::{
+        env, fs,
+        io::{self, Write},
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    fn publish(payload: Vec<u8>) -> Notification {
+        Notification::Publish(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "ota/image".to_owned(),
+            pkid: Some(PacketIdentifier(7)),
+            payload: Arc::new(payload),
+        })
+    }
+
+    #[test]
+    fn payloads_above_the_threshold_should_be_written_to_files() {
+        let dir = env::temp_dir().join(format!("rumqtt-payload-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let spill = MqttOptions::new("test-id", "localhost", 1883).set_payload_spill(4, &dir).payload_spill();
+
+        match spill_large_payload(publish(vec![1, 2, 3, 4]), &spill) {
+            Notification::Publish(publish) => assert_eq!(*publish.payload, vec![1, 2, 3, 4]),
+            n => panic!("Expecting in memory publish. Received = {:?}", n),
+        }
+
+        let file = match spill_large_payload(publish(vec![1, 2, 3, 4, 5]), &spill) {
+            Notification::PublishFile(file) => file,
+            n => panic!("Expecting publish file. Received = {:?}", n),
+        };
+
+        assert_eq!(file.topic_name, "ota/image");
+        assert_eq!(file.len, 5);
+        assert!(file.path.starts_with(&dir));
+        assert_eq!(fs::read(&file.path).unwrap(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(persisted_pkid(&Notification::PublishFile(file)), Some(PacketIdentifier(7)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn payloads_should_be_written_to_the_user_sink() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let written = Shared::default();
+        let sink = written.clone();
+        let sink = move |topic: &str, len: usize| -> io::Result<(Box<dyn Write>, PathBuf)> {
+            Ok((Box::new(sink.clone()), PathBuf::from(format!("{}/{}", topic, len))))
+        };
+
+        let spill = MqttOptions::new("test-id", "localhost", 1883).set_payload_sink(2, sink).payload_spill();
+        match spill_large_payload(publish(vec![9; 3]), &spill) {
+            Notification::PublishFile(file) => assert_eq!(file.path, PathBuf::from("ota/image/3")),
+            n => panic!("Expecting publish file. Received = {:?}", n),
+        }
+
+        assert_eq!(*written.0.lock().unwrap(), vec![9; 3]);
+    }
+}
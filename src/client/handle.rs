@@ -0,0 +1,149 @@
+use crate::client::{Inflight, MqttClient};
+use crate::error::ClientError;
+use mqtt311::QoS;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Subscription reference counts and subscribed qos shared by all the handles of
+/// a client
+pub(crate) type SubscriptionRefs = Arc<Mutex<HashMap<String, (usize, QoS)>>>;
+
+/// Lightweight handle to a shared client for a component (plugin, task etc).
+/// Publishes go straight to the eventloop. Subscriptions are tracked per handle
+/// and reference counted across handles so that a component unsubscribing a
+/// filter doesn't affect other components subscribed to it. Dropping the handle
+/// releases its subscriptions
+pub struct ClientHandle {
+    client: MqttClient,
+    subscription_refs: SubscriptionRefs,
+    subscriptions: Vec<String>,
+}
+
+impl ClientHandle {
+    pub(crate) fn new(client: MqttClient, subscription_refs: SubscriptionRefs) -> ClientHandle {
+        ClientHandle {
+            client,
+            subscription_refs,
+            subscriptions: Vec::new(),
+        }
+    }
+
+    pub fn publish<S, V>(&mut self, topic: S, qos: QoS, retained: bool, payload: V) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        self.client.publish(topic, qos, retained, payload)
+    }
+
+    /// Publishes of the client waiting for acknowledgements. See [MqttClient::inflight]
+    ///
+    /// [MqttClient::inflight]: struct.MqttClient.html#method.inflight
+    pub fn inflight(&mut self, timeout: Duration) -> Result<Vec<Inflight>, ClientError> {
+        self.client.inflight(timeout)
+    }
+
+    /// Subscribes to the filter. Nothing is sent to the broker if another handle
+    /// is already subscribed to it with the same or a higher qos. A higher qos
+    /// renews the subscription with it. The qos of a filter is never lowered, not
+    /// even when the handle which raised it unsubscribes
+    pub fn subscribe<S: Into<String>>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError> {
+        let topic = topic.into();
+        let subscribed = self.subscriptions.contains(&topic);
+        let (send, previous) = {
+            let mut refs = self.subscription_refs.lock().unwrap();
+            let (count, subscribed_qos) = refs.entry(topic.clone()).or_insert((0, qos));
+            if !subscribed {
+                *count += 1;
+            }
+
+            let previous = *subscribed_qos;
+            let upgrade = qos.to_u8() > previous.to_u8();
+            if upgrade {
+                *subscribed_qos = qos;
+            }
+
+            ((*count == 1 && !subscribed) || upgrade, previous)
+        };
+
+        if send {
+            if let Err(e) = self.client.subscribe(topic.clone(), qos) {
+                if let Some((_, subscribed_qos)) = self.subscription_refs.lock().unwrap().get_mut(&topic) {
+                    *subscribed_qos = previous;
+                }
+
+                if !subscribed {
+                    self.release(&topic);
+                }
+
+                return Err(e);
+            }
+        }
+
+        if !subscribed {
+            self.subscriptions.push(topic);
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribes from the filter. Broker is asked to unsubscribe only when
+    /// this is the last handle subscribed to it
+    pub fn unsubscribe<S: Into<String>>(&mut self, topic: S) -> Result<(), ClientError> {
+        let topic = topic.into();
+        let index = match self.subscriptions.iter().position(|t| *t == topic) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        self.subscriptions.remove(index);
+        if self.release(&topic) {
+            self.client.unsubscribe(topic)?;
+        }
+
+        Ok(())
+    }
+
+    /// Filters subscribed through this handle
+    pub fn subscriptions(&self) -> &[String] {
+        &self.subscriptions
+    }
+
+    /// Unsubscribes all the filters of this handle
+    pub fn close(mut self) -> Result<(), ClientError> {
+        for topic in self.subscriptions.clone() {
+            self.unsubscribe(topic)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrements the reference count of the filter. Returns true if it's the last reference
+    fn release(&self, topic: &str) -> bool {
+        let mut refs = self.subscription_refs.lock().unwrap();
+        match refs.get_mut(topic) {
+            Some((count, _)) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                refs.remove(topic);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        for topic in self.subscriptions.clone() {
+            if let Err(e) = self.unsubscribe(topic) {
+                error!("Failed to unsubscribe a dropped handle. Error = {:?}", e);
+            }
+        }
+    }
+}
@@ -1,11 +1,32 @@
 //! Structs to interact with mqtt eventloop
+#[cfg(feature = "compression")]
+use crate::compression;
 use crate::error::{ClientError, ConnectError};
+use crate::fragment;
+use crate::mqttoptions::{BrokerCapabilities, DeadLetter, PkidExhaustion, PublishProfiles, Reconfigure, SubscriptionGuardrails};
+use crate::probe::ProbeHandle;
+use crate::sequence::Gap;
+use crate::session::Session;
+use crate::stats::{Snapshot, TopicStats};
+use crate::transform::Transformers;
+use crate::validation::Validators;
 use crate::MqttOptions;
 use crossbeam_channel;
 use futures::{sync::mpsc, Future, Sink};
-use mqtt311::{PacketIdentifier, Publish, QoS, Subscribe, Unsubscribe, SubscribeTopic};
-use std::sync::Arc;
+use mqtt311::{PacketIdentifier, Publish, QoS, Subscribe, SubscribeReturnCodes, Unsubscribe, SubscribeTopic};
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc as std_mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
+mod backlog;
+mod budget;
 #[doc(hidden)]
 pub mod connection;
 #[doc(hidden)]
@@ -14,25 +35,117 @@ pub mod mqttstate;
 pub mod network;
 #[doc(hidden)]
 pub mod prepend;
+mod priority;
+mod handle;
+mod notifier;
+mod offline;
+
+pub use self::handle::ClientHandle;
+use self::offline::OfflineBuffer;
+use self::handle::SubscriptionRefs;
+pub use self::notifier::{MessageHandler, MessageRef, NotificationSender, Tagged};
 
 /// Incoming notifications from the broker
 #[derive(Debug)]
 pub enum Notification {
     Publish(Publish),
+    PublishFile(PublishFile),
+    /// Incoming publish which failed validation along with the reason
+    Invalid(Publish, String),
+    /// Confirms the option changed on the running client
+    Reconfigured(Reconfigure),
+    /// Connection to the broker is lost
+    Disconnected(DisconnectReason),
+    /// Power saving transmission window opened. Radio is in use till `RadioIdle`
+    RadioActive,
+    /// Power saving transmission window closed. Radio can sleep till `RadioActive`
+    RadioIdle,
     PubAck(PacketIdentifier),
     PubRec(PacketIdentifier),
     PubRel(PacketIdentifier),
     PubComp(PacketIdentifier),
-    SubAck(PacketIdentifier),
+    /// Broker confirmed the subscribe with this packet id. Carries the granted qos
+    /// (or failure) of every filter of the subscribe
+    SubAck(PacketIdentifier, Vec<(String, SubscribeReturnCodes)>),
+    /// Broker confirmed the unsubscribe with this packet id
+    UnsubAck(PacketIdentifier),
+    /// Broker keeps closing the connection right after connack, which happens when
+    /// another client connects with the same client id. See
+    /// [takeover detection](../mqttoptions/struct.MqttOptions.html#method.set_takeover_detection)
+    TakenOver,
+    /// Eventloop panicked and restarted. Inflight publishes are resent and
+    /// subscriptions renewed. Carries the panic message and backtrace
+    Panicked(String),
+    /// Eventloop gave up reconnecting and stopped. Carries the number of failed
+    /// reconnection attempts. Nothing is notified after this
+    ReconnectExhausted(u32),
+    /// Sequence numbers were skipped on a topic. Sent before the publish which
+    /// revealed the gap. See
+    /// [sequence tracking](../mqttoptions/struct.MqttOptions.html#method.add_sequence_tracking)
+    SequenceGap(Gap),
     None,
 }
 
+/// Why the connection to the broker is lost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// Broker closed the connection in an orderly way (FIN). Also covers half
+    /// closed sockets as nothing more can be read from them
+    BrokerClosed,
+    /// Connection reset or aborted (RST)
+    Reset,
+    /// No response from the broker within keep alive
+    Timeout,
+    /// Other network errors
+    Io,
+    /// Disconnect, pause or reconnect requested by the user
+    User,
+}
+
+/// Connection state of the eventloop. See
+/// [state channel](../mqttoptions/struct.MqttOptions.html#method.set_state_channel)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// Broker accepted the connection
+    Connected,
+    /// Established connection is lost
+    Disconnected(DisconnectReason),
+    /// Connection attempt failed. Carries the error
+    ConnectFailed(String),
+    /// Eventloop starts another connection attempt
+    Reconnecting,
+}
+
+/// Connection state transition
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateChange {
+    pub state: ConnectionState,
+    /// wall clock time of the transition
+    pub at: SystemTime,
+}
+
+/// Incoming publish whose payload is written to a file. See
+/// [payload spill](../mqttoptions/struct.MqttOptions.html#method.set_payload_spill)
+#[derive(Debug)]
+pub struct PublishFile {
+    pub topic_name: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub pkid: Option<PacketIdentifier>,
+    /// file containing the payload
+    pub path: PathBuf,
+    /// payload size in bytes
+    pub len: usize,
+}
+
 #[doc(hidden)]
 /// Requests by the client to mqtt event loop. Request are
 /// handle one by one#[derive(Debug)]
 #[derive(Debug)]
 pub enum Request {
     Publish(Publish),
+    /// Publish which is dropped unless it's sent on the connection of this generation
+    ConnectionPublish(Publish, u64),
     Subscribe(Subscribe),
     Unsubscribe(Unsubscribe),
     PubAck(PacketIdentifier),
@@ -40,7 +153,24 @@ pub enum Request {
     PubRel(PacketIdentifier),
     PubComp(PacketIdentifier),
     Ping,
-    Reconnect(MqttOptions),
+    Reconnect(Box<MqttOptions>),
+    /// New (username, password) for the current and future connections
+    SetCredentials(String, String),
+    Reconfigure(Reconfigure),
+    /// Marker which is acked once all the requests before it are handed to the network
+    Flush(crossbeam_channel::Sender<()>),
+    /// Marker which is acked once all the qos 1 and 2 publishes before it are acknowledged
+    Watch(crossbeam_channel::Sender<BatchStatus>),
+    /// Asks for the publishes in flight
+    Inflight(crossbeam_channel::Sender<Vec<Inflight>>),
+    /// Asks for a snapshot of the session
+    ExportSession(crossbeam_channel::Sender<Session>),
+    /// Topic of a self test. Its publish is signalled instead of being notified
+    SelfTest(String, crossbeam_channel::Sender<()>),
+    /// Ends the self test of this topic, whether or not its publish came back
+    SelfTestDone(String),
+    /// Asks for the publishes which aren't done yet and disconnects
+    ShutdownPending(crossbeam_channel::Sender<PendingWork>),
     Disconnect,
     None,
 }
@@ -57,20 +187,260 @@ pub enum Command {
     Resume,
 }
 
+/// Outcome of a batch of publishes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchStatus {
+    /// All the qos 1 and 2 publishes of the batch are acknowledged by the broker
+    Delivered,
+    /// Publishes were dropped with the session (clean session reconnection or
+    /// eventloop shutdown) before all of them were acknowledged
+    Lost,
+}
+
+/// What happens to a qos 1 or 2 publish when the connection it's sent on dies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PublishScope {
+    /// Resent after reconnection while the session lasts. Suits commands
+    Reconnects,
+    /// Dropped along with the connection it's issued on, including when it's
+    /// still queued. Suits telemetry which is stale by the time of reconnection
+    Connection,
+}
+
+/// Completes when a qos 1 or 2 publish is acknowledged by the broker (puback or
+/// pubcomp) or lost with the session. Publishes in flight along with it are
+/// waited for as well, so the token never completes early
+#[derive(Debug)]
+pub struct DeliveryToken {
+    rx: crossbeam_channel::Receiver<BatchStatus>,
+}
+
+impl DeliveryToken {
+    /// Blocks till the publish completes
+    pub fn wait(&self) -> BatchStatus {
+        self.rx.recv().unwrap_or(BatchStatus::Lost)
+    }
+
+    /// Blocks till the publish completes or the timeout elapses
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<BatchStatus> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(status) => Some(status),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => None,
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Some(BatchStatus::Lost),
+        }
+    }
+
+    /// Status of the publish if it's completed
+    pub fn try_status(&self) -> Option<BatchStatus> {
+        match self.rx.try_recv() {
+            Ok(status) => Some(status),
+            Err(crossbeam_channel::TryRecvError::Empty) => None,
+            Err(crossbeam_channel::TryRecvError::Disconnected) => Some(BatchStatus::Lost),
+        }
+    }
+}
+
+/// Publish sent to the broker and waiting for its acknowledgement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inflight {
+    pub pkid: PacketIdentifier,
+    pub topic: String,
+    pub qos: QoS,
+    /// time since the publish was first sent
+    pub age: Duration,
+    /// number of times the publish was sent again after reconnections
+    pub retransmits: usize,
+}
+
+/// Publishes which weren't done when the client shut down. See
+/// [shutdown_with_pending]. Publishes stay in the [store] as well, so clear it
+/// before handing them to another durability layer to avoid resending them
+///
+/// [shutdown_with_pending]: struct.MqttClient.html#method.shutdown_with_pending
+/// [store]: ../mqttoptions/struct.MqttOptions.html#method.set_store
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingWork {
+    /// Publishes which never went out, in publish order. Qos 0 publishes among
+    /// them don't have a packet id
+    pub unsent: Vec<Publish>,
+    /// Qos 1 and 2 publishes which were sent and are waiting for puback or pubrec
+    pub inflight: Vec<Publish>,
+    /// Qos 2 publishes which the broker received and are waiting for pubcomp. The
+    /// broker owns these messages
+    pub released: Vec<PacketIdentifier>,
+}
+
+/// Outcome of a [self test]
+///
+/// [self test]: struct.MqttClient.html#method.self_test
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTest {
+    /// unique topic the probe was published to
+    pub topic: String,
+    /// time from the publish till its delivery back to this client
+    pub round_trip: Duration,
+}
+
+/// Outgoing publishes which are yet to be handed to the network
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pending {
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+#[doc(hidden)]
+/// Publishes queued in the request channel. Shared by the clients and the eventloop
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl QueueStats {
+    pub(crate) fn add(&self, bytes: usize) {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(crate) fn remove(&self, bytes: usize) {
+        self.messages.fetch_sub(1, Ordering::SeqCst);
+        self.bytes.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    fn pending(&self) -> Pending {
+        Pending {
+            messages: self.messages.load(Ordering::SeqCst),
+            bytes: self.bytes.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[doc(hidden)]
+/// State of the current connection. Updated by the eventloop and read by the clients
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    connected: AtomicBool,
+    keep_alive: AtomicU64,
+    pkid_exhausted: AtomicBool,
+    generation: AtomicU64,
+    connect_errors: AtomicU64,
+    connect_timeouts: AtomicU64,
+    connack_timeouts: AtomicU64,
+    dns_failures: AtomicU64,
+    /// wakes up publishers blocked on exhausted packet ids
+    pkid_freed: (Mutex<()>, Condvar),
+}
+
+impl ConnectionStats {
+    pub(crate) fn set_connected(&self, keep_alive: Duration) {
+        self.keep_alive.store(keep_alive.as_millis() as u64, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.connected.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_disconnected(&self) {
+        self.connected.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_pkid_exhausted(&self, exhausted: bool) {
+        let previous = self.pkid_exhausted.swap(exhausted, Ordering::SeqCst);
+        if previous && !exhausted {
+            // taken so that the wake up can't slip in between a check and a wait
+            let _lock = self.pkid_freed.0.lock().unwrap();
+            self.pkid_freed.1.notify_all();
+        }
+    }
+
+    fn is_pkid_exhausted(&self) -> bool {
+        self.pkid_exhausted.load(Ordering::SeqCst)
+    }
+
+    /// Blocks till the broker acks a request when all the packet ids are in use
+    fn wait_for_free_pkid(&self) {
+        let mut lock = self.pkid_freed.0.lock().unwrap();
+        while self.is_pkid_exhausted() {
+            lock = self.pkid_freed.1.wait(lock).unwrap();
+        }
+    }
+
+    pub(crate) fn add_connect_failure(&self, error: &ConnectError) {
+        let counter = match error {
+            ConnectError::Timeout => &self.connect_timeouts,
+            ConnectError::ConnackTimeout => &self.connack_timeouts,
+            ConnectError::Dns(..) | ConnectError::DnsListEmpty => &self.dns_failures,
+            _ => &self.connect_errors,
+        };
+
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn connect_failures(&self) -> ConnectFailures {
+        ConnectFailures {
+            errors: self.connect_errors.load(Ordering::SeqCst),
+            connect_timeouts: self.connect_timeouts.load(Ordering::SeqCst),
+            connack_timeouts: self.connack_timeouts.load(Ordering::SeqCst),
+            dns_failures: self.dns_failures.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Failed connection attempts since the client started
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectFailures {
+    /// Attempts failed with a network or mqtt error
+    pub errors: u64,
+    /// Attempts which couldn't set up the tcp (and tls) connection in time
+    pub connect_timeouts: u64,
+    /// Attempts where the broker didn't respond to connect in time
+    pub connack_timeouts: u64,
+    /// Attempts where the broker (or proxy) address couldn't be resolved
+    pub dns_failures: u64,
+}
+
 #[doc(hidden)]
 /// Combines handles returned by the eventloop
 pub struct UserHandle {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
-    notification_rx: crossbeam_channel::Receiver<Notification>,
+    connection_stats: Arc<ConnectionStats>,
+    queue_stats: Arc<QueueStats>,
+    offline_buffer: Option<Arc<OfflineBuffer>>,
+    eventloop: EventloopThread,
 }
 
-/// Handle to send requests and commands to the network eventloop
+/// Eventloop thread shared by all the clones of a client. Taken by the clone
+/// which stops the eventloop
+pub(crate) type EventloopThread = Arc<Mutex<Option<thread::JoinHandle<()>>>>;
+
+/// Handle to send requests and commands to the network eventloop and to query
+/// connection state. Cheap to clone and safe to share across threads
 #[derive(Clone)]
 pub struct MqttClient {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
     max_packet_size: usize,
+    manual_acks: bool,
+    dead_letter: DeadLetter,
+    handler_retry: (u32, Duration),
+    validators: Validators,
+    transformers: Transformers,
+    connection_stats: Arc<ConnectionStats>,
+    subscription_refs: SubscriptionRefs,
+    publish_profiles: PublishProfiles,
+    pkid_exhaustion: PkidExhaustion,
+    broker_capabilities: BrokerCapabilities,
+    subscription_guardrails: Option<SubscriptionGuardrails>,
+    probe: Option<ProbeHandle>,
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+    topic_stats: TopicStats,
+    queue_stats: Arc<QueueStats>,
+    offline_buffer: Option<Arc<OfflineBuffer>>,
+    eventloop: EventloopThread,
 }
 
 impl MqttClient {
@@ -81,20 +451,78 @@ impl MqttClient {
     /// See `select.rs` example
     /// [mqttclient]: struct.MqttClient.html
     pub fn start(opts: MqttOptions) -> Result<(Self, crossbeam_channel::Receiver<Notification>), ConnectError> {
+        let (notification_tx, notification_rx) = crossbeam_channel::bounded(opts.notification_channel_capacity());
+        let client = MqttClient::start_with_sender(opts, notification_tx)?;
+        Ok((client, notification_rx))
+    }
+
+    /// Same as [start] but every notification is tagged with the generation of the
+    /// connection it happened on. See [Tagged]
+    ///
+    /// [start]: struct.MqttClient.html#method.start
+    /// [Tagged]: struct.Tagged.html
+    pub fn start_tagged(opts: MqttOptions) -> Result<(Self, crossbeam_channel::Receiver<Tagged>), ConnectError> {
+        let (notification_tx, notification_rx) = crossbeam_channel::bounded(opts.notification_channel_capacity());
+        let client = MqttClient::start_with_sender(opts, notification_tx)?;
+        Ok((client, notification_rx))
+    }
+
+    /// Same as [start] but notifications are sent on the user supplied channel.
+    /// Notification channel capacity option is not in effect here
+    ///
+    /// [start]: struct.MqttClient.html#method.start
+    pub fn start_with_sender<N>(opts: MqttOptions, notification_tx: N) -> Result<Self, ConnectError>
+    where
+        N: NotificationSender + 'static,
+    {
         let max_packet_size = opts.max_packet_size();
+        let manual_acks = opts.manual_acks();
+        let dead_letter = opts.dead_letter();
+        let handler_retry = opts.handler_retry();
+        let validators = opts.validators();
+        let transformers = opts.transformers();
+        let publish_profiles = opts.publish_profiles();
+        let pkid_exhaustion = opts.pkid_exhaustion();
+        let broker_capabilities = opts.broker_capabilities();
+        let subscription_guardrails = opts.subscription_guardrails();
+        let probe = opts.probe();
+        #[cfg(feature = "compression")]
+        let compression = opts.compression();
+        let topic_stats = opts.topic_stats();
         let UserHandle {
             request_tx,
             command_tx,
-            notification_rx,
-        } = connection::Connection::run(opts)?;
+            connection_stats,
+            queue_stats,
+            offline_buffer,
+            eventloop,
+        } = connection::Connection::run(opts, Box::new(notification_tx))?;
 
         let client = MqttClient {
             request_tx,
             command_tx,
             max_packet_size,
+            manual_acks,
+            dead_letter,
+            handler_retry,
+            validators,
+            transformers,
+            connection_stats,
+            subscription_refs: SubscriptionRefs::default(),
+            publish_profiles,
+            pkid_exhaustion,
+            broker_capabilities,
+            subscription_guardrails,
+            probe,
+            #[cfg(feature = "compression")]
+            compression,
+            topic_stats,
+            queue_stats,
+            offline_buffer,
+            eventloop,
         };
 
-        Ok((client, notification_rx))
+        Ok(client)
     }
 
     /// Requests the eventloop for mqtt publish
@@ -109,17 +537,306 @@ impl MqttClient {
             return Err(ClientError::PacketSizeLimitExceeded);
         }
 
+        self.publish_shared(topic.into(), qos, retained.into(), Arc::new(payload), PublishScope::Reconnects)
+    }
+
+    /// Requests the eventloop for mqtt publish which either survives reconnections
+    /// or is dropped with the current connection. See [PublishScope]
+    ///
+    /// [PublishScope]: enum.PublishScope.html
+    pub fn publish_scoped<S, V>(&mut self, topic: S, qos: QoS, retained: bool, payload: V, scope: PublishScope) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let payload = payload.into();
+        if payload.len() > self.max_packet_size {
+            return Err(ClientError::PacketSizeLimitExceeded);
+        }
+
+        self.publish_shared(topic.into(), qos, retained, Arc::new(payload), scope)
+    }
+
+    /// Publishes the same payload to all the topics. Payload buffer is shared by the
+    /// publishes instead of being copied for each topic. Stops at the first failure,
+    /// publishes to the earlier topics are already queued by then
+    pub fn publish_to_many<I, S, V>(&mut self, topics: I, payload: V, qos: QoS) -> Result<(), ClientError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let payload = payload.into();
+        if payload.len() > self.max_packet_size {
+            return Err(ClientError::PacketSizeLimitExceeded);
+        }
+
+        let payload = Arc::new(payload);
+        for topic in topics {
+            self.publish_shared(topic.into(), qos, false, payload.clone(), PublishScope::Reconnects)?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes every (topic, payload, qos) which arrives on the receiver till all
+    /// its senders are dropped. Lets code built around std channels hand messages
+    /// to the client as is. Failed publishes are logged and skipped. Blocks the
+    /// calling thread, so run it on a thread of its own with a clone of the client.
+    /// Returns the number of queued publishes
+    pub fn drive_from(&mut self, receiver: std_mpsc::Receiver<(String, Vec<u8>, QoS)>) -> usize {
+        let mut published = 0;
+        for (topic, payload, qos) in receiver {
+            match self.publish(topic, qos, false, payload) {
+                Ok(()) => published += 1,
+                Err(e) => error!("Channel publish failed. Error = {:?}", e),
+            }
+        }
+
+        published
+    }
+
+    fn publish_shared(&mut self, topic: String, qos: QoS, retained: bool, payload: Arc<Vec<u8>>, scope: PublishScope) -> Result<(), ClientError> {
+        if let Err(reason) = self.validators.validate(&topic, &payload) {
+            return Err(ClientError::InvalidPayload(topic, reason));
+        }
+
+        if retained && !self.broker_capabilities.retain_available {
+            return Err(ClientError::Unsupported("retained messages"));
+        }
+
+        #[cfg(feature = "compression")]
+        let payload = match self.compression {
+            Some(threshold) if payload.len() >= threshold => match compression::compress_if_smaller(&payload) {
+                Some(compressed) => Arc::new(compressed),
+                None => payload,
+            },
+            _ => payload,
+        };
+
+        let payload = if self.transformers.applies(&topic) {
+            let payload = Arc::try_unwrap(payload).unwrap_or_else(|payload| (*payload).clone());
+            match self.transformers.encode(&topic, payload) {
+                Ok(payload) => Arc::new(payload),
+                Err(reason) => return Err(ClientError::Transform(topic, reason)),
+            }
+        } else {
+            payload
+        };
+
+        let qos = self.broker_capabilities.degrade_qos(qos);
+        if qos != QoS::AtMostOnce {
+            self.wait_for_pkid()?;
+        }
+
         let publish = Publish {
             dup: false,
             qos,
-            retain: retained.into(),
-            topic_name: topic.into(),
+            retain: retained,
+            topic_name: topic,
             pkid: None,
-            payload: Arc::new(payload),
+            payload,
+        };
+
+        let len = publish.payload.len();
+        let enqueued = Instant::now();
+        let topic = publish.topic_name.clone();
+        let publish = match (&self.offline_buffer, scope) {
+            (Some(buffer), PublishScope::Reconnects) => buffer.buffer(publish)?,
+            _ => Some(publish),
+        };
+
+        self.queue_stats.add(len);
+        if let Some(probe) = &self.probe {
+            probe.enqueued(&topic, len, enqueued);
+        }
+
+        // buffered till the next connack
+        let publish = match publish {
+            Some(publish) => publish,
+            None => return Ok(()),
+        };
+
+        let request = match scope {
+            PublishScope::Reconnects => Request::Publish(publish),
+            PublishScope::Connection => Request::ConnectionPublish(publish, self.connection_stats.generation()),
         };
 
         let tx = &mut self.request_tx;
-        tx.send(Request::Publish(publish)).wait()?;
+        if let Err(e) = tx.send(request).wait() {
+            self.queue_stats.remove(len);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Applies the packet id exhaustion behaviour to publishes, subscribes and
+    /// unsubscribes. Eventloop spills the requests which race past this check
+    fn wait_for_pkid(&self) -> Result<(), ClientError> {
+        match self.pkid_exhaustion {
+            PkidExhaustion::Spill => Ok(()),
+            PkidExhaustion::Fail if self.connection_stats.is_pkid_exhausted() => Err(ClientError::PkidExhausted),
+            PkidExhaustion::Fail => Ok(()),
+            PkidExhaustion::Block => {
+                self.connection_stats.wait_for_free_pkid();
+                Ok(())
+            }
+        }
+    }
+
+    /// Publishes the batch and returns a channel which receives the status once all
+    /// the qos 1 and 2 publishes of the batch are acknowledged or lost. Qos 0 publishes
+    /// aren't tracked. Publishes of other clones which are in flight along with the
+    /// batch are waited for as well, so `Delivered` never comes early
+    pub fn publish_all<I, S, V>(&mut self, batch: I) -> Result<crossbeam_channel::Receiver<BatchStatus>, ClientError>
+    where
+        I: IntoIterator<Item = (S, QoS, bool, V)>,
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        for (topic, qos, retain, payload) in batch {
+            self.publish(topic, qos, retain, payload)?;
+        }
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let request_tx = &mut self.request_tx;
+        request_tx.send(Request::Watch(tx)).wait()?;
+        Ok(rx)
+    }
+
+    /// Requests the eventloop for mqtt publish and returns a token which completes
+    /// when the broker acknowledges it. Tokens of qos 0 publishes complete once the
+    /// publishes in flight with them are acknowledged
+    pub fn publish_with_token<S, V>(&mut self, topic: S, qos: QoS, retained: bool, payload: V) -> Result<DeliveryToken, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let rx = self.publish_all(vec![(topic, qos, retained, payload)])?;
+        Ok(DeliveryToken { rx })
+    }
+
+    /// Publishes the batch and calls the callback with the status of the batch. See
+    /// [publish_all]. Callback is called on a separate thread
+    ///
+    /// [publish_all]: struct.MqttClient.html#method.publish_all
+    pub fn publish_all_then<I, S, V, F>(&mut self, batch: I, callback: F) -> Result<(), ClientError>
+    where
+        I: IntoIterator<Item = (S, QoS, bool, V)>,
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+        F: FnOnce(BatchStatus) + Send + 'static,
+    {
+        let rx = self.publish_all(batch)?;
+        thread::spawn(move || callback(rx.recv().unwrap_or(BatchStatus::Lost)));
+        Ok(())
+    }
+
+    /// Publishes sent to the broker and waiting for acknowledgements. The eventloop
+    /// answers after handling the requests queued before this call and doesn't
+    /// answer while it is reconnecting, in which case this fails after the timeout
+    pub fn inflight(&mut self, timeout: Duration) -> Result<Vec<Inflight>, ClientError> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let request_tx = &mut self.request_tx;
+        request_tx.send(Request::Inflight(tx)).wait()?;
+
+        rx.recv_timeout(timeout).map_err(|_| ClientError::EventloopTimeout)
+    }
+
+    /// Snapshot of the session (subscriptions, messages in flight and packet ids) to
+    /// import in another client with [set_session]. Pause or stop publishing first
+    /// so that nothing changes after the snapshot. Fails after the timeout while
+    /// the eventloop is reconnecting
+    ///
+    /// [set_session]: ../mqttoptions/struct.MqttOptions.html#method.set_session
+    pub fn export_session(&mut self, timeout: Duration) -> Result<Session, ClientError> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let request_tx = &mut self.request_tx;
+        request_tx.send(Request::ExportSession(tx)).wait()?;
+
+        rx.recv_timeout(timeout).map_err(|_| ClientError::EventloopTimeout)
+    }
+
+    /// Verifies the path through the broker end to end. Subscribes to a unique
+    /// probe topic, publishes to it with qos 1 and waits for the publish to come
+    /// back. The probe publish isn't notified. Fails with `SelfTestTimeout` when
+    /// nothing arrives within the timeout
+    pub fn self_test(&mut self, timeout: Duration) -> Result<SelfTest, ClientError> {
+        let topic = format!("rumqtt/self-test/{}", uuid::Uuid::new_v4());
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let request_tx = &mut self.request_tx;
+        request_tx.send(Request::SelfTest(topic.clone(), tx)).wait()?;
+
+        // the topic is deregistered on failures too so that it doesn't linger in the eventloop
+        let round_trip = self.self_test_round_trip(&topic, &rx, timeout);
+        let request_tx = &mut self.request_tx;
+        let done = request_tx.send(Request::SelfTestDone(topic.clone())).wait();
+
+        let round_trip = round_trip?;
+        done?;
+        Ok(SelfTest { topic, round_trip })
+    }
+
+    fn self_test_round_trip(&mut self, topic: &str, rx: &crossbeam_channel::Receiver<()>, timeout: Duration) -> Result<Duration, ClientError> {
+        self.subscribe(topic, QoS::AtLeastOnce)?;
+        let start = Instant::now();
+        let received = self
+            .publish(topic, QoS::AtLeastOnce, false, "self-test")
+            .and_then(|_| rx.recv_timeout(timeout).map_err(|_| ClientError::SelfTestTimeout(timeout)));
+        let round_trip = start.elapsed();
+        let unsubscribed = self.unsubscribe(topic);
+
+        received?;
+        unsubscribed?;
+        Ok(round_trip)
+    }
+
+    /// Waits till all the requests queued before this call are handed to the network
+    /// or the timeout elapses. Returns the publishes which are still queued, which
+    /// might include publishes of other clones made during the flush
+    pub fn flush(&mut self, timeout: Duration) -> Result<Pending, ClientError> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let request_tx = &mut self.request_tx;
+        request_tx.send(Request::Flush(tx)).wait()?;
+
+        match rx.recv_timeout(timeout) {
+            Ok(()) => Ok(Pending::default()),
+            Err(_) => Ok(self.queue_stats.pending()),
+        }
+    }
+
+    /// Requests the eventloop for mqtt publish with the qos and retain flag of the
+    /// matching [publish profile] or the [publish defaults]
+    ///
+    /// [publish profile]: ../mqttoptions/struct.MqttOptions.html#method.add_publish_profile
+    /// [publish defaults]: ../mqttoptions/struct.MqttOptions.html#method.set_publish_defaults
+    pub fn publish_default<S, V>(&mut self, topic: S, payload: V) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        let (qos, retain) = self.publish_profiles.resolve(&topic);
+        self.publish(topic, qos, retain, payload)
+    }
+
+    /// Splits the payload into parts of at most 'max_part_size' bytes and publishes
+    /// them in order. Use [Reassembler] on the receiving side to get the payload back
+    ///
+    /// [Reassembler]: ../fragment/struct.Reassembler.html
+    pub fn publish_fragmented<S, V>(&mut self, topic: S, qos: QoS, payload: V, max_part_size: usize) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: AsRef<[u8]>,
+    {
+        let topic = topic.into();
+        let parts = fragment::split(fragment::next_message_id(), payload.as_ref(), max_part_size)?;
+
+        for part in parts {
+            self.publish(topic.clone(), qos, false, part)?;
+        }
+
         Ok(())
     }
 
@@ -128,15 +845,27 @@ impl MqttClient {
     where
         S: Into<String>,
     {
+        let topic = topic.into();
+        if !self.broker_capabilities.wildcard_subscriptions && (topic.contains('+') || topic.contains('#')) {
+            return Err(ClientError::Unsupported("wildcard subscriptions"));
+        }
+
+        if let Some(guardrails) = &self.subscription_guardrails {
+            if let Err(reason) = guardrails.check(&topic) {
+                return Err(ClientError::BroadSubscription(topic, reason));
+            }
+        }
+
         let topic = SubscribeTopic {
-            topic_path: topic.into(),
-            qos,
+            topic_path: topic,
+            qos: self.broker_capabilities.degrade_qos(qos),
         };
         let subscribe = Subscribe {
             pkid: PacketIdentifier::zero(),
             topics: vec![topic],
         };
 
+        self.wait_for_pkid()?;
         let tx = &mut self.request_tx;
         tx.send(Request::Subscribe(subscribe)).wait()?;
         Ok(())
@@ -147,16 +876,114 @@ impl MqttClient {
         where
             S: Into<String>,
     {
+        self.unsubscribe_many(vec![topic])
+    }
+
+    /// Requests the eventloop to unsubscribe from all the topics with a single
+    /// unsubscribe packet. [Notification::UnsubAck] confirms it
+    ///
+    /// [Notification::UnsubAck]: enum.Notification.html#variant.UnsubAck
+    pub fn unsubscribe_many<I, S>(&mut self, topics: I) -> Result<(), ClientError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let topics: Vec<String> = topics.into_iter().map(Into::into).collect();
+        if topics.is_empty() {
+            return Err(ClientError::ZeroSubscriptions);
+        }
+
         let unsubscribe = Unsubscribe {
             pkid: PacketIdentifier::zero(),
-            topics: vec![topic.into()],
+            topics,
         };
 
+        self.wait_for_pkid()?;
         let tx = &mut self.request_tx;
         tx.send(Request::Unsubscribe(unsubscribe)).wait()?;
         Ok(())
     }
 
+    /// Acknowledges an incoming publish in manual ack mode. Sends PUBACK for
+    /// QoS1 and PUBCOMP for QoS2 publishes. Nothing to do for QoS0 publishes
+    /// or when manual acks are disabled
+    pub fn ack(&mut self, publish: &Publish) -> Result<(), ClientError> {
+        self.ack_pkid(publish.qos, publish.pkid)
+    }
+
+    /// Same as [ack] for a publish whose payload is spilled to a file
+    ///
+    /// [ack]: struct.MqttClient.html#method.ack
+    pub fn ack_file(&mut self, publish: &PublishFile) -> Result<(), ClientError> {
+        self.ack_pkid(publish.qos, publish.pkid)
+    }
+
+    fn ack_pkid(&mut self, qos: QoS, pkid: Option<PacketIdentifier>) -> Result<(), ClientError> {
+        if !self.manual_acks {
+            return Ok(());
+        }
+
+        let request = match (qos, pkid) {
+            (QoS::AtLeastOnce, Some(pkid)) => Request::PubAck(pkid),
+            (QoS::ExactlyOnce, Some(pkid)) => Request::PubComp(pkid),
+            _ => return Ok(()),
+        };
+
+        let tx = &mut self.request_tx;
+        tx.send(request).wait()?;
+        Ok(())
+    }
+
+    /// Rejects an incoming publish. In manual ack mode, the publish is acked so that
+    /// the broker doesn't redeliver it. The publish is then routed to the configured
+    /// [dead letter] route
+    ///
+    /// [dead letter]: ../mqttoptions/enum.DeadLetter.html
+    pub fn nack(&mut self, publish: Publish) -> Result<(), ClientError> {
+        if self.manual_acks {
+            self.ack(&publish)?;
+        }
+
+        match self.dead_letter.clone() {
+            DeadLetter::Drop => warn!("Dropping rejected publish. Topic = {}", publish.topic_name),
+            DeadLetter::Channel(tx) => tx.send(publish)?,
+            DeadLetter::Republish(topic) => {
+                let payload = publish.payload.to_vec();
+                self.publish(topic, publish.qos, false, payload)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the handler on an incoming publish and acks it on success. Failed
+    /// handlers are retried as per [handler retry] options before the publish
+    /// is rejected with [nack]
+    ///
+    /// [handler retry]: ../mqttoptions/struct.MqttOptions.html#method.set_handler_retry
+    /// [nack]: struct.MqttClient.html#method.nack
+    pub fn handle<F, E>(&mut self, publish: Publish, mut handler: F) -> Result<(), ClientError>
+    where
+        F: FnMut(&Publish) -> Result<(), E>,
+        E: fmt::Debug,
+    {
+        let (attempts, mut backoff) = self.handler_retry;
+
+        for attempt in 1..=attempts {
+            match handler(&publish) {
+                Ok(()) => return self.ack(&publish),
+                Err(e) => warn!("Handler failed. Attempt = {}/{}, Error = {:?}", attempt, attempts, e),
+            }
+
+            if attempt < attempts {
+                thread::sleep(backoff);
+                backoff = backoff.checked_mul(2).unwrap_or(backoff);
+            }
+        }
+
+        self.nack(publish)
+    }
+
     /// Commands the network eventloop to disconnect from the broker.
     /// ReconnectOptions are not in affect here. [Resume] the
     /// network for reconnection
@@ -176,6 +1003,77 @@ impl MqttClient {
         Ok(())
     }
 
+    /// Creates a lightweight handle for a component to publish and manage its
+    /// own subscriptions on this client
+    pub fn client_handle(&self) -> ClientHandle {
+        ClientHandle::new(self.clone(), self.subscription_refs.clone())
+    }
+
+    /// Replaces the username and password used to connect to the broker and
+    /// reconnects with them. Useful to rotate passwords of long running clients.
+    /// State of the current session is retained across the reconnection
+    pub fn set_credentials<U, P>(&mut self, username: U, password: P) -> Result<(), ClientError>
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let tx = &mut self.request_tx;
+        tx.send(Request::SetCredentials(username.into(), password.into())).wait()?;
+        Ok(())
+    }
+
+    /// Changes an option of the running eventloop. [Notification::Reconfigured] confirms
+    /// the change. See [Reconfigure] for when the option takes effect
+    ///
+    /// [Notification::Reconfigured]: enum.Notification.html#variant.Reconfigured
+    /// [Reconfigure]: ../mqttoptions/enum.Reconfigure.html
+    pub fn reconfigure(&mut self, reconfigure: Reconfigure) -> Result<(), ClientError> {
+        if !reconfigure.is_valid() {
+            return Err(ClientError::InvalidReconfigure(reconfigure));
+        }
+
+        let tx = &mut self.request_tx;
+        tx.send(Request::Reconfigure(reconfigure)).wait()?;
+        Ok(())
+    }
+
+    /// Checks if the eventloop is currently connected to the broker
+    pub fn is_connected(&self) -> bool {
+        self.connection_stats.connected.load(Ordering::SeqCst)
+    }
+
+    /// Keep alive in effect for the current (or last) connection. This is the
+    /// configured keep alive capped by the [broker keep alive limit]
+    ///
+    /// [broker keep alive limit]: ../mqttoptions/struct.MqttOptions.html#method.set_broker_keep_alive_limit
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_millis(self.connection_stats.keep_alive.load(Ordering::SeqCst))
+    }
+
+    /// Generation of the current (or last) connection. Starts at 1 and goes up by
+    /// one on every reconnection
+    pub fn generation(&self) -> u64 {
+        self.connection_stats.generation()
+    }
+
+    /// Failed connection attempts by the kind of failure
+    pub fn connect_failures(&self) -> ConnectFailures {
+        self.connection_stats.connect_failures()
+    }
+
+    /// Publish counters of the [topic stats] filters. Diff two snapshots for the
+    /// counts in between
+    ///
+    /// [topic stats]: ../mqttoptions/struct.MqttOptions.html#method.add_topic_stats
+    pub fn topic_stats(&self) -> Snapshot {
+        self.topic_stats.snapshot()
+    }
+
+    /// Publish counters of the topic stats filters. Counters start again from zero
+    pub fn reset_topic_stats(&self) -> Snapshot {
+        self.topic_stats.reset()
+    }
+
     /// Commands the network eventloop to gracefully shutdown
     /// the connection to the broker.
     pub fn shutdown(&mut self) -> Result<(), ClientError> {
@@ -183,6 +1081,84 @@ impl MqttClient {
         tx.send(Request::Disconnect).wait()?;
         Ok(())
     }
+
+    /// Same as [shutdown] but returns the publishes which are yet to be sent or
+    /// acknowledged, so that the application can hand them to its own durability
+    /// layer. Fails after the timeout while the eventloop is reconnecting
+    ///
+    /// [shutdown]: struct.MqttClient.html#method.shutdown
+    pub fn shutdown_with_pending(&mut self, timeout: Duration) -> Result<PendingWork, ClientError> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let request_tx = &mut self.request_tx;
+        request_tx.send(Request::ShutdownPending(tx)).wait()?;
+
+        let mut pending = rx.recv_timeout(timeout).map_err(|_| ClientError::EventloopTimeout)?;
+        if let Some(buffer) = &self.offline_buffer {
+            pending.unsent.extend(buffer.take());
+        }
+
+        Ok(pending)
+    }
+
+    /// Stops the eventloop cleanly. Waits for the queued requests to go out and
+    /// for the inflight publishes to be acknowledged, disconnects and joins the
+    /// eventloop thread, all within the timeout. Publishes which aren't
+    /// acknowledged by then are dropped with the connection (see [shutdown_with_pending]
+    /// to keep them). Fails if the eventloop thread is still running at the deadline
+    /// or is already stopped by another clone. The thread can still be joined after
+    /// other failures
+    ///
+    /// [shutdown_with_pending]: struct.MqttClient.html#method.shutdown_with_pending
+    pub fn stop(&mut self, timeout: Duration) -> Result<(), ClientError> {
+        let deadline = Instant::now() + timeout;
+        if self.eventloop.lock().unwrap().is_none() {
+            return Err(ClientError::EventloopStopped);
+        }
+
+        self.flush(timeout)?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.inflight(remaining) {
+                Ok(ref inflight) if inflight.is_empty() => break,
+                Ok(_) if remaining > Duration::from_millis(0) => thread::sleep(Duration::from_millis(10)),
+                Ok(_) => break,
+                Err(e) => {
+                    warn!("Couldn't drain inflight publishes before stopping. Error = {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        self.shutdown()?;
+        let eventloop = self.eventloop.lock().unwrap().take().ok_or(ClientError::EventloopStopped)?;
+        while !eventloop.is_finished() {
+            if Instant::now() >= deadline {
+                *self.eventloop.lock().unwrap() = Some(eventloop);
+                return Err(ClientError::EventloopTimeout);
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        if eventloop.join().is_err() {
+            error!("Eventloop thread panicked while stopping");
+        }
+
+        Ok(())
+    }
+
+    /// Blocks till the eventloop thread exits, i.e after a shutdown or once
+    /// reconnections are over as per the reconnect options. For applications
+    /// which want to park a thread on the client. Fails if the eventloop is
+    /// already stopped or joined by another clone
+    pub fn join(&self) -> Result<(), ClientError> {
+        let eventloop = self.eventloop.lock().unwrap().take().ok_or(ClientError::EventloopStopped)?;
+        if eventloop.join().is_err() {
+            error!("Eventloop thread panicked");
+        }
+
+        Ok(())
+    }
 }
 
 // use std::fmt;
@@ -204,3 +1180,813 @@ impl MqttClient {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{handle::SubscriptionRefs, BatchStatus, DeliveryToken, MessageHandler, MessageRef, MqttClient, Notification, NotificationSender, Request};
+    use crate::{ConnectError, MqttOptions};
+    use crossbeam_channel::Receiver;
+    use futures::{sync::mpsc, Stream};
+    use mqtt311::{MqttRead, MqttWrite, Packet, PacketIdentifier, Publish, QoS};
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{mpsc as std_mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn stalled_broker_should_fail_with_connack_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stalled = thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port)
+            .set_connect_timeout(Duration::from_secs(5))
+            .set_connack_timeout(Duration::from_millis(200));
+
+        match MqttClient::start(opts) {
+            Err(ConnectError::ConnackTimeout) => (),
+            out => panic!("Expecting connack timeout. Received = {:?}", out.map(|_| ())),
+        }
+
+        stalled.join().unwrap();
+    }
+
+    #[test]
+    fn eventloop_panic_should_be_notified_and_restart_the_connection() {
+        use crate::{Notification, Probe, ReconnectOptions};
+        use mqtt311::PacketIdentifier;
+        use std::{
+            sync::atomic::{AtomicBool, Ordering},
+            time::Instant,
+        };
+
+        struct PanicOnce(AtomicBool);
+
+        impl Probe for PanicOnce {
+            fn sent(&self, _pkid: PacketIdentifier, _at: Instant) {
+                if !self.0.swap(true, Ordering::SeqCst) {
+                    panic!("probe failure");
+                }
+            }
+        }
+
+        let (port, packets_rx) = recording_broker();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port)
+            .set_clean_session(false)
+            .set_reconnect_opts(ReconnectOptions::Always(0))
+            .set_probe(PanicOnce(AtomicBool::new(false)));
+        let (mut client, notifications) = MqttClient::start(opts).unwrap();
+        client.subscribe("hello/#", QoS::AtLeastOnce).unwrap();
+        match packets_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            (0, Packet::Subscribe(_)) => (),
+            packet => panic!("Expecting subscribe. Received = {:?}", packet),
+        }
+
+        // probe panics once the publish is in flight and before it is written
+        client.publish("hello/world", QoS::AtLeastOnce, false, vec![1, 2, 3]).unwrap();
+        let report = loop {
+            match notifications.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Notification::Panicked(report) => break report,
+                _ => continue,
+            }
+        };
+
+        assert!(report.contains("probe failure"));
+        let packets: Vec<Packet> = (0..2).map(|_| packets_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .map(|(connection, packet)| {
+                assert_eq!(connection, 1);
+                packet
+            })
+            .collect();
+        match &packets[..] {
+            [Packet::Publish(publish), Packet::Subscribe(subscribe)] => {
+                assert_eq!(publish.topic_name, "hello/world");
+                assert_eq!(publish.pkid, Some(PacketIdentifier(2)));
+                assert_eq!(subscribe.topics[0].topic_path, "hello/#");
+            }
+            packets => panic!("Expecting replayed publish and resubscribe. Received = {:?}", packets),
+        }
+    }
+
+    #[test]
+    fn reconnect_policy_should_decide_retries_of_the_first_connection() {
+        use crate::Attempt;
+
+        // nothing listens on a port which was just released
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port).set_reconnect_policy(move |attempt: &Attempt| {
+            tx.send(*attempt).unwrap();
+            if attempt.attempt < 3 {
+                Some(Duration::from_millis(10))
+            } else {
+                None
+            }
+        });
+
+        let _client = MqttClient::start(opts).unwrap();
+        let attempts: Vec<u32> = rx.iter().map(|attempt| {
+            assert!(!attempt.connected_before);
+            attempt.attempt
+        }).collect();
+
+        // the channel closes once the policy gives up and the eventloop drops it
+        assert_eq!(attempts, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn eventloop_should_give_up_after_max_reconnect_attempts() {
+        use crate::{Notification, ReconnectOptions};
+
+        // nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port)
+            .set_reconnect_opts(ReconnectOptions::Always(0))
+            .set_max_reconnect_attempts(2);
+        let (client, notifications) = MqttClient::start(opts).unwrap();
+
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::ReconnectExhausted(2)) => (),
+            notification => panic!("Expecting reconnect exhaustion. Received = {:?}", notification),
+        }
+
+        assert_eq!(client.connect_failures().errors, 3);
+        assert!(notifications.recv_timeout(Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn broker_closing_mid_write_should_disconnect_and_reconnect() {
+        use crate::{DisconnectReason, Notification, ReconnectOptions};
+
+        // first connection is dropped after a bit of the publish is read
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (accepted_tx, accepted_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+                accepted_tx.send(()).unwrap();
+                if i == 0 {
+                    let _ = stream.read_exact(&mut buf);
+                    continue;
+                }
+
+                while let Ok(n) = stream.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Always(0));
+        let (mut client, notifications) = MqttClient::start(opts).unwrap();
+        accepted_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        client.publish("hello/world", QoS::AtLeastOnce, false, vec![1; 200 * 1024]).unwrap();
+        let reason = loop {
+            match notifications.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Notification::Disconnected(reason) => break reason,
+                _ => continue,
+            }
+        };
+
+        assert!(reason == DisconnectReason::BrokerClosed || reason == DisconnectReason::Reset, "{:?}", reason);
+        accepted_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn second_client_with_the_same_id_should_fail_with_duplicate_connect_protection() {
+        let (port, _accepted_rx) = fake_broker();
+
+        let opts = MqttOptions::new("duplicate-id", "127.0.0.1", port).set_duplicate_connect_protection(true);
+        let _client = MqttClient::start(opts.clone()).unwrap();
+        match MqttClient::start(opts) {
+            Err(ConnectError::AlreadyConnecting(id)) => assert_eq!(id, "duplicate-id"),
+            out => panic!("Expecting already connecting. Received = {:?}", out.map(|_| ())),
+        }
+
+        // other ids and unprotected clients aren't affected
+        let opts = MqttOptions::new("other-id", "127.0.0.1", port).set_duplicate_connect_protection(true);
+        let _other = MqttClient::start(opts).unwrap();
+        let _unprotected = MqttClient::start(MqttOptions::new("duplicate-id", "127.0.0.1", port)).unwrap();
+    }
+
+    #[test]
+    fn stop_should_drain_inflight_disconnect_and_join_the_eventloop() {
+
+        // acks the publish late, reports the packets it exchanges and closes on disconnect
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (packets_tx, packets_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+
+                packets_tx.send(buf[0] & 0xF0).unwrap();
+                match buf[0] & 0xF0 {
+                    0x30 => {
+                        thread::sleep(Duration::from_millis(200));
+                        stream.write_all(&[0x40, 0x02, 0x00, 0x01]).unwrap();
+                        packets_tx.send(0x40).unwrap();
+                    }
+                    0xE0 => break,
+                    _ => (),
+                }
+            }
+        });
+
+        let (mut client, _notifications) = MqttClient::start(MqttOptions::new("test-id", "127.0.0.1", port)).unwrap();
+        client.publish("hello/world", QoS::AtLeastOnce, false, vec![1, 2, 3]).unwrap();
+        client.stop(Duration::from_secs(5)).unwrap();
+
+        // disconnect goes out only after the publish is acked
+        let packets: Vec<u8> = packets_rx.try_iter().collect();
+        assert_eq!(packets, vec![0x30, 0x40, 0xE0]);
+        match client.stop(Duration::from_secs(1)) {
+            Err(crate::ClientError::EventloopStopped) => (),
+            out => panic!("Expecting stopped eventloop. Received = {:?}", out),
+        }
+    }
+
+    #[test]
+    fn failed_stop_should_keep_the_eventloop_joinable() {
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let client = client(MqttOptions::new("test-id", "localhost", 1883), request_tx);
+        let (exit_tx, exit_rx) = crossbeam_channel::bounded::<()>(1);
+        *client.eventloop.lock().unwrap() = Some(thread::spawn(move || {
+            let _ = exit_rx.recv();
+        }));
+
+        // eventloop doesn't take requests anymore
+        drop(request_rx);
+        assert!(client.clone().stop(Duration::from_millis(100)).is_err());
+        assert!(client.eventloop.lock().unwrap().is_some());
+
+        exit_tx.send(()).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn join_should_return_once_the_eventloop_stops() {
+        use crate::ReconnectOptions;
+
+        // closes the connection after a while
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never);
+        let (client, _notifications) = MqttClient::start(opts).unwrap();
+        assert!(client.is_connected());
+
+        let joiner = client.clone();
+        let joined = thread::spawn(move || joiner.join());
+        joined.join().unwrap().unwrap();
+        assert!(!client.is_connected());
+        assert!(client.join().is_err());
+    }
+
+    #[test]
+    fn reconnect_hooks_should_run_around_reconnections() {
+        use crate::{Attempt, ReconnectOptions};
+        let (port, _accepted_rx) = flaky_broker();
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let after_tx = tx.clone();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port)
+            .set_reconnect_opts(ReconnectOptions::Always(0))
+            .set_before_reconnect(move |attempt: &Attempt| tx.send(("before", *attempt)).unwrap())
+            .set_after_connect(move |attempt: &Attempt| after_tx.send(("after", *attempt)).unwrap());
+        let _client = MqttClient::start(opts).unwrap();
+
+        let first = Attempt { attempt: 1, connected_before: false };
+        let again = Attempt { attempt: 1, connected_before: true };
+        let hooks: Vec<(&str, Attempt)> = (0..3).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(hooks, vec![("after", first), ("before", again), ("after", again)]);
+    }
+
+    #[test]
+    fn state_transitions_should_be_sent_on_the_state_channel() {
+        use crate::{ConnectionState, DisconnectReason, ReconnectOptions};
+        let (port, _accepted_rx) = flaky_broker();
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port)
+            .set_reconnect_opts(ReconnectOptions::Always(0))
+            .set_state_channel(tx);
+        let _client = MqttClient::start(opts).unwrap();
+
+        let states: Vec<ConnectionState> = (0..4).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap().state).collect();
+        assert_eq!(states, vec![
+            ConnectionState::Connected,
+            ConnectionState::Disconnected(DisconnectReason::BrokerClosed),
+            ConnectionState::Reconnecting,
+            ConnectionState::Connected,
+        ]);
+    }
+
+    #[test]
+    fn presence_should_be_published_retained_every_interval() {
+        use crate::Presence;
+
+        // reports every retained qos 0 publish after connack
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+
+                // packets are small enough to arrive one per read
+                if buf[0] == 0x31 {
+                    tx.send(buf[..n].to_vec()).unwrap();
+                }
+            }
+        });
+
+        let presence = Presence::new("devices/d1/presence", "online", Duration::from_millis(100));
+        let opts = MqttOptions::new("test-id", "127.0.0.1", port).set_presence(presence);
+        let _client = MqttClient::start(opts).unwrap();
+
+        for _ in 0..3 {
+            let packet = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            assert!(packet.ends_with(b"devices/d1/presenceonline"));
+        }
+    }
+
+    #[test]
+    fn hook_should_run_on_the_named_eventloop_thread() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1)
+            .set_reconnect_opts(crate::ReconnectOptions::Never)
+            .set_thread_name("gateway-mqtt")
+            .set_thread_hook(move || {
+                let name = thread::current().name().map(str::to_owned);
+                tx.send(name).unwrap();
+            });
+
+        assert!(MqttClient::start(opts).is_err());
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("gateway-mqtt"));
+    }
+
+    #[test]
+    fn client_should_be_clone_send_and_sync() {
+        fn assert_handle<T: Clone + Send + Sync>() {}
+        assert_handle::<MqttClient>();
+    }
+
+    #[test]
+    fn token_should_be_lost_when_eventloop_drops_the_publish() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let token = DeliveryToken { rx };
+        assert_eq!(token.try_status(), None);
+        assert_eq!(token.wait_timeout(Duration::from_millis(10)), None);
+
+        drop(tx);
+        assert_eq!(token.wait(), BatchStatus::Lost);
+    }
+
+    fn client(opts: MqttOptions, request_tx: mpsc::Sender<Request>) -> MqttClient {
+        let (command_tx, _command_rx) = mpsc::channel(10);
+        MqttClient {
+            request_tx,
+            command_tx,
+            max_packet_size: opts.max_packet_size(),
+            manual_acks: opts.manual_acks(),
+            dead_letter: opts.dead_letter(),
+            handler_retry: opts.handler_retry(),
+            validators: opts.validators(),
+            transformers: opts.transformers(),
+            connection_stats: Default::default(),
+            subscription_refs: SubscriptionRefs::default(),
+            publish_profiles: opts.publish_profiles(),
+            pkid_exhaustion: opts.pkid_exhaustion(),
+            broker_capabilities: opts.broker_capabilities(),
+            subscription_guardrails: opts.subscription_guardrails(),
+            probe: opts.probe(),
+            #[cfg(feature = "compression")]
+            compression: opts.compression(),
+            topic_stats: opts.topic_stats(),
+            queue_stats: Default::default(),
+            offline_buffer: None,
+            eventloop: Default::default(),
+        }
+    }
+
+    /// Broker which accepts every connection with a connack and ignores everything else.
+    /// Every accepted connection is reported on the returned channel
+    fn fake_broker() -> (u16, Receiver<()>) {
+        let (port, accepted_rx, _) = spawn_broker(false, Vec::new());
+        (port, accepted_rx)
+    }
+
+    /// Same as `fake_broker` but closes the first connection right after connack
+    fn flaky_broker() -> (u16, Receiver<()>) {
+        let (port, accepted_rx, _) = spawn_broker(true, Vec::new());
+        (port, accepted_rx)
+    }
+
+    /// Same as `fake_broker` but reports the packets read after connect along with
+    /// the number of their connection
+    pub(crate) fn recording_broker() -> (u16, Receiver<(usize, Packet)>) {
+        let (port, _, packets_rx) = spawn_broker(false, Vec::new());
+        (port, packets_rx)
+    }
+
+    /// Same as `recording_broker` but writes the packets on every connection right
+    /// after connack
+    fn publishing_broker(packets: Vec<Packet>) -> (u16, Receiver<(usize, Packet)>) {
+        let (port, _, packets_rx) = spawn_broker(false, packets);
+        (port, packets_rx)
+    }
+
+    fn incoming_publish(pkid: u16) -> Packet {
+        Packet::Publish(Publish {
+            dup: false,
+            qos: if pkid == 0 { QoS::AtMostOnce } else { QoS::AtLeastOnce },
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: if pkid == 0 { None } else { Some(PacketIdentifier(pkid)) },
+            payload: Arc::new(vec![1, 2, 3]),
+        })
+    }
+
+    fn spawn_broker(close_first: bool, packets: Vec<Packet>) -> (u16, Receiver<()>, Receiver<(usize, Packet)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (accepted_tx, accepted_rx) = crossbeam_channel::unbounded();
+        let (packets_tx, packets_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let accepted_tx = accepted_tx.clone();
+                let packets_tx = packets_tx.clone();
+                let packets = packets.clone();
+                thread::spawn(move || {
+                    let _ = stream.read_packet();
+                    stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+                    for packet in packets.iter() {
+                        stream.write_packet(packet).unwrap();
+                    }
+
+                    let _ = accepted_tx.send(());
+                    if close_first && i == 0 {
+                        return;
+                    }
+
+                    while let Ok(packet) = stream.read_packet() {
+                        let _ = packets_tx.send((i, packet));
+                    }
+                });
+            }
+        });
+
+        (port, accepted_rx, packets_rx)
+    }
+
+    #[test]
+    fn message_handler_should_run_on_the_eventloop_thread_with_incoming_publishes() {
+        let (port, packets_rx) = publishing_broker(vec![incoming_publish(1)]);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let handler = MessageHandler::new(move |message: MessageRef| {
+            let _ = tx.send((message.topic.to_owned(), message.payload.to_vec(), message.pkid, thread::current().id()));
+        });
+
+        let opts = MqttOptions::new("message-handler", "127.0.0.1", port);
+        let _client = MqttClient::start_with_sender(opts, handler).unwrap();
+        let (topic, payload, pkid, thread_id) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(topic, "hello/world");
+        assert_eq!(payload, vec![1, 2, 3]);
+        assert_eq!(pkid, Some(PacketIdentifier(1)));
+        assert_ne!(thread_id, thread::current().id());
+
+        // handled publish is acked like a delivered one
+        let (_, packet) = packets_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(packet, Packet::Puback(PacketIdentifier(1)));
+    }
+
+    /// Waits for the incoming publish among the notifications
+    fn received_publish<I: Iterator<Item = Notification>>(mut notifications: I) -> bool {
+        notifications.any(|notification| match notification {
+            Notification::Publish(publish) => publish.topic_name == "hello/world",
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn std_and_futures_senders_should_deliver_notifications() {
+        let opts = |port| MqttOptions::new("std-sender", "127.0.0.1", port);
+
+        let (port, _) = publishing_broker(vec![incoming_publish(0)]);
+        let (tx, rx) = std_mpsc::channel();
+        let _client = MqttClient::start_with_sender(opts(port), tx).unwrap();
+        assert!(received_publish(std::iter::from_fn(|| rx.recv_timeout(Duration::from_secs(5)).ok())));
+
+        let (port, _) = publishing_broker(vec![incoming_publish(0)]);
+        let (tx, rx) = std_mpsc::sync_channel(10);
+        let _client = MqttClient::start_with_sender(opts(port), tx).unwrap();
+        assert!(received_publish(std::iter::from_fn(|| rx.recv_timeout(Duration::from_secs(5)).ok())));
+
+        let (port, _) = publishing_broker(vec![incoming_publish(0)]);
+        let (tx, rx) = mpsc::channel(10);
+        let _client = MqttClient::start_with_sender(opts(port), tx).unwrap();
+        assert!(received_publish(rx.wait().filter_map(Result::ok)));
+
+        let (port, _) = publishing_broker(vec![incoming_publish(0)]);
+        let (tx, rx) = mpsc::unbounded();
+        let _client = MqttClient::start_with_sender(opts(port), tx).unwrap();
+        assert!(received_publish(rx.wait().filter_map(Result::ok)));
+    }
+
+    /// Floods the sender with incoming publishes and checks that the eventloop
+    /// still sends the user's publishes
+    fn assert_eventloop_survives<N: NotificationSender + 'static>(sender: N) {
+        let (port, packets_rx) = publishing_broker(vec![incoming_publish(0); 5]);
+        let opts = MqttOptions::new("stuck-sender", "127.0.0.1", port);
+        let mut client = MqttClient::start_with_sender(opts, sender).unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        client.publish("hello/alive", QoS::AtMostOnce, false, vec![1]).unwrap();
+        match packets_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok((_, Packet::Publish(publish))) => assert_eq!(publish.topic_name, "hello/alive"),
+            packet => panic!("Expecting a publish. Received = {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn full_or_closed_senders_should_not_stall_the_eventloop() {
+        let (tx, _rx) = std_mpsc::sync_channel::<Notification>(1);
+        assert_eventloop_survives(tx);
+
+        let (tx, rx) = std_mpsc::channel::<Notification>();
+        drop(rx);
+        assert_eventloop_survives(tx);
+
+        let (tx, _rx) = mpsc::channel::<Notification>(0);
+        assert_eventloop_survives(tx);
+
+        let (tx, rx) = mpsc::unbounded::<Notification>();
+        drop(rx);
+        assert_eventloop_survives(tx);
+    }
+
+    #[test]
+    fn subscription_handles_should_be_refcounted_and_unsubscribe_on_drop() {
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let client = client(MqttOptions::new("test-id", "localhost", 1883), request_tx);
+        let mut first = client.client_handle();
+        let mut second = client.client_handle();
+
+        first.subscribe("hello/#", QoS::AtMostOnce).unwrap();
+        second.subscribe("hello/#", QoS::AtMostOnce).unwrap();
+        // higher qos renews the subscription, lower or same qos is a no-op
+        second.subscribe("hello/#", QoS::AtLeastOnce).unwrap();
+        first.subscribe("hello/#", QoS::AtMostOnce).unwrap();
+        assert_eq!(second.subscriptions(), ["hello/#".to_owned()]);
+
+        drop(first);
+        assert_eq!(client.subscription_refs.lock().unwrap().get("hello/#"), Some(&(1, QoS::AtLeastOnce)));
+        drop(second);
+        assert!(client.subscription_refs.lock().unwrap().is_empty());
+        drop(client);
+
+        let requests: Vec<String> = request_rx
+            .wait()
+            .map(|request| match request.unwrap() {
+                Request::Subscribe(subscribe) => format!("subscribe {} {:?}", subscribe.topics[0].topic_path, subscribe.topics[0].qos),
+                Request::Unsubscribe(unsubscribe) => format!("unsubscribe {}", unsubscribe.topics[0]),
+                request => format!("{:?}", request),
+            })
+            .collect();
+        assert_eq!(requests, vec!["subscribe hello/# AtMostOnce", "subscribe hello/# AtLeastOnce", "unsubscribe hello/#"]);
+    }
+
+    #[test]
+    fn spilled_publishes_should_be_acked_in_manual_ack_mode() {
+        use super::PublishFile;
+        use mqtt311::PacketIdentifier;
+        use std::path::PathBuf;
+
+        let opts = MqttOptions::new("test-id", "localhost", 1883).set_manual_acks(true);
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let mut client = client(opts, request_tx);
+        let file = |qos, pkid| PublishFile {
+            topic_name: "ota/image".to_owned(),
+            qos,
+            retain: false,
+            pkid: Some(PacketIdentifier(pkid)),
+            path: PathBuf::from("image.payload"),
+            len: 1024,
+        };
+
+        client.ack_file(&file(QoS::AtLeastOnce, 1)).unwrap();
+        client.ack_file(&file(QoS::ExactlyOnce, 2)).unwrap();
+        client.ack_file(&file(QoS::AtMostOnce, 3)).unwrap();
+        drop(client);
+
+        let acks: Vec<_> = request_rx.wait().map(|request| format!("{:?}", request.unwrap())).collect();
+        assert_eq!(acks, vec!["PubAck(PacketIdentifier(1))", "PubComp(PacketIdentifier(2))"]);
+    }
+
+    #[test]
+    fn failing_handler_should_be_retried_and_then_nacked() {
+        use crate::DeadLetter;
+        use mqtt311::{PacketIdentifier, Publish};
+
+        let (dead_tx, dead_rx) = crossbeam_channel::unbounded();
+        let opts = MqttOptions::new("test-id", "localhost", 1883)
+            .set_manual_acks(true)
+            .set_dead_letter(DeadLetter::Channel(dead_tx))
+            .set_handler_retry(3, Duration::from_millis(1));
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let mut client = client(opts, request_tx);
+        let publish = |pkid| Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(pkid)),
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        let mut attempts = 0;
+        client.handle(publish(1), |_| {
+            attempts += 1;
+            Err("broken")
+        })
+        .unwrap();
+        assert_eq!(attempts, 3);
+        assert_eq!(dead_rx.try_recv().unwrap().pkid, Some(PacketIdentifier(1)));
+
+        let mut attempts = 0;
+        client.handle(publish(2), |_| {
+            attempts += 1;
+            if attempts < 2 { Err("flaky") } else { Ok(()) }
+        })
+        .unwrap();
+        assert_eq!(attempts, 2);
+        assert!(dead_rx.try_recv().is_err());
+        drop(client);
+
+        // nacked publish is still acked so that the broker doesn't resend it
+        let acks: Vec<_> = request_rx.wait().map(|request| format!("{:?}", request.unwrap())).collect();
+        assert_eq!(acks, vec!["PubAck(PacketIdentifier(1))", "PubAck(PacketIdentifier(2))"]);
+    }
+
+    #[test]
+    fn self_test_should_be_deregistered_when_it_fails() {
+        use crate::ClientError;
+
+        let requests = |request_rx: mpsc::Receiver<Request>| -> Vec<String> {
+            request_rx
+                .wait()
+                .map(|request| match request.unwrap() {
+                    Request::SelfTest(..) => "self test".to_owned(),
+                    Request::Subscribe(_) => "subscribe".to_owned(),
+                    Request::Publish(_) => "publish".to_owned(),
+                    Request::Unsubscribe(_) => "unsubscribe".to_owned(),
+                    Request::SelfTestDone(topic) => format!("done {}", topic.starts_with("rumqtt/self-test/")),
+                    request => panic!("Unexpected request = {:?}", request),
+                })
+                .collect()
+        };
+
+        // nothing comes back
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let mut tester = client(MqttOptions::new("test-id", "localhost", 1883), request_tx);
+        match tester.self_test(Duration::from_millis(10)) {
+            Err(ClientError::SelfTestTimeout(_)) => (),
+            out => panic!("Expecting self test timeout. Received = {:?}", out),
+        }
+
+        drop(tester);
+        assert_eq!(requests(request_rx), vec!["self test", "subscribe", "publish", "unsubscribe", "done true"]);
+
+        // probe publish is rejected by the client
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let mut tester = client(MqttOptions::new("test-id", "localhost", 1883).set_max_packet_size(0), request_tx);
+        match tester.self_test(Duration::from_millis(10)) {
+            Err(ClientError::PacketSizeLimitExceeded) => (),
+            out => panic!("Expecting packet size error. Received = {:?}", out),
+        }
+
+        drop(tester);
+        assert_eq!(requests(request_rx), vec!["self test", "subscribe", "unsubscribe", "done true"]);
+    }
+
+    #[test]
+    fn blocked_publisher_should_wake_up_when_a_pkid_is_freed() {
+        let (request_tx, _request_rx) = mpsc::channel(10);
+        let blocked = client(MqttOptions::new("test-id", "localhost", 1883), request_tx);
+        let stats = blocked.connection_stats.clone();
+        stats.set_pkid_exhausted(true);
+
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        thread::spawn(move || {
+            blocked.wait_for_pkid().unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        stats.set_pkid_exhausted(false);
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn subscriptions_should_fail_on_exhausted_pkids_like_publishes() {
+        use crate::{ClientError, PkidExhaustion};
+
+        let (request_tx, _request_rx) = mpsc::channel(10);
+        let opts = MqttOptions::new("test-id", "localhost", 1883).set_pkid_exhaustion(PkidExhaustion::Fail);
+        let mut client = client(opts, request_tx);
+        client.connection_stats.set_pkid_exhausted(true);
+
+        assert!(matches!(client.publish("a/b", QoS::AtLeastOnce, false, vec![1]), Err(ClientError::PkidExhausted)));
+        assert!(matches!(client.subscribe("a/b", QoS::AtMostOnce), Err(ClientError::PkidExhausted)));
+        assert!(matches!(client.unsubscribe("a/b"), Err(ClientError::PkidExhausted)));
+
+        client.connection_stats.set_pkid_exhausted(false);
+        client.subscribe("a/b", QoS::AtMostOnce).unwrap();
+        client.unsubscribe("a/b").unwrap();
+    }
+
+    #[test]
+    fn publish_to_many_should_share_the_payload_buffer() {
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let mut client = client(MqttOptions::new("test-id", "localhost", 1883), request_tx);
+        client.publish_to_many(vec!["a/1", "a/2", "a/3"], vec![1, 2, 3], QoS::AtLeastOnce).unwrap();
+        drop(client);
+
+        let publishes: Vec<_> = request_rx
+            .wait()
+            .map(|request| match request {
+                Ok(Request::Publish(publish)) => publish,
+                request => panic!("Unexpected request = {:?}", request),
+            })
+            .collect();
+
+        let topics: Vec<_> = publishes.iter().map(|publish| publish.topic_name.as_str()).collect();
+        assert_eq!(topics, vec!["a/1", "a/2", "a/3"]);
+        assert!(publishes.iter().all(|publish| Arc::ptr_eq(&publish.payload, &publishes[0].payload)));
+    }
+    #[test]
+    fn drive_from_should_publish_everything_sent_on_the_channel() {
+        let opts = MqttOptions::new("test-id", "localhost", 1883).add_validator("numbers/#", |_: &str, payload: &[u8]| {
+            match payload.iter().all(u8::is_ascii_digit) {
+                true => Ok(()),
+                false => Err("not a number".to_owned()),
+            }
+        });
+        let (request_tx, request_rx) = mpsc::channel(10);
+        let mut client = client(opts, request_tx);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let legacy = thread::spawn(move || {
+            tx.send(("numbers/a".to_owned(), b"1".to_vec(), QoS::AtMostOnce)).unwrap();
+            tx.send(("numbers/b".to_owned(), b"x".to_vec(), QoS::AtMostOnce)).unwrap();
+            tx.send(("numbers/c".to_owned(), b"3".to_vec(), QoS::AtLeastOnce)).unwrap();
+        });
+
+        // invalid publish is skipped and driving ends with the senders
+        assert_eq!(client.drive_from(rx), 2);
+        legacy.join().unwrap();
+        drop(client);
+
+        let publishes: Vec<(String, QoS)> = request_rx
+            .wait()
+            .map(|request| match request {
+                Ok(Request::Publish(publish)) => (publish.topic_name, publish.qos),
+                request => panic!("Unexpected request = {:?}", request),
+            })
+            .collect();
+        assert_eq!(publishes, vec![("numbers/a".to_owned(), QoS::AtMostOnce), ("numbers/c".to_owned(), QoS::AtLeastOnce)]);
+    }
+}
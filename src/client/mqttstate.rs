@@ -1,13 +1,38 @@
+#[cfg(feature = "simulation")]
+use std::{cell::Cell, rc::Rc};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     result::Result,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use crate::client::{Notification, Request};
+use crate::client::{BatchStatus, Inflight, Notification, PendingWork, Request};
 use crate::error::{ConnectError, NetworkError};
-use crate::mqttoptions::{MqttOptions, SecurityOptions};
-use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Subscribe, Protocol};
+use crate::mqttoptions::{MqttOptions, Qos2Delivery, QueuePolicy, Reconfigure, SecurityOptions};
+use crate::pkid::PkidAllocatorHandle;
+use crate::probe::ProbeHandle;
+use crate::session::Session;
+use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic, Unsubscribe, Protocol};
+
+/// Source of time for the state. Simulations advance a manual clock to test
+/// timing behaviour deterministically. System clock is monotonic (`Instant`) so
+/// keep alive and retries aren't affected by wall clock corrections
+#[derive(Debug, Clone)]
+pub(crate) enum Clock {
+    System,
+    #[cfg(feature = "simulation")]
+    Manual(Rc<Cell<Instant>>),
+}
+
+impl Clock {
+    fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            #[cfg(feature = "simulation")]
+            Clock::Manual(now) => now.get(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum MqttConnectionStatus {
@@ -17,13 +42,30 @@ enum MqttConnectionStatus {
     Disconnected,
 }
 
+/// Send times and retransmissions of a publish in flight
+#[derive(Debug, Clone, Copy)]
+struct SendMeta {
+    first: Instant,
+    last: Instant,
+    retransmits: usize,
+}
+
+impl SendMeta {
+    fn new(now: Instant) -> Self {
+        SendMeta { first: now, last: now, retransmits: 0 }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct MqttState {
     pub opts: MqttOptions,
+    clock: Clock,
 
     // --------  State  ----------
     connection_status: MqttConnectionStatus,
     await_pingresp: bool,
+    ping_sent: Instant,
+    pending_keep_alive: Option<u16>, // applied on next connection
     last_incoming: Instant,
     last_outgoing: Instant,
     last_pkid: PacketIdentifier,
@@ -31,9 +73,28 @@ pub(crate) struct MqttState {
     // Stores outgoing data to handle quality of service
     outgoing_pub: VecDeque<Publish>, // QoS1 & 2 publishes
     outgoing_rel: VecDeque<PacketIdentifier>,
+    outgoing_meta: BTreeMap<PacketIdentifier, SendMeta>,
+    outgoing_spill: VecDeque<Publish>, // QoS1 & 2 publishes waiting for an inflight slot or a free packet id
+    outgoing_sub_spill: VecDeque<Request>, // Subscribes and unsubscribes waiting for a free packet id
+    outgoing_sub: BTreeMap<PacketIdentifier, Vec<SubscribeTopic>>, // Subscribes awaiting suback
+    outgoing_unsub: VecDeque<PacketIdentifier>, // Unsubscribes awaiting unsuback
+    connection_scoped: BTreeSet<PacketIdentifier>, // QoS1 & 2 publishes which aren't resent after reconnection
 
     // Store incoming data to handle quality of service
-    incoming_pub: VecDeque<PacketIdentifier>, // QoS2 publishes
+    incoming_pub: VecDeque<Publish>, // QoS2 publishes held until pubrel
+    incoming_comp: VecDeque<PacketIdentifier>, // Released QoS2 publishes awaiting user ack
+    incoming_rec: VecDeque<(PacketIdentifier, bool)>, // QoS2 publishes delivered before pubrel and user ack status
+
+    // Subscriptions of the client and the ones to renew after a session import
+    subscriptions: BTreeMap<String, QoS>,
+    resubscribe: Option<Subscribe>,
+
+    // Batches waiting for the acks of these packet ids
+    watchers: Vec<(Vec<PacketIdentifier>, crossbeam_channel::Sender<BatchStatus>)>,
+    probe: Option<ProbeHandle>,
+    pkid_allocator: PkidAllocatorHandle,
+    // Self test topics waiting for their publish to come back
+    self_tests: Vec<(String, crossbeam_channel::Sender<()>)>,
 }
 
 /// Design: `MqttState` methods will just modify the state of the object
@@ -42,38 +103,167 @@ pub(crate) struct MqttState {
 ///         operate directly. This abstracts the functionality better
 ///         so that it's easy to switch between synchronous code, tokio (or)
 ///         async/await
-
 impl MqttState {
     pub fn new(opts: MqttOptions) -> Self {
-        MqttState {
-            opts,
+        MqttState::with_clock(opts, Clock::System)
+    }
+
+    pub fn with_clock(mut opts: MqttOptions, clock: Clock) -> Self {
+        let now = clock.now();
+        let session = opts.take_session();
+        let mut state = MqttState {
+            clock,
             connection_status: MqttConnectionStatus::Disconnected,
             await_pingresp: false,
-            last_incoming: Instant::now(),
-            last_outgoing: Instant::now(),
+            ping_sent: now,
+            pending_keep_alive: None,
+            last_incoming: now,
+            last_outgoing: now,
             last_pkid: PacketIdentifier(0),
             outgoing_pub: VecDeque::new(),
             outgoing_rel: VecDeque::new(),
+            outgoing_meta: BTreeMap::new(),
+            outgoing_spill: VecDeque::new(),
+            outgoing_sub_spill: VecDeque::new(),
+            outgoing_sub: BTreeMap::new(),
+            outgoing_unsub: VecDeque::new(),
+            connection_scoped: BTreeSet::new(),
             incoming_pub: VecDeque::new(),
+            incoming_comp: VecDeque::new(),
+            incoming_rec: VecDeque::new(),
+            subscriptions: BTreeMap::new(),
+            resubscribe: None,
+            watchers: Vec::new(),
+            probe: opts.probe(),
+            pkid_allocator: opts.pkid_allocator(),
+            self_tests: Vec::new(),
+            opts,
+        };
+
+        if let Some(session) = session {
+            state.import_session(session);
+        } else {
+            state.restore_outgoing();
+            if !state.opts.clean_session() {
+                state.restore_subscriptions();
+            }
+        }
+
+        state
+    }
+
+    /// Renews the subscriptions saved by the previous run on the first connection.
+    /// Broker can report a session as present but still have lost subscriptions,
+    /// and subscribing again is the only way to find out in mqtt 3.1.1
+    fn restore_subscriptions(&mut self) {
+        let subscriptions = match self.opts.store().map(|store| store.subscriptions()) {
+            Some(Ok(subscriptions)) => subscriptions,
+            Some(Err(e)) => {
+                error!("Failed to read subscriptions from store. Error = {:?}", e);
+                return;
+            }
+            None => return,
+        };
+
+        if subscriptions.is_empty() {
+            return;
+        }
+
+        for topic in subscriptions.iter() {
+            self.subscriptions.insert(topic.topic_path.clone(), topic.qos);
+        }
+
+        self.resubscribe = Some(Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: subscriptions,
+        });
+    }
+
+    fn persist_subscriptions(&mut self) {
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.put_subscriptions(&self.subscription_topics()) {
+                error!("Failed to persist subscriptions. Error = {:?}", e);
+            }
+        }
+    }
+
+    fn import_session(&mut self, session: Session) {
+        let now = self.clock.now();
+        self.last_pkid = session.last_pkid;
+        for publish in session.outgoing_pub.iter() {
+            if let Some(pkid) = publish.pkid {
+                self.outgoing_meta.insert(pkid, SendMeta::new(now));
+            }
+        }
+
+        for pkid in session.outgoing_rel.iter() {
+            self.outgoing_meta.insert(*pkid, SendMeta::new(now));
+        }
+
+        self.outgoing_pub = session.outgoing_pub.into();
+        self.outgoing_rel = session.outgoing_rel.into();
+        self.incoming_pub = session.incoming_pub.into();
+        self.incoming_rec = session.incoming_rec.into_iter().map(|pkid| (pkid, false)).collect();
+        if !session.subscriptions.is_empty() {
+            self.resubscribe = Some(Subscribe {
+                pkid: PacketIdentifier::zero(),
+                topics: session.subscriptions,
+            });
+        }
+    }
+
+    fn subscription_topics(&self) -> Vec<SubscribeTopic> {
+        self.subscriptions
+            .iter()
+            .map(|(topic, qos)| SubscribeTopic { topic_path: topic.clone(), qos: *qos })
+            .collect()
+    }
+
+    /// Publishes which aren't done yet. Used on shutdown
+    pub fn pending_work(&self) -> PendingWork {
+        PendingWork {
+            unsent: self.outgoing_spill.iter().cloned().collect(),
+            inflight: self.outgoing_pub.iter().cloned().collect(),
+            released: self.outgoing_rel.iter().cloned().collect(),
+        }
+    }
+
+    /// Snapshot of the session which can be imported in another client
+    pub fn export_session(&self) -> Session {
+        let subscriptions = self.subscription_topics();
+
+        Session {
+            last_pkid: self.last_pkid,
+            subscriptions,
+            outgoing_pub: self.outgoing_pub.iter().cloned().collect(),
+            outgoing_rel: self.outgoing_rel.iter().cloned().collect(),
+            incoming_pub: self.incoming_pub.iter().cloned().collect(),
+            incoming_rec: self.incoming_rec.iter().map(|(pkid, _)| *pkid).collect(),
         }
     }
 
     pub fn handle_outgoing_mqtt_packet(&mut self, packet: Packet) -> Result<Request, NetworkError> {
         let out = match packet {
-            Packet::Publish(publish) => {
-                let publish = self.handle_outgoing_publish(publish)?;
-                Request::Publish(publish)
-            }
+            Packet::Publish(publish) => match self.spill_if_inflight_full(publish) {
+                Some(publish) => Request::Publish(self.handle_outgoing_publish(publish)?),
+                None => Request::None,
+            },
             Packet::Pingreq => self.handle_outgoing_ping()?,
+            Packet::Subscribe(subs) if self.is_pkid_exhausted() => self.spill_until_pkid_is_free(Request::Subscribe(subs)),
             Packet::Subscribe(subs) => {
                 let subscription = self.handle_outgoing_subscribe(subs)?;
                 Request::Subscribe(subscription)
             }
+            Packet::Unsubscribe(unsubscribe) if self.is_pkid_exhausted() => self.spill_until_pkid_is_free(Request::Unsubscribe(unsubscribe)),
+            Packet::Unsubscribe(unsubscribe) => Request::Unsubscribe(self.handle_outgoing_unsubscribe(unsubscribe)?),
             Packet::Disconnect => self.handle_outgoing_disconnect()?,
+            Packet::Puback(pkid) => self.handle_outgoing_puback(pkid)?,
+            Packet::Pubcomp(pkid) => self.handle_outgoing_pubcomp(pkid)?,
+            Packet::Pubrel(pkid) => self.handle_outgoing_pubrel(pkid)?,
             _ => unimplemented!(),
         };
 
-        self.last_outgoing = Instant::now();
+        self.last_outgoing = self.clock.now();
         Ok(out)
     }
 
@@ -89,8 +279,8 @@ impl MqttState {
         let out = match packet {
             Packet::Pingresp => self.handle_incoming_pingresp(),
             Packet::Publish(publish) => self.handle_incoming_publish(publish.clone()),
-            Packet::Suback(_pkid) => Ok((Notification::None, Request::None)),
-            Packet::Unsuback(_pkid) => Ok((Notification::None, Request::None)),
+            Packet::Suback(suback) => self.handle_incoming_suback(suback),
+            Packet::Unsuback(pkid) => self.handle_incoming_unsuback(pkid),
             Packet::Puback(pkid) => self.handle_incoming_puback(pkid),
             Packet::Pubrec(pkid) => self.handle_incoming_pubrec(pkid),
             Packet::Pubrel(pkid) => self.handle_incoming_pubrel(pkid),
@@ -98,26 +288,74 @@ impl MqttState {
             _ => panic!("{:?}", packet),
         };
 
-        self.last_incoming = Instant::now();
-        out
+        self.last_incoming = self.clock.now();
+        match out {
+            Ok((Notification::Publish(publish), reply)) => Ok(self.handle_self_test_publish(publish, reply)),
+            out => out,
+        }
+    }
+
+    /// Registers the topic of a self test. Its publish is swallowed when it comes
+    /// back from the broker and the client is signalled instead
+    pub fn handle_self_test(&mut self, topic: String, tx: crossbeam_channel::Sender<()>) {
+        self.self_tests.push((topic, tx));
+    }
+
+    /// Forgets the topic of a self test which is over. A later publish on it is
+    /// notified like any other
+    pub fn handle_self_test_done(&mut self, topic: &str) {
+        self.self_tests.retain(|(self_test, _)| self_test != topic);
+    }
+
+    fn handle_self_test_publish(&mut self, publish: Publish, reply: Request) -> (Notification, Request) {
+        let position = self.self_tests.iter().position(|(topic, _)| *topic == publish.topic_name);
+        match position {
+            Some(position) => {
+                let (_, tx) = self.self_tests.remove(position);
+                let _ = tx.try_send(());
+                let reply = self.handle_incoming_rejected(&publish, reply);
+                (Notification::None, reply)
+            }
+            None => (Notification::Publish(publish), reply),
+        }
     }
 
     pub fn handle_outgoing_connect(&mut self) -> Result<Connect, ConnectError> {
         self.connection_status = MqttConnectionStatus::Handshake;
+        self.last_outgoing = self.clock.now();
+        if let Some(keep_alive) = self.pending_keep_alive.take() {
+            self.opts = self.opts.clone().set_keep_alive(keep_alive);
+        }
+
+        self.opts = self.opts.clone().apply_broker_keep_alive_limit();
         connect_packet(&self.opts)
     }
 
+    /// Applies options changed on the running client. Keep alive is negotiated
+    /// in the connect packet and hence is deferred till the next connection
+    pub fn handle_reconfigure(&mut self, reconfigure: Reconfigure) {
+        match reconfigure {
+            Reconfigure::KeepAlive(keep_alive) => self.pending_keep_alive = Some(keep_alive),
+            reconfigure => self.opts = self.opts.clone().reconfigure(reconfigure),
+        }
+    }
+
     pub fn handle_incoming_connack(&mut self, connack: Connack) -> Result<(), ConnectError> {
-        let response = connack.code;
-        if response != ConnectReturnCode::Accepted {
-            self.connection_status = MqttConnectionStatus::Disconnected;
-            Err(ConnectError::MqttConnectionRefused(response.to_u8()))
-        } else {
-            self.connection_status = MqttConnectionStatus::Connected;
-            self.handle_previous_session();
+        let error = match connack.code {
+            ConnectReturnCode::Accepted => {
+                self.connection_status = MqttConnectionStatus::Connected;
+                self.handle_previous_session(connack.session_present);
+                return Ok(());
+            }
+            ConnectReturnCode::RefusedProtocolVersion => ConnectError::UnacceptableProtocolVersion,
+            ConnectReturnCode::RefusedIdentifierRejected => ConnectError::IdentifierRejected,
+            ConnectReturnCode::ServerUnavailable => ConnectError::ServiceUnavailable,
+            ConnectReturnCode::BadUsernamePassword => ConnectError::BadUsernamePassword,
+            ConnectReturnCode::NotAuthorized => ConnectError::NotAuthorized,
+        };
 
-            Ok(())
-        }
+        self.connection_status = MqttConnectionStatus::Disconnected;
+        Err(error)
     }
 
     pub fn handle_outgoing_disconnect(&mut self) -> Result<Request, NetworkError> {
@@ -126,24 +364,85 @@ impl MqttState {
     }
 
     pub fn handle_reconnection(&mut self) -> VecDeque<Request> {
-        if self.opts.clean_session() {
+        let mut requests: VecDeque<Request> = if self.opts.clean_session() {
             VecDeque::new()
         } else {
-            //TODO: Write unittest for checking state during reconnection
-            self.outgoing_pub.split_off(0).into_iter().map(Request::Publish).collect()
-        }
+            // qos2 handshakes interrupted after pubrec resume with pubrel (spec 4.4)
+            let pubrels = self.outgoing_rel.split_off(0).into_iter().map(Request::PubRel);
+            let (dropped, publishes): (Vec<Publish>, Vec<Publish>) = self
+                .outgoing_pub
+                .split_off(0)
+                .into_iter()
+                .partition(|publish| publish.pkid.is_some_and(|pkid| self.connection_scoped.contains(&pkid)));
+
+            for pkid in dropped.into_iter().filter_map(|publish| publish.pkid) {
+                debug!("Dropping connection scoped publish. Pkid = {:?}", pkid);
+                self.outgoing_meta.remove(&pkid);
+                self.connection_scoped.remove(&pkid);
+                self.lost(pkid);
+            }
+
+            // replays are redeliveries (spec 3.3.1.1)
+            let publishes = publishes.into_iter().map(|mut publish| {
+                publish.dup = true;
+                Request::Publish(publish)
+            });
+
+            pubrels.chain(publishes).collect()
+        };
+
+        // spilled requests weren't sent yet and go out after the replays
+        requests.extend(self.outgoing_sub_spill.drain(..));
+        requests.extend(self.outgoing_spill.drain(..).map(Request::Publish));
+        requests.extend(self.resubscribe.take().map(Request::Subscribe));
+        requests
     }
 
-    fn add_packet_id_and_save(&mut self, mut publish: Publish) -> Publish {
-        let publish = if publish.pkid == None {
-            let pkid = self.next_pkid();
-            publish.pkid = Some(pkid);
-            publish
-        } else {
-            publish
+    fn add_packet_id_and_save(&mut self, mut publish: Publish) -> Result<Publish, NetworkError> {
+        let now = self.clock.now();
+        let publish = match publish.pkid {
+            None => {
+                let pkid = self.next_pkid().ok_or(NetworkError::PkidExhausted)?;
+                publish.pkid = Some(pkid);
+                self.outgoing_meta.insert(pkid, SendMeta::new(now));
+                self.persist_outgoing(&publish);
+                publish
+            }
+            // replays count as retransmissions. connection scoped publishes get their
+            // pkid before they are sent
+            Some(pkid) => {
+                self.outgoing_meta
+                    .entry(pkid)
+                    .and_modify(|meta| {
+                        meta.last = now;
+                        meta.retransmits += 1;
+                    })
+                    .or_insert_with(|| SendMeta::new(now));
+                publish
+            }
         };
 
         self.outgoing_pub.push_back(publish.clone());
+        if let (Some(probe), Some(pkid)) = (&self.probe, publish.pkid) {
+            probe.sent(pkid, now);
+        }
+
+        Ok(publish)
+    }
+
+    /// Reserves a packet id for a publish which isn't resent after reconnection
+    pub fn handle_connection_scoped_publish(&mut self, mut publish: Publish) -> Publish {
+        if publish.qos == QoS::AtMostOnce {
+            return publish;
+        }
+
+        let pkid = match self.next_pkid() {
+            Some(pkid) => pkid,
+            None => return publish,
+        };
+
+        publish.pkid = Some(pkid);
+        self.connection_scoped.insert(pkid);
         publish
     }
 
@@ -153,30 +452,239 @@ impl MqttState {
         
         let publish = match publish.qos {
             QoS::AtMostOnce => publish,
-            QoS::AtLeastOnce | QoS::ExactlyOnce => self.add_packet_id_and_save(publish),
+            QoS::AtLeastOnce | QoS::ExactlyOnce => self.add_packet_id_and_save(publish)?,
         };
 
         // debug!("Publish. Topic = {:?}, Pkid = {:?}, Payload Size = {:?}", publish.topic_name, publish.pkid, publish.payload.len());
         Ok(publish)
     }
 
+    /// Holds fresh qos 1 and 2 publishes back when max inflight publishes are
+    /// unacknowledged or all the packet ids are in flight
+    fn spill_if_inflight_full(&mut self, publish: Publish) -> Option<Publish> {
+        if publish.qos == QoS::AtMostOnce || publish.pkid.is_some() || !self.is_inflight_full() {
+            return Some(publish);
+        }
+
+        if let Some((limit, policy)) = self.opts.outgoing_queue_policy() {
+            if self.outgoing_spill.len() >= limit {
+                match policy {
+                    QueuePolicy::DropNewest => {
+                        warn!("Outgoing queue full. Dropping publish. Topic = {}", publish.topic_name);
+                        return None;
+                    }
+                    QueuePolicy::DropOldest => {
+                        if let Some(dropped) = self.outgoing_spill.pop_front() {
+                            warn!("Outgoing queue full. Dropping oldest publish. Topic = {}", dropped.topic_name);
+                        }
+                    }
+                    // requests which were taken before the queue filled up
+                    QueuePolicy::Block => (),
+                }
+            }
+        }
+
+        warn!("Inflight limit reached. Spilling publish. Topic = {}", publish.topic_name);
+        self.outgoing_spill.push_back(publish);
+        None
+    }
+
+    /// Holds subscribes and unsubscribes back while all the packet ids are in use
+    fn spill_until_pkid_is_free(&mut self, request: Request) -> Request {
+        warn!("Packet ids exhausted. Spilling subscription request");
+        self.outgoing_sub_spill.push_back(request);
+        Request::None
+    }
+
+    /// Checks if the eventloop should stop taking requests till a publish is acked
+    pub fn is_outgoing_queue_blocked(&self) -> bool {
+        match self.opts.outgoing_queue_policy() {
+            Some((limit, QueuePolicy::Block)) => self.outgoing_spill.len() >= limit,
+            _ => false,
+        }
+    }
+
+    /// Qos 1 and 2 publishes holding a packet id
+    pub fn inflight_count(&self) -> usize {
+        self.outgoing_pub.len() + self.outgoing_rel.len()
+    }
+
+    /// Packet ids held by publishes, subscribes and unsubscribes waiting for their acks
+    fn reserved_pkids(&self) -> usize {
+        // connection scoped publishes reserve their id before they are sent
+        let scoped = self.connection_scoped.iter().filter(|pkid| !self.outgoing_meta.contains_key(pkid)).count();
+        self.outgoing_meta.len() + scoped + self.outgoing_sub.len() + self.outgoing_unsub.len()
+    }
+
+    fn is_pkid_in_use(&self, pkid: PacketIdentifier) -> bool {
+        self.outgoing_meta.contains_key(&pkid)
+            || self.outgoing_rel.contains(&pkid)
+            || self.outgoing_sub.contains_key(&pkid)
+            || self.outgoing_unsub.contains(&pkid)
+            || self.connection_scoped.contains(&pkid)
+    }
+
+    pub fn is_pkid_exhausted(&self) -> bool {
+        self.reserved_pkids() >= self.pkid_allocator.capacity()
+    }
+
+    fn is_inflight_full(&self) -> bool {
+        let capacity = self.pkid_allocator.capacity();
+        let max_inflight = self.opts.max_inflight().map_or(capacity, |max| max.min(capacity));
+        self.inflight_count() >= max_inflight || self.is_pkid_exhausted()
+    }
+
+    /// Sends the oldest request waiting for the packet id freed by an ack. Subscribes
+    /// and unsubscribes go first as they don't count towards max inflight
+    fn release_spilled(&mut self) -> Result<Request, NetworkError> {
+        if self.is_pkid_exhausted() {
+            return Ok(Request::None);
+        }
+
+        if let Some(request) = self.outgoing_sub_spill.pop_front() {
+            return self.handle_outgoing_mqtt_packet(request.into());
+        }
+
+        if self.is_inflight_full() {
+            return Ok(Request::None);
+        }
+
+        match self.outgoing_spill.pop_front() {
+            Some(publish) => Ok(Request::Publish(self.add_packet_id_and_save(publish)?)),
+            None => Ok(Request::None),
+        }
+    }
+
     pub fn publish_queue_len(&self) -> usize {
         self.outgoing_pub.len()
     }
 
     pub fn is_disconnecting(&self) -> bool {
-        match self.connection_status {
-            MqttConnectionStatus::Disconnecting => true,
-            _ => false
+        self.connection_status == MqttConnectionStatus::Disconnecting
+    }
+
+    /// Watches all the qos 1 and 2 publishes in flight. Status is sent once
+    /// they are all acknowledged (pubcomp for qos 2) or lost with the session
+    pub fn handle_watch(&mut self, tx: crossbeam_channel::Sender<BatchStatus>) {
+        let mut pkids: Vec<PacketIdentifier> = self.outgoing_pub.iter().filter_map(|p| p.pkid).collect();
+        pkids.extend(self.outgoing_rel.iter());
+
+        if pkids.is_empty() {
+            let _ = tx.try_send(BatchStatus::Delivered);
+        } else {
+            self.watchers.push((pkids, tx));
+        }
+    }
+
+    /// Qos 1 publishes which aren't acked within the retry interval. They are
+    /// resent with the dup flag straight to the network as they are already
+    /// in the outgoing queue
+    pub fn handle_retransmissions(&mut self) -> Vec<Packet> {
+        let interval = match self.opts.retry_interval() {
+            Some(interval) => interval,
+            None => return Vec::new(),
+        };
+
+        let now = self.clock.now();
+        let outgoing_meta = &mut self.outgoing_meta;
+        let mut packets = Vec::new();
+        for publish in self.outgoing_pub.iter_mut() {
+            let meta = match publish.pkid.and_then(|pkid| outgoing_meta.get_mut(&pkid)) {
+                Some(meta) if now.duration_since(meta.last) >= interval => meta,
+                _ => continue,
+            };
+
+            debug!("Retransmitting publish. Pkid = {:?}", publish.pkid);
+            meta.last = now;
+            meta.retransmits += 1;
+            if let (Some(probe), Some(pkid)) = (&self.probe, publish.pkid) {
+                probe.sent(pkid, now);
+            }
+
+            publish.dup = true;
+            packets.push(Packet::Publish(publish.clone()));
+        }
+
+        // qos 2 handshakes waiting for pubcomp
+        for pkid in self.outgoing_rel.iter() {
+            let meta = match outgoing_meta.get_mut(pkid) {
+                Some(meta) if now.duration_since(meta.last) >= interval => meta,
+                _ => continue,
+            };
+
+            debug!("Retransmitting pubrel. Pkid = {:?}", pkid);
+            meta.last = now;
+            meta.retransmits += 1;
+            packets.push(Packet::Pubrel(*pkid));
         }
+
+        if !packets.is_empty() {
+            self.last_outgoing = now;
+        }
+
+        packets
+    }
+
+    /// Publishes waiting for puback (qos 1) or pubrec (qos 2)
+    pub fn inflight(&self) -> Vec<Inflight> {
+        let now = self.clock.now();
+        self.outgoing_pub
+            .iter()
+            .filter_map(|publish| {
+                let pkid = publish.pkid?;
+                let meta = self.outgoing_meta.get(&pkid).cloned().unwrap_or_else(|| SendMeta::new(now));
+                Some(Inflight {
+                    pkid,
+                    topic: publish.topic_name.clone(),
+                    qos: publish.qos,
+                    age: now - meta.first,
+                    retransmits: meta.retransmits,
+                })
+            })
+            .collect()
+    }
+
+    fn acked(&mut self, pkid: PacketIdentifier) {
+        if let Some(probe) = &self.probe {
+            probe.acked(pkid, self.clock.now());
+        }
+
+        self.connection_scoped.remove(&pkid);
+        for (pkids, _) in self.watchers.iter_mut() {
+            pkids.retain(|p| *p != pkid);
+        }
+
+        self.watchers.retain(|(pkids, tx)| {
+            if !pkids.is_empty() {
+                return true;
+            }
+
+            let _ = tx.try_send(BatchStatus::Delivered);
+            false
+        });
+    }
+
+    /// Batches with a dropped publish can't be delivered anymore
+    fn lost(&mut self, pkid: PacketIdentifier) {
+        self.watchers.retain(|(pkids, tx)| {
+            if !pkids.contains(&pkid) {
+                return true;
+            }
+
+            let _ = tx.try_send(BatchStatus::Lost);
+            false
+        });
     }
 
     pub fn handle_incoming_puback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
         match self.outgoing_pub.iter().position(|x| x.pkid == Some(pkid)) {
             Some(index) => {
                 let _publish = self.outgoing_pub.remove(index).expect("Wrong index");
+                self.outgoing_meta.remove(&pkid);
+                self.remove_persisted_outgoing(pkid);
+                self.acked(pkid);
 
-                let request = Request::None;
+                let request = self.release_spilled()?;
                 let notification = if cfg!(feature = "acknotify") {
                     Notification::PubAck(pkid)
                 } else {
@@ -198,7 +706,10 @@ impl MqttState {
         match self.outgoing_pub.iter().position(|x| x.pkid == Some(pkid)) {
             Some(index) => {
                 let _publish = self.outgoing_pub.remove(index).expect("Wrong index");
+                // pubrel is retransmitted on its own timer
+                self.outgoing_meta.insert(pkid, SendMeta::new(self.clock.now()));
                 self.outgoing_rel.push_back(pkid);
+                self.persist_released(pkid);
 
                 let reply = Request::PubRel(pkid);
                 let notification = if cfg!(feature = "acknotify") {
@@ -209,6 +720,8 @@ impl MqttState {
 
                 Ok((notification, reply))
             }
+            // pubrec retransmission as our pubrel didn't reach the broker yet
+            None if self.outgoing_rel.contains(&pkid) => Ok((Notification::None, Request::PubRel(pkid))),
             None => {
                 error!("Unsolicited pubrec packet: {:?}", pkid);
                 Err(NetworkError::Unsolicited)
@@ -228,41 +741,364 @@ impl MqttState {
             }
             QoS::AtLeastOnce => {
                 let pkid = publish.pkid.unwrap();
-                let request = Request::PubAck(pkid);
+                self.persist_incoming(&publish)?;
+
+                let request = if self.opts.manual_acks() {
+                    Request::None
+                } else {
+                    Request::PubAck(pkid)
+                };
                 let notification = Notification::Publish(publish);
                 Ok((notification, request))
             }
+            // Method B (spec 4.3.3): deliver now and remember the pkid till pubrel. A
+            // retransmitted publish with the same pkid is just acked again
+            QoS::ExactlyOnce if self.opts.qos2_delivery() == Qos2Delivery::OnPublish => {
+                let pkid = publish.pkid.unwrap();
+                let request = Request::PubRec(pkid);
+
+                if self.is_duplicate_qos2(pkid) {
+                    debug!("Duplicate qos2 publish. Pkid = {:?}", pkid);
+                    return Ok((Notification::None, request));
+                }
+
+                self.persist_incoming(&publish)?;
+                self.persist_received(pkid)?;
+                self.incoming_rec.push_back((pkid, false));
+                Ok((Notification::Publish(publish), request))
+            }
+            // Method A (spec 4.3.3): hold the message and deliver it to the user only
+            // after pubrel. A retransmitted publish with the same pkid is just acked
+            // again so that the user never sees it twice
             QoS::ExactlyOnce => {
                 let pkid = publish.pkid.unwrap();
                 let request = Request::PubRec(pkid);
-                let notification = Notification::Publish(publish);
 
-                self.incoming_pub.push_back(pkid);
-                Ok((notification, request))
+                if self.is_duplicate_qos2(pkid) {
+                    debug!("Duplicate qos2 publish. Pkid = {:?}", pkid);
+                } else {
+                    self.persist_incoming(&publish)?;
+                    self.persist_received(pkid)?;
+                    self.incoming_pub.push_back(publish);
+                }
+
+                Ok((Notification::None, request))
             }
         }
     }
 
+    /// Qos 2 publishes received and waiting for pubrel. Includes the ones
+    /// restored from the store
+    fn is_duplicate_qos2(&self, pkid: PacketIdentifier) -> bool {
+        self.incoming_pub.iter().any(|p| p.pkid == Some(pkid)) || self.incoming_rec.iter().any(|(p, _)| *p == pkid)
+    }
+
     pub fn handle_incoming_pubrel(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
-        match self.incoming_pub.iter().position(|x| *x == pkid) {
+        self.remove_persisted_received(pkid);
+
+        // publish is already delivered with method B
+        if let Some(index) = self.incoming_rec.iter().position(|(p, _)| *p == pkid) {
+            let (_, user_acked) = self.incoming_rec.remove(index).expect("Wrong index");
+            let reply = if self.opts.manual_acks() && !user_acked {
+                self.incoming_comp.push_back(pkid);
+                Request::None
+            } else {
+                Request::PubComp(pkid)
+            };
+
+            return Ok((Notification::None, reply));
+        }
+
+        match self.incoming_pub.iter().position(|x| x.pkid == Some(pkid)) {
             Some(index) => {
-                let _pkid = self.incoming_pub.remove(index);
-                let notification = Notification::None;
-                let reply = Request::PubComp(pkid);
+                let publish = self.incoming_pub.remove(index).expect("Wrong index");
+                let notification = Notification::Publish(publish);
+                let reply = if self.opts.manual_acks() {
+                    self.incoming_comp.push_back(pkid);
+                    Request::None
+                } else {
+                    Request::PubComp(pkid)
+                };
+
                 Ok((notification, reply))
             }
+            // pubcomp for this publish will be sent when the user acks it
+            None if self.incoming_comp.contains(&pkid) => Ok((Notification::None, Request::None)),
+            // pubrel retransmission for a publish which is already delivered. Complete
+            // the handshake again without delivering anything
             None => {
-                error!("Unsolicited pubrel packet: {:?}", pkid);
-                Err(NetworkError::Unsolicited)
+                warn!("Pubrel for unknown packet: {:?}", pkid);
+                Ok((Notification::None, Request::PubComp(pkid)))
+            }
+        }
+    }
+
+    /// User ack of an incoming QoS1 publish in manual ack mode
+    pub fn handle_outgoing_puback(&mut self, pkid: PacketIdentifier) -> Result<Request, NetworkError> {
+        self.remove_persisted_incoming(pkid);
+        Ok(Request::PubAck(pkid))
+    }
+
+    /// User ack of an incoming QoS2 publish in manual ack mode
+    pub fn handle_outgoing_pubcomp(&mut self, pkid: PacketIdentifier) -> Result<Request, NetworkError> {
+        match self.incoming_comp.iter().position(|x| *x == pkid) {
+            Some(index) => {
+                self.incoming_comp.remove(index);
+                self.remove_persisted_incoming(pkid);
+                Ok(Request::PubComp(pkid))
+            }
+            // method B publish acked by the user before pubrel. Pubcomp goes out on pubrel
+            None if self.incoming_rec.iter().any(|(p, _)| *p == pkid) => {
+                for (_, user_acked) in self.incoming_rec.iter_mut().filter(|(p, _)| *p == pkid) {
+                    *user_acked = true;
+                }
+
+                self.remove_persisted_incoming(pkid);
+                Ok(Request::None)
+            }
+            None => {
+                error!("Ack for unknown qos2 publish: {:?}", pkid);
+                Ok(Request::None)
+            }
+        }
+    }
+
+    /// Pubrel resent after reconnection. Pkid is queued again till pubcomp
+    pub fn handle_outgoing_pubrel(&mut self, pkid: PacketIdentifier) -> Result<Request, NetworkError> {
+        if !self.outgoing_rel.contains(&pkid) {
+            self.outgoing_rel.push_back(pkid);
+        }
+
+        let now = self.clock.now();
+        self.outgoing_meta.entry(pkid).or_insert_with(|| SendMeta::new(now)).last = now;
+        Ok(Request::PubRel(pkid))
+    }
+
+    /// Publish rejected by the incoming authorizer is never handed to the user.
+    /// Acks which would otherwise wait for the user are sent right away
+    pub fn handle_incoming_rejected(&mut self, publish: &Publish, reply: Request) -> Request {
+        let pkid = match publish.pkid {
+            Some(pkid) => pkid,
+            None => return reply,
+        };
+
+        if !self.opts.manual_acks() {
+            self.remove_persisted_incoming(pkid);
+            return reply;
+        }
+
+        let ack = match publish.qos {
+            QoS::AtMostOnce => return reply,
+            QoS::AtLeastOnce => self.handle_outgoing_puback(pkid),
+            QoS::ExactlyOnce => self.handle_outgoing_pubcomp(pkid),
+        };
+
+        match ack {
+            Ok(Request::None) | Err(_) => reply,
+            Ok(ack) => ack,
+        }
+    }
+
+    /// Saves incoming publish to the store (if persistence is enabled) before
+    /// it's acknowledged to the broker
+    fn persist_incoming(&mut self, publish: &Publish) -> Result<(), NetworkError> {
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.put_incoming(publish) {
+                error!("Failed to persist incoming publish. Error = {:?}", e);
+                return Err(NetworkError::Io(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn persist_received(&mut self, pkid: PacketIdentifier) -> Result<(), NetworkError> {
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.put_received(pkid) {
+                error!("Failed to persist received qos2 pkid. Error = {:?}", e);
+                return Err(NetworkError::Io(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_persisted_received(&mut self, pkid: PacketIdentifier) {
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.remove_received(pkid) {
+                error!("Failed to remove released qos2 pkid from store. Error = {:?}", e);
+            }
+        }
+    }
+
+    /// Restores qos 2 publishes of the previous run which are waiting for pubrel
+    /// so that the broker resending them doesn't deliver them again. Publishes
+    /// which aren't delivered yet are redelivered from the store. A clean session
+    /// discards them
+    fn restore_received(&mut self) {
+        let store = match self.opts.store() {
+            Some(store) => store,
+            None => return,
+        };
+
+        let received = match store.received() {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Failed to read received qos2 pkids from store. Error = {:?}", e);
+                return;
+            }
+        };
+
+        for pkid in received {
+            if self.opts.clean_session() {
+                self.remove_persisted_received(pkid);
+            } else if !self.is_duplicate_qos2(pkid) {
+                self.incoming_rec.push_back((pkid, false));
+            }
+        }
+    }
+
+    /// Saves a qos 1/2 publish which got its packet id. This happens before the
+    /// publish is written to the network, so every publish which might have
+    /// reached the broker is in the store till puback/pubcomp. Publishes are
+    /// resent from the store only with persistent sessions. A failed save is
+    /// logged and the publish still goes out
+    fn persist_outgoing(&mut self, publish: &Publish) {
+        if self.opts.clean_session() {
+            return;
+        }
+
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.put_outgoing(publish) {
+                error!("Failed to persist outgoing publish. Error = {:?}", e);
+            }
+        }
+    }
+
+    fn remove_persisted_outgoing(&mut self, pkid: PacketIdentifier) {
+        if self.opts.clean_session() {
+            return;
+        }
+
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.remove_outgoing(pkid) {
+                error!("Failed to remove acknowledged publish from store. Error = {:?}", e);
+            }
+        }
+    }
+
+    /// Marks a qos 2 publish as received by the broker so that the next run
+    /// continues with pubrel instead of publishing it again
+    fn persist_released(&mut self, pkid: PacketIdentifier) {
+        if self.opts.clean_session() {
+            return;
+        }
+
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.put_released(pkid) {
+                error!("Failed to persist released qos2 pkid. Error = {:?}", e);
+            }
+        }
+    }
+
+    fn remove_persisted_released(&mut self, pkid: PacketIdentifier) {
+        if self.opts.clean_session() {
+            return;
+        }
+
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.remove_released(pkid) {
+                error!("Failed to remove released qos2 pkid from store. Error = {:?}", e);
+            }
+        }
+    }
+
+    /// Restores outgoing publishes of the previous run which the broker didn't
+    /// acknowledge. They are resent, marked as duplicates, on the first
+    /// connection. Qos 2 publishes which the broker received resume with pubrel.
+    /// A clean session discards them
+    fn restore_outgoing(&mut self) {
+        let store = match self.opts.store() {
+            Some(store) => store,
+            None => return,
+        };
+
+        let (publishes, released) = match (store.outgoing(), store.released()) {
+            (Ok(publishes), Ok(released)) => (publishes, released),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to read outgoing publishes from store. Error = {:?}", e);
+                return;
+            }
+        };
+
+        if self.opts.clean_session() {
+            let pkids = publishes.iter().filter_map(|publish| publish.pkid);
+            for pkid in pkids.chain(released.iter().cloned()) {
+                if let Err(e) = store.remove_outgoing(pkid).and_then(|_| store.remove_released(pkid)) {
+                    error!("Failed to remove outgoing publish from store. Error = {:?}", e);
+                }
+            }
+
+            return;
+        }
+
+        let now = self.clock.now();
+        for mut publish in publishes {
+            let pkid = match publish.pkid {
+                Some(pkid) => pkid,
+                None => continue,
+            };
+
+            self.last_pkid = pkid;
+            self.outgoing_meta.insert(pkid, SendMeta::new(now));
+            if released.contains(&pkid) {
+                self.outgoing_rel.push_back(pkid);
+            } else {
+                publish.dup = true;
+                self.outgoing_pub.push_back(publish);
             }
         }
     }
 
+    /// Removes a persisted incoming publish once it's handed over to the user.
+    /// In manual ack mode, this happens when the user acks the publish instead
+    pub fn handle_incoming_delivered(&mut self, pkid: PacketIdentifier) {
+        if !self.opts.manual_acks() {
+            self.remove_persisted_incoming(pkid);
+        }
+    }
+
+    fn remove_persisted_incoming(&mut self, pkid: PacketIdentifier) {
+        if let Some(store) = self.opts.store() {
+            if let Err(e) = store.remove_incoming(pkid) {
+                error!("Failed to remove delivered publish from store. Error = {:?}", e);
+            }
+        }
+    }
+
+    /// Incoming publishes of the previous run which never reached the user
+    pub fn handle_stored_incoming(&mut self) -> Vec<Publish> {
+        self.restore_received();
+        match self.opts.store().map(|store| store.incoming()) {
+            Some(Ok(publishes)) => publishes,
+            Some(Err(e)) => {
+                error!("Failed to read incoming publishes from store. Error = {:?}", e);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+
     pub fn handle_incoming_pubcomp(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
         match self.outgoing_rel.iter().position(|x| *x == pkid) {
             Some(index) => {
                 self.outgoing_rel.remove(index).expect("Wrong index");
-                let request = Request::None;
+                self.outgoing_meta.remove(&pkid);
+                self.remove_persisted_outgoing(pkid);
+                self.remove_persisted_released(pkid);
+                self.acked(pkid);
+
+                let request = self.release_spilled()?;
                 let notification = if cfg!(feature = "acknotify") {
                     Notification::PubComp(pkid)
                 } else {
@@ -284,72 +1120,204 @@ impl MqttState {
     // NOTE: status will be checked for zero keepalive times also
     pub fn handle_outgoing_ping(&mut self) -> Result<Request, NetworkError> {
         let keep_alive = self.opts.keep_alive();
-        let elapsed_in = self.last_incoming.elapsed();
-        let elapsed_out = self.last_outgoing.elapsed();
+        let now = self.clock.now();
+        let elapsed_in = now.duration_since(self.last_incoming);
+        let elapsed_out = now.duration_since(self.last_outgoing);
 
-        // raise error if last ping didn't receive ack
+        // raise error if last ping didn't receive ack within 1.5 keep alives
         if self.await_pingresp {
+            if now.duration_since(self.ping_sent) < keep_alive * 3 / 2 {
+                return Ok(Request::None);
+            }
+
             error!("Error awaiting for last ping response");
             return Err(NetworkError::AwaitPingResp);
         }
 
-
-        let packet = if elapsed_in > keep_alive || elapsed_out > keep_alive {
+        // ping only when nothing else was sent for a keep alive
+        let packet = if elapsed_out >= keep_alive {
             self.await_pingresp = true;
+            self.ping_sent = now;
             Request::Ping
         } else {
             Request::None
         };
 
         debug!(
-            "Ping = {:?}. keep alive = {},
-            last incoming packet before {} secs,
-            last outgoing packet before {} secs",
-            packet, keep_alive.as_secs(), elapsed_in.as_secs(), elapsed_out.as_secs());
+            "Ping = {:?}. keep alive = {} ms,
+            last incoming packet before {} ms,
+            last outgoing packet before {} ms",
+            packet, keep_alive.as_millis(), elapsed_in.as_millis(), elapsed_out.as_millis());
 
         Ok(packet)
     }
 
+    /// Time of the next ping or, when a ping is waiting for its response, the time
+    /// after which the connection is considered dead
+    pub fn keep_alive_deadline(&self) -> Instant {
+        let keep_alive = self.opts.keep_alive();
+        if self.await_pingresp {
+            self.ping_sent + keep_alive * 3 / 2
+        } else {
+            self.last_outgoing + keep_alive
+        }
+    }
+
     pub fn handle_incoming_pingresp(&mut self) -> Result<(Notification, Request), NetworkError> {
         self.await_pingresp = false;
         Ok((Notification::None, Request::None))
     }
 
-    pub fn handle_outgoing_subscribe(&mut self, mut subscription: Subscribe) -> Result<Subscribe, NetworkError> {        
-        let pkid = self.next_pkid();
+    pub fn handle_outgoing_subscribe(&mut self, mut subscription: Subscribe) -> Result<Subscribe, NetworkError> {
+        let pkid = self.next_pkid().ok_or(NetworkError::PkidExhausted)?;
         subscription.pkid = pkid;
+        for topic in subscription.topics.iter() {
+            self.subscriptions.insert(topic.topic_path.clone(), topic.qos);
+        }
 
+        self.outgoing_sub.insert(pkid, subscription.topics.clone());
+        self.persist_subscriptions();
         info!("Subscribe. Topics = {:?}, Pkid = {:?}", subscription.topics, subscription.pkid);   
         Ok(subscription)
     }
 
-    // pub fn handle_incoming_suback(&mut self, ack: Suback) -> Result<(), SubackError> {
-    //     if ack.return_codes.iter().any(|v| *v == SubscribeReturnCodes::Failure) {
-    //         Err(SubackError::Rejected)
-    //     } else {
-    //         Ok(())
-    //     }
-    // }
+    pub fn handle_outgoing_unsubscribe(&mut self, mut unsubscribe: Unsubscribe) -> Result<Unsubscribe, NetworkError> {
+        let pkid = self.next_pkid().ok_or(NetworkError::PkidExhausted)?;
+        unsubscribe.pkid = pkid;
+        self.outgoing_unsub.push_back(pkid);
+        for topic in unsubscribe.topics.iter() {
+            self.subscriptions.remove(topic);
+        }
+
+        self.persist_subscriptions();
+
+        info!("Unsubscribe. Topics = {:?}, Pkid = {:?}", unsubscribe.topics, unsubscribe.pkid);
+        Ok(unsubscribe)
+    }
+
+    pub fn handle_incoming_unsuback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
+        match self.outgoing_unsub.iter().position(|p| *p == pkid) {
+            Some(index) => {
+                self.outgoing_unsub.remove(index);
+                Ok((Notification::UnsubAck(pkid), self.release_spilled()?))
+            }
+            None => {
+                error!("Unsolicited unsuback packet: {:?}", pkid);
+                Err(NetworkError::Unsolicited)
+            }
+        }
+    }
+
+    /// Matches the suback with the subscribe of its packet id and hands the granted
+    /// qos of every filter to the user. Broker can grant a lower qos than requested
+    /// or reject a filter
+    pub fn handle_incoming_suback(&mut self, suback: Suback) -> Result<(Notification, Request), NetworkError> {
+        let topics = match self.outgoing_sub.remove(&suback.pkid) {
+            Some(topics) => topics,
+            None => {
+                error!("Unsolicited suback packet: {:?}", suback.pkid);
+                return Err(NetworkError::Unsolicited);
+            }
+        };
+
+        if topics.len() != suback.return_codes.len() {
+            warn!("Suback doesn't match subscribe. Topics = {:?}, Return codes = {:?}", topics, suback.return_codes);
+        }
+
+        let granted: Vec<(String, SubscribeReturnCodes)> = topics
+            .into_iter()
+            .zip(suback.return_codes)
+            .map(|(topic, code)| {
+                match code {
+                    SubscribeReturnCodes::Failure => warn!("Subscription rejected by the broker. Filter = {}", topic.topic_path),
+                    SubscribeReturnCodes::Success(qos) if qos.to_u8() < topic.qos.to_u8() => {
+                        warn!("Subscription downgraded by the broker. Filter = {}, Qos = {:?}", topic.topic_path, qos)
+                    }
+                    _ => (),
+                }
+
+                (topic.topic_path, code)
+            })
+            .collect();
+
+        Ok((Notification::SubAck(suback.pkid, granted), self.release_spilled()?))
+    }
 
-    fn handle_previous_session(&mut self) {
+    fn handle_previous_session(&mut self, session_present: bool) {
         self.await_pingresp = false;
+        // unsubscribes aren't resent. broker can't ack them on the new connection
+        self.outgoing_sub.clear();
+        self.outgoing_unsub.clear();
 
         if self.opts.clean_session() {
             self.outgoing_pub.clear();
+            self.outgoing_rel.clear();
+            self.outgoing_meta.clear();
+            self.connection_scoped.clear();
+            self.forget_incoming_qos2();
+            for (_, tx) in self.watchers.drain(..) {
+                let _ = tx.try_send(BatchStatus::Lost);
+            }
+        } else if !session_present {
+            self.handle_lost_session();
+        }
+
+        self.last_incoming = self.clock.now();
+        self.last_outgoing = self.clock.now();
+    }
+
+    /// Broker doesn't have the session (never created, expired or lost in a broker
+    /// restart). Unacked publishes are still replayed and subscriptions are renewed.
+    /// Qos 2 handshakes past pubrec can't be continued but the broker owns those
+    /// messages already
+    fn handle_lost_session(&mut self) {
+        let has_state = !self.outgoing_pub.is_empty() || !self.outgoing_rel.is_empty() || !self.incoming_rec.is_empty();
+        if has_state {
+            warn!("Broker doesn't have the session. Starting a fresh one");
+        }
+
+        for pkid in self.outgoing_rel.split_off(0) {
+            self.outgoing_meta.remove(&pkid);
+            self.remove_persisted_outgoing(pkid);
+            self.remove_persisted_released(pkid);
+            self.acked(pkid);
         }
 
-        self.last_incoming = Instant::now();
-        self.last_outgoing = Instant::now();
+        self.forget_incoming_qos2();
+        if self.resubscribe.is_none() && !self.subscriptions.is_empty() {
+            self.resubscribe = Some(Subscribe {
+                pkid: PacketIdentifier::zero(),
+                topics: self.subscription_topics(),
+            });
+        }
+    }
+
+    /// Broker doesn't continue qos2 handshakes of the old session. Stale pkids
+    /// would drop new publishes as duplicates
+    fn forget_incoming_qos2(&mut self) {
+        let held = self.incoming_pub.iter().filter_map(|publish| publish.pkid);
+        let received: Vec<PacketIdentifier> = held.chain(self.incoming_rec.iter().map(|(pkid, _)| *pkid)).collect();
+        for pkid in received {
+            self.remove_persisted_received(pkid);
+        }
+
+        self.incoming_pub.clear();
+        self.incoming_rec.clear();
+        self.incoming_comp.clear();
     }
 
     // http://stackoverflow.com/questions/11115364/mqtt-messageid-practical-implementation
-    fn next_pkid(&mut self) -> PacketIdentifier {
-        let PacketIdentifier(mut pkid) = self.last_pkid;
-        if pkid == 65_535 {
-            pkid = 0;
+    // Packet ids still in use after a roll over are skipped. None when all of them
+    // are in use
+    fn next_pkid(&mut self) -> Option<PacketIdentifier> {
+        for _ in 0..self.pkid_allocator.capacity() {
+            self.last_pkid = self.pkid_allocator.next(self.last_pkid);
+            if !self.is_pkid_in_use(self.last_pkid) {
+                return Some(self.last_pkid);
+            }
         }
-        self.last_pkid = PacketIdentifier(pkid + 1);
-        self.last_pkid
+
+        None
     }
 }
 
@@ -366,7 +1334,7 @@ fn connect_packet(mqttoptions: &MqttOptions) -> Result<Connect, ConnectError> {
     };
     let connect = Connect {
         protocol: Protocol::MQTT(4),
-        keep_alive: mqttoptions.keep_alive().as_secs() as u16,
+        keep_alive: keep_alive_secs(mqttoptions.keep_alive()),
         client_id: mqttoptions.client_id(),
         clean_session: mqttoptions.clean_session(),
         last_will: mqttoptions.last_will(),
@@ -376,6 +1344,13 @@ fn connect_packet(mqttoptions: &MqttOptions) -> Result<Connect, ConnectError> {
     Ok(connect)
 }
 
+/// Keep alive of the connect packet. Rounded up to whole seconds so that the broker
+/// doesn't time out a client which pings on a sub second keep alive
+fn keep_alive_secs(keep_alive: Duration) -> u16 {
+    let secs = keep_alive.as_millis().div_ceil(1000);
+    secs.min(u128::from(u16::MAX)) as u16
+}
+
 #[cfg(feature = "jwt")]
 // Generates a new password for mqtt client authentication
 fn gen_iotcore_password(project: String, key: &[u8], expiry: i64) -> Result<String, ConnectError> {
@@ -400,17 +1375,26 @@ fn gen_iotcore_password(project: String, key: &[u8], expiry: i64) -> Result<Stri
 
     let claims = Claims { iat, exp, aud: project };
 
-    Ok(encode(&jwt_header, &claims, &key)?)
+    Ok(encode(&jwt_header, &claims, key)?)
 }
 
 #[cfg(test)]
 mod test {
-    use std::{sync::Arc, thread, time::Duration};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
 
-    use super::{MqttConnectionStatus, MqttState};
-    use crate::client::{Notification, Request};
+    use super::{connect_packet, MqttConnectionStatus, MqttState};
+    use crate::client::{BatchStatus, Notification, Request};
     use crate::error::NetworkError;
-    use crate::mqttoptions::MqttOptions;
+    use crate::mqttoptions::{MqttOptions, PowerSaving, Qos2Delivery, QueuePolicy, Reconfigure};
+    use crate::persistence::{FileStore, Store};
+    use crate::pkid::Partition;
+    use crate::probe::Probe;
+    use crate::session::Session;
     use mqtt311::*;
 
     fn build_outgoing_publish(qos: QoS) -> Publish {
@@ -435,22 +1419,155 @@ mod test {
         }
     }
 
+    #[derive(Clone, Default)]
+    struct TestStore(Arc<Mutex<Vec<Publish>>>);
+
+    impl Store for TestStore {
+        fn put_incoming(&mut self, publish: &Publish) -> io::Result<()> {
+            let mut publishes = self.0.lock().unwrap();
+            publishes.retain(|p| p.pkid != publish.pkid);
+            publishes.push(publish.clone());
+            Ok(())
+        }
+
+        fn remove_incoming(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+            self.0.lock().unwrap().retain(|p| p.pkid != Some(pkid));
+            Ok(())
+        }
+
+        fn incoming(&mut self) -> io::Result<Vec<Publish>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
     fn build_mqttstate() -> MqttState {
         let opts = MqttOptions::new("test-id", "127.0.0.1", 1883);
         MqttState::new(opts)
     }
 
+    #[test]
+    fn sub_second_keep_alive_should_round_up_in_connect_packet() {
+        let power_saving = PowerSaving {
+            interval: Duration::from_secs(20),
+            window: Duration::from_millis(500),
+            keep_alive: Duration::from_millis(10_500),
+        };
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_power_saving(power_saving);
+        assert_eq!(connect_packet(&opts).unwrap().keep_alive, 11);
+
+        let power_saving = PowerSaving { keep_alive: Duration::from_secs(100_000), ..power_saving };
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_power_saving(power_saving);
+        assert_eq!(connect_packet(&opts).unwrap().keep_alive, u16::MAX);
+    }
+
     #[test]
     fn next_pkid_roll() {
         let mut mqtt = build_mqttstate();
         let mut pkt_id = PacketIdentifier(0);
 
         for _ in 0..65536 {
-            pkt_id = mqtt.next_pkid();
+            pkt_id = mqtt.next_pkid().unwrap();
         }
         assert_eq!(PacketIdentifier(1), pkt_id);
     }
 
+    #[test]
+    fn partitioned_pkids_should_skip_ids_in_flight_and_spill_when_exhausted() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_pkid_allocator(Partition::new(500, 502));
+        let mut mqtt = MqttState::new(opts);
+
+        for pkid in 500..=502 {
+            let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+            assert_eq!(publish.pkid, Some(PacketIdentifier(pkid)));
+        }
+
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce)));
+        assert!(matches!(request, Ok(Request::None)));
+
+        mqtt.handle_incoming_puback(PacketIdentifier(501)).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(500)).unwrap();
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(500)));
+    }
+
+    #[test]
+    fn pkids_of_subscriptions_should_count_towards_exhaustion() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_pkid_allocator(Partition::new(500, 502));
+        let mut mqtt = MqttState::new(opts);
+        let subscribe = || Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec![SubscribeTopic { topic_path: "a/#".to_owned(), qos: QoS::AtLeastOnce }],
+        };
+
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Subscribe(subscribe())).unwrap();
+        assert!(matches!(request, Request::Subscribe(Subscribe { pkid: PacketIdentifier(500), .. })));
+        for pkid in 501..=502 {
+            let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+            assert!(matches!(request, Request::Publish(Publish { pkid: Some(PacketIdentifier(p)), .. }) if p == pkid));
+        }
+
+        // nothing is handed out an id in use
+        assert!(mqtt.is_pkid_exhausted());
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+        assert!(matches!(request, Request::None));
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Subscribe(subscribe())).unwrap();
+        assert!(matches!(request, Request::None));
+        assert!(mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).is_err());
+
+        // freed ids go to the waiting subscribe first and then to the publish
+        let suback = Suback { pkid: PacketIdentifier(500), return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)] };
+        let (_, request) = mqtt.handle_incoming_suback(suback).unwrap();
+        assert!(matches!(request, Request::Subscribe(Subscribe { pkid: PacketIdentifier(500), .. })));
+        let (_, request) = mqtt.handle_incoming_puback(PacketIdentifier(501)).unwrap();
+        assert!(matches!(request, Request::Publish(Publish { pkid: Some(PacketIdentifier(501)), .. })));
+    }
+
+    #[test]
+    fn pending_should_have_spilled_inflight_and_released_publishes() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_pkid_allocator(Partition::new(1, 3));
+        let mut mqtt = MqttState::new(opts);
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::ExactlyOnce))).unwrap();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::ExactlyOnce))).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+
+        // puback released the spilled publish
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+        let pending = mqtt.pending_work();
+        let inflight: Vec<Option<PacketIdentifier>> = pending.inflight.iter().map(|publish| publish.pkid).collect();
+        assert_eq!(inflight, vec![Some(PacketIdentifier(3)), Some(PacketIdentifier(1))]);
+        assert_eq!(pending.released, vec![PacketIdentifier(2)]);
+        assert_eq!(pending.unsent.len(), 1);
+        assert_eq!(pending.unsent[0].pkid, None);
+    }
+
+    #[test]
+    fn outgoing_queue_policy_should_bound_spilled_publishes() {
+        let publish = |payload: u8| {
+            let mut publish = build_outgoing_publish(QoS::AtLeastOnce);
+            publish.payload = Arc::new(vec![payload]);
+            Packet::Publish(publish)
+        };
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_max_inflight(1);
+        for (policy, kept) in [(QueuePolicy::DropNewest, vec![2, 3]), (QueuePolicy::DropOldest, vec![4, 5]), (QueuePolicy::Block, vec![2, 3])] {
+            let mut mqtt = MqttState::new(opts.clone().set_outgoing_queue_policy(2, policy));
+            mqtt.handle_outgoing_mqtt_packet(publish(1)).unwrap();
+            for payload in 2..=5 {
+                mqtt.handle_outgoing_mqtt_packet(publish(payload)).unwrap();
+                if policy == QueuePolicy::Block && mqtt.is_outgoing_queue_blocked() {
+                    break;
+                }
+            }
+
+            let spilled: Vec<u8> = mqtt.pending_work().unsent.iter().map(|publish| publish.payload[0]).collect();
+            assert_eq!(spilled, kept, "{:?}", policy);
+        }
+    }
+
     #[test]
     fn outgoing_publish_handle_should_set_pkid_correctly_and_add_publish_to_queue_correctly() {
         let mut mqtt = build_mqttstate();
@@ -503,22 +1620,62 @@ mod test {
         mqtt.handle_incoming_publish(publish2).unwrap();
         mqtt.handle_incoming_publish(publish3).unwrap();
 
-        let pkid = *mqtt.incoming_pub.get(0).unwrap();
+        let pkid = mqtt.incoming_pub.front().unwrap().pkid;
 
         // only qos2 publish should be add to queue
         assert_eq!(mqtt.incoming_pub.len(), 1);
-        assert_eq!(pkid, PacketIdentifier(3));
+        assert_eq!(pkid, Some(PacketIdentifier(3)));
     }
 
     #[test]
-    fn incoming_qos2_publish_should_send_rec_to_network_and_publish_to_user() {
+    fn self_test_publish_should_be_signalled_and_acked_but_not_notified() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_manual_acks(true);
+        let mut mqtt = MqttState::new(opts);
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        mqtt.handle_self_test("rumqtt/self-test/1".to_owned(), tx);
+
+        let mut publish = build_incoming_publish(QoS::AtLeastOnce, 1);
+        publish.topic_name = "rumqtt/self-test/1".to_owned();
+        let (notification, request) = mqtt.handle_incoming_mqtt_packet(Packet::Publish(publish.clone())).unwrap();
+        match (notification, request) {
+            (Notification::None, Request::PubAck(PacketIdentifier(1))) => (),
+            out => panic!("Invalid self test handling: {:?}", out),
+        }
+        assert!(rx.try_recv().is_ok());
+
+        // the test is done. later publishes on the topic are regular ones
+        let (notification, _) = mqtt.handle_incoming_mqtt_packet(Packet::Publish(publish)).unwrap();
+        match notification {
+            Notification::Publish(publish) => assert_eq!(publish.topic_name, "rumqtt/self-test/1"),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+    }
+
+    #[test]
+    fn timed_out_self_test_should_be_forgotten() {
+        let mut mqtt = build_mqttstate();
+        let (tx, _rx) = crossbeam_channel::bounded(1);
+        mqtt.handle_self_test("rumqtt/self-test/1".to_owned(), tx);
+        mqtt.handle_self_test_done("rumqtt/self-test/1");
+        assert!(mqtt.self_tests.is_empty());
+
+        let mut publish = build_incoming_publish(QoS::AtMostOnce, 1);
+        publish.topic_name = "rumqtt/self-test/1".to_owned();
+        match mqtt.handle_incoming_mqtt_packet(Packet::Publish(publish)).unwrap() {
+            (Notification::Publish(publish), _) => assert_eq!(publish.topic_name, "rumqtt/self-test/1"),
+            out => panic!("Expecting a regular publish. Received = {:?}", out),
+        }
+    }
+
+    #[test]
+    fn incoming_qos2_publish_should_send_rec_to_network_and_nothing_to_user() {
         let mut mqtt = build_mqttstate();
         let publish = build_incoming_publish(QoS::ExactlyOnce, 1);
 
         let (notification, request) = mqtt.handle_incoming_publish(publish).unwrap();
 
         match notification {
-            Notification::Publish(publish) => assert_eq!(publish.pkid.unwrap(), PacketIdentifier(1)),
+            Notification::None => (),
             _ => panic!("Invalid notification: {:?}", notification),
         }
 
@@ -528,6 +1685,146 @@ mod test {
         }
     }
 
+    #[test]
+    fn incoming_qos1_and_qos2_publishes_should_be_persisted_till_delivered() {
+        let store = TestStore::default();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_store(store.clone());
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtMostOnce, 1)).unwrap();
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 2)).unwrap();
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 3)).unwrap();
+        assert_eq!(store.0.lock().unwrap().len(), 2);
+
+        mqtt.handle_incoming_delivered(PacketIdentifier(2));
+        let stored = mqtt.handle_stored_incoming();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].pkid, Some(PacketIdentifier(3)));
+
+        // qos2 publish stays in the store after release until it's handed over
+        mqtt.handle_incoming_pubrel(PacketIdentifier(3)).unwrap();
+        assert_eq!(mqtt.handle_stored_incoming().len(), 1);
+        mqtt.handle_incoming_delivered(PacketIdentifier(3));
+        assert_eq!(mqtt.handle_stored_incoming().len(), 0);
+    }
+
+    #[test]
+    fn unacked_outgoing_publishes_should_be_resent_by_the_next_run() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-outgoing-{}", uuid::Uuid::new_v4()));
+        let opts = || MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false).set_store(FileStore::new(&dir).unwrap());
+
+        let mut mqtt = MqttState::new(opts());
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtMostOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(3)).unwrap();
+        drop(mqtt);
+
+        // crash. unacked publishes are resent as duplicates and the qos 2 publish
+        // past pubrec resumes with pubrel instead of a second delivery
+        let mut mqtt = MqttState::new(opts());
+        let replays: Vec<String> = mqtt
+            .handle_reconnection()
+            .into_iter()
+            .map(|request| match request {
+                Request::Publish(ref publish) if publish.dup => format!("publish {}", publish.pkid.unwrap().0),
+                Request::PubRel(pkid) => format!("pubrel {}", pkid.0),
+                request => panic!("Expecting replay. Received = {:?}", request),
+            })
+            .collect();
+        assert_eq!(replays, vec!["pubrel 3", "publish 2", "publish 4"]);
+
+        // completed handshake is gone from the store
+        mqtt.handle_outgoing_pubrel(PacketIdentifier(3)).unwrap();
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(3)).unwrap();
+        assert_eq!(MqttState::new(opts()).handle_reconnection().len(), 2);
+
+        // new publishes don't reuse the restored packet ids
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(5)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn released_qos2_publishes_should_be_restored_into_pubrel_queue() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-released-{}", uuid::Uuid::new_v4()));
+        let opts = || MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false).set_store(FileStore::new(&dir).unwrap());
+
+        let mut mqtt = MqttState::new(opts());
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        drop(mqtt);
+
+        let mqtt = MqttState::new(opts());
+        assert_eq!(mqtt.outgoing_rel, vec![PacketIdentifier(1)]);
+        let resent: Vec<_> = mqtt.outgoing_pub.iter().map(|publish| (publish.pkid, publish.dup)).collect();
+        assert_eq!(resent, vec![(Some(PacketIdentifier(2)), true)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clean_session_should_purge_released_qos2_publishes() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-released-{}", uuid::Uuid::new_v4()));
+        let opts = |clean| MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(clean).set_store(FileStore::new(&dir).unwrap());
+
+        let mut mqtt = MqttState::new(opts(false));
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        drop(mqtt);
+        assert_eq!(FileStore::new(&dir).unwrap().released().unwrap(), vec![PacketIdentifier(1)]);
+
+        let mqtt = MqttState::new(opts(true));
+        assert!(mqtt.outgoing_rel.is_empty());
+        assert!(mqtt.outgoing_pub.is_empty());
+
+        let mut store = FileStore::new(&dir).unwrap();
+        assert!(store.released().unwrap().is_empty());
+        assert!(store.outgoing().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incoming_publishes_should_be_acked_only_after_user_ack_in_manual_ack_mode() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_manual_acks(true);
+        let mut mqtt = MqttState::new(opts);
+
+        let (_, request) = mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 1)).unwrap();
+        match request {
+            Request::None => (),
+            _ => panic!("Invalid network request: {:?}", request),
+        }
+
+        match mqtt.handle_outgoing_mqtt_packet(Packet::Puback(PacketIdentifier(1))).unwrap() {
+            Request::PubAck(PacketIdentifier(1)) => (),
+            request => panic!("Invalid network request: {:?}", request),
+        }
+
+        let (_, request) = mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 2)).unwrap();
+        match request {
+            Request::PubRec(PacketIdentifier(2)) => (),
+            _ => panic!("Invalid network request: {:?}", request),
+        }
+
+        // neither the pubrel nor its retransmission should be completed before user ack
+        for _ in 0..2 {
+            let (_, request) = mqtt.handle_incoming_pubrel(PacketIdentifier(2)).unwrap();
+            match request {
+                Request::None => (),
+                _ => panic!("Invalid network request: {:?}", request),
+            }
+        }
+
+        match mqtt.handle_outgoing_mqtt_packet(Packet::Pubcomp(PacketIdentifier(2))).unwrap() {
+            Request::PubComp(PacketIdentifier(2)) => (),
+            request => panic!("Invalid network request: {:?}", request),
+        }
+        assert_eq!(mqtt.incoming_comp.len(), 0);
+    }
+
     #[test]
     fn incoming_puback_should_remove_correct_publish_from_queue() {
         let mut mqtt = build_mqttstate();
@@ -541,7 +1838,7 @@ mod test {
         mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
         assert_eq!(mqtt.outgoing_pub.len(), 1);
 
-        let backup = mqtt.outgoing_pub.get(0).unwrap().clone();
+        let backup = mqtt.outgoing_pub.front().unwrap().clone();
         assert_eq!(backup.pkid, Some(PacketIdentifier(2)));
 
         mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
@@ -562,47 +1859,76 @@ mod test {
         assert_eq!(mqtt.outgoing_pub.len(), 1);
 
         // check if the remaining element's pkid is 1
-        let backup = mqtt.outgoing_pub.get(0).unwrap().clone();
+        let backup = mqtt.outgoing_pub.front().unwrap().clone();
         assert_eq!(backup.pkid, Some(PacketIdentifier(1)));
 
         assert_eq!(mqtt.outgoing_rel.len(), 1);
 
         // check if the  element's pkid is 2
-        let pkid = *mqtt.outgoing_rel.get(0).unwrap();
+        let pkid = *mqtt.outgoing_rel.front().unwrap();
         assert_eq!(pkid, PacketIdentifier(2));
     }
 
     #[test]
-    fn incoming_pubrec_should_send_release_to_network_and_nothing_to_user() {
+    fn incoming_pubrec_should_send_release_to_network_and_nothing_to_user() {
+        let mut mqtt = build_mqttstate();
+
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        mqtt.handle_outgoing_publish(publish).unwrap();
+
+        let (notification, request) = mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+
+        match notification {
+            Notification::None if !cfg!(feature = "acknotify") => (),
+            Notification::PubRec(PacketIdentifier(1)) if cfg!(feature = "acknotify") => (),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        match request {
+            Request::PubRel(PacketIdentifier(pkid)) => assert_eq!(pkid, 1),
+            _ => panic!("Invalid network request: {:?}", request),
+        }
+    }
+
+    #[test]
+    fn incoming_pubrel_should_send_comp_to_network_and_publish_to_user() {
         let mut mqtt = build_mqttstate();
+        let publish = build_incoming_publish(QoS::ExactlyOnce, 1);
 
-        let publish = build_outgoing_publish(QoS::ExactlyOnce);
-        mqtt.handle_outgoing_publish(publish).unwrap();
-
-        let (notification, request) = mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_publish(publish).unwrap();
+        println!("{:?}", mqtt);
+        let (notification, request) = mqtt.handle_incoming_pubrel(PacketIdentifier(1)).unwrap();
 
         match notification {
-            Notification::None => assert!(true),
+            Notification::Publish(publish) => assert_eq!(publish.pkid.unwrap(), PacketIdentifier(1)),
             _ => panic!("Invalid notification: {:?}", notification),
         }
 
         match request {
-            Request::PubRel(PacketIdentifier(pkid)) => assert_eq!(pkid, 1),
+            Request::PubComp(PacketIdentifier(pkid)) => assert_eq!(pkid, 1),
             _ => panic!("Invalid network request: {:?}", request),
         }
     }
 
     #[test]
-    fn incoming_pubrel_should_send_comp_to_network_and_nothing_to_user() {
+    fn duplicate_qos2_publishes_and_pubrels_should_be_delivered_to_user_only_once() {
         let mut mqtt = build_mqttstate();
         let publish = build_incoming_publish(QoS::ExactlyOnce, 1);
 
+        mqtt.handle_incoming_publish(publish.clone()).unwrap();
         mqtt.handle_incoming_publish(publish).unwrap();
-        println!("{:?}", mqtt);
-        let (notification, request) = mqtt.handle_incoming_pubrel(PacketIdentifier(1)).unwrap();
+        assert_eq!(mqtt.incoming_pub.len(), 1);
+
+        let (notification, _) = mqtt.handle_incoming_pubrel(PacketIdentifier(1)).unwrap();
+        match notification {
+            Notification::Publish(_) => (),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
 
+        // retransmitted pubrel should be completed again without a second delivery
+        let (notification, request) = mqtt.handle_incoming_pubrel(PacketIdentifier(1)).unwrap();
         match notification {
-            Notification::None => assert!(true),
+            Notification::None => (),
             _ => panic!("Invalid notification: {:?}", notification),
         }
 
@@ -636,14 +1962,14 @@ mod test {
         // should ping
          match  mqtt.handle_outgoing_ping().unwrap() {
             Request::Ping => (),
-            _ => assert!(false, "expecting ping")
+            _ => panic!("expecting ping")
         }
 
         // network activity other than pingresp
         let publish = build_outgoing_publish(QoS::AtLeastOnce);
         mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish)).unwrap();
         mqtt.handle_incoming_mqtt_packet(Packet::Puback(PacketIdentifier(1))).unwrap();
-        thread::sleep(Duration::from_secs(10));
+        thread::sleep(Duration::from_secs(15));
 
         // should throw error because we didn't get pingresp for previous ping
         match mqtt.handle_outgoing_ping() {
@@ -666,7 +1992,7 @@ mod test {
         // should ping
         match  mqtt.handle_outgoing_ping().unwrap() {
             Request::Ping => (),
-            _ => assert!(false, "expecting ping")
+            _ => panic!("expecting ping")
         }
         mqtt.handle_incoming_mqtt_packet(Packet::Pingresp).unwrap();
 
@@ -674,7 +2000,7 @@ mod test {
         // should ping
          match  mqtt.handle_outgoing_ping().unwrap() {
             Request::Ping => (),
-            _ => assert!(false, "expecting ping")
+            _ => panic!("expecting ping")
         }
     }
 
@@ -697,10 +2023,10 @@ mod test {
         let _ = mqtt.handle_outgoing_publish(publish.clone());
         let _ = mqtt.handle_outgoing_publish(publish);
 
-        mqtt.handle_previous_session();
+        mqtt.handle_previous_session(false);
         assert_eq!(mqtt.outgoing_pub.len(), 0);
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
-        assert_eq!(mqtt.await_pingresp, false);
+        assert!(!mqtt.await_pingresp);
     }
 
     #[test]
@@ -719,10 +2045,10 @@ mod test {
         let _ = mqtt.handle_outgoing_publish(publish.clone());
         let _ = mqtt.handle_outgoing_publish(publish);
 
-        mqtt.handle_previous_session();
+        mqtt.handle_previous_session(true);
         assert_eq!(mqtt.outgoing_pub.len(), 3);
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
-        assert_eq!(mqtt.await_pingresp, false);
+        assert!(!mqtt.await_pingresp);
     }
 
     #[test]
@@ -750,6 +2076,30 @@ mod test {
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
     }
 
+    #[test]
+    fn connack_refusals_should_be_typed_errors() {
+        use crate::error::ConnectError;
+
+        let mut mqtt = build_mqttstate();
+        let refusal = |code| Connack { session_present: false, code };
+
+        let out = mqtt.handle_incoming_connack(refusal(ConnectReturnCode::BadUsernamePassword));
+        assert!(matches!(out, Err(ConnectError::BadUsernamePassword)));
+
+        let out = mqtt.handle_incoming_connack(refusal(ConnectReturnCode::NotAuthorized));
+        assert!(matches!(out, Err(ConnectError::NotAuthorized)));
+
+        let out = mqtt.handle_incoming_connack(refusal(ConnectReturnCode::ServerUnavailable));
+        assert!(matches!(out, Err(ConnectError::ServiceUnavailable)));
+
+        let out = mqtt.handle_incoming_connack(refusal(ConnectReturnCode::RefusedIdentifierRejected));
+        assert!(matches!(out, Err(ConnectError::IdentifierRejected)));
+
+        let out = mqtt.handle_incoming_connack(refusal(ConnectReturnCode::RefusedProtocolVersion));
+        assert!(matches!(out, Err(ConnectError::UnacceptableProtocolVersion)));
+        assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
+    }
+
     #[test]
     fn connack_handle_should_not_return_list_of_incomplete_messages_to_be_sent_in_clean_session() {
         let mut mqtt = build_mqttstate();
@@ -799,6 +2149,195 @@ mod test {
         assert_eq!(3, pubs.len());
     }
 
+    #[test]
+    fn reconfigured_keep_alive_should_apply_from_next_connection() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_keep_alive(50);
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_reconfigure(Reconfigure::KeepAlive(20));
+        assert_eq!(mqtt.opts.keep_alive(), Duration::from_secs(50));
+
+        let pkt = mqtt.handle_outgoing_connect().unwrap();
+        assert_eq!(pkt.keep_alive, 20);
+        assert_eq!(mqtt.opts.keep_alive(), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn watched_batch_should_be_delivered_after_all_acks() {
+        let mut mqtt = build_mqttstate();
+        let (tx, rx) = crossbeam_channel::bounded(1);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_watch(tx);
+
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(2)).unwrap();
+        assert_eq!(rx.try_recv(), Ok(BatchStatus::Delivered));
+    }
+
+    #[test]
+    fn inflight_should_count_retransmissions_after_reconnection() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false);
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtMostOnce)).unwrap();
+        assert_eq!(mqtt.inflight()[0].retransmits, 0);
+
+        for request in mqtt.handle_reconnection() {
+            mqtt.handle_outgoing_mqtt_packet(request.into()).unwrap();
+        }
+
+        let inflight = mqtt.inflight();
+        assert_eq!(inflight.len(), 1);
+        assert_eq!(inflight[0].pkid, PacketIdentifier(1));
+        assert_eq!(inflight[0].retransmits, 1);
+
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        assert!(mqtt.inflight().is_empty());
+    }
+
+    #[derive(Default)]
+    struct Timeline(Mutex<Vec<(&'static str, PacketIdentifier)>>);
+
+    impl Probe for Timeline {
+        fn sent(&self, pkid: PacketIdentifier, _at: Instant) {
+            self.0.lock().unwrap().push(("sent", pkid));
+        }
+
+        fn acked(&self, pkid: PacketIdentifier, _at: Instant) {
+            self.0.lock().unwrap().push(("acked", pkid));
+        }
+    }
+
+    #[test]
+    fn probe_should_see_sends_and_acks_of_qos1_and_qos2_publishes() {
+        let timeline = Arc::new(Timeline::default());
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_probe(timeline.clone());
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtMostOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(2)).unwrap();
+
+        let expected = vec![
+            ("sent", PacketIdentifier(1)),
+            ("sent", PacketIdentifier(2)),
+            ("acked", PacketIdentifier(1)),
+            ("acked", PacketIdentifier(2)),
+        ];
+        assert_eq!(*timeline.0.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn publishes_beyond_max_inflight_should_wait_for_acks() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_max_inflight(2);
+        let mut mqtt = MqttState::new(opts);
+        for _ in 0..2 {
+            let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::ExactlyOnce)));
+            assert!(matches!(request, Ok(Request::Publish(_))));
+        }
+
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce)));
+        assert!(matches!(request, Ok(Request::None)));
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtMostOnce)));
+        assert!(matches!(request, Ok(Request::Publish(_))));
+
+        // pubrec doesn't free the slot. pubcomp does
+        let (_, request) = mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        assert!(matches!(request, Request::PubRel(_)));
+        let (_, request) = mqtt.handle_incoming_pubcomp(PacketIdentifier(1)).unwrap();
+        match request {
+            Request::Publish(publish) => assert_eq!(publish.pkid, Some(PacketIdentifier(3))),
+            request => panic!("Expecting spilled publish. Received = {:?}", request),
+        }
+
+        assert_eq!(mqtt.inflight_count(), 2);
+    }
+
+    #[test]
+    fn publishes_should_spill_when_pkids_are_exhausted_and_reuse_freed_pkids() {
+        let mut mqtt = build_mqttstate();
+        for _ in 0..65_535 {
+            mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+        }
+
+        assert!(mqtt.is_pkid_exhausted());
+        let request = mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce)));
+        assert!(matches!(request, Ok(Request::None)));
+
+        let (_, request) = mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
+        match request {
+            Request::Publish(publish) => assert_eq!(publish.pkid, Some(PacketIdentifier(2))),
+            request => panic!("Expecting spilled publish. Received = {:?}", request),
+        }
+    }
+
+    #[test]
+    fn reconnection_should_resend_pubrel_before_unacked_publishes() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false);
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+
+        let mut requests = Vec::new();
+        for request in mqtt.handle_reconnection() {
+            requests.push(mqtt.handle_outgoing_mqtt_packet(request.into()).unwrap());
+        }
+
+        match (&requests[0], &requests[1]) {
+            (Request::PubRel(PacketIdentifier(1)), Request::Publish(publish)) => assert_eq!(publish.pkid, Some(PacketIdentifier(2))),
+            requests => panic!("Unexpected replay = {:?}", requests),
+        }
+
+        // handshake completes with the pubcomp of the resent pubrel
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(1)).unwrap();
+        assert_eq!(mqtt.outgoing_rel.len(), 0);
+    }
+
+    #[test]
+    fn method_b_qos2_publish_should_be_delivered_once_on_publish() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_qos2_delivery(Qos2Delivery::OnPublish);
+        let mut mqtt = MqttState::new(opts);
+        let mut publish = build_incoming_publish(QoS::ExactlyOnce, 1);
+
+        let (notification, request) = mqtt.handle_incoming_publish(publish.clone()).unwrap();
+        assert!(matches!(notification, Notification::Publish(_)));
+        assert!(matches!(request, Request::PubRec(PacketIdentifier(1))));
+
+        // retransmission before pubrel
+        publish.dup = true;
+        let (notification, request) = mqtt.handle_incoming_publish(publish).unwrap();
+        assert!(matches!(notification, Notification::None));
+        assert!(matches!(request, Request::PubRec(PacketIdentifier(1))));
+
+        let (notification, request) = mqtt.handle_incoming_pubrel(PacketIdentifier(1)).unwrap();
+        assert!(matches!(notification, Notification::None));
+        assert!(matches!(request, Request::PubComp(PacketIdentifier(1))));
+        assert!(mqtt.incoming_rec.is_empty());
+    }
+
+    #[test]
+    fn keep_alive_should_be_capped_by_broker_limit() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883)
+            .set_keep_alive(60)
+            .set_broker_keep_alive_limit(5);
+        let mut mqtt = MqttState::new(opts);
+
+        let pkt = mqtt.handle_outgoing_connect().unwrap();
+        assert_eq!(pkt.keep_alive, 5);
+        assert_eq!(mqtt.opts.keep_alive(), Duration::from_secs(5));
+    }
+
     #[test]
     fn connect_should_respect_options() {
         use crate::mqttoptions::SecurityOptions::UsernamePassword;
@@ -832,4 +2371,348 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn suback_should_carry_granted_qos_and_rejections() {
+        let mut mqtt = build_mqttstate();
+        let subscribe = |filters: &[&str]| Subscribe {
+            pkid: PacketIdentifier(0),
+            topics: filters.iter().map(|f| SubscribeTopic { topic_path: f.to_string(), qos: QoS::ExactlyOnce }).collect(),
+        };
+
+        // overlapping subscribes are acked out of order
+        mqtt.handle_outgoing_subscribe(subscribe(&["a/+", "b/#"])).unwrap();
+        mqtt.handle_outgoing_subscribe(subscribe(&["c"])).unwrap();
+
+        let suback = Suback {
+            pkid: PacketIdentifier(2),
+            return_codes: vec![SubscribeReturnCodes::Success(QoS::AtMostOnce)],
+        };
+        match mqtt.handle_incoming_mqtt_packet(Packet::Suback(suback)).unwrap() {
+            (Notification::SubAck(PacketIdentifier(2), granted), Request::None) => {
+                assert_eq!(granted, vec![("c".to_owned(), SubscribeReturnCodes::Success(QoS::AtMostOnce))]);
+            }
+            out => panic!("Unexpected suback handling = {:?}", out),
+        }
+
+        let suback = Suback {
+            pkid: PacketIdentifier(1),
+            return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure],
+        };
+        match mqtt.handle_incoming_mqtt_packet(Packet::Suback(suback.clone())).unwrap() {
+            (Notification::SubAck(PacketIdentifier(1), granted), Request::None) => {
+                let expected = vec![
+                    ("a/+".to_owned(), SubscribeReturnCodes::Success(QoS::AtLeastOnce)),
+                    ("b/#".to_owned(), SubscribeReturnCodes::Failure),
+                ];
+                assert_eq!(granted, expected);
+            }
+            out => panic!("Unexpected suback handling = {:?}", out),
+        }
+
+        assert!(mqtt.handle_incoming_suback(suback).is_err());
+    }
+
+    #[test]
+    fn unsubscribe_should_be_confirmed_by_unsuback_of_its_pkid() {
+        let mut mqtt = build_mqttstate();
+        let unsubscribe = Unsubscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec!["a/b".to_owned(), "c/d".to_owned()],
+        };
+
+        let pkid = match mqtt.handle_outgoing_mqtt_packet(Packet::Unsubscribe(unsubscribe)).unwrap() {
+            Request::Unsubscribe(unsubscribe) => unsubscribe.pkid,
+            request => panic!("Unexpected request = {:?}", request),
+        };
+
+        assert_eq!(pkid, PacketIdentifier(1));
+        assert_eq!(mqtt.next_pkid(), Some(PacketIdentifier(2)));
+
+        let (notification, _) = mqtt.handle_incoming_mqtt_packet(Packet::Unsuback(pkid)).unwrap();
+        assert!(matches!(notification, Notification::UnsubAck(p) if p == pkid));
+        assert!(mqtt.handle_incoming_unsuback(pkid).is_err());
+    }
+
+    #[test]
+    fn connection_scoped_publishes_should_be_dropped_on_reconnection() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false);
+        let mut mqtt = MqttState::new(opts);
+
+        let scoped = mqtt.handle_connection_scoped_publish(build_outgoing_publish(QoS::AtLeastOnce));
+        mqtt.handle_outgoing_publish(scoped).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        mqtt.handle_watch(tx);
+
+        let requests = mqtt.handle_reconnection();
+        assert_eq!(requests.len(), 1);
+        match &requests[0] {
+            Request::Publish(publish) => assert_eq!(publish.pkid, Some(PacketIdentifier(2))),
+            request => panic!("Unexpected replay = {:?}", request),
+        }
+
+        assert_eq!(rx.try_recv(), Ok(BatchStatus::Lost));
+        assert!(!mqtt.outgoing_meta.contains_key(&PacketIdentifier(1)));
+        assert_eq!(mqtt.outgoing_meta.get(&PacketIdentifier(2)).map(|meta| meta.retransmits), Some(0));
+    }
+
+    #[test]
+    fn unacked_qos1_publishes_should_be_resent_with_dup_after_retry_interval() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_retry_interval(Duration::from_millis(10));
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert!(mqtt.handle_retransmissions().is_empty());
+
+        mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        let packets = mqtt.handle_retransmissions();
+        match &packets[..] {
+            [Packet::Publish(publish)] => {
+                assert_eq!(publish.pkid, Some(PacketIdentifier(1)));
+                assert!(publish.dup);
+            }
+            packets => panic!("Unexpected retransmissions = {:?}", packets),
+        }
+
+        assert_eq!(mqtt.inflight()[0].retransmits, 1);
+        assert!(mqtt.handle_retransmissions().is_empty());
+    }
+
+    #[test]
+    fn qos2_publishes_and_pubrels_should_be_resent_after_retry_interval() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_retry_interval(Duration::from_millis(10));
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        match &mqtt.handle_retransmissions()[..] {
+            [Packet::Publish(publish), Packet::Pubrel(PacketIdentifier(2))] => {
+                assert_eq!(publish.pkid, Some(PacketIdentifier(1)));
+                assert!(publish.dup);
+            }
+            packets => panic!("Unexpected retransmissions = {:?}", packets),
+        }
+
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(2)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        match &mqtt.handle_retransmissions()[..] {
+            [Packet::Pubrel(PacketIdentifier(1))] => (),
+            packets => panic!("Unexpected retransmissions = {:?}", packets),
+        }
+
+        assert!(!mqtt.outgoing_meta.contains_key(&PacketIdentifier(2)));
+    }
+
+    #[test]
+    fn qos2_handshakes_should_survive_retransmissions_and_reset_with_clean_session() {
+        let mut mqtt = build_mqttstate();
+
+        // outgoing. duplicate pubrec is answered with pubrel again
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        match mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap() {
+            (_, Request::PubRel(PacketIdentifier(1))) => (),
+            reply => panic!("Unexpected reply = {:?}", reply),
+        }
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(1)).unwrap();
+        assert!(mqtt.handle_incoming_pubrec(PacketIdentifier(1)).is_err());
+
+        // incoming. publish held till pubrel is forgotten with the session
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 7)).unwrap();
+        mqtt.handle_previous_session(false);
+
+        let mut publish = build_incoming_publish(QoS::ExactlyOnce, 7);
+        publish.payload = Arc::new(vec![4, 5, 6]);
+        mqtt.handle_incoming_publish(publish).unwrap();
+        match mqtt.handle_incoming_pubrel(PacketIdentifier(7)).unwrap() {
+            (Notification::Publish(publish), Request::PubComp(PacketIdentifier(7))) => assert_eq!(*publish.payload, vec![4, 5, 6]),
+            reply => panic!("Unexpected reply = {:?}", reply),
+        }
+        assert_eq!(mqtt.incoming_pub.len(), 0);
+    }
+
+    #[test]
+    fn rejected_publishes_should_still_be_acked_in_manual_ack_mode() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_manual_acks(true);
+        let mut mqtt = MqttState::new(opts);
+
+        let publish = build_incoming_publish(QoS::AtLeastOnce, 1);
+        let (_, reply) = mqtt.handle_incoming_publish(publish.clone()).unwrap();
+        match mqtt.handle_incoming_rejected(&publish, reply) {
+            Request::PubAck(PacketIdentifier(1)) => (),
+            reply => panic!("Unexpected reply = {:?}", reply),
+        }
+
+        let publish = build_incoming_publish(QoS::ExactlyOnce, 2);
+        mqtt.handle_incoming_publish(publish.clone()).unwrap();
+        let (_, reply) = mqtt.handle_incoming_pubrel(PacketIdentifier(2)).unwrap();
+        match mqtt.handle_incoming_rejected(&publish, reply) {
+            Request::PubComp(PacketIdentifier(2)) => (),
+            reply => panic!("Unexpected reply = {:?}", reply),
+        }
+        assert_eq!(mqtt.incoming_comp.len(), 0);
+    }
+
+    #[derive(Clone, Default)]
+    struct ReceivedStore(Arc<Mutex<Vec<PacketIdentifier>>>, Arc<Mutex<Vec<SubscribeTopic>>>);
+
+    impl Store for ReceivedStore {
+        fn put_incoming(&mut self, _publish: &Publish) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn remove_incoming(&mut self, _pkid: PacketIdentifier) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn incoming(&mut self) -> io::Result<Vec<Publish>> {
+            Ok(Vec::new())
+        }
+
+        fn put_received(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+            self.0.lock().unwrap().push(pkid);
+            Ok(())
+        }
+
+        fn remove_received(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+            self.0.lock().unwrap().retain(|p| *p != pkid);
+            Ok(())
+        }
+
+        fn received(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn put_subscriptions(&mut self, subscriptions: &[SubscribeTopic]) -> io::Result<()> {
+            *self.1.lock().unwrap() = subscriptions.to_vec();
+            Ok(())
+        }
+
+        fn subscriptions(&mut self) -> io::Result<Vec<SubscribeTopic>> {
+            Ok(self.1.lock().unwrap().clone())
+        }
+    }
+
+    #[test]
+    fn saved_subscriptions_should_be_renewed_after_restart_even_with_session_present() {
+        let store = ReceivedStore::default();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false).set_store(store.clone());
+        let mut mqtt = MqttState::new(opts.clone());
+
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec![
+                SubscribeTopic { topic_path: "a/#".to_owned(), qos: QoS::AtLeastOnce },
+                SubscribeTopic { topic_path: "b".to_owned(), qos: QoS::AtMostOnce },
+            ],
+        };
+        mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+        mqtt.handle_outgoing_unsubscribe(Unsubscribe { pkid: PacketIdentifier::zero(), topics: vec!["b".to_owned()] }).unwrap();
+
+        // restart
+        let mut mqtt = MqttState::new(opts);
+        mqtt.handle_incoming_connack(Connack { session_present: true, code: ConnectReturnCode::Accepted }).unwrap();
+        let requests: Vec<Request> = mqtt.handle_reconnection().into_iter().collect();
+        match &requests[..] {
+            [Request::Subscribe(subscribe)] => {
+                assert_eq!(subscribe.topics, vec![SubscribeTopic { topic_path: "a/#".to_owned(), qos: QoS::AtLeastOnce }]);
+            }
+            requests => panic!("Unexpected replay = {:?}", requests),
+        }
+    }
+
+    #[test]
+    fn qos2_publish_replayed_after_restart_should_not_be_delivered_again() {
+        let store = ReceivedStore::default();
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883)
+            .set_clean_session(false)
+            .set_qos2_delivery(Qos2Delivery::OnPublish)
+            .set_store(store.clone());
+
+        let mut mqtt = MqttState::new(opts.clone());
+        let (notification, _) = mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 5)).unwrap();
+        assert!(matches!(notification, Notification::Publish(_)));
+        assert_eq!(*store.0.lock().unwrap(), vec![PacketIdentifier(5)]);
+
+        // restart before pubrel. broker resends the publish
+        let mut mqtt = MqttState::new(opts);
+        mqtt.handle_stored_incoming();
+        match mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 5)).unwrap() {
+            (Notification::None, Request::PubRec(PacketIdentifier(5))) => (),
+            reply => panic!("Unexpected reply = {:?}", reply),
+        }
+
+        mqtt.handle_incoming_pubrel(PacketIdentifier(5)).unwrap();
+        assert!(store.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn imported_session_should_resend_inflight_and_renew_subscriptions() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false);
+        let mut mqtt = MqttState::new(opts.clone());
+
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec![SubscribeTopic { topic_path: "a/#".to_owned(), qos: QoS::AtLeastOnce }],
+        };
+        mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+
+        let session = Session::from_bytes(&mqtt.export_session().to_bytes()).unwrap();
+        let mut mqtt = MqttState::new(opts.set_session(session));
+        let requests: Vec<Request> = mqtt.handle_reconnection().into_iter().collect();
+        match &requests[..] {
+            [Request::Publish(publish), Request::Subscribe(subscribe)] => {
+                assert_eq!(publish.pkid, Some(PacketIdentifier(2)));
+                assert_eq!(subscribe.topics[0].topic_path, "a/#");
+            }
+            requests => panic!("Unexpected replay = {:?}", requests),
+        }
+
+        assert_eq!(mqtt.next_pkid(), Some(PacketIdentifier(3)));
+    }
+
+    #[test]
+    fn lost_broker_session_should_replay_publishes_and_renew_subscriptions() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false);
+        let mut mqtt = MqttState::new(opts);
+        let accepted = |session_present| Connack { session_present, code: ConnectReturnCode::Accepted };
+        mqtt.handle_incoming_connack(accepted(false)).unwrap();
+
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec![SubscribeTopic { topic_path: "a/#".to_owned(), qos: QoS::AtLeastOnce }],
+        };
+        mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(3)).unwrap();
+
+        // session is continued as is
+        mqtt.handle_incoming_connack(accepted(true)).unwrap();
+        assert_eq!(mqtt.outgoing_rel.len(), 1);
+        assert!(mqtt.resubscribe.is_none());
+
+        // pubrel is dropped with the session. publish is replayed and subscription renewed
+        mqtt.handle_incoming_connack(accepted(false)).unwrap();
+        let requests: Vec<Request> = mqtt.handle_reconnection().into_iter().collect();
+        match &requests[..] {
+            [Request::Publish(publish), Request::Subscribe(subscribe)] => {
+                assert_eq!(publish.pkid, Some(PacketIdentifier(2)));
+                assert!(publish.dup);
+                assert_eq!(subscribe.topics[0].topic_path, "a/#");
+            }
+            requests => panic!("Unexpected replay = {:?}", requests),
+        }
+    }
 }
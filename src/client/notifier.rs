@@ -0,0 +1,231 @@
+use crate::client::{ConnectionStats, Notification};
+use crate::error::NotificationError;
+use futures::{sync::mpsc, Future, Sink};
+use mqtt311::{PacketIdentifier, Publish, QoS};
+use std::sync::{mpsc as std_mpsc, Arc};
+
+/// Channel senders which can carry notifications from the eventloop to the user.
+/// Implemented for crossbeam, std and futures channels so that notifications can
+/// flow directly into the application's existing channels
+pub trait NotificationSender: Send {
+    /// Hands over the notification without blocking the eventloop
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError>;
+    /// Hands over the notification, blocking till there is space in the channel
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError>;
+
+    /// Same as `try_notify` along with the generation of the connection the
+    /// notification belongs to. Senders which don't carry generations ignore it
+    fn try_notify_tagged(&mut self, _generation: u64, notification: Notification) -> Result<(), NotificationError> {
+        self.try_notify(notification)
+    }
+
+    /// Same as `notify` along with the generation of the connection
+    fn notify_tagged(&mut self, _generation: u64, notification: Notification) -> Result<(), NotificationError> {
+        self.notify(notification)
+    }
+}
+
+/// Notification along with the generation of the connection it happened on.
+/// Generation goes up by one on every successful (re)connection, so replies
+/// belonging to a previous session can be told apart after reconnections
+#[derive(Debug)]
+pub struct Tagged {
+    pub generation: u64,
+    pub notification: Notification,
+}
+
+/// Notifications sent without a generation are tagged with generation 0
+impl NotificationSender for crossbeam_channel::Sender<Tagged> {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.try_notify_tagged(0, notification)
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.notify_tagged(0, notification)
+    }
+
+    fn try_notify_tagged(&mut self, generation: u64, notification: Notification) -> Result<(), NotificationError> {
+        self.try_send(Tagged { generation, notification }).map_err(|e| match e {
+            crossbeam_channel::TrySendError::Full(_) => NotificationError::Full,
+            crossbeam_channel::TrySendError::Disconnected(_) => NotificationError::Disconnected,
+        })
+    }
+
+    fn notify_tagged(&mut self, generation: u64, notification: Notification) -> Result<(), NotificationError> {
+        self.send(Tagged { generation, notification }).map_err(|_| NotificationError::Disconnected)
+    }
+}
+
+/// Passes the generation of the current connection to the user's sender
+pub(crate) struct GenerationSender {
+    inner: Box<dyn NotificationSender>,
+    connection_stats: Arc<ConnectionStats>,
+}
+
+impl GenerationSender {
+    pub(crate) fn new(inner: Box<dyn NotificationSender>, connection_stats: Arc<ConnectionStats>) -> GenerationSender {
+        GenerationSender { inner, connection_stats }
+    }
+}
+
+impl NotificationSender for GenerationSender {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        let generation = self.connection_stats.generation();
+        self.inner.try_notify_tagged(generation, notification)
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        let generation = self.connection_stats.generation();
+        self.inner.notify_tagged(generation, notification)
+    }
+}
+
+impl NotificationSender for crossbeam_channel::Sender<Notification> {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.try_send(notification).map_err(|e| match e {
+            crossbeam_channel::TrySendError::Full(_) => NotificationError::Full,
+            crossbeam_channel::TrySendError::Disconnected(_) => NotificationError::Disconnected,
+        })
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.send(notification).map_err(|_| NotificationError::Disconnected)
+    }
+}
+
+impl NotificationSender for std_mpsc::Sender<Notification> {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.notify(notification)
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.send(notification).map_err(|_| NotificationError::Disconnected)
+    }
+}
+
+impl NotificationSender for std_mpsc::SyncSender<Notification> {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.try_send(notification).map_err(|e| match e {
+            std_mpsc::TrySendError::Full(_) => NotificationError::Full,
+            std_mpsc::TrySendError::Disconnected(_) => NotificationError::Disconnected,
+        })
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.send(notification).map_err(|_| NotificationError::Disconnected)
+    }
+}
+
+impl NotificationSender for mpsc::Sender<Notification> {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.try_send(notification).map_err(|e| {
+            if e.is_full() {
+                NotificationError::Full
+            } else {
+                NotificationError::Disconnected
+            }
+        })
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.clone()
+            .send(notification)
+            .wait()
+            .map(|_| ())
+            .map_err(|_| NotificationError::Disconnected)
+    }
+}
+
+impl NotificationSender for mpsc::UnboundedSender<Notification> {
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.notify(notification)
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.unbounded_send(notification).map_err(|_| NotificationError::Disconnected)
+    }
+}
+
+/// Incoming publish as seen by a [MessageHandler]. Borrows the topic and payload
+/// of the decoded publish, which is an owned copy of the bytes read from the
+/// network like every other publish
+///
+/// [MessageHandler]: struct.MessageHandler.html
+#[derive(Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    pub pkid: Option<PacketIdentifier>,
+}
+
+impl<'a> From<&'a Publish> for MessageRef<'a> {
+    fn from(publish: &'a Publish) -> Self {
+        MessageRef {
+            topic: &publish.topic_name,
+            payload: &publish.payload,
+            qos: publish.qos,
+            retain: publish.retain,
+            dup: publish.dup,
+            pkid: publish.pkid,
+        }
+    }
+}
+
+/// Runs the handler on the eventloop thread with every incoming publish instead
+/// of sending it on a channel. Handlers should be quick as they block network io.
+/// Notifications other than publishes are ignored
+pub struct MessageHandler<F> {
+    handler: F,
+}
+
+impl<F> MessageHandler<F>
+where
+    F: FnMut(MessageRef) + Send,
+{
+    pub fn new(handler: F) -> MessageHandler<F> {
+        MessageHandler { handler }
+    }
+}
+
+impl<F> NotificationSender for MessageHandler<F>
+where
+    F: FnMut(MessageRef) + Send,
+{
+    fn try_notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        match notification {
+            Notification::Publish(publish) => (self.handler)(MessageRef::from(&publish)),
+            notification => debug!("Ignoring notification = {:?}", notification),
+        }
+
+        Ok(())
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<(), NotificationError> {
+        self.try_notify(notification)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GenerationSender, NotificationSender, Tagged};
+    use crate::client::{ConnectionStats, Notification};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn notifications_should_carry_generation_of_current_connection() {
+        let (tx, rx) = crossbeam_channel::unbounded::<Tagged>();
+        let stats = Arc::new(ConnectionStats::default());
+        let mut sender = GenerationSender::new(Box::new(tx), stats.clone());
+
+        stats.set_connected(Duration::from_secs(10));
+        sender.try_notify(Notification::None).unwrap();
+        stats.set_connected(Duration::from_secs(10));
+        sender.notify(Notification::None).unwrap();
+
+        let generations: Vec<u64> = rx.try_iter().map(|tagged| tagged.generation).collect();
+        assert_eq!(generations, vec![1, 2]);
+    }
+}
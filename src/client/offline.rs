@@ -0,0 +1,107 @@
+use crate::error::ClientError;
+use mqtt311::Publish;
+use std::{collections::VecDeque, sync::Mutex};
+
+/// Publishes made while the eventloop is disconnected. Clients buffer them here
+/// instead of the request channel and the eventloop takes them over after the
+/// next connack. Online state and the buffer are changed under the same lock so
+/// that nothing gets buffered after the eventloop took the buffer over
+#[derive(Debug)]
+pub struct OfflineBuffer {
+    max_messages: usize,
+    max_bytes: usize,
+    state: Mutex<Buffered>,
+}
+
+#[derive(Debug, Default)]
+struct Buffered {
+    online: bool,
+    publishes: VecDeque<Publish>,
+    bytes: usize,
+}
+
+impl OfflineBuffer {
+    pub fn new(max_messages: usize, max_bytes: usize) -> OfflineBuffer {
+        OfflineBuffer {
+            max_messages,
+            max_bytes,
+            state: Mutex::new(Buffered::default()),
+        }
+    }
+
+    /// Buffers the publish while offline. Hands the publish back while online
+    pub fn buffer(&self, publish: Publish) -> Result<Option<Publish>, ClientError> {
+        let mut state = self.state.lock().unwrap();
+        if state.online {
+            return Ok(Some(publish));
+        }
+
+        let len = publish.payload.len();
+        if state.publishes.len() >= self.max_messages || state.bytes + len > self.max_bytes {
+            return Err(ClientError::OfflineBufferFull);
+        }
+
+        state.bytes += len;
+        state.publishes.push_back(publish);
+        Ok(None)
+    }
+
+    /// Marks the connection up and takes the buffered publishes out in order
+    pub fn go_online(&self) -> VecDeque<Publish> {
+        let mut state = self.state.lock().unwrap();
+        state.online = true;
+        state.bytes = 0;
+        state.publishes.split_off(0)
+    }
+
+    pub fn go_offline(&self) {
+        self.state.lock().unwrap().online = false;
+    }
+
+    /// Takes the buffered publishes out without going online
+    pub fn take(&self) -> VecDeque<Publish> {
+        let mut state = self.state.lock().unwrap();
+        state.bytes = 0;
+        state.publishes.split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OfflineBuffer;
+    use crate::error::ClientError;
+    use mqtt311::{Publish, QoS};
+    use std::sync::Arc;
+
+    fn publish(payload: Vec<u8>) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(payload),
+        }
+    }
+
+    #[test]
+    fn publishes_should_be_buffered_only_while_offline_and_within_limits() {
+        let buffer = OfflineBuffer::new(2, 10);
+        assert!(buffer.buffer(publish(vec![1; 6])).unwrap().is_none());
+        match buffer.buffer(publish(vec![2; 6])) {
+            Err(ClientError::OfflineBufferFull) => (),
+            v => panic!("Expecting full buffer. Received = {:?}", v),
+        }
+
+        assert!(buffer.buffer(publish(vec![3; 4])).unwrap().is_none());
+        assert!(buffer.buffer(publish(vec![4])).is_err());
+
+        let buffered = buffer.go_online();
+        let payloads: Vec<u8> = buffered.iter().map(|publish| publish.payload[0]).collect();
+        assert_eq!(payloads, vec![1, 3]);
+        assert!(buffer.buffer(publish(vec![5])).unwrap().is_some());
+
+        buffer.go_offline();
+        assert!(buffer.buffer(publish(vec![6; 10])).unwrap().is_none());
+    }
+}
@@ -0,0 +1,96 @@
+use futures::{Async, Poll, Stream};
+
+pub trait PriorityExt: Stream {
+    fn prioritize<L>(self, low: L) -> Priority<Self, L>
+    where
+        Self: Sized,
+        L: Stream<Item = Self::Item, Error = Self::Error>,
+    {
+        Priority {
+            high: self,
+            low,
+            high_done: false,
+            low_done: false,
+        }
+    }
+}
+
+impl<T: ?Sized> PriorityExt for T where T: Stream {}
+
+/// An adapter for merging two streams where the items of the first stream always
+/// go first.
+///
+/// Unlike `select`, which alternates between the streams, the second stream is
+/// only polled when the first one isn't ready. Used to keep protocol replies
+/// (acks, pings) ahead of a backlog of user publishes so that a large backlog
+/// doesn't delay acks and make the broker redeliver
+#[must_use = "streams do nothing unless polled"]
+pub struct Priority<H, L> {
+    high: H,
+    low: L,
+    high_done: bool,
+    low_done: bool,
+}
+
+impl<H, L> Stream for Priority<H, L>
+where
+    H: Stream,
+    L: Stream<Item = H::Item, Error = H::Error>,
+{
+    type Item = H::Item;
+    type Error = H::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.high_done {
+            match self.high.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.high_done = true,
+                Async::NotReady => (),
+            }
+        }
+
+        if !self.low_done {
+            match self.low.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.low_done = true,
+                Async::NotReady => (),
+            }
+        }
+
+        if self.high_done && self.low_done {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PriorityExt;
+    use futures::{
+        stream::{self, Stream},
+        sync::mpsc,
+        Future, Sink,
+    };
+
+    #[test]
+    fn high_priority_items_should_bypass_the_low_priority_backlog() {
+        let (acks_tx, acks_rx) = mpsc::unbounded::<&str>();
+        let backlog = stream::iter_ok(vec!["publish 1", "publish 2", "publish 3"]);
+        let mut merged = acks_rx.prioritize(backlog).wait();
+
+        // acks which become ready in between overtake the remaining backlog
+        assert_eq!(merged.next(), Some(Ok("publish 1")));
+        acks_tx.unbounded_send("puback").unwrap();
+        acks_tx.unbounded_send("pingreq").unwrap();
+        assert_eq!(merged.next(), Some(Ok("puback")));
+        assert_eq!(merged.next(), Some(Ok("pingreq")));
+        assert_eq!(merged.next(), Some(Ok("publish 2")));
+
+        // stream ends only after both the streams end
+        acks_tx.send("pubrel").wait().unwrap();
+        let rest: Vec<&str> = merged.map(Result::unwrap).collect();
+        assert_eq!(rest, vec!["pubrel", "publish 3"]);
+    }
+}
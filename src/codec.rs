@@ -1,13 +1,50 @@
 //! Codec to convert incoming bytes of a tcp stream into mqtt packets
-//! and outgoing mqtt packets to raw bytes
+//! and outgoing mqtt packets to raw bytes. [encode] and [decode] are stateless
+//! and work on plain buffers for tooling (fuzzers, packet inspectors) which
+//! should handle packets exactly like the client does
+//!
+//! [encode]: fn.encode.html
+//! [decode]: fn.decode.html
 use bytes::BytesMut;
-use mqtt311::{self, MqttRead, MqttWrite, Packet};
+use mqtt311::{self, Connect, MqttRead, MqttWrite, Packet};
 use std::io::{self, Cursor, ErrorKind};
 use tokio_codec::{Decoder, Encoder};
 
-/// Mqtt codec
+/// Mqtt codec. Incoming packets above the max packet size fail with `InvalidData`
+/// as soon as their fixed header is received
 #[derive(Debug)]
-pub struct MqttCodec;
+pub struct MqttCodec {
+    max_packet_size: usize,
+    will_message: Option<Vec<u8>>,
+}
+
+impl MqttCodec {
+    pub fn new(max_packet_size: usize) -> MqttCodec {
+        MqttCodec {
+            max_packet_size,
+            will_message: None,
+        }
+    }
+
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    /// Binary message which replaces the will message of connect packets. See
+    /// [encode_connect]
+    ///
+    /// [encode_connect]: fn.encode_connect.html
+    pub fn set_will_message(&mut self, will_message: Option<Vec<u8>>) {
+        self.will_message = will_message;
+    }
+}
+
+impl Default for MqttCodec {
+    /// Same limit as the default of `MqttOptions`
+    fn default() -> Self {
+        MqttCodec::new(256 * 1024)
+    }
+}
 
 impl Decoder for MqttCodec {
     type Item = Packet;
@@ -20,63 +57,354 @@ impl Decoder for MqttCodec {
         // Ok(0) => translated to UnexpectedEOF by `byteorder` crate.
         // `read` call Ok(0) happens when buffer specified was 0 bytes in len
         // https://doc.rust-lang.org/std/io/trait.Read.html#tymethod.read
-        if buf.len() < 2 {
-            return Ok(None);
+        let len = match packet_len(buf)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        // remaining length comes from the peer. check it before allocating for it
+        if len > self.max_packet_size {
+            error!("Incoming packet too big. Size = {}, Max = {}", len, self.max_packet_size);
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("Packet of {} bytes is above the max packet size", len)));
         }
 
-        let (packet, len) = {
-            let mut buf_ref = buf.as_ref();
-            match buf_ref.read_packet_with_len() {
-                Err(e) => {
-                    if let mqtt311::Error::Io(e) = e {
-                        match e.kind() {
-                            ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::UnexpectedEof => return Ok(None),
-                            _ => {
-                                error!("mqtt3 io error = {:?}", e);
-                                return Err(e);
-                            }
-                        }
-                    } else {
-                        error!("mqtt3 read error = {:?}", e);
-                        return Err(io::Error::new(ErrorKind::Other, "Mqtt Error"));
-                    }
-                }
-                Ok(v) => v,
+        match decode(buf)? {
+            Some((packet, len)) => {
+                buf.split_to(len);
+                Ok(Some(packet))
             }
+            None => {
+                // Make room for the rest of a partially received packet at once
+                buf.reserve(len - buf.len());
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Packet, buf: &mut BytesMut) -> io::Result<()> {
+        // TODO: Implement `write_packet` for `&mut BytesMut`
+        let bytes = match (&msg, &self.will_message) {
+            (Packet::Connect(connect), Some(will_message)) => encode_connect(connect, will_message)?,
+            _ => encode(&msg)?,
         };
 
-        // NOTE: It's possible that `decode` got called before `buf` has full bytes
-        // necessary to frame raw bytes into a packet. In that case return Ok(None)
-        // and the next time decode` gets called, there will be more bytes in `buf`,
-        // hopefully enough to frame the packet
-        if buf.len() < len {
-            return Ok(None);
+        buf.extend(bytes);
+        Ok(())
+    }
+}
+
+/// Encodes the packet
+pub fn encode(packet: &Packet) -> io::Result<Vec<u8>> {
+    let mut stream = Cursor::new(Vec::new());
+    if let Err(e) = stream.write_packet(packet) {
+        error!("Encode error. Error = {:?}", e);
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Unable to encode! Error = {:?}", e)));
+    }
+
+    Ok(stream.into_inner())
+}
+
+/// Encodes the connect packet with a binary will message. Will messages are
+/// binary in mqtt 3.1.1 but `LastWill` of mqtt311 only carries strings, so the
+/// message of `connect.last_will` is ignored
+pub fn encode_connect(connect: &Connect, will_message: &[u8]) -> io::Result<Vec<u8>> {
+    let will = match &connect.last_will {
+        Some(will) => will,
+        None => return encode(&Packet::Connect(connect.clone())),
+    };
+
+    let mut flags = 0x04 | will.qos.to_u8() << 3;
+    if will.retain {
+        flags |= 0x20;
+    }
+
+    if connect.clean_session {
+        flags |= 0x02;
+    }
+
+    if connect.password.is_some() {
+        flags |= 0x40;
+    }
+
+    if connect.username.is_some() {
+        flags |= 0x80;
+    }
+
+    let mut body = Vec::new();
+    put_bytes(&mut body, connect.protocol.name().as_bytes())?;
+    body.push(connect.protocol.level());
+    body.push(flags);
+    body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+    put_bytes(&mut body, connect.client_id.as_bytes())?;
+    put_bytes(&mut body, will.topic.as_bytes())?;
+    put_bytes(&mut body, will_message)?;
+    for field in [&connect.username, &connect.password].iter().filter_map(|field| field.as_ref()) {
+        put_bytes(&mut body, field.as_bytes())?;
+    }
+
+    let mut out = vec![0x10];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            out.push(byte);
+            break;
         }
 
-        // println!("buf = {:?}", buf);
-        // println!("{:?}, {:?}, {:?}", len, packet, buf.len());
+        out.push(byte | 0x80);
+    }
 
-        buf.split_to(len);
+    out.extend(body);
+    Ok(out)
+}
 
-        Ok(Some(packet))
+/// Writes a length prefixed field of the connect packet
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > usize::from(u16::MAX) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Connect field longer than 65535 bytes"));
     }
+
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
 }
 
-impl Encoder for MqttCodec {
-    type Item = Packet;
-    type Error = io::Error;
+/// Decodes the packet at the start of the buffer. Returns the packet along with
+/// the number of bytes it takes. `None` if the whole packet isn't in the buffer yet.
+/// Malformed packets fail with `InvalidData`
+pub fn decode(buf: &[u8]) -> io::Result<Option<(Packet, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
 
-    fn encode(&mut self, msg: Packet, buf: &mut BytesMut) -> io::Result<()> {
-        let mut stream = Cursor::new(Vec::new());
+    // Wait for the whole packet before decoding so that a large packet
+    // received over many reads isn't parsed again on every read
+    let len = match packet_len(buf)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
 
-        // TODO: Implement `write_packet` for `&mut BytesMut`
-        if let Err(e) = stream.write_packet(&msg) {
-            error!("Encode error. Error = {:?}", e);
-            return Err(io::Error::new(io::ErrorKind::Other, "Unable to encode!"));
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    // the whole packet is in the buffer. running out of bytes while decoding
+    // means the packet contradicts its own remaining length
+    let mut buf_ref = &buf[..len];
+    match buf_ref.read_packet_with_len() {
+        Err(mqtt311::Error::Io(e)) => match e.kind() {
+            ErrorKind::UnexpectedEof => {
+                error!("Packet shorter than its contents. Error = {:?}", e);
+                Err(io::Error::new(ErrorKind::InvalidData, "Malformed packet. Contents overrun the remaining length"))
+            }
+            _ => {
+                error!("mqtt3 io error = {:?}", e);
+                Err(e)
+            }
+        },
+        Err(e) => {
+            error!("mqtt3 read error = {:?}", e);
+            Err(io::Error::new(ErrorKind::InvalidData, format!("Mqtt Error = {:?}", e)))
         }
+        Ok(v) => Ok(Some(v)),
+    }
+}
 
-        buf.extend(stream.get_ref());
+/// Length of the packet (fixed header + remaining length) at the start of the
+/// buffer. `None` if the remaining length field isn't fully received yet
+fn packet_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    let mut remaining_len = 0;
 
-        Ok(())
+    // remaining length is encoded in at most 4 bytes after the first byte
+    for (i, byte) in buf.iter().skip(1).take(4).enumerate() {
+        remaining_len += (*byte as usize & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(1 + i + 1 + remaining_len));
+        }
+    }
+
+    if buf.len() > 4 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Malformed remaining length"));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::MqttCodec;
+    use bytes::BytesMut;
+    use futures::{task, Future, Poll, Sink};
+    use mqtt311::{Connect, LastWill, MqttWrite, Packet, PacketIdentifier, Protocol, Publish, QoS};
+    use std::{
+        io::{self, Cursor, ErrorKind, Read, Write},
+        sync::Arc,
+    };
+    use tokio_codec::{Decoder, Encoder};
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    /// Socket which accepts a few bytes per write and blocks on every other write
+    struct SlowSocket {
+        written: Vec<u8>,
+        block: bool,
+    }
+
+    impl Read for SlowSocket {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for SlowSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                task::current().notify();
+                return Err(ErrorKind::WouldBlock.into());
+            }
+
+            let len = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for SlowSocket {}
+    impl AsyncWrite for SlowSocket {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+
+    #[test]
+    fn partial_and_blocked_writes_should_not_drop_or_duplicate_bytes() {
+        let packets: Vec<Packet> = (1..4)
+            .map(|i| {
+                Packet::Publish(Publish {
+                    dup: false,
+                    qos: QoS::AtLeastOnce,
+                    retain: false,
+                    topic_name: "hello/world".to_owned(),
+                    pkid: Some(PacketIdentifier(i)),
+                    payload: Arc::new(vec![i as u8; 100]),
+                })
+            })
+            .collect();
+
+        let mut expected = Cursor::new(Vec::new());
+        for packet in packets.iter() {
+            expected.write_packet(packet).unwrap();
+        }
+
+        let socket = SlowSocket {
+            written: Vec::new(),
+            block: false,
+        };
+
+        let mut framed = MqttCodec::default().framed(socket);
+        for packet in packets {
+            framed = framed.send(packet).wait().unwrap();
+        }
+
+        assert_eq!(&framed.get_ref().written, expected.get_ref());
+    }
+
+    #[test]
+    fn packet_received_in_pieces_should_be_decoded_only_when_complete() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(1)),
+            payload: Arc::new(vec![1; 1000]),
+        });
+
+        let mut bytes = BytesMut::new();
+        MqttCodec::default().encode(publish.clone(), &mut bytes).unwrap();
+        MqttCodec::default().encode(Packet::Pingresp, &mut bytes).unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        for chunk in bytes.chunks(7) {
+            buf.extend_from_slice(chunk);
+            while let Some(packet) = MqttCodec::default().decode(&mut buf).unwrap() {
+                packets.push(packet);
+            }
+        }
+
+        assert_eq!(packets, vec![publish, Packet::Pingresp]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn standalone_decode_should_report_consumed_bytes_and_partial_packets() {
+        let mut bytes = super::encode(&Packet::Pingreq).unwrap();
+        bytes.extend(super::encode(&Packet::Disconnect).unwrap());
+
+        assert_eq!(super::decode(&bytes[..1]).unwrap(), None);
+        assert_eq!(super::decode(&bytes).unwrap(), Some((Packet::Pingreq, 2)));
+        assert_eq!(super::decode(&bytes[2..]).unwrap(), Some((Packet::Disconnect, 2)));
+        assert!(super::decode(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).is_err());
+
+        // complete qos 1 publish frame which ends before its packet id
+        match super::decode(&[0x32, 0x02, 0x00, 0x00]) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            out => panic!("Expecting malformed packet. Received = {:?}", out),
+        }
+        assert!(MqttCodec::default().decode(&mut BytesMut::from(&[0x32, 0x02, 0x00, 0x00][..])).is_err());
+    }
+
+    #[test]
+    fn connect_with_a_binary_will_should_be_encoded_like_a_string_will() {
+        let connect = Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 10,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: Some(LastWill {
+                topic: "/a".to_owned(),
+                message: "offline".to_owned(),
+                retain: true,
+                qos: QoS::AtLeastOnce,
+            }),
+            username: Some("rust".to_owned()),
+            password: Some("mq".to_owned()),
+        };
+
+        let expected = super::encode(&Packet::Connect(connect.clone())).unwrap();
+        assert_eq!(super::encode_connect(&connect, b"offline").unwrap(), expected);
+
+        let mut codec = MqttCodec::default();
+        codec.set_will_message(Some(vec![0x80, 0xFF, 0x01]));
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Connect(connect), &mut buf).unwrap();
+        assert_eq!(buf[1] as usize, buf.len() - 2);
+        assert!(buf.windows(5).any(|field| field == [0x00, 0x03, 0x80, 0xFF, 0x01]));
+    }
+
+    #[test]
+    fn packet_above_max_size_should_fail_before_its_buffer_is_reserved() {
+        // remaining length of 268435455 bytes in a 5 byte frame
+        let mut buf = BytesMut::from(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+        match MqttCodec::new(1024).decode(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            out => panic!("Expecting too big packet. Received = {:?}", out),
+        }
+        assert!(buf.capacity() < 1024);
+
+        let mut buf = BytesMut::from(&[0x30, 0x80, 0x08][..]);
+        assert_eq!(MqttCodec::new(1027).decode(&mut buf).unwrap(), None);
+        assert!(buf.capacity() >= 1027);
     }
 }
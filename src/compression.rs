@@ -0,0 +1,85 @@
+//! Payload compression which is safe with receivers that don't support it.
+//! Compressed payloads carry a 4 byte marker and only marked payloads are
+//! decompressed on receive. Everything else is delivered as is
+//!
+//! ```text
+//! | 0x00 | 'R' | 'Z' | encoding (u8) | compressed data |
+//! ```
+//!
+//! Deflate (encoding 1) is the only encoding for now
+use crate::error::CompressionError;
+use miniz_oxide::{deflate, inflate};
+
+/// Size of the marker prepended to compressed payloads
+pub const HEADER_LEN: usize = 4;
+
+/// Raw deflate stream
+pub const DEFLATE: u8 = 1;
+
+const MAGIC: [u8; 3] = [0x00, b'R', b'Z'];
+
+/// Checks for the compression marker
+pub fn is_compressed(payload: &[u8]) -> bool {
+    payload.len() >= HEADER_LEN && payload[..3] == MAGIC
+}
+
+/// Deflates the payload and prepends the marker
+pub fn compress(payload: &[u8]) -> Vec<u8> {
+    let compressed = deflate::compress_to_vec(payload, 6);
+    let mut out = Vec::with_capacity(HEADER_LEN + compressed.len());
+    out.extend_from_slice(&MAGIC);
+    out.push(DEFLATE);
+    out.extend_from_slice(&compressed);
+    out
+}
+
+/// Compresses the payload only when it gets smaller. Payloads which look
+/// compressed are always compressed so that receivers don't misread them
+pub fn compress_if_smaller(payload: &[u8]) -> Option<Vec<u8>> {
+    let compressed = compress(payload);
+    if compressed.len() < payload.len() || is_compressed(payload) {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+/// Decompresses a marked payload. Output bigger than 'limit' bytes is an error
+pub fn decompress(payload: &[u8], limit: usize) -> Result<Vec<u8>, CompressionError> {
+    if !is_compressed(payload) {
+        return Err(CompressionError::NotCompressed);
+    }
+
+    match payload[3] {
+        DEFLATE => inflate::decompress_to_vec_with_limit(&payload[HEADER_LEN..], limit).map_err(|_| CompressionError::Malformed),
+        encoding => Err(CompressionError::UnknownEncoding(encoding)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress_if_smaller, decompress, is_compressed};
+    use crate::error::CompressionError;
+
+    #[test]
+    fn only_marked_payloads_should_be_decompressed() {
+        let payload = vec![b'a'; 1000];
+        let compressed = compress_if_smaller(&payload).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress(&compressed, 1000).unwrap(), payload);
+        assert!(decompress(&compressed, 999).is_err());
+
+        // plain payloads of peers without compression are left alone
+        assert!(!is_compressed(b"{\"temperature\": 20}"));
+        assert!(compress_if_smaller(b"ab").is_none());
+        match decompress(&[0x00, b'R', b'Z', 9, 1, 2], 1000) {
+            Err(CompressionError::UnknownEncoding(9)) => (),
+            v => panic!("Expecting unknown encoding. Received = {:?}", v),
+        }
+
+        // plain payload which looks compressed is sent compressed
+        let lookalike = vec![0x00, b'R', b'Z', 1];
+        let compressed = compress_if_smaller(&lookalike).unwrap();
+        assert_eq!(decompress(&compressed, 1000).unwrap(), lookalike);
+    }
+}
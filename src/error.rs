@@ -1,88 +1,199 @@
-//! All errors
+//! All errors. They implement `std::error::Error` and, through its blanket
+//! impl for std errors, `failure::Fail`
+use crate::actor::Message;
 use crate::client::{Command, Request};
+use crate::mqttoptions::Reconfigure;
 use crossbeam_channel::RecvError;
-use derive_more::From;
-use failure::Fail;
+use derive_more::{Display, From};
 use futures::sync::mpsc::SendError;
 #[cfg(feature = "jwt")]
 use jsonwebtoken;
-use mqtt311::Packet;
+use mqtt311::{Packet, Publish};
+use std::error::Error;
 use std::io::Error as IoError;
+use std::time::Duration;
 use tokio_timer::{self, timeout};
 
-#[derive(Debug, Fail, From)]
+#[derive(Debug, Display, From)]
 pub enum ClientError {
-    #[fail(display = "No subscriptions")]
+    #[display(fmt = "No subscriptions")]
     ZeroSubscriptions,
-    #[fail(display = "Packet size limit has crossed maximum")]
+    #[display(fmt = "Packet size limit has crossed maximum")]
     PacketSizeLimitExceeded,
-    #[fail(display = "Client id should not be empty")]
+    #[display(fmt = "Client id should not be empty")]
     EmptyClientId,
-    #[fail(display = "Failed sending request to connection thread. Error = {}", _0)]
+    #[display(fmt = "Failed sending request to connection thread. Error = {}", _0)]
     MpscRequestSend(SendError<Request>),
-    #[fail(display = "Failed sending request to connection thread. Error = {}", _0)]
+    #[display(fmt = "Failed sending request to connection thread. Error = {}", _0)]
     MpscCommandSend(SendError<Command>),
+    #[display(fmt = "Failed sending publish to dead letter channel. Error = {}", _0)]
+    DeadLetterSend(crossbeam_channel::SendError<Publish>),
+    #[display(fmt = "Failed sending message to actor. Error = {}", _0)]
+    ActorSend(crossbeam_channel::SendError<Message>),
+    #[display(fmt = "Fragmentation failed. Error = {}", _0)]
+    Fragment(FragmentError),
+    #[display(fmt = "Invalid payload. Topic = {}, Reason = {}", _0, _1)]
+    InvalidPayload(String, String),
+    #[display(fmt = "Payload transformation failed. Topic = {}, Reason = {}", _0, _1)]
+    Transform(String, String),
+    #[display(fmt = "Missing topic parameter = {}", _0)]
+    MissingTopicParam(String),
+    #[display(fmt = "Invalid reconfiguration = {:?}", _0)]
+    InvalidReconfigure(Reconfigure),
+    #[display(fmt = "Eventloop didn't respond in time")]
+    EventloopTimeout,
+    #[display(fmt = "All the packet ids are in use")]
+    PkidExhausted,
+    #[display(fmt = "Broker doesn't support {}", _0)]
+    Unsupported(&'static str),
+    #[display(fmt = "Subscription rejected by guardrails. Filter = {}, Reason = {}", _0, _1)]
+    BroadSubscription(String, &'static str),
+    #[display(fmt = "Self test publish didn't come back within {:?}", _0)]
+    SelfTestTimeout(Duration),
+    #[display(fmt = "Offline buffer is full")]
+    OfflineBufferFull,
+    #[display(fmt = "Eventloop is already stopped")]
+    EventloopStopped,
 }
 
-#[derive(Debug, Fail, From)]
+impl Error for ClientError {}
+
+#[derive(Debug, Display)]
+pub enum NotificationError {
+    #[display(fmt = "Notification channel is full")]
+    Full,
+    #[display(fmt = "Notification receiver is dropped")]
+    Disconnected,
+}
+
+impl Error for NotificationError {}
+
+#[derive(Debug, Display, From)]
 pub enum MqttError {
-    #[fail(display = "Connection failed")]
+    #[display(fmt = "Connection failed")]
     ConnectError,
-    #[fail(display = "Network call failed")]
+    #[display(fmt = "Network call failed")]
     NetworkError,
 }
 
+impl Error for MqttError {}
+
 // TODO: Modify mqtt311 to return enums for mqtt connect error
-#[derive(Debug, Fail, From)]
+#[derive(Debug, Display, From)]
 pub enum ConnectError {
-    #[fail(display = "Mqtt connection failed. Error = {}", _0)]
-    MqttConnectionRefused(u8),
+    #[display(fmt = "Mqtt connection refused. Unacceptable protocol version")]
+    UnacceptableProtocolVersion,
+    #[display(fmt = "Mqtt connection refused. Identifier rejected")]
+    IdentifierRejected,
+    #[display(fmt = "Mqtt connection refused. Service unavailable")]
+    ServiceUnavailable,
+    #[display(fmt = "Mqtt connection refused. Bad username or password")]
+    BadUsernamePassword,
+    #[display(fmt = "Mqtt connection refused. Not authorized")]
+    NotAuthorized,
     #[cfg(feature = "jwt")]
-    #[fail(display = "Mqtt connection failed. Error = {}", _0)]
+    #[display(fmt = "Mqtt connection failed. Error = {}", _0)]
     Jwt(jsonwebtoken::errors::Error),
-    #[fail(display = "Io failed. Error = {}", _0)]
+    #[display(fmt = "Io failed. Error = {}", _0)]
     Io(IoError),
-    #[fail(display = "Receiving connection status failed. Error = {}", _0)]
+    #[cfg(feature = "nativetls")]
+    #[display(fmt = "Tls failed. Error = {}", _0)]
+    Tls(native_tls::Error),
+    #[display(fmt = "Receiving connection status failed. Error = {}", _0)]
     Recv(RecvError),
-    #[fail(display = "Empty dns list")]
+    #[display(fmt = "Dns resolution failed. Host = {}, Error = {}", _0, _1)]
+    Dns(String, IoError),
+    #[display(fmt = "Empty dns list")]
     DnsListEmpty,
-    #[fail(display = "Couldn't create mqtt connection in time")]
+    #[display(fmt = "Couldn't create mqtt connection in time")]
     Timeout,
-    #[fail(
-        display = "Unsolicited packet received while waiting for connack. Recived packet = {:?}",
+    #[display(fmt = "Broker didn't respond to connect in time")]
+    ConnackTimeout,
+    #[display(
+        fmt = "Unsolicited packet received while waiting for connack. Recived packet = {:?}",
         _0
     )]
-    NotConnackPacket(Packet),
-    #[fail(display = "Empty response")]
+    NotConnackPacket(Box<Packet>),
+    #[display(fmt = "Empty response")]
     NoResponse,
-    #[fail(display = "Builder doesn't contain certificate authority")]
+    #[display(fmt = "Builder doesn't contain certificate authority")]
     NoCertificateAuthority,
+    #[display(fmt = "Invalid client certificate or private key. {}", _0)]
+    ClientAuth(String),
+    #[display(fmt = "Invalid tls server name = {}", _0)]
+    InvalidServerName(String),
+    #[display(fmt = "Another eventloop is connecting or connected with client id = {}", _0)]
+    AlreadyConnecting(String),
 }
 
-#[derive(Debug, Fail, From)]
+impl Error for ConnectError {}
+
+#[derive(Debug, Display, From)]
 pub enum NetworkError {
-    #[fail(display = "Io failed. Error = {}", _0)]
+    #[display(fmt = "Io failed. Error = {}", _0)]
     Io(IoError),
-    #[fail(display = "Last ping response not received")]
+    #[display(fmt = "Last ping response not received")]
     AwaitPingResp,
-    #[fail(display = "Client not in connected state")]
+    #[display(fmt = "Client not in connected state")]
     InvalidState,
-    #[fail(display = "Couldn't ping in time")]
+    #[display(fmt = "Couldn't ping in time")]
     Timeout,
-    #[fail(display = "Received unsolicited acknowledgment")]
+    #[display(fmt = "Received unsolicited acknowledgment")]
     Unsolicited,
-    #[fail(display = "Tokio timer error = {}", _0)]
+    #[display(fmt = "Tokio timer error = {}", _0)]
     Timer(tokio_timer::Error),
-    #[fail(display = "Tokio timer error = {}", _0)]
+    #[display(fmt = "Tokio timer error = {}", _0)]
     TimeOut(timeout::Error<IoError>),
-    #[fail(display = "User requested for reconnect")]
+    #[display(fmt = "User requested for reconnect")]
     UserReconnect,
-    #[fail(display = "User requested for disconnect")]
+    #[display(fmt = "User requested for disconnect")]
     UserDisconnect,
-    #[fail(display = "Network stream closed")]
+    #[display(fmt = "Network stream closed")]
     NetworkStreamClosed,
-    #[fail(display = "Throttle error while rate limiting")]
+    #[display(fmt = "Throttle error while rate limiting")]
     Throttle,
-    #[fail(display = "Dummy error for converting () to network error")]
+    #[display(fmt = "All the packet ids are in use")]
+    PkidExhausted,
+    #[display(fmt = "Dummy error for converting () to network error")]
     Blah,
 }
+
+impl Error for NetworkError {}
+
+#[derive(Debug, Display)]
+pub enum CompressionError {
+    #[display(fmt = "Payload doesn't have the compression marker")]
+    NotCompressed,
+    #[display(fmt = "Unknown content encoding = {}", _0)]
+    UnknownEncoding(u8),
+    #[display(fmt = "Malformed or too big compressed payload")]
+    Malformed,
+}
+
+impl Error for CompressionError {}
+
+#[derive(Debug, Display)]
+pub enum SparkplugError {
+    #[display(fmt = "Malformed sparkplug payload")]
+    Malformed,
+    #[display(fmt = "Unsupported protobuf wire type = {}", _0)]
+    UnsupportedWireType(u8),
+}
+
+impl Error for SparkplugError {}
+
+#[derive(Debug, Display)]
+pub enum FragmentError {
+    #[display(fmt = "Part size should be bigger than the part header")]
+    PartSizeTooSmall,
+    #[display(fmt = "Payload needs more than 65535 parts")]
+    TooManyParts,
+    #[display(fmt = "Malformed part header")]
+    Malformed,
+    #[display(fmt = "Missing part. Expected = {}, Received = {}", _0, _1)]
+    Gap(u16, u16),
+    #[display(fmt = "Message not completed in time")]
+    Timeout,
+}
+
+impl Error for FragmentError {}
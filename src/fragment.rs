@@ -0,0 +1,211 @@
+//! Splits payloads which exceed broker limits into parts and reassembles them
+//! on the receiving side. Every part carries an 8 byte header
+//!
+//! ```text
+//! | message id (u32) | part index (u16) | part count (u16) | data |
+//! ```
+//!
+//! All the header fields are big endian. Parts of a message should be published
+//! to the same topic with the same QoS so that the broker keeps them in order
+use crate::error::FragmentError;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Size of the header prepended to every part
+pub const HEADER_LEN: usize = 8;
+
+static MESSAGE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Process wide unique (till wrap around) message id
+pub fn next_message_id() -> u32 {
+    MESSAGE_ID.fetch_add(1, Ordering::Relaxed) as u32
+}
+
+/// Splits the payload into parts of at most 'max_part_size' bytes (including header)
+pub fn split(message_id: u32, payload: &[u8], max_part_size: usize) -> Result<Vec<Vec<u8>>, FragmentError> {
+    if max_part_size <= HEADER_LEN {
+        return Err(FragmentError::PartSizeTooSmall);
+    }
+
+    let chunk_size = max_part_size - HEADER_LEN;
+    let count = payload.len().div_ceil(chunk_size).max(1);
+    if count > usize::from(u16::MAX) {
+        return Err(FragmentError::TooManyParts);
+    }
+
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![payload]
+    } else {
+        payload.chunks(chunk_size).collect()
+    };
+
+    let parts = chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut part = Vec::with_capacity(HEADER_LEN + chunk.len());
+            part.extend_from_slice(&message_id.to_be_bytes());
+            part.extend_from_slice(&(index as u16).to_be_bytes());
+            part.extend_from_slice(&(count as u16).to_be_bytes());
+            part.extend_from_slice(chunk);
+            part
+        })
+        .collect();
+
+    Ok(parts)
+}
+
+#[derive(Debug)]
+struct Partial {
+    message_id: u32,
+    count: u16,
+    next: u16,
+    payload: Vec<u8>,
+    started: Instant,
+}
+
+/// Reassembles parts created by [split] per topic
+///
+/// [split]: fn.split.html
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    partials: HashMap<String, Partial>,
+}
+
+impl Reassembler {
+    /// Incomplete messages older than 'timeout' are discarded
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout,
+            partials: HashMap::new(),
+        }
+    }
+
+    /// Adds a part received on the topic. Returns the full payload once the last
+    /// part is received. Out of order or missing parts discard the message
+    pub fn push(&mut self, topic: &str, part: &[u8]) -> Result<Option<Vec<u8>>, FragmentError> {
+        if part.len() < HEADER_LEN {
+            return Err(FragmentError::Malformed);
+        }
+
+        let message_id = u32::from_be_bytes([part[0], part[1], part[2], part[3]]);
+        let index = u16::from_be_bytes([part[4], part[5]]);
+        let count = u16::from_be_bytes([part[6], part[7]]);
+        let data = &part[HEADER_LEN..];
+
+        if count == 0 || index >= count {
+            return Err(FragmentError::Malformed);
+        }
+
+        // first part of a new message replaces any incomplete message on this topic
+        if index == 0 {
+            if self.partials.remove(topic).is_some() {
+                warn!("Discarding incomplete message. Topic = {}", topic);
+            }
+
+            if count == 1 {
+                return Ok(Some(data.to_vec()));
+            }
+
+            let partial = Partial {
+                message_id,
+                count,
+                next: 1,
+                payload: data.to_vec(),
+                started: Instant::now(),
+            };
+
+            self.partials.insert(topic.to_owned(), partial);
+            return Ok(None);
+        }
+
+        let mut partial = match self.partials.remove(topic) {
+            Some(partial) => partial,
+            None => return Err(FragmentError::Gap(0, index)),
+        };
+
+        if partial.started.elapsed() > self.timeout {
+            return Err(FragmentError::Timeout);
+        }
+
+        if partial.message_id != message_id || partial.count != count || partial.next != index {
+            return Err(FragmentError::Gap(partial.next, index));
+        }
+
+        partial.payload.extend_from_slice(data);
+        partial.next += 1;
+
+        if partial.next == partial.count {
+            Ok(Some(partial.payload))
+        } else {
+            self.partials.insert(topic.to_owned(), partial);
+            Ok(None)
+        }
+    }
+
+    /// Discards incomplete messages older than the timeout. Returns their topics
+    pub fn expire(&mut self) -> Vec<String> {
+        let timeout = self.timeout;
+        let expired: Vec<String> = self
+            .partials
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() > timeout)
+            .map(|(topic, _)| topic.clone())
+            .collect();
+
+        for topic in expired.iter() {
+            self.partials.remove(topic);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split, Reassembler, HEADER_LEN};
+    use crate::error::FragmentError;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn split_and_reassemble_should_return_original_payload() {
+        let payload: Vec<u8> = (0..100).collect();
+        let parts = split(7, &payload, HEADER_LEN + 30).unwrap();
+        assert_eq!(parts.len(), 4);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        for part in parts.iter().take(3) {
+            assert_eq!(reassembler.push("a/b", part).unwrap(), None);
+        }
+
+        assert_eq!(reassembler.push("a/b", &parts[3]).unwrap(), Some(payload));
+    }
+
+    #[test]
+    fn missing_part_should_be_detected() {
+        let payload: Vec<u8> = (0..100).collect();
+        let parts = split(1, &payload, HEADER_LEN + 30).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        reassembler.push("a/b", &parts[0]).unwrap();
+        match reassembler.push("a/b", &parts[2]) {
+            Err(FragmentError::Gap(1, 2)) => (),
+            v => panic!("Expecting gap error. Received = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn stale_partial_message_should_expire() {
+        let payload: Vec<u8> = (0..100).collect();
+        let parts = split(1, &payload, HEADER_LEN + 60).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_millis(10));
+        reassembler.push("a/b", &parts[0]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(reassembler.expire(), vec!["a/b".to_owned()]);
+    }
+}
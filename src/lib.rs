@@ -114,14 +114,44 @@
 #[macro_use]
 extern crate log;
 
+pub mod actor;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod binding;
 pub mod client;
 pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compression;
 pub mod error;
+pub mod fragment;
+pub mod limiter;
+#[cfg(feature = "logstore")]
+pub mod logstore;
 pub mod mqttoptions;
+pub mod persistence;
+pub mod pkid;
+pub mod probe;
+pub mod reconnect;
+pub mod sampling;
+pub mod sequence;
+pub mod session;
+#[cfg(feature = "sparkplug")]
+pub mod sparkplug;
+pub mod stats;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+pub mod topic;
+pub mod transform;
+pub mod validation;
 
-pub use crate::client::{MqttClient, Notification};
-pub use crate::mqttoptions::{ConnectionMethod, MqttOptions, Proxy, ReconnectOptions, SecurityOptions};
+pub use crate::client::{BatchStatus, ClientHandle, ConnectFailures, ConnectionState, DeliveryToken, DisconnectReason, Inflight, MessageHandler, MessageRef, MqttClient, Notification, NotificationSender, PendingWork, PublishFile, PublishScope, SelfTest, StateChange, Tagged};
+pub use crate::mqttoptions::{BrokerCapabilities, ClientAuth, ConnectionMethod, DeadLetter, MqttOptions, PayloadSink, PkidExhaustion, PowerSaving, Presence, Proxy, Qos2Delivery, QueuePolicy, Reconfigure, ReconnectOptions, SecurityOptions, SubscriptionGuardrails, TakeoverAction, TakeoverDetection, TlsOptions};
 pub use crate::error::{ConnectError, ClientError};
+pub use crate::persistence::{FileStore, Store};
+pub use crate::probe::Probe;
+pub use crate::reconnect::{Attempt, ReconnectPolicy};
+pub use crate::sequence::{Gap, SequenceExtractor};
+pub use crate::transform::Transformer;
 pub use crossbeam_channel::Receiver;
 #[doc(hidden)]
 pub use mqtt311::*;
@@ -0,0 +1,64 @@
+//! Connection attempt rate limiting shared by many clients in a process
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Spaces out connection attempts of all the clients sharing it so that a broker
+/// coming back from an outage isn't hit by all of them at the same instant. Clone
+/// it into the options of every client which should share the limit
+#[derive(Clone, Debug)]
+pub struct ReconnectLimiter {
+    interval: Duration,
+    next_slot: Arc<Mutex<Instant>>,
+}
+
+impl ReconnectLimiter {
+    /// Allows at most 'attempts' connection attempts per second across all the clients
+    pub fn new(attempts: u32) -> ReconnectLimiter {
+        if attempts == 0 {
+            panic!("zero connection attempts are not allowed");
+        }
+
+        ReconnectLimiter {
+            interval: Duration::from_secs(1) / attempts,
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Reserves the next free slot and returns the time to wait for it
+    pub fn reserve(&self) -> Duration {
+        let now = Instant::now();
+        let mut next_slot = self.next_slot.lock().unwrap();
+
+        let slot = if *next_slot > now { *next_slot } else { now };
+        *next_slot = slot + self.interval;
+        slot - now
+    }
+
+    /// Blocks till this client's turn to connect
+    pub fn acquire(&self) {
+        let wait = self.reserve();
+        if wait > Duration::from_millis(0) {
+            debug!("Waiting for reconnection slot. Delay = {:?}", wait);
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReconnectLimiter;
+    use std::time::Duration;
+
+    #[test]
+    fn attempts_from_clones_should_be_spaced_by_interval() {
+        let limiter = ReconnectLimiter::new(10);
+        let other = limiter.clone();
+
+        assert_eq!(limiter.reserve(), Duration::from_millis(0));
+        assert!(other.reserve() > Duration::from_millis(90));
+        assert!(limiter.reserve() > Duration::from_millis(190));
+    }
+}
@@ -0,0 +1,555 @@
+//! Log structured [store] for gateways which buffer a lot of data while offline.
+//! Every change is appended to a single log file and synced, which is cheaper
+//! than a file per message. Live records are rewritten into a fresh log once
+//! the log holds more dead records than live ones, and the live records are
+//! bounded by a disk budget
+//!
+//! ```text
+//! | body len (u32 be) | op (u8) | body |
+//! ```
+//!
+//! Publish and subscription bodies are their mqtt encoding, range removals the
+//! first and last packet ids and the others the packet id (u16 be). A record
+//! cut short by a crash is dropped on open
+//!
+//! [store]: ../persistence/trait.Store.html
+use crate::persistence::{decode, encode, Store};
+use mqtt311::{Packet, PacketIdentifier, Publish, Subscribe, SubscribeTopic};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+const PUT_INCOMING: u8 = 1;
+const REMOVE_INCOMING: u8 = 2;
+const PUT_RECEIVED: u8 = 3;
+const REMOVE_RECEIVED: u8 = 4;
+const PUT_SUBSCRIPTIONS: u8 = 5;
+const PUT_OUTGOING: u8 = 6;
+const REMOVE_OUTGOING: u8 = 7;
+const PUT_RELEASED: u8 = 8;
+const REMOVE_RELEASED: u8 = 9;
+const REMOVE_INCOMING_RANGE: u8 = 10;
+const REMOVE_OUTGOING_RANGE: u8 = 11;
+
+/// Size of the record header
+const HEADER_LEN: u64 = 5;
+
+/// Logs smaller than this aren't compacted
+const MIN_COMPACTION_LEN: u64 = 64 * 1024;
+
+/// Position of a publish (or subscriptions) body in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Record {
+    sequence: u64,
+    offset: u64,
+    len: u32,
+}
+
+impl Record {
+    fn size(&self) -> u64 {
+        HEADER_LEN + u64::from(self.len)
+    }
+}
+
+/// Live records of the log
+#[derive(Debug, Default)]
+struct Index {
+    sequence: u64,
+    incoming: BTreeMap<PacketIdentifier, Record>,
+    outgoing: BTreeMap<PacketIdentifier, Record>,
+    received: BTreeSet<PacketIdentifier>,
+    released: BTreeSet<PacketIdentifier>,
+    subscriptions: Option<Record>,
+}
+
+impl Index {
+    fn live_len(&self) -> u64 {
+        let publishes: u64 = self.incoming.values().chain(self.outgoing.values()).map(Record::size).sum();
+        let received = (self.received.len() + self.released.len()) as u64 * (HEADER_LEN + 2);
+        publishes + received + self.subscriptions.map_or(0, |record| record.size())
+    }
+
+    fn apply(&mut self, op: u8, offset: u64, body: &[u8]) -> io::Result<()> {
+        let record = Record {
+            sequence: self.sequence,
+            offset,
+            len: body.len() as u32,
+        };
+
+        self.sequence += 1;
+        match op {
+            PUT_INCOMING => {
+                self.incoming.insert(publish_pkid(body)?, record);
+            }
+            PUT_OUTGOING => {
+                self.outgoing.insert(publish_pkid(body)?, record);
+            }
+            PUT_SUBSCRIPTIONS => self.subscriptions = Some(record),
+            REMOVE_INCOMING => {
+                self.incoming.remove(&pkid(body)?);
+            }
+            REMOVE_OUTGOING => {
+                self.outgoing.remove(&pkid(body)?);
+            }
+            REMOVE_INCOMING_RANGE => {
+                let range = pkid_range(body)?;
+                self.incoming.retain(|pkid, _| !range.contains(pkid));
+            }
+            REMOVE_OUTGOING_RANGE => {
+                let range = pkid_range(body)?;
+                self.outgoing.retain(|pkid, _| !range.contains(pkid));
+            }
+            PUT_RECEIVED => {
+                self.received.insert(pkid(body)?);
+            }
+            REMOVE_RECEIVED => {
+                self.received.remove(&pkid(body)?);
+            }
+            PUT_RELEASED => {
+                self.released.insert(pkid(body)?);
+            }
+            REMOVE_RELEASED => {
+                self.released.remove(&pkid(body)?);
+            }
+            op => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown log op {}", op))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Single file log store. Live records are limited to 'max_bytes'. Saves beyond
+/// that fail, which stops acknowledging incoming publishes and leaves outgoing
+/// publishes in memory only. Use a directory per client id
+#[derive(Debug)]
+pub struct LogStore {
+    path: PathBuf,
+    log: File,
+    len: u64,
+    max_bytes: u64,
+    index: Index,
+}
+
+impl LogStore {
+    /// Opens the log in the directory and replays it. Directory is created if it
+    /// doesn't exist
+    pub fn new<P: AsRef<Path>>(dir: P, max_bytes: u64) -> io::Result<LogStore> {
+        fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join("store.log");
+        let mut log = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        let mut index = Index::default();
+        let len = replay(&bytes, &mut index)?;
+        if len < bytes.len() as u64 {
+            warn!("Dropping partial record at the end of the log. Offset = {}", len);
+            log.set_len(len)?;
+        }
+
+        Ok(LogStore {
+            path,
+            log,
+            len,
+            max_bytes,
+            index,
+        })
+    }
+
+    /// Size of the log file in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the live records in bytes
+    pub fn live_len(&self) -> u64 {
+        self.index.live_len()
+    }
+
+    fn append(&mut self, op: u8, body: &[u8]) -> io::Result<()> {
+        let size = HEADER_LEN + body.len() as u64;
+        let is_put = op == PUT_INCOMING || op == PUT_OUTGOING || op == PUT_SUBSCRIPTIONS || op == PUT_RECEIVED;
+        if is_put && self.index.live_len() + size > self.max_bytes {
+            return Err(io::Error::other("Log store is full"));
+        }
+
+        let mut record = Vec::with_capacity(size as usize);
+        record.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        record.push(op);
+        record.extend_from_slice(body);
+        self.log.write_all(&record)?;
+        self.log.sync_data()?;
+
+        self.index.apply(op, self.len + HEADER_LEN, body)?;
+        self.len += size;
+        if self.len > MIN_COMPACTION_LEN && self.len > 2 * self.index.live_len() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the incoming publishes with packet ids in the range with a single
+    /// record
+    pub fn remove_incoming_range(&mut self, range: RangeInclusive<PacketIdentifier>) -> io::Result<()> {
+        match self.index.incoming.keys().any(|pkid| range.contains(pkid)) {
+            true => self.remove_range(REMOVE_INCOMING_RANGE, range),
+            false => Ok(()),
+        }
+    }
+
+    /// Removes the outgoing publishes with packet ids in the range with a single
+    /// record. E.g to drop the data buffered while offline which went stale
+    pub fn remove_outgoing_range(&mut self, range: RangeInclusive<PacketIdentifier>) -> io::Result<()> {
+        match self.index.outgoing.keys().any(|pkid| range.contains(pkid)) {
+            true => self.remove_range(REMOVE_OUTGOING_RANGE, range),
+            false => Ok(()),
+        }
+    }
+
+    /// Range removals can kill most of the log at once. The log is compacted
+    /// as soon as it holds more dead records than live ones, irrespective of
+    /// its size
+    fn remove_range(&mut self, op: u8, range: RangeInclusive<PacketIdentifier>) -> io::Result<()> {
+        let mut body = Vec::with_capacity(4);
+        body.extend_from_slice(&range.start().0.to_be_bytes());
+        body.extend_from_slice(&range.end().0.to_be_bytes());
+        self.append(op, &body)?;
+
+        if self.len > 2 * self.index.live_len() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the live records into a fresh log in their saving order
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut records: Vec<(u8, Record)> = Vec::new();
+        records.extend(self.index.incoming.values().map(|record| (PUT_INCOMING, *record)));
+        records.extend(self.index.outgoing.values().map(|record| (PUT_OUTGOING, *record)));
+        records.extend(self.index.subscriptions.map(|record| (PUT_SUBSCRIPTIONS, record)));
+        records.sort_by_key(|(_, record)| record.sequence);
+
+        let mut compacted = Vec::new();
+        for (op, record) in records {
+            let body = self.read(&record)?;
+            compacted.extend_from_slice(&record.len.to_be_bytes());
+            compacted.push(op);
+            compacted.extend_from_slice(&body);
+        }
+
+        let received = self.index.received.iter().map(|pkid| (PUT_RECEIVED, pkid));
+        let released = self.index.released.iter().map(|pkid| (PUT_RELEASED, pkid));
+        for (op, pkid) in received.chain(released) {
+            compacted.extend_from_slice(&2u32.to_be_bytes());
+            compacted.push(op);
+            compacted.extend_from_slice(&pkid.0.to_be_bytes());
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&compacted)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        debug!("Compacted log store. Before = {}, After = {}", self.len, compacted.len());
+        self.log = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.index = Index::default();
+        self.len = replay(&compacted, &mut self.index)?;
+        Ok(())
+    }
+
+    fn read(&mut self, record: &Record) -> io::Result<Vec<u8>> {
+        let mut body = vec![0; record.len as usize];
+        self.log.seek(SeekFrom::Start(record.offset))?;
+        self.log.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    fn publishes(&mut self, outgoing: bool) -> io::Result<Vec<Publish>> {
+        let records = if outgoing { &self.index.outgoing } else { &self.index.incoming };
+        let mut records: Vec<Record> = records.values().cloned().collect();
+        records.sort();
+
+        let mut publishes = Vec::with_capacity(records.len());
+        for record in records {
+            match decode(&self.read(&record)?)? {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected record {:?}", packet))),
+            }
+        }
+
+        Ok(publishes)
+    }
+
+    /// Publishes are indexed by their packet id. Publishes without one are
+    /// rejected before they reach the log as their records can't be replayed
+    fn put_publish(&mut self, op: u8, publish: &Publish) -> io::Result<()> {
+        if publish.pkid.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Publish without packet id"));
+        }
+
+        self.append(op, &encode(&Packet::Publish(publish.clone()))?)
+    }
+}
+
+impl Store for LogStore {
+    fn put_incoming(&mut self, publish: &Publish) -> io::Result<()> {
+        self.put_publish(PUT_INCOMING, publish)
+    }
+
+    fn remove_incoming(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        match self.index.incoming.contains_key(&pkid) {
+            true => self.append(REMOVE_INCOMING, &pkid.0.to_be_bytes()),
+            false => Ok(()),
+        }
+    }
+
+    fn incoming(&mut self) -> io::Result<Vec<Publish>> {
+        self.publishes(false)
+    }
+
+    fn put_received(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.append(PUT_RECEIVED, &pkid.0.to_be_bytes())
+    }
+
+    fn remove_received(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        match self.index.received.contains(&pkid) {
+            true => self.append(REMOVE_RECEIVED, &pkid.0.to_be_bytes()),
+            false => Ok(()),
+        }
+    }
+
+    fn received(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+        Ok(self.index.received.iter().cloned().collect())
+    }
+
+    fn put_subscriptions(&mut self, subscriptions: &[SubscribeTopic]) -> io::Result<()> {
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: subscriptions.to_vec(),
+        };
+
+        self.append(PUT_SUBSCRIPTIONS, &encode(&Packet::Subscribe(subscribe))?)
+    }
+
+    fn subscriptions(&mut self) -> io::Result<Vec<SubscribeTopic>> {
+        let record = match self.index.subscriptions {
+            Some(record) => record,
+            None => return Ok(Vec::new()),
+        };
+
+        match decode(&self.read(&record)?)? {
+            Packet::Subscribe(subscribe) => Ok(subscribe.topics),
+            packet => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected record {:?}", packet))),
+        }
+    }
+
+    fn put_outgoing(&mut self, publish: &Publish) -> io::Result<()> {
+        self.put_publish(PUT_OUTGOING, publish)
+    }
+
+    fn remove_outgoing(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        match self.index.outgoing.contains_key(&pkid) {
+            true => self.append(REMOVE_OUTGOING, &pkid.0.to_be_bytes()),
+            false => Ok(()),
+        }
+    }
+
+    fn outgoing(&mut self) -> io::Result<Vec<Publish>> {
+        self.publishes(true)
+    }
+
+    fn put_released(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.append(PUT_RELEASED, &pkid.0.to_be_bytes())
+    }
+
+    fn remove_released(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        match self.index.released.contains(&pkid) {
+            true => self.append(REMOVE_RELEASED, &pkid.0.to_be_bytes()),
+            false => Ok(()),
+        }
+    }
+
+    fn released(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+        Ok(self.index.released.iter().cloned().collect())
+    }
+}
+
+/// Applies the records of the log to the index. Returns the length of the log
+/// up to the last complete record
+fn replay(bytes: &[u8], index: &mut Index) -> io::Result<u64> {
+    let mut offset = 0;
+    while bytes.len() - offset >= HEADER_LEN as usize {
+        let mut len = [0; 4];
+        len.copy_from_slice(&bytes[offset..offset + 4]);
+        let len = u32::from_be_bytes(len) as usize;
+        let start = offset + HEADER_LEN as usize;
+        if bytes.len() - start < len {
+            break;
+        }
+
+        index.apply(bytes[offset + 4], start as u64, &bytes[start..start + len])?;
+        offset = start + len;
+    }
+
+    Ok(offset as u64)
+}
+
+fn publish_pkid(body: &[u8]) -> io::Result<PacketIdentifier> {
+    match decode(body)? {
+        Packet::Publish(Publish { pkid: Some(pkid), .. }) => Ok(pkid),
+        packet => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected record {:?}", packet))),
+    }
+}
+
+fn pkid_range(body: &[u8]) -> io::Result<RangeInclusive<PacketIdentifier>> {
+    match body {
+        [start_msb, start_lsb, end_msb, end_lsb] => {
+            let start = PacketIdentifier(u16::from_be_bytes([*start_msb, *start_lsb]));
+            let end = PacketIdentifier(u16::from_be_bytes([*end_msb, *end_lsb]));
+            Ok(start..=end)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed packet id range record")),
+    }
+}
+
+fn pkid(body: &[u8]) -> io::Result<PacketIdentifier> {
+    match body {
+        [msb, lsb] => Ok(PacketIdentifier(u16::from_be_bytes([*msb, *lsb]))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed packet id record")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LogStore, MIN_COMPACTION_LEN};
+    use crate::persistence::Store;
+    use mqtt311::{PacketIdentifier, Publish, QoS};
+    use std::{env, fs::{self, OpenOptions}, io::Write, sync::Arc};
+
+    fn publish(pkid: u16, len: usize) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(pkid)),
+            payload: Arc::new(vec![pkid as u8; len]),
+        }
+    }
+
+    #[test]
+    fn log_should_be_compacted_bounded_and_replayed_after_a_crash() {
+        let dir = env::temp_dir().join(format!("rumqtt-log-{}", uuid::Uuid::new_v4()));
+        let mut store = LogStore::new(&dir, 9 * 1024 + 512).unwrap();
+
+        // churn of acked publishes keeps the log small
+        for i in 0..200u16 {
+            store.put_outgoing(&publish(i, 1000)).unwrap();
+            store.remove_outgoing(PacketIdentifier(i)).unwrap();
+        }
+
+        assert!(store.len() <= MIN_COMPACTION_LEN + 1100);
+        assert_eq!(store.live_len(), 0);
+
+        // live records are bounded
+        for i in 1..=9 {
+            store.put_outgoing(&publish(i, 1000)).unwrap();
+        }
+
+        assert!(store.put_outgoing(&publish(10, 1000)).is_err());
+        store.put_received(PacketIdentifier(4)).unwrap();
+        drop(store);
+
+        // record cut short by a crash
+        let mut log = OpenOptions::new().append(true).open(dir.join("store.log")).unwrap();
+        log.write_all(&[0, 0, 4, 0, 6, 1, 2]).unwrap();
+        drop(log);
+
+        let mut store = LogStore::new(&dir, 9 * 1024 + 512).unwrap();
+        let pkids: Vec<u16> = store.outgoing().unwrap().iter().filter_map(|publish| publish.pkid).map(|pkid| pkid.0).collect();
+        assert_eq!(pkids, (1..=9).collect::<Vec<u16>>());
+        assert_eq!(store.received().unwrap(), vec![PacketIdentifier(4)]);
+        store.put_outgoing(&publish(10, 10)).unwrap();
+        assert_eq!(store.outgoing().unwrap().len(), 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn publish_without_pkid_should_be_rejected_without_breaking_the_log() {
+        let dir = env::temp_dir().join(format!("rumqtt-log-{}", uuid::Uuid::new_v4()));
+        let mut store = LogStore::new(&dir, 1024 * 1024).unwrap();
+        store.put_outgoing(&publish(1, 10)).unwrap();
+        let publish = Publish { pkid: None, ..publish(2, 10) };
+        assert!(store.put_outgoing(&publish).is_err());
+        assert!(store.put_incoming(&publish).is_err());
+        drop(store);
+
+        let mut store = LogStore::new(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.outgoing().unwrap().len(), 1);
+        assert!(store.incoming().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn range_removal_should_compact_the_log_and_survive_a_reopen() {
+        let dir = env::temp_dir().join(format!("rumqtt-log-{}", uuid::Uuid::new_v4()));
+        let mut store = LogStore::new(&dir, 1024 * 1024).unwrap();
+        for i in 1..=20 {
+            store.put_outgoing(&publish(i, 100)).unwrap();
+            store.put_incoming(&publish(i, 100)).unwrap();
+        }
+
+        let before = store.len();
+        store.remove_outgoing_range(PacketIdentifier(5)..=PacketIdentifier(15)).unwrap();
+        assert_eq!(store.len(), before + super::HEADER_LEN + 4);
+        store.remove_incoming_range(PacketIdentifier(1)..=PacketIdentifier(18)).unwrap();
+        assert!(store.len() < before / 2);
+        assert_eq!(store.len(), store.live_len());
+
+        // nothing to remove, nothing appended
+        let len = store.len();
+        store.remove_outgoing_range(PacketIdentifier(100)..=PacketIdentifier(200)).unwrap();
+        assert_eq!(store.len(), len);
+        drop(store);
+
+        let mut store = LogStore::new(&dir, 1024 * 1024).unwrap();
+        let pkids: Vec<u16> = store.outgoing().unwrap().iter().filter_map(|publish| publish.pkid).map(|pkid| pkid.0).collect();
+        assert_eq!(pkids, (1..=4).chain(16..=20).collect::<Vec<u16>>());
+        assert_eq!(store.incoming().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_should_keep_released_pkids() {
+        let dir = env::temp_dir().join(format!("rumqtt-log-{}", uuid::Uuid::new_v4()));
+        let mut store = LogStore::new(&dir, 1024 * 1024).unwrap();
+        store.put_outgoing(&publish(1, 10)).unwrap();
+        store.put_released(PacketIdentifier(1)).unwrap();
+        store.put_released(PacketIdentifier(2)).unwrap();
+        store.remove_released(PacketIdentifier(2)).unwrap();
+        store.put_outgoing(&publish(3, 10)).unwrap();
+        store.remove_outgoing(PacketIdentifier(3)).unwrap();
+
+        let before = store.len();
+        store.compact().unwrap();
+        assert!(store.len() < before);
+        assert_eq!(store.released().unwrap(), vec![PacketIdentifier(1)]);
+        drop(store);
+
+        let mut store = LogStore::new(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.released().unwrap(), vec![PacketIdentifier(1)]);
+        assert_eq!(store.outgoing().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
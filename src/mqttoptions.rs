@@ -1,6 +1,30 @@
 //! Options to set mqtt client behaviour
-use mqtt311::LastWill;
-use std::time::Duration;
+#[cfg(feature = "rustls")]
+use crate::client::network::{KeySigner, KeySigning};
+use crate::client::network::{Resolution, Resolver};
+use crate::client::{DisconnectReason, StateChange};
+use crate::limiter::ReconnectLimiter;
+use crate::persistence::{Store, StoreHandle};
+use crate::pkid::{PkidAllocator, PkidAllocatorHandle};
+use crate::probe::{Probe, ProbeHandle};
+use crate::reconnect::{Attempt, ReconnectHook, ReconnectPolicy, ReconnectPolicyHandle};
+use crate::sampling::{Sample, Sampling};
+use crate::sequence::{SequenceExtractor, SequenceTracking};
+use crate::session::Session;
+use crate::stats::TopicStats;
+use crate::transform::{Transformer, Transformers};
+use crate::validation::{matches, Authorization, Authorizer, Validator, Validators};
+use crossbeam_channel::Sender;
+use mqtt311::{LastWill, Publish, QoS};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use uuid::Uuid;
 
 /// Control how the connection is re-established if it is lost.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -36,6 +60,110 @@ pub enum ConnectionMethod {
     Tcp,
     /// Encrypted connection. (ca data, optional client cert and key data)
     Tls(Vec<u8>, Option<(Vec<u8>, Vec<u8>)>),
+    /// Encrypted connection with the full tls configuration
+    TlsConfig(TlsOptions),
+}
+
+impl ConnectionMethod {
+    /// Tls configuration of encrypted connections
+    pub fn tls_options(&self) -> Option<TlsOptions> {
+        match self {
+            ConnectionMethod::Tcp => None,
+            ConnectionMethod::Tls(ca, None) => Some(TlsOptions::new(ca.clone())),
+            ConnectionMethod::Tls(ca, Some((cert, key))) => Some(TlsOptions::new(ca.clone()).set_client_auth(cert.clone(), key.clone())),
+            ConnectionMethod::TlsConfig(tls) => Some(tls.clone()),
+        }
+    }
+}
+
+/// Tls configuration. Same for the rustls (default) and the native-tls backends,
+/// which are picked with cargo features
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsOptions {
+    /// pem bundle of the certificate authorities to verify the broker with
+    ca: Vec<u8>,
+    /// client certificate and private key for mutual tls
+    client_auth: Option<ClientAuth>,
+    /// server name sent with sni and verified against the broker certificate
+    sni: Option<String>,
+    /// protocols advertised with alpn during the handshake
+    alpn: Vec<String>,
+}
+
+impl TlsOptions {
+    pub fn new(ca: Vec<u8>) -> TlsOptions {
+        TlsOptions {
+            ca,
+            client_auth: None,
+            sni: None,
+            alpn: Vec::new(),
+        }
+    }
+
+    /// Set the client certificate (chain) and private key in pem format
+    pub fn set_client_auth(mut self, cert: Vec<u8>, key: Vec<u8>) -> Self {
+        self.client_auth = Some(ClientAuth::Pem(cert, key));
+        self
+    }
+
+    /// Set the client certificate chain (leaf first) and private key in der format
+    pub fn set_client_auth_der(mut self, certs: Vec<Vec<u8>>, key: Vec<u8>) -> Self {
+        self.client_auth = Some(ClientAuth::Der(certs, key));
+        self
+    }
+
+    /// Set the paths of the pem client certificate (chain) and private key. Files
+    /// are read on every connection, which picks up rotated certificates
+    pub fn set_client_auth_files<P: Into<PathBuf>>(mut self, cert: P, key: P) -> Self {
+        self.client_auth = Some(ClientAuth::PemFiles(cert.into(), key.into()));
+        self
+    }
+
+    /// Set the server name for sni and certificate verification. Defaults to the
+    /// broker host. Useful when connecting with an ip or through a tunnel
+    pub fn set_sni<S: Into<String>>(mut self, sni: S) -> Self {
+        self.sni = Some(sni.into());
+        self
+    }
+
+    /// Set the protocols to advertise with alpn. E.g AWS IoT takes mqtt on port
+    /// 443 with "x-amzn-mqtt-ca"
+    pub fn set_alpn(mut self, protocols: Vec<String>) -> Self {
+        self.alpn = protocols;
+        self
+    }
+
+    /// Certificate authorities
+    pub fn ca(&self) -> &[u8] {
+        &self.ca
+    }
+
+    /// Client certificate and private key
+    pub fn client_auth(&self) -> Option<&ClientAuth> {
+        self.client_auth.as_ref()
+    }
+
+    /// Server name override
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// Alpn protocols
+    pub fn alpn(&self) -> &[String] {
+        &self.alpn
+    }
+}
+
+/// Client certificate and private key to authenticate to brokers which
+/// mandate mutual tls (e.g AWS IoT). Keys are pkcs8 or pkcs1 rsa
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientAuth {
+    /// pem certificate (chain) and private key
+    Pem(Vec<u8>, Vec<u8>),
+    /// der certificate chain, leaf first, and private key
+    Der(Vec<Vec<u8>>, Vec<u8>),
+    /// paths of the pem certificate (chain) and private key
+    PemFiles(PathBuf, PathBuf),
 }
 
 /// Mqtt through http proxy
@@ -48,6 +176,371 @@ pub enum Proxy {
     HttpConnect(String, u16, Vec<u8>, i64),
 }
 
+/// Route for the incoming messages which are rejected by the user
+#[derive(Clone, Debug)]
+pub enum DeadLetter {
+    /// Drop rejected messages
+    Drop,
+    /// Forward rejected messages on this channel
+    Channel(Sender<Publish>),
+    /// Republish rejected messages to this topic with their original QoS
+    Republish(String),
+}
+
+/// Features supported by the broker. MQTT 3.1.1 has no way of asking the broker
+/// (v5 connack carries them as properties), so they are configured. Publishes and
+/// subscriptions above the maximum qos are downgraded and the other unsupported
+/// features fail with `ClientError::Unsupported` instead of getting disconnected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BrokerCapabilities {
+    pub max_qos: QoS,
+    pub retain_available: bool,
+    pub wildcard_subscriptions: bool,
+}
+
+impl Default for BrokerCapabilities {
+    fn default() -> Self {
+        BrokerCapabilities {
+            max_qos: QoS::ExactlyOnce,
+            retain_available: true,
+            wildcard_subscriptions: true,
+        }
+    }
+}
+
+impl BrokerCapabilities {
+    /// Requested qos capped by the maximum qos of the broker
+    pub fn degrade_qos(&self, qos: QoS) -> QoS {
+        if qos.to_u8() > self.max_qos.to_u8() {
+            debug!("Downgrading qos. Requested = {:?}, Max = {:?}", qos, self.max_qos);
+            self.max_qos
+        } else {
+            qos
+        }
+    }
+}
+
+/// Guardrails against accidentally broad subscriptions. Filters without wildcards
+/// always pass. Filters in `allowed` pass as they are explicitly asked for
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubscriptionGuardrails {
+    /// Reject filters starting with a multi level wildcard (`#`)
+    pub reject_root_wildcard: bool,
+    /// Topic levels required in front of the first wildcard
+    pub min_levels: usize,
+    /// Filters which are subscribed regardless of the guardrails
+    pub allowed: Vec<String>,
+}
+
+impl Default for SubscriptionGuardrails {
+    fn default() -> Self {
+        SubscriptionGuardrails {
+            reject_root_wildcard: true,
+            min_levels: 1,
+            allowed: Vec::new(),
+        }
+    }
+}
+
+impl SubscriptionGuardrails {
+    /// Allows the filter regardless of the guardrails
+    pub fn allow<S: Into<String>>(mut self, filter: S) -> Self {
+        self.allowed.push(filter.into());
+        self
+    }
+
+    /// Checks the filter and returns the violated guardrail
+    pub fn check(&self, filter: &str) -> Result<(), &'static str> {
+        if self.allowed.iter().any(|allowed| allowed == filter) {
+            return Ok(());
+        }
+
+        let levels: Vec<&str> = filter.split('/').collect();
+        let first_wildcard = match levels.iter().position(|level| *level == "+" || *level == "#") {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+
+        if self.reject_root_wildcard && levels[0] == "#" {
+            return Err("multi level wildcard at root");
+        }
+
+        if first_wildcard < self.min_levels {
+            return Err("too few topic levels before wildcard");
+        }
+
+        Ok(())
+    }
+}
+
+/// Low power mode for battery devices. Outgoing requests are held till the next
+/// transmission window so that the modem can sleep between bursts. Windows start
+/// every `interval` and stay open for `window`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerSaving {
+    /// Time between the starts of two transmission windows
+    pub interval: Duration,
+    /// How long a transmission window stays open
+    pub window: Duration,
+    /// Keep alive while in low power mode. Usually much longer than the regular one
+    pub keep_alive: Duration,
+}
+
+impl PowerSaving {
+    /// Time till the next window opens, `elapsed` after the first window opened
+    pub fn next_window(&self, elapsed: Duration) -> Duration {
+        let interval = self.interval.as_nanos();
+        let position = elapsed.as_nanos() % interval;
+        Duration::from_nanos((interval - position) as u64)
+    }
+
+    /// Time till requests can be sent. `None` while a window is open
+    pub fn time_to_window(&self, elapsed: Duration) -> Option<Duration> {
+        let position = elapsed.as_nanos() % self.interval.as_nanos();
+        if position < self.window.as_nanos() {
+            None
+        } else {
+            Some(self.next_window(elapsed))
+        }
+    }
+}
+
+/// Detection of another client connecting with the same client id. Broker closes
+/// the older connection on a takeover and as both the clients keep reconnecting,
+/// their connections get closed right after connack. A connection closed by the
+/// broker within `window` of connack is a flap and `flaps` of them in a row are
+/// taken as a takeover
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TakeoverDetection {
+    /// Connections closed by the broker within this time after connack are flaps
+    pub window: Duration,
+    /// Flaps in a row which mean a takeover
+    pub flaps: u32,
+    /// What the eventloop does on a takeover
+    pub action: TakeoverAction,
+}
+
+impl Default for TakeoverDetection {
+    fn default() -> Self {
+        TakeoverDetection {
+            window: Duration::from_secs(5),
+            flaps: 3,
+            action: TakeoverAction::Stop,
+        }
+    }
+}
+
+impl TakeoverDetection {
+    /// Connection which lived for `lived` and was lost for `reason` is a flap
+    pub fn is_flap(&self, lived: Duration, reason: DisconnectReason) -> bool {
+        let closed_by_broker = reason == DisconnectReason::BrokerClosed || reason == DisconnectReason::Reset;
+        closed_by_broker && lived < self.window
+    }
+}
+
+/// Retained update which is published periodically while connected so that
+/// monitoring based on message freshness sees the device as alive. Unlike pings,
+/// these reach other subscribers of the topic
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// Time between two updates. First update goes out right after connack
+    pub interval: Duration,
+}
+
+impl Presence {
+    pub fn new<S: Into<String>, P: Into<Vec<u8>>>(topic: S, payload: P, interval: Duration) -> Presence {
+        Presence {
+            topic: topic.into(),
+            payload: payload.into(),
+            interval,
+        }
+    }
+
+    /// Qos 0 retained publish of the update
+    pub fn publish(&self) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic_name: self.topic.clone(),
+            pkid: None,
+            payload: Arc::new(self.payload.clone()),
+        }
+    }
+}
+
+/// Eventloop behaviour on a client id takeover
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TakeoverAction {
+    /// Stop reconnecting so that the other client keeps the client id
+    Stop,
+    /// Keep reconnecting as per the reconnect options
+    Retry,
+}
+
+/// When incoming qos 2 publishes are handed to the user (spec 4.3.3)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Qos2Delivery {
+    /// Method A. Hold the publish till pubrel. Retransmissions before pubrel are
+    /// ignored, at the cost of a round trip of latency
+    OnPubrel,
+    /// Method B. Deliver on publish and remember the packet id till pubrel so that
+    /// retransmissions aren't delivered again
+    OnPublish,
+}
+
+/// What a qos 1 or 2 publish, a subscribe or an unsubscribe does when all the
+/// packet ids of the allocator are in use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PkidExhaustion {
+    /// Block the caller till the broker acks a request
+    Block,
+    /// Fail the request with `ClientError::PkidExhausted`
+    Fail,
+    /// Queue the request in the eventloop till the broker acks a request
+    Spill,
+}
+
+/// What happens to a publish which doesn't fit in a full queue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    /// Wait for room. Outgoing publishers block and incoming publishes stop the
+    /// eventloop from reading the network
+    Block,
+    /// Drop the publish which doesn't fit
+    DropNewest,
+    /// Drop the oldest queued publish to make room
+    DropOldest,
+}
+
+/// Options which can be changed on a running client. Reconnection options are
+/// applied immediately. Others are applied from the next reconnection as keep
+/// alive is part of the connect packet and limits are set up per connection
+#[derive(Clone, Debug)]
+pub enum Reconfigure {
+    /// Keep alive in seconds
+    KeepAlive(u16),
+    Reconnect(ReconnectOptions),
+    /// Outgoing messages per second
+    OutgoingRateLimit(u64),
+    /// (queue size, delay)
+    OutgoingQueueLimit(usize, Duration),
+}
+
+impl Reconfigure {
+    /// Checks the option against the limits enforced by the setters of [MqttOptions]
+    ///
+    /// [MqttOptions]: struct.MqttOptions.html
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Reconfigure::KeepAlive(secs) => secs >= 10,
+            Reconfigure::Reconnect(_) => true,
+            Reconfigure::OutgoingRateLimit(rate) => rate > 0,
+            Reconfigure::OutgoingQueueLimit(queue_size, _) => queue_size > 0,
+        }
+    }
+}
+
+/// Qos and retain flag of publishes which don't specify them. Per topic filter
+/// profiles take precedence over the defaults
+#[derive(Clone, Debug)]
+pub struct PublishProfiles {
+    defaults: (QoS, bool),
+    profiles: Vec<(String, QoS, bool)>,
+}
+
+impl Default for PublishProfiles {
+    fn default() -> Self {
+        PublishProfiles {
+            defaults: (QoS::AtMostOnce, false),
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl PublishProfiles {
+    /// Qos and retain flag of a publish on this topic
+    pub fn resolve(&self, topic: &str) -> (QoS, bool) {
+        self.profiles
+            .iter()
+            .find(|(filter, _, _)| matches(topic, filter))
+            .map(|(_, qos, retain)| (*qos, *retain))
+            .unwrap_or(self.defaults)
+    }
+}
+
+/// Runs on the eventloop thread before it connects. E.g to set the priority
+/// or cpu affinity of the thread
+#[derive(Clone)]
+pub struct ThreadHook(Arc<dyn Fn() + Send + Sync>);
+
+impl ThreadHook {
+    pub fn run(&self) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for ThreadHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ThreadHook")
+    }
+}
+
+/// Destination of incoming payloads above the spill threshold. E.g a temp file
+/// or a flash partition reserved for firmware images. Runs on the eventloop thread
+pub trait PayloadSink: Send + Sync {
+    /// Writer for the 'len' bytes payload of a publish on 'topic'. The payload is
+    /// already in memory and written in one go. The path is handed to the user
+    /// with [Notification::PublishFile] to find the payload
+    ///
+    /// [Notification::PublishFile]: ../client/enum.Notification.html#variant.PublishFile
+    fn open(&self, topic: &str, len: usize) -> io::Result<(Box<dyn Write>, PathBuf)>;
+}
+
+impl<F> PayloadSink for F
+where
+    F: Fn(&str, usize) -> io::Result<(Box<dyn Write>, PathBuf)> + Send + Sync,
+{
+    fn open(&self, topic: &str, len: usize) -> io::Result<(Box<dyn Write>, PathBuf)> {
+        self(topic, len)
+    }
+}
+
+/// Writes every payload to a new file in the directory
+struct DirSink(PathBuf);
+
+impl PayloadSink for DirSink {
+    fn open(&self, _topic: &str, _len: usize) -> io::Result<(Box<dyn Write>, PathBuf)> {
+        let path = self.0.join(format!("{}.payload", Uuid::new_v4()));
+        let file = File::create(&path)?;
+        Ok((Box::new(file), path))
+    }
+}
+
+/// Cloneable handle to a [payload sink]
+///
+/// [payload sink]: trait.PayloadSink.html
+#[derive(Clone)]
+pub struct PayloadSinkHandle(Arc<dyn PayloadSink>);
+
+impl PayloadSinkHandle {
+    pub(crate) fn new<S: PayloadSink + 'static>(sink: S) -> PayloadSinkHandle {
+        PayloadSinkHandle(Arc::new(sink))
+    }
+
+    pub fn open(&self, topic: &str, len: usize) -> io::Result<(Box<dyn Write>, PathBuf)> {
+        self.0.open(topic, len)
+    }
+}
+
+impl fmt::Debug for PayloadSinkHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PayloadSinkHandle")
+    }
+}
+
 /// Mqtt options
 #[derive(Clone, Debug)]
 pub struct MqttOptions {
@@ -66,20 +559,111 @@ pub struct MqttOptions {
     proxy: Proxy,
     /// reconnection options
     reconnect: ReconnectOptions,
+    /// user defined reconnection decisions. overrides 'reconnect'
+    reconnect_policy: Option<ReconnectPolicyHandle>,
+    /// failed reconnection attempts in a row after which the eventloop stops
+    max_reconnect_attempts: Option<u32>,
+    /// runs before every reconnection attempt
+    before_reconnect: Option<ReconnectHook>,
+    /// runs after every successful connection
+    after_connect: Option<ReconnectHook>,
+    /// refuse to start a second eventloop with the same client id and broker
+    duplicate_connect_protection: bool,
     /// security options
     security: SecurityOptions,
     /// maximum packet size
     max_packet_size: usize,
     /// last will and testament
     last_will: Option<LastWill>,
+    /// binary message of the last will
+    last_will_bytes: Option<Vec<u8>>,
     /// request (publish, subscribe) channel capacity
     request_channel_capacity: usize,
+    /// limits of publishes buffered while disconnected
+    offline_buffer: Option<(usize, usize)>,
     /// notification channel capacity
     notification_channel_capacity: usize,
     /// rate limit for outgoing messages (no. of messages per second)
     outgoing_ratelimit: Option<u64>,
     /// rate limit applied after queue size limit (size, sleep time after every message)
-    outgoing_queuelimit: (usize, Duration)
+    outgoing_queuelimit: (usize, Duration),
+    /// store for incoming messages which are not yet handed over to the user
+    store: Option<StoreHandle>,
+    /// acknowledge incoming publishes only when the user acks them
+    manual_acks: bool,
+    /// route for rejected incoming publishes
+    dead_letter: DeadLetter,
+    /// message handler retries (attempts, backoff before first retry)
+    handler_retry: (u32, Duration),
+    /// incoming payloads above this size (bytes) are written to this sink
+    payload_spill: Option<(usize, PayloadSinkHandle)>,
+    /// threshold and directory for holding back incoming publishes when the notification channel is full
+    incoming_spill: Option<(usize, PathBuf)>,
+    /// outgoing payloads of at least this size (bytes) are compressed
+    compression: Option<usize>,
+    /// payload validators per topic filter
+    validators: Validators,
+    /// payload transformers per topic prefix
+    transformers: Transformers,
+    /// qos and retain flag of publishes which don't specify them
+    publish_profiles: PublishProfiles,
+    /// connection attempt rate limit shared with other clients
+    reconnect_limiter: Option<ReconnectLimiter>,
+    /// payload sampling rules for observability
+    sampling: Sampling,
+    /// sequence number extractors per topic filter
+    sequence_tracking: SequenceTracking,
+    /// message and byte counters per topic filter
+    topic_stats: TopicStats,
+    /// connection state transitions are sent here
+    state_tx: Option<Sender<StateChange>>,
+    /// maximum keep alive accepted by the broker
+    broker_keep_alive_limit: Option<Duration>,
+    /// behaviour of publishes when all the packet ids are in flight
+    pkid_exhaustion: PkidExhaustion,
+    /// limit and overflow behaviour of publishes waiting for an inflight slot
+    outgoing_queue: Option<(usize, QueuePolicy)>,
+    /// overflow behaviour of a full notification channel
+    incoming_queue_policy: QueuePolicy,
+    /// packets processed by the eventloop before it yields to timers and other streams
+    max_packets_per_turn: usize,
+    /// when incoming qos 2 publishes are delivered
+    qos2_delivery: Qos2Delivery,
+    /// features supported by the broker
+    broker_capabilities: BrokerCapabilities,
+    /// guardrails against broad subscriptions
+    subscription_guardrails: Option<SubscriptionGuardrails>,
+    /// transmission windows of low power mode
+    power_saving: Option<PowerSaving>,
+    /// periodic retained presence updates
+    presence: Option<Presence>,
+    /// time after which unacknowledged publishes are resent
+    retry_interval: Option<Duration>,
+    /// local policy on incoming publishes
+    incoming_authorizer: Option<Authorization>,
+    /// session exported by another client
+    session: Option<Session>,
+    /// resolver of broker and proxy hosts
+    resolver: Option<Resolution>,
+    /// client certificate and signer of a key in a hardware token
+    #[cfg(feature = "rustls")]
+    client_auth_signer: Option<(Vec<u8>, KeySigning)>,
+    /// maximum unacknowledged qos 1 and 2 publishes
+    max_inflight: Option<usize>,
+    /// timestamping hooks of publishes
+    probe: Option<ProbeHandle>,
+    /// detection of other clients taking over the client id
+    takeover_detection: Option<TakeoverDetection>,
+    /// time to set up the tcp (and tls) connection
+    connect_timeout: Duration,
+    /// time to wait for connack after connect is sent
+    connack_timeout: Duration,
+    /// packet id allocation
+    pkid_allocator: PkidAllocatorHandle,
+    /// name of the eventloop thread
+    thread_name: String,
+    /// runs on the eventloop thread before it connects
+    thread_hook: Option<ThreadHook>,
 }
 
 impl Default for MqttOptions {
@@ -93,13 +677,59 @@ impl Default for MqttOptions {
             connection_method: ConnectionMethod::Tcp,
             proxy: Proxy::None,
             reconnect: ReconnectOptions::AfterFirstSuccess(10),
+            reconnect_policy: None,
+            max_reconnect_attempts: None,
+            before_reconnect: None,
+            after_connect: None,
+            duplicate_connect_protection: false,
             security: SecurityOptions::None,
             max_packet_size: 256 * 1024,
             last_will: None,
+            last_will_bytes: None,
             request_channel_capacity: 10,
+            offline_buffer: None,
             notification_channel_capacity: 10,
             outgoing_ratelimit: None,
             outgoing_queuelimit: (100, Duration::from_secs(3)),
+            store: None,
+            manual_acks: false,
+            dead_letter: DeadLetter::Drop,
+            handler_retry: (1, Duration::from_secs(0)),
+            payload_spill: None,
+            incoming_spill: None,
+            compression: None,
+            validators: Validators::default(),
+            transformers: Transformers::default(),
+            publish_profiles: PublishProfiles::default(),
+            reconnect_limiter: None,
+            sampling: Sampling::default(),
+            sequence_tracking: SequenceTracking::default(),
+            topic_stats: TopicStats::default(),
+            state_tx: None,
+            broker_keep_alive_limit: None,
+            pkid_exhaustion: PkidExhaustion::Block,
+            outgoing_queue: None,
+            incoming_queue_policy: QueuePolicy::DropNewest,
+            max_packets_per_turn: 100,
+            qos2_delivery: Qos2Delivery::OnPubrel,
+            broker_capabilities: BrokerCapabilities::default(),
+            subscription_guardrails: None,
+            power_saving: None,
+            presence: None,
+            retry_interval: None,
+            incoming_authorizer: None,
+            session: None,
+            resolver: None,
+            #[cfg(feature = "rustls")]
+            client_auth_signer: None,
+            max_inflight: None,
+            probe: None,
+            takeover_detection: None,
+            connect_timeout: Duration::from_secs(30),
+            connack_timeout: Duration::from_secs(30),
+            pkid_allocator: PkidAllocatorHandle::default(),
+            thread_name: "rumqtt-network".to_owned(),
+            thread_hook: None,
         }
     }
 }
@@ -122,13 +752,59 @@ impl MqttOptions {
             connection_method: ConnectionMethod::Tcp,
             proxy: Proxy::None,
             reconnect: ReconnectOptions::AfterFirstSuccess(10),
+            reconnect_policy: None,
+            max_reconnect_attempts: None,
+            before_reconnect: None,
+            after_connect: None,
+            duplicate_connect_protection: false,
             security: SecurityOptions::None,
             max_packet_size: 256 * 1024,
             last_will: None,
+            last_will_bytes: None,
             request_channel_capacity: 10,
+            offline_buffer: None,
             notification_channel_capacity: 10,
             outgoing_ratelimit: None,
             outgoing_queuelimit: (100, Duration::from_secs(3)),
+            store: None,
+            manual_acks: false,
+            dead_letter: DeadLetter::Drop,
+            handler_retry: (1, Duration::from_secs(0)),
+            payload_spill: None,
+            incoming_spill: None,
+            compression: None,
+            validators: Validators::default(),
+            transformers: Transformers::default(),
+            publish_profiles: PublishProfiles::default(),
+            reconnect_limiter: None,
+            sampling: Sampling::default(),
+            sequence_tracking: SequenceTracking::default(),
+            topic_stats: TopicStats::default(),
+            state_tx: None,
+            broker_keep_alive_limit: None,
+            pkid_exhaustion: PkidExhaustion::Block,
+            outgoing_queue: None,
+            incoming_queue_policy: QueuePolicy::DropNewest,
+            max_packets_per_turn: 100,
+            qos2_delivery: Qos2Delivery::OnPubrel,
+            broker_capabilities: BrokerCapabilities::default(),
+            subscription_guardrails: None,
+            power_saving: None,
+            presence: None,
+            retry_interval: None,
+            incoming_authorizer: None,
+            session: None,
+            resolver: None,
+            #[cfg(feature = "rustls")]
+            client_auth_signer: None,
+            max_inflight: None,
+            probe: None,
+            takeover_detection: None,
+            connect_timeout: Duration::from_secs(30),
+            connack_timeout: Duration::from_secs(30),
+            pkid_allocator: PkidAllocatorHandle::default(),
+            thread_name: "rumqtt-network".to_owned(),
+            thread_hook: None,
         }
     }
 
@@ -140,11 +816,7 @@ impl MqttOptions {
     /// Set number of seconds after which client should ping the broker
     /// if there is no other data exchange
     pub fn set_keep_alive(mut self, secs: u16) -> Self {
-        if secs < 10 {
-            panic!("Keep alives should be >= 10 secs");
-        }
-
-        self.keep_alive = Duration::from_secs(u64::from(secs));
+        self.keep_alive = validate_keep_alive(Duration::from_secs(u64::from(secs)));
         self
     }
 
@@ -158,7 +830,9 @@ impl MqttOptions {
         self.client_id.clone()
     }
 
-    /// Set packet size limit (in Kilo Bytes)
+    /// Set packet size limit (in Kilo Bytes). Limits the payload of outgoing
+    /// publishes and the size of incoming packets. Bigger incoming packets fail
+    /// the connection before they are buffered
     pub fn set_max_packet_size(mut self, sz: usize) -> Self {
         self.max_packet_size = sz * 1024;
         self
@@ -216,6 +890,113 @@ impl MqttOptions {
         self.reconnect
     }
 
+    /// Set a policy which decides if and when to connect again, e.g to give up
+    /// after a number of attempts. Overrides the [reconnect options]. The client
+    /// is returned even if the first connection fails, like `ReconnectOptions::Always`
+    ///
+    /// [reconnect options]: struct.MqttOptions.html#method.set_reconnect_opts
+    pub fn set_reconnect_policy<P: ReconnectPolicy + 'static>(mut self, policy: P) -> Self {
+        self.reconnect_policy = Some(ReconnectPolicyHandle::new(policy));
+        self
+    }
+
+    /// User defined reconnect policy
+    pub fn reconnect_policy(&self) -> Option<ReconnectPolicyHandle> {
+        self.reconnect_policy.clone()
+    }
+
+    /// Stops the eventloop after this many reconnection attempts fail in a row
+    /// and sends [Notification::ReconnectExhausted] as the last notification.
+    /// Lets a supervisor restart the process instead of the client retrying
+    /// forever. Applies to the reconnect options and the reconnect policy
+    ///
+    /// [Notification::ReconnectExhausted]: ../client/enum.Notification.html#variant.ReconnectExhausted
+    pub fn set_max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// Failed reconnection attempts in a row before giving up
+    pub fn max_reconnect_attempts(&self) -> Option<u32> {
+        self.max_reconnect_attempts
+    }
+
+    /// Set a hook which runs right before every reconnection attempt (after the
+    /// reconnect delay), e.g to power cycle a modem or refresh a vpn. Doesn't run
+    /// before the first connection attempt
+    pub fn set_before_reconnect<F: Fn(&Attempt) + Send + Sync + 'static>(mut self, hook: F) -> Self {
+        self.before_reconnect = Some(ReconnectHook::new(hook));
+        self
+    }
+
+    /// Hook which runs before every reconnection attempt
+    pub fn before_reconnect(&self) -> Option<ReconnectHook> {
+        self.before_reconnect.clone()
+    }
+
+    /// Set a hook which runs right after every successful handshake with the
+    /// attempt which succeeded, e.g to publish a resync request. Requests made
+    /// from the hook go out once it returns. Don't wait on them in the hook
+    pub fn set_after_connect<F: Fn(&Attempt) + Send + Sync + 'static>(mut self, hook: F) -> Self {
+        self.after_connect = Some(ReconnectHook::new(hook));
+        self
+    }
+
+    /// Hook which runs after every successful connection
+    pub fn after_connect(&self) -> Option<ReconnectHook> {
+        self.after_connect.clone()
+    }
+
+    /// Serializes connections of this client id to this broker within the
+    /// process. Starting a client while another eventloop with the same id is
+    /// connecting or connected fails with [ConnectError::AlreadyConnecting]
+    /// instead of two handshakes racing and the broker kicking one of them out.
+    /// The id is released when the eventloop stops
+    ///
+    /// [ConnectError::AlreadyConnecting]: ../error/enum.ConnectError.html#variant.AlreadyConnecting
+    pub fn set_duplicate_connect_protection(mut self, enable: bool) -> Self {
+        self.duplicate_connect_protection = enable;
+        self
+    }
+
+    /// Duplicate connect protection
+    pub fn duplicate_connect_protection(&self) -> bool {
+        self.duplicate_connect_protection
+    }
+
+    /// Set the time to set up the tcp (and tls) connection with the broker. Fails
+    /// the attempt with `ConnectError::Timeout`. Defaults to 30 seconds
+    pub fn set_connect_timeout(mut self, timeout: Duration) -> Self {
+        if timeout.as_nanos() == 0 {
+            panic!("Connect timeout should be non zero");
+        }
+
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Timeout of the tcp (and tls) connection
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Set the time to wait for connack once the connection is up. Brokers can accept
+    /// connections and stall while their auth backend is down. Fails the attempt with
+    /// `ConnectError::ConnackTimeout`. Defaults to 30 seconds
+    pub fn set_connack_timeout(mut self, timeout: Duration) -> Self {
+        if timeout.as_nanos() == 0 {
+            panic!("Connack timeout should be non zero");
+        }
+
+        self.connack_timeout = timeout;
+        self
+    }
+
+    /// Timeout of connack
+    pub fn connack_timeout(&self) -> Duration {
+        self.connack_timeout
+    }
+
     /// Set security option
     /// Supports username-password auth, tls client cert auth, gcloud iotcore jwt auth
     pub fn set_security_opts(mut self, opts: SecurityOptions) -> Self {
@@ -231,6 +1012,20 @@ impl MqttOptions {
     /// Set last will and testament
     pub fn set_last_will(mut self, last_will: LastWill) -> Self {
         self.last_will = Some(last_will);
+        self.last_will_bytes = None;
+        self
+    }
+
+    /// Set last will and testament with a binary message. `LastWill` can only
+    /// carry utf-8 strings. Its message is left empty
+    pub fn set_last_will_bytes<S: Into<String>>(mut self, topic: S, message: Vec<u8>, qos: QoS, retain: bool) -> Self {
+        self.last_will = Some(LastWill {
+            topic: topic.into(),
+            message: String::new(),
+            qos,
+            retain,
+        });
+        self.last_will_bytes = Some(message);
         self
     }
 
@@ -239,6 +1034,11 @@ impl MqttOptions {
         self.last_will.clone()
     }
 
+    /// Binary message of a last will set with `set_last_will_bytes`
+    pub fn last_will_bytes(&self) -> Option<Vec<u8>> {
+        self.last_will_bytes.clone()
+    }
+
     /// Set notification channel capacity
     pub fn set_notification_channel_capacity(mut self, capacity: usize) -> Self {
         self.notification_channel_capacity = capacity;
@@ -260,6 +1060,22 @@ impl MqttOptions {
     pub fn request_channel_capacity(&self) -> usize {
         self.request_channel_capacity
     }
+
+    /// Buffers publishes made while disconnected, up to 'messages' publishes and
+    /// 'bytes' of payload, and sends them after the next connack. Publishes beyond
+    /// the limits fail with [ClientError::OfflineBufferFull] instead of blocking
+    /// on the request channel. Connection scoped publishes aren't buffered
+    ///
+    /// [ClientError::OfflineBufferFull]: ../error/enum.ClientError.html#variant.OfflineBufferFull
+    pub fn set_offline_buffer(mut self, messages: usize, bytes: usize) -> Self {
+        self.offline_buffer = Some((messages, bytes));
+        self
+    }
+
+    /// Message and byte limits of the offline buffer
+    pub fn offline_buffer(&self) -> Option<(usize, usize)> {
+        self.offline_buffer
+    }
     
     /// Enables throttling and sets outoing message rate to the specified 'rate'
     pub fn set_outgoing_ratelimit(mut self, rate: u64) -> Self {
@@ -291,11 +1107,628 @@ impl MqttOptions {
     pub fn outgoing_queuelimit(&self) -> (usize, Duration) {
         self.outgoing_queuelimit
     }
+
+    /// Enables persistence of incoming QoS 1/2 messages. Messages are saved before
+    /// they are acknowledged to the broker and removed once they are handed over
+    /// to the notification channel. Messages left in the store by a previous run
+    /// are redelivered after the first successful connection. With clean session
+    /// off, outgoing QoS 1/2 messages are saved too and the ones the broker didn't
+    /// acknowledge are resent by the next run. See [FileStore]
+    ///
+    /// [FileStore]: ../persistence/struct.FileStore.html
+    pub fn set_store<S: Store + 'static>(mut self, store: S) -> Self {
+        self.store = Some(StoreHandle::new(store));
+        self
+    }
+
+    /// Persistence store
+    pub fn store(&self) -> Option<StoreHandle> {
+        self.store.clone()
+    }
+
+    /// When set, incoming QoS 1/2 publishes are PUBACK'd/PUBCOMP'd only after the
+    /// user acks them with [MqttClient::ack]. Persisted publishes are removed from
+    /// the store on ack instead of when they are handed over to the notification channel
+    ///
+    /// [MqttClient::ack]: ../client/struct.MqttClient.html#method.ack
+    pub fn set_manual_acks(mut self, manual_acks: bool) -> Self {
+        self.manual_acks = manual_acks;
+        self
+    }
+
+    /// Manual acks
+    pub fn manual_acks(&self) -> bool {
+        self.manual_acks
+    }
+
+    /// Set where incoming publishes rejected with [MqttClient::nack] are routed
+    ///
+    /// [MqttClient::nack]: ../client/struct.MqttClient.html#method.nack
+    pub fn set_dead_letter(mut self, dead_letter: DeadLetter) -> Self {
+        self.dead_letter = dead_letter;
+        self
+    }
+
+    /// Dead letter route
+    pub fn dead_letter(&self) -> DeadLetter {
+        self.dead_letter.clone()
+    }
+
+    /// Number of times [MqttClient::handle] runs a failing handler before the message
+    /// is dead lettered. 'backoff' is the sleep before the first retry and doubles
+    /// after every retry
+    ///
+    /// [MqttClient::handle]: ../client/struct.MqttClient.html#method.handle
+    pub fn set_handler_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        if attempts == 0 {
+            panic!("zero handler attempts are not allowed");
+        }
+
+        self.handler_retry = (attempts, backoff);
+        self
+    }
+
+    /// Message handler retries
+    pub fn handler_retry(&self) -> (u32, Duration) {
+        self.handler_retry
+    }
+
+    /// Incoming payloads larger than 'threshold' bytes are written to a new file in
+    /// 'dir' and delivered as [Notification::PublishFile] instead of an in memory
+    /// publish. Keeps big payloads out of memory while they wait for a slow user.
+    ///
+    /// Payloads aren't streamed to the file. The whole publish is decoded into
+    /// memory first and released once it's written, so peak memory still goes up
+    /// to the [max packet size] per publish.
+    ///
+    /// Files are written on the eventloop thread, which blocks network io and
+    /// pings while a big payload is written to a slow disk
+    ///
+    /// [max packet size]: struct.MqttOptions.html#method.set_max_packet_size
+    ///
+    /// [Notification::PublishFile]: ../client/enum.Notification.html#variant.PublishFile
+    pub fn set_payload_spill<P: AsRef<Path>>(mut self, threshold: usize, dir: P) -> Self {
+        self.payload_spill = Some((threshold, PayloadSinkHandle::new(DirSink(dir.as_ref().to_path_buf()))));
+        self
+    }
+
+    /// Same as [payload spill] with payloads written to the writers of a user
+    /// supplied [sink]. The sink runs on the eventloop thread and should not
+    /// block for long
+    ///
+    /// [payload spill]: struct.MqttOptions.html#method.set_payload_spill
+    /// [sink]: trait.PayloadSink.html
+    pub fn set_payload_sink<S: PayloadSink + 'static>(mut self, threshold: usize, sink: S) -> Self {
+        self.payload_spill = Some((threshold, PayloadSinkHandle::new(sink)));
+        self
+    }
+
+    /// Payload spill threshold and sink
+    pub fn payload_spill(&self) -> Option<(usize, PayloadSinkHandle)> {
+        self.payload_spill.clone()
+    }
+
+    /// Holds back incoming publishes which don't fit in a full notification channel
+    /// instead of dropping them. Up to 'threshold' payload bytes are held in memory
+    /// and the rest in a temporary file in 'dir'. They are delivered in order as the
+    /// channel frees up. Lets a consumer stall for a while without losing qos 0
+    /// publishes. Held back publishes don't survive a restart
+    pub fn set_incoming_spill<P: AsRef<Path>>(mut self, threshold: usize, dir: P) -> Self {
+        self.incoming_spill = Some((threshold, dir.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Incoming spill threshold and directory
+    pub fn incoming_spill(&self) -> Option<(usize, PathBuf)> {
+        self.incoming_spill.clone()
+    }
+
+    /// Compresses outgoing payloads of at least 'threshold' bytes when that makes
+    /// them smaller. Compressed payloads are [marked] and decompressed by the
+    /// receiving clients with this feature. Marked incoming payloads are always
+    /// decompressed, so only enable this once all the receivers are updated
+    ///
+    /// [marked]: ../compression/index.html
+    #[cfg(feature = "compression")]
+    pub fn set_compression(mut self, threshold: usize) -> Self {
+        self.compression = Some(threshold);
+        self
+    }
+
+    /// Compression threshold of outgoing payloads
+    pub fn compression(&self) -> Option<usize> {
+        self.compression
+    }
+
+    /// Adds a payload validator for topics matching the filter. Outgoing publishes
+    /// failing validation are rejected with [ClientError::InvalidPayload] and incoming
+    /// ones are delivered as [Notification::Invalid]
+    ///
+    /// [ClientError::InvalidPayload]: ../error/enum.ClientError.html#variant.InvalidPayload
+    /// [Notification::Invalid]: ../client/enum.Notification.html#variant.Invalid
+    pub fn add_validator<S: Into<String>, V: Validator + 'static>(mut self, filter: S, validator: V) -> Self {
+        self.validators.add(filter.into(), validator);
+        self
+    }
+
+    /// Payload validators
+    pub fn validators(&self) -> Validators {
+        self.validators.clone()
+    }
+
+    /// Adds a payload transformer for topics starting with the prefix. Outgoing
+    /// payloads are encoded by the matching transformers in the order they are
+    /// added, after validation and compression. Incoming payloads are decoded in
+    /// the reverse order before decompression and validation. Failures are reported
+    /// as [ClientError::Transform] and [Notification::Invalid] respectively
+    ///
+    /// [ClientError::Transform]: ../error/enum.ClientError.html#variant.Transform
+    /// [Notification::Invalid]: ../client/enum.Notification.html#variant.Invalid
+    pub fn add_transformer<S: Into<String>, T: Transformer + 'static>(mut self, prefix: S, transformer: T) -> Self {
+        self.transformers.add(prefix.into(), transformer);
+        self
+    }
+
+    /// Payload transformers
+    pub fn transformers(&self) -> Transformers {
+        self.transformers.clone()
+    }
+
+    /// Set qos and retain flag of publishes sent with [publish_default]. Publishes
+    /// with explicit qos and retain flag aren't affected
+    ///
+    /// [publish_default]: ../client/struct.MqttClient.html#method.publish_default
+    pub fn set_publish_defaults(mut self, qos: QoS, retain: bool) -> Self {
+        self.publish_profiles.defaults = (qos, retain);
+        self
+    }
+
+    /// Default qos and retain flag of publishes
+    pub fn publish_defaults(&self) -> (QoS, bool) {
+        self.publish_profiles.defaults
+    }
+
+    /// Set qos and retain flag of [publish_default] publishes on topics matching the
+    /// filter. E.g `cmd/#` with QoS 1 and `telemetry/#` with QoS 0. Profiles are
+    /// matched in the order they are added
+    ///
+    /// [publish_default]: ../client/struct.MqttClient.html#method.publish_default
+    pub fn add_publish_profile<S: Into<String>>(mut self, filter: S, qos: QoS, retain: bool) -> Self {
+        self.publish_profiles.profiles.push((filter.into(), qos, retain));
+        self
+    }
+
+    /// Applies an option changed on a running client
+    pub fn reconfigure(self, reconfigure: Reconfigure) -> Self {
+        match reconfigure {
+            Reconfigure::KeepAlive(secs) => self.set_keep_alive(secs),
+            Reconfigure::Reconnect(opts) => self.set_reconnect_opts(opts),
+            Reconfigure::OutgoingRateLimit(rate) => self.set_outgoing_ratelimit(rate),
+            Reconfigure::OutgoingQueueLimit(queue_size, delay) => self.set_outgoing_queuelimit(queue_size, delay),
+        }
+    }
+
+    /// Publish defaults along with per topic filter profiles
+    pub fn publish_profiles(&self) -> PublishProfiles {
+        self.publish_profiles.clone()
+    }
+
+    /// Set a connection attempt rate limiter shared with other clients of this process
+    pub fn set_reconnect_limiter(mut self, limiter: ReconnectLimiter) -> Self {
+        self.reconnect_limiter = Some(limiter);
+        self
+    }
+
+    /// Shared connection attempt rate limiter
+    pub fn reconnect_limiter(&self) -> Option<ReconnectLimiter> {
+        self.reconnect_limiter.clone()
+    }
+
+    /// Samples every 'every'th incoming and outgoing publish on topics matching the
+    /// filter with the first 'preview_len' bytes of the payload. Samples are logged
+    /// unless a [sample channel] is set
+    ///
+    /// [sample channel]: struct.MqttOptions.html#method.set_sample_channel
+    pub fn add_payload_sampling<S: Into<String>>(mut self, filter: S, every: usize, preview_len: usize) -> Self {
+        self.sampling.add_rule(filter.into(), every, preview_len);
+        self
+    }
+
+    /// Sends payload samples on this channel instead of the log. Samples are
+    /// dropped when the channel is full
+    pub fn set_sample_channel(mut self, tx: Sender<Sample>) -> Self {
+        self.sampling.set_channel(tx);
+        self
+    }
+
+    /// Payload sampling rules
+    pub fn sampling(&self) -> Sampling {
+        self.sampling.clone()
+    }
+
+    /// Tracks sequence numbers which the extractor reads from incoming payloads on
+    /// topics matching the filter. Skipped sequence numbers on a topic are
+    /// notified with [Notification::SequenceGap]
+    ///
+    /// [Notification::SequenceGap]: ../client/enum.Notification.html#variant.SequenceGap
+    pub fn add_sequence_tracking<S: Into<String>, E: SequenceExtractor + 'static>(mut self, filter: S, extractor: E) -> Self {
+        self.sequence_tracking.add(filter.into(), extractor);
+        self
+    }
+
+    /// Sequence number extractors
+    pub fn sequence_tracking(&self) -> SequenceTracking {
+        self.sequence_tracking.clone()
+    }
+
+    /// Counts incoming and outgoing publishes and their payload bytes on topics
+    /// matching the filter. See [topic_stats]. Clients started with clones of
+    /// these options share the counters
+    ///
+    /// [topic_stats]: ../client/struct.MqttClient.html#method.topic_stats
+    pub fn add_topic_stats<S: Into<String>>(self, filter: S) -> Self {
+        self.topic_stats.add_filter(filter.into());
+        self
+    }
+
+    /// Counters per topic filter
+    pub fn topic_stats(&self) -> TopicStats {
+        self.topic_stats.clone()
+    }
+
+    /// Sends connection state transitions on this channel. Lets applications pause
+    /// producers while the link is down. Transitions are dropped when the channel
+    /// is full
+    pub fn set_state_channel(mut self, tx: Sender<StateChange>) -> Self {
+        self.state_tx = Some(tx);
+        self
+    }
+
+    /// Channel for connection state transitions
+    pub fn state_channel(&self) -> Option<Sender<StateChange>> {
+        self.state_tx.clone()
+    }
+
+    /// Set the maximum keep alive (in seconds) the broker accepts. Brokers disconnect
+    /// clients with a keep alive above their limit or ping less often than needed.
+    /// Connections use the smaller of this and the configured keep alive
+    pub fn set_broker_keep_alive_limit(mut self, secs: u16) -> Self {
+        if secs == 0 {
+            panic!("zero keep alive limit is not allowed");
+        }
+
+        self.broker_keep_alive_limit = Some(Duration::from_secs(u64::from(secs)));
+        self
+    }
+
+    /// Maximum keep alive accepted by the broker
+    pub fn broker_keep_alive_limit(&self) -> Option<Duration> {
+        self.broker_keep_alive_limit
+    }
+
+    /// Set the allocator of packet ids. Sub clients sharing a session can use a
+    /// partition of the id space each. Defaults to sequential ids.
+    ///
+    /// Publishes beyond the capacity of a partition are queued in the eventloop
+    /// irrespective of the [packet id exhaustion] behaviour
+    ///
+    /// [packet id exhaustion]: struct.MqttOptions.html#method.set_pkid_exhaustion
+    pub fn set_pkid_allocator<A: PkidAllocator + 'static>(mut self, allocator: A) -> Self {
+        self.pkid_allocator = PkidAllocatorHandle::new(allocator);
+        self
+    }
+
+    /// Packet id allocator
+    pub fn pkid_allocator(&self) -> PkidAllocatorHandle {
+        self.pkid_allocator.clone()
+    }
+
+    /// Set the name of the eventloop thread as shown by debuggers and `top`. Defaults
+    /// to `rumqtt-network`. Linux shows only the first 15 bytes
+    pub fn set_thread_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Name of the eventloop thread
+    pub fn thread_name(&self) -> String {
+        self.thread_name.clone()
+    }
+
+    /// Set a hook which runs on the eventloop thread before it connects. Use it
+    /// to set the priority or cpu affinity of the thread with the platform apis
+    pub fn set_thread_hook<F: Fn() + Send + Sync + 'static>(mut self, hook: F) -> Self {
+        self.thread_hook = Some(ThreadHook(Arc::new(hook)));
+        self
+    }
+
+    /// Hook which runs on the eventloop thread before it connects
+    pub fn thread_hook(&self) -> Option<ThreadHook> {
+        self.thread_hook.clone()
+    }
+
+    /// Set what qos 1 and 2 publishes, subscribes and unsubscribes do when all the
+    /// packet ids are in use. Defaults to blocking the caller
+    pub fn set_pkid_exhaustion(mut self, pkid_exhaustion: PkidExhaustion) -> Self {
+        self.pkid_exhaustion = pkid_exhaustion;
+        self
+    }
+
+    /// Behaviour on packet id exhaustion
+    pub fn pkid_exhaustion(&self) -> PkidExhaustion {
+        self.pkid_exhaustion
+    }
+
+    /// Limits the qos 1 and 2 publishes which the eventloop holds back while max
+    /// inflight publishes are unacknowledged (or packet ids are exhausted). The
+    /// queue is unbounded by default. With `QueuePolicy::Block`, the eventloop
+    /// stops taking requests till the broker acks a publish, which blocks
+    /// publishers once the request channel is full
+    pub fn set_outgoing_queue_policy(mut self, limit: usize, policy: QueuePolicy) -> Self {
+        self.outgoing_queue = Some((limit, policy));
+        self
+    }
+
+    /// Outgoing queue limit and overflow behaviour
+    pub fn outgoing_queue_policy(&self) -> Option<(usize, QueuePolicy)> {
+        self.outgoing_queue
+    }
+
+    /// Set what incoming publishes do when the notification channel is full.
+    /// Defaults to dropping them. `QueuePolicy::DropOldest` holds back up to
+    /// channel capacity publishes and drops the oldest of them. Ignored with an
+    /// [incoming spill], which holds back everything
+    ///
+    /// [incoming spill]: struct.MqttOptions.html#method.set_incoming_spill
+    pub fn set_incoming_queue_policy(mut self, policy: QueuePolicy) -> Self {
+        self.incoming_queue_policy = policy;
+        self
+    }
+
+    /// Incoming overflow behaviour
+    pub fn incoming_queue_policy(&self) -> QueuePolicy {
+        self.incoming_queue_policy
+    }
+
+    /// Set the number of packets the eventloop processes in a row before it yields.
+    /// Lower values keep pings and user requests timely under a flood of incoming
+    /// publishes at the cost of more wake ups
+    pub fn set_max_packets_per_turn(mut self, max: usize) -> Self {
+        if max == 0 {
+            panic!("zero packets per turn are not allowed");
+        }
+
+        self.max_packets_per_turn = max;
+        self
+    }
+
+    /// Packets processed by the eventloop before it yields
+    pub fn max_packets_per_turn(&self) -> usize {
+        self.max_packets_per_turn
+    }
+
+    /// Set when incoming qos 2 publishes are delivered. Defaults to delivering on pubrel
+    pub fn set_qos2_delivery(mut self, qos2_delivery: Qos2Delivery) -> Self {
+        self.qos2_delivery = qos2_delivery;
+        self
+    }
+
+    /// Incoming qos 2 delivery method
+    pub fn qos2_delivery(&self) -> Qos2Delivery {
+        self.qos2_delivery
+    }
+
+    /// Set the features supported by the broker. Defaults to everything
+    pub fn set_broker_capabilities(mut self, capabilities: BrokerCapabilities) -> Self {
+        self.broker_capabilities = capabilities;
+        self
+    }
+
+    /// Features supported by the broker
+    pub fn broker_capabilities(&self) -> BrokerCapabilities {
+        self.broker_capabilities
+    }
+
+    /// Set guardrails which reject broad wildcard subscriptions. Disabled by default
+    pub fn set_subscription_guardrails(mut self, guardrails: SubscriptionGuardrails) -> Self {
+        self.subscription_guardrails = Some(guardrails);
+        self
+    }
+
+    /// Guardrails against broad subscriptions
+    pub fn subscription_guardrails(&self) -> Option<SubscriptionGuardrails> {
+        self.subscription_guardrails.clone()
+    }
+
+    /// Set low power mode. Replaces keep alive with the power saving keep alive,
+    /// which should be >= 10 secs like the regular one. Window should be shorter
+    /// than the interval
+    pub fn set_power_saving(mut self, power_saving: PowerSaving) -> Self {
+        if power_saving.window.as_nanos() == 0 || power_saving.window >= power_saving.interval {
+            panic!("Power saving window should be non zero and shorter than the interval");
+        }
+
+        self.keep_alive = validate_keep_alive(power_saving.keep_alive);
+        self.power_saving = Some(power_saving);
+        self
+    }
+
+    /// Low power mode transmission windows
+    pub fn power_saving(&self) -> Option<PowerSaving> {
+        self.power_saving
+    }
+
+    /// Set periodic retained presence updates while connected. They count as
+    /// outgoing activity, so an interval below keep alive replaces pings
+    pub fn set_presence(mut self, presence: Presence) -> Self {
+        if presence.interval.as_nanos() == 0 {
+            panic!("Presence interval should be non zero");
+        }
+
+        self.presence = Some(presence);
+        self
+    }
+
+    /// Periodic presence updates
+    pub fn presence(&self) -> Option<Presence> {
+        self.presence.clone()
+    }
+
+    /// Set the maximum number of qos 1 and 2 publishes which are sent but not
+    /// acknowledged yet (puback or pubcomp). Further publishes queue in the
+    /// eventloop till the broker acks. By default all the packet ids can be in flight
+    pub fn set_max_inflight(mut self, max_inflight: usize) -> Self {
+        if max_inflight == 0 {
+            panic!("Max inflight should be non zero");
+        }
+
+        self.max_inflight = Some(max_inflight);
+        self
+    }
+
+    /// Maximum unacknowledged qos 1 and 2 publishes
+    pub fn max_inflight(&self) -> Option<usize> {
+        self.max_inflight
+    }
+
+    /// Set the time after which qos 1 and 2 publishes which aren't acknowledged are
+    /// resent with the dup flag on the same connection. Pubrels waiting for pubcomp
+    /// are resent as well. By default they are only resent after reconnection
+    pub fn set_retry_interval(mut self, interval: Duration) -> Self {
+        if interval.as_nanos() == 0 {
+            panic!("Retry interval should be non zero");
+        }
+
+        self.retry_interval = Some(interval);
+        self
+    }
+
+    /// Retransmission interval of unacknowledged publishes and pubrels
+    pub fn retry_interval(&self) -> Option<Duration> {
+        self.retry_interval
+    }
+
+    /// Set a policy which is consulted with the topic, payload size and qos of
+    /// every incoming publish before it's delivered. Rejected publishes are
+    /// dropped but still acknowledged so that the broker doesn't resend them
+    pub fn set_incoming_authorizer<A: Authorizer + 'static>(mut self, authorizer: A) -> Self {
+        self.incoming_authorizer = Some(Authorization::new(authorizer));
+        self
+    }
+
+    /// Policy on incoming publishes
+    pub fn incoming_authorizer(&self) -> Option<Authorization> {
+        self.incoming_authorizer.clone()
+    }
+
+    /// Set a session exported by another client with [MqttClient::export_session].
+    /// Messages in flight are resent and subscriptions are renewed on connection.
+    /// Use the client id of the exporting client and disable clean session to keep
+    /// the qos guarantees with the broker
+    ///
+    /// [MqttClient::export_session]: ../client/struct.MqttClient.html#method.export_session
+    pub fn set_session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Set a resolver for the broker and proxy hosts. System resolver is used by default
+    pub fn set_resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Some(Resolution::new(resolver));
+        self
+    }
+
+    /// Resolver of the broker and proxy hosts
+    pub fn resolver(&self) -> Option<Resolution> {
+        self.resolver.clone()
+    }
+
+    /// Set tls client authentication with a key which can't be exported from its
+    /// PKCS#11 token or TPM. 'cert' is the pem certificate chain of the key and the
+    /// signer signs the handshake with it. Takes precedence over the client key of
+    /// [ConnectionMethod::Tls]
+    ///
+    /// Only available with the `rustls` feature. native-tls has no hook for
+    /// signing with an external key
+    ///
+    /// [ConnectionMethod::Tls]: enum.ConnectionMethod.html#variant.Tls
+    #[cfg(feature = "rustls")]
+    pub fn set_client_auth_signer<S: KeySigner + 'static>(mut self, cert: Vec<u8>, signer: S) -> Self {
+        self.client_auth_signer = Some((cert, KeySigning::new(signer)));
+        self
+    }
+
+    /// Client certificate and signer of a key in a hardware token
+    #[cfg(feature = "rustls")]
+    pub fn client_auth_signer(&self) -> Option<(Vec<u8>, KeySigning)> {
+        self.client_auth_signer.clone()
+    }
+
+    /// Set hooks which are called with the time a publish is queued, written for the
+    /// network and acknowledged. See the `bench` feature for ready made measurements
+    pub fn set_probe<P: Probe + 'static>(mut self, probe: P) -> Self {
+        self.probe = Some(ProbeHandle::new(probe));
+        self
+    }
+
+    /// Timestamping hooks of publishes
+    pub fn probe(&self) -> Option<ProbeHandle> {
+        self.probe.clone()
+    }
+
+    /// Set detection of another client connecting with the same client id. A
+    /// takeover is notified with `Notification::TakenOver` and stops the eventloop
+    /// unless the action is to retry. Disabled by default
+    pub fn set_takeover_detection(mut self, takeover_detection: TakeoverDetection) -> Self {
+        if takeover_detection.flaps == 0 {
+            panic!("Takeover flaps should be non zero");
+        }
+
+        self.takeover_detection = Some(takeover_detection);
+        self
+    }
+
+    /// Client id takeover detection
+    pub fn takeover_detection(&self) -> Option<TakeoverDetection> {
+        self.takeover_detection
+    }
+
+    /// Takes the imported session out of the options so that it's applied once
+    pub(crate) fn take_session(&mut self) -> Option<Session> {
+        self.session.take()
+    }
+
+    /// Caps keep alive with the broker limit. Limit can be below the minimum
+    /// keep alive allowed by the setter
+    pub(crate) fn apply_broker_keep_alive_limit(mut self) -> Self {
+        if let Some(limit) = self.broker_keep_alive_limit {
+            if limit < self.keep_alive {
+                warn!("Keep alive capped by broker limit. Configured = {:?}, Limit = {:?}", self.keep_alive, limit);
+                self.keep_alive = limit;
+            }
+        }
+
+        self
+    }
+}
+
+/// Keep alives below 10 secs are rejected
+fn validate_keep_alive(keep_alive: Duration) -> Duration {
+    if keep_alive < Duration::from_secs(10) {
+        panic!("Keep alives should be >= 10 secs");
+    }
+
+    keep_alive
 }
 
 #[cfg(test)]
 mod test {
-    use crate::mqttoptions::{MqttOptions, ReconnectOptions};
+    use crate::client::DisconnectReason;
+    use crate::mqttoptions::{
+        BrokerCapabilities, ClientAuth, ConnectionMethod, MqttOptions, PowerSaving, ReconnectOptions, SubscriptionGuardrails, TakeoverDetection, TlsOptions,
+    };
+    use mqtt311::QoS;
+    use std::time::Duration;
 
     #[test]
     #[should_panic]
@@ -312,4 +1745,107 @@ mod test {
             .set_reconnect_opts(ReconnectOptions::Always(10))
             .set_clean_session(true);
     }
+
+    #[test]
+    #[should_panic]
+    fn zero_handler_attempts() {
+        let _mqtt_opts = MqttOptions::new("client_a", "127.0.0.1", 1883).set_handler_retry(0, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn publish_profiles_should_override_publish_defaults() {
+        let mqtt_opts = MqttOptions::new("client_a", "127.0.0.1", 1883)
+            .set_publish_defaults(QoS::AtMostOnce, false)
+            .add_publish_profile("cmd/#", QoS::AtLeastOnce, false)
+            .add_publish_profile("status/+", QoS::AtLeastOnce, true);
+
+        let profiles = mqtt_opts.publish_profiles();
+        assert_eq!(profiles.resolve("cmd/reboot"), (QoS::AtLeastOnce, false));
+        assert_eq!(profiles.resolve("status/d1"), (QoS::AtLeastOnce, true));
+        assert_eq!(profiles.resolve("telemetry/d1"), (QoS::AtMostOnce, false));
+    }
+
+    #[test]
+    fn qos_above_broker_maximum_should_be_downgraded() {
+        let capabilities = BrokerCapabilities {
+            max_qos: QoS::AtLeastOnce,
+            ..BrokerCapabilities::default()
+        };
+
+        assert_eq!(capabilities.degrade_qos(QoS::ExactlyOnce), QoS::AtLeastOnce);
+        assert_eq!(capabilities.degrade_qos(QoS::AtMostOnce), QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn broad_subscriptions_should_be_rejected_unless_allowed() {
+        let guardrails = SubscriptionGuardrails {
+            min_levels: 2,
+            ..SubscriptionGuardrails::default()
+        }
+        .allow("a/#");
+
+        assert!(guardrails.check("#").is_err());
+        assert!(guardrails.check("b/#").is_err());
+        assert!(guardrails.check("+/b/c").is_err());
+        assert!(guardrails.check("a/#").is_ok());
+        assert!(guardrails.check("a/b/+").is_ok());
+        assert!(guardrails.check("a").is_ok());
+    }
+
+    #[test]
+    fn requests_should_wait_for_the_next_transmission_window() {
+        let power_saving = PowerSaving {
+            interval: Duration::from_secs(60),
+            window: Duration::from_secs(5),
+            keep_alive: Duration::from_secs(1200),
+        };
+
+        let mqtt_opts = MqttOptions::new("client_a", "127.0.0.1", 1883).set_power_saving(power_saving);
+        assert_eq!(mqtt_opts.keep_alive(), Duration::from_secs(1200));
+
+        assert_eq!(power_saving.time_to_window(Duration::from_secs(63)), None);
+        assert_eq!(power_saving.time_to_window(Duration::from_secs(65)), Some(Duration::from_secs(55)));
+        assert_eq!(power_saving.next_window(Duration::from_secs(63)), Duration::from_secs(57));
+    }
+
+    #[test]
+    #[should_panic(expected = "Keep alives should be >= 10 secs")]
+    fn power_saving_keep_alive_should_be_validated_like_keep_alive() {
+        let power_saving = PowerSaving {
+            interval: Duration::from_secs(60),
+            window: Duration::from_secs(5),
+            keep_alive: Duration::from_secs(5),
+        };
+
+        let _mqtt_opts = MqttOptions::new("client_a", "127.0.0.1", 1883).set_power_saving(power_saving);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_sampling_interval() {
+        let _mqtt_opts = MqttOptions::new("client_a", "127.0.0.1", 1883).add_payload_sampling("a/#", 0, 10);
+    }
+
+    #[test]
+    fn only_quick_broker_closes_should_be_flaps() {
+        let detection = TakeoverDetection::default();
+        let quick = Duration::from_millis(200);
+
+        assert!(detection.is_flap(quick, DisconnectReason::BrokerClosed));
+        assert!(detection.is_flap(quick, DisconnectReason::Reset));
+        assert!(!detection.is_flap(quick, DisconnectReason::Timeout));
+        assert!(!detection.is_flap(quick, DisconnectReason::User));
+        assert!(!detection.is_flap(Duration::from_secs(60), DisconnectReason::BrokerClosed));
+    }
+
+    #[test]
+    fn legacy_tls_connection_method_should_map_to_tls_options() {
+        let (ca, cert, key) = (b"ca".to_vec(), b"cert".to_vec(), b"key".to_vec());
+        let tls = ConnectionMethod::Tls(ca.clone(), Some((cert.clone(), key.clone()))).tls_options().unwrap();
+        assert_eq!(tls, TlsOptions::new(ca.clone()).set_client_auth(cert.clone(), key.clone()));
+        assert_eq!(tls.client_auth(), Some(&ClientAuth::Pem(cert, key)));
+
+        assert_eq!(ConnectionMethod::Tls(ca.clone(), None).tls_options(), Some(TlsOptions::new(ca)));
+        assert_eq!(ConnectionMethod::Tcp.tls_options(), None);
+    }
 }
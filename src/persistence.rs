@@ -0,0 +1,428 @@
+//! Persistence of messages the client is responsible for across process restarts
+use crate::codec;
+use mqtt311::{Packet, PacketIdentifier, Publish, Subscribe, SubscribeTopic};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Storage backend for messages which are acknowledged to the broker but not
+/// yet handed over to the user, and for messages the broker is yet to
+/// acknowledge. Implement this over a durable medium (file, database) to
+/// survive crashes and restarts. See [FileStore] for a built in one
+///
+/// [FileStore]: struct.FileStore.html
+pub trait Store: Send {
+    /// Saves an incoming QoS 1/2 publish before it's acknowledged to the broker.
+    /// A publish with the same packet id replaces the existing one
+    fn put_incoming(&mut self, publish: &Publish) -> io::Result<()>;
+    /// Deletes an incoming publish once it's handed over to the user
+    fn remove_incoming(&mut self, pkid: PacketIdentifier) -> io::Result<()>;
+    /// All the incoming publishes which are yet to be handed over to the user
+    fn incoming(&mut self) -> io::Result<Vec<Publish>>;
+    /// Saves the packet id of an incoming QoS 2 publish which is received and
+    /// waiting for pubrel. Used to drop the publish when the broker resends it
+    /// after a restart. Nothing is saved by default
+    fn put_received(&mut self, _pkid: PacketIdentifier) -> io::Result<()> {
+        Ok(())
+    }
+    /// Deletes the packet id once the broker releases the publish with pubrel
+    fn remove_received(&mut self, _pkid: PacketIdentifier) -> io::Result<()> {
+        Ok(())
+    }
+    /// Packet ids of all the QoS 2 publishes waiting for pubrel
+    fn received(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+        Ok(Vec::new())
+    }
+    /// Replaces the saved subscriptions with all the subscriptions the client
+    /// wants. Saved on every subscribe and unsubscribe. Nothing is saved by default
+    fn put_subscriptions(&mut self, _subscriptions: &[SubscribeTopic]) -> io::Result<()> {
+        Ok(())
+    }
+    /// Subscriptions saved by the previous run
+    fn subscriptions(&mut self) -> io::Result<Vec<SubscribeTopic>> {
+        Ok(Vec::new())
+    }
+    /// Saves an outgoing QoS 1/2 publish once it has a packet id. Only used with
+    /// persistent sessions (clean session off). Nothing is saved by default
+    fn put_outgoing(&mut self, _publish: &Publish) -> io::Result<()> {
+        Ok(())
+    }
+    /// Deletes an outgoing publish once the broker completes the handshake
+    /// (puback for QoS 1, pubcomp for QoS 2)
+    fn remove_outgoing(&mut self, _pkid: PacketIdentifier) -> io::Result<()> {
+        Ok(())
+    }
+    /// Outgoing publishes of the previous run in the order they were saved.
+    /// They are resent on the first connection
+    fn outgoing(&mut self) -> io::Result<Vec<Publish>> {
+        Ok(Vec::new())
+    }
+    /// Saves the packet id of an outgoing QoS 2 publish which the broker received
+    /// (pubrec). The publish stays saved till pubcomp but the next run continues
+    /// with pubrel instead of sending it again. Nothing is saved by default
+    fn put_released(&mut self, _pkid: PacketIdentifier) -> io::Result<()> {
+        Ok(())
+    }
+    /// Deletes the packet id once the broker completes the handshake with pubcomp
+    fn remove_released(&mut self, _pkid: PacketIdentifier) -> io::Result<()> {
+        Ok(())
+    }
+    /// Packet ids of all the outgoing QoS 2 publishes waiting for pubcomp
+    fn released(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Cloneable handle to a user supplied [store]
+///
+/// [store]: trait.Store.html
+#[derive(Clone)]
+pub struct StoreHandle(Arc<Mutex<dyn Store>>);
+
+impl StoreHandle {
+    pub(crate) fn new<S: Store + 'static>(store: S) -> StoreHandle {
+        StoreHandle(Arc::new(Mutex::new(store)))
+    }
+
+    pub(crate) fn put_incoming(&self, publish: &Publish) -> io::Result<()> {
+        self.0.lock().unwrap().put_incoming(publish)
+    }
+
+    pub(crate) fn remove_incoming(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.0.lock().unwrap().remove_incoming(pkid)
+    }
+
+    pub(crate) fn incoming(&self) -> io::Result<Vec<Publish>> {
+        self.0.lock().unwrap().incoming()
+    }
+
+    pub(crate) fn put_received(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.0.lock().unwrap().put_received(pkid)
+    }
+
+    pub(crate) fn remove_received(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.0.lock().unwrap().remove_received(pkid)
+    }
+
+    pub(crate) fn received(&self) -> io::Result<Vec<PacketIdentifier>> {
+        self.0.lock().unwrap().received()
+    }
+
+    pub(crate) fn put_subscriptions(&self, subscriptions: &[SubscribeTopic]) -> io::Result<()> {
+        self.0.lock().unwrap().put_subscriptions(subscriptions)
+    }
+
+    pub(crate) fn subscriptions(&self) -> io::Result<Vec<SubscribeTopic>> {
+        self.0.lock().unwrap().subscriptions()
+    }
+
+    pub(crate) fn put_outgoing(&self, publish: &Publish) -> io::Result<()> {
+        self.0.lock().unwrap().put_outgoing(publish)
+    }
+
+    pub(crate) fn remove_outgoing(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.0.lock().unwrap().remove_outgoing(pkid)
+    }
+
+    pub(crate) fn outgoing(&self) -> io::Result<Vec<Publish>> {
+        self.0.lock().unwrap().outgoing()
+    }
+
+    pub(crate) fn put_released(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.0.lock().unwrap().put_released(pkid)
+    }
+
+    pub(crate) fn remove_released(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        self.0.lock().unwrap().remove_released(pkid)
+    }
+
+    pub(crate) fn released(&self) -> io::Result<Vec<PacketIdentifier>> {
+        self.0.lock().unwrap().released()
+    }
+}
+
+impl fmt::Debug for StoreHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StoreHandle")
+    }
+}
+
+/// Directory backed [store]. Every record is a file with the mqtt encoding of
+/// the packet, written to a temporary file, synced and renamed in place so that
+/// a crash never leaves a half written record behind
+///
+/// ```text
+/// <dir>/incoming/<sequence>-<pkid>   incoming publishes
+/// <dir>/outgoing/<sequence>-<pkid>   outgoing publishes
+/// <dir>/received/<pkid>              qos 2 packet ids waiting for pubrel
+/// <dir>/released/<pkid>              qos 2 packet ids waiting for pubcomp
+/// <dir>/subscriptions                subscribe packet with all the subscriptions
+/// ```
+///
+/// Use a directory per client id
+///
+/// [store]: trait.Store.html
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    /// sequence of the next publish. Keeps the saving order across restarts
+    sequence: u64,
+    incoming: BTreeMap<PacketIdentifier, (u64, PathBuf)>,
+    outgoing: BTreeMap<PacketIdentifier, (u64, PathBuf)>,
+}
+
+impl FileStore {
+    /// Opens the store in the directory. Directory is created if it doesn't
+    /// exist and records of the previous run are picked up if it does
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<FileStore> {
+        let dir = dir.as_ref().to_owned();
+        for kind in ["incoming", "outgoing", "received", "released"].iter() {
+            fs::create_dir_all(dir.join(kind))?;
+        }
+
+        let incoming = scan_publishes(&dir.join("incoming"))?;
+        let outgoing = scan_publishes(&dir.join("outgoing"))?;
+        let sequence = incoming.values().chain(outgoing.values()).map(|(sequence, _)| sequence + 1).max().unwrap_or(0);
+
+        Ok(FileStore {
+            dir,
+            sequence,
+            incoming,
+            outgoing,
+        })
+    }
+
+    fn put_publish(&mut self, kind: &str, publish: &Publish) -> io::Result<()> {
+        let pkid = publish.pkid.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Publish without packet id"))?;
+        let sequence = self.sequence;
+        let path = self.dir.join(kind).join(format!("{}-{}", sequence, pkid.0));
+        write_atomic(&path, &encode(&Packet::Publish(publish.clone()))?)?;
+        self.sequence += 1;
+
+        let records = if kind == "incoming" { &mut self.incoming } else { &mut self.outgoing };
+        if let Some((_, replaced)) = records.insert(pkid, (sequence, path)) {
+            remove_file(&replaced)?;
+        }
+
+        Ok(())
+    }
+
+    fn publishes(&self, kind: &str) -> io::Result<Vec<Publish>> {
+        let records = if kind == "incoming" { &self.incoming } else { &self.outgoing };
+        let mut records: Vec<&(u64, PathBuf)> = records.values().collect();
+        records.sort();
+
+        let mut publishes = Vec::with_capacity(records.len());
+        for (_, path) in records {
+            match decode(&fs::read(path)?)? {
+                Packet::Publish(publish) => publishes.push(publish),
+                packet => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected record {:?}", packet))),
+            }
+        }
+
+        Ok(publishes)
+    }
+}
+
+impl Store for FileStore {
+    fn put_incoming(&mut self, publish: &Publish) -> io::Result<()> {
+        self.put_publish("incoming", publish)
+    }
+
+    fn remove_incoming(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        match self.incoming.remove(&pkid) {
+            Some((_, path)) => remove_file(&path),
+            None => Ok(()),
+        }
+    }
+
+    fn incoming(&mut self) -> io::Result<Vec<Publish>> {
+        self.publishes("incoming")
+    }
+
+    fn put_received(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        write_atomic(&self.dir.join("received").join(pkid.0.to_string()), &[])
+    }
+
+    fn remove_received(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        remove_file(&self.dir.join("received").join(pkid.0.to_string()))
+    }
+
+    fn received(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+        scan_pkids(&self.dir.join("received"))
+    }
+
+    fn put_subscriptions(&mut self, subscriptions: &[SubscribeTopic]) -> io::Result<()> {
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: subscriptions.to_vec(),
+        };
+
+        write_atomic(&self.dir.join("subscriptions"), &encode(&Packet::Subscribe(subscribe))?)
+    }
+
+    fn subscriptions(&mut self) -> io::Result<Vec<SubscribeTopic>> {
+        let record = match fs::read(self.dir.join("subscriptions")) {
+            Ok(record) => record,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        match decode(&record)? {
+            Packet::Subscribe(subscribe) => Ok(subscribe.topics),
+            packet => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected record {:?}", packet))),
+        }
+    }
+
+    fn put_outgoing(&mut self, publish: &Publish) -> io::Result<()> {
+        self.put_publish("outgoing", publish)
+    }
+
+    fn remove_outgoing(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        match self.outgoing.remove(&pkid) {
+            Some((_, path)) => remove_file(&path),
+            None => Ok(()),
+        }
+    }
+
+    fn outgoing(&mut self) -> io::Result<Vec<Publish>> {
+        self.publishes("outgoing")
+    }
+
+    fn put_released(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        write_atomic(&self.dir.join("released").join(pkid.0.to_string()), &[])
+    }
+
+    fn remove_released(&mut self, pkid: PacketIdentifier) -> io::Result<()> {
+        remove_file(&self.dir.join("released").join(pkid.0.to_string()))
+    }
+
+    fn released(&mut self) -> io::Result<Vec<PacketIdentifier>> {
+        scan_pkids(&self.dir.join("released"))
+    }
+}
+
+/// Packet id markers in the directory in packet id order
+fn scan_pkids(dir: &Path) -> io::Result<Vec<PacketIdentifier>> {
+    let mut pkids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Ok(pkid) = entry?.file_name().to_string_lossy().parse() {
+            pkids.push(PacketIdentifier(pkid));
+        }
+    }
+
+    pkids.sort();
+    Ok(pkids)
+}
+
+/// Publish records in the directory by packet id. Leftover temporary files of a
+/// crash are deleted
+fn scan_publishes(dir: &Path) -> io::Result<BTreeMap<PacketIdentifier, (u64, PathBuf)>> {
+    let mut records: BTreeMap<PacketIdentifier, (u64, PathBuf)> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let mut parts = name.splitn(2, '-');
+        let (sequence, pkid) = match (parts.next().map(str::parse::<u64>), parts.next().map(str::parse::<u16>)) {
+            (Some(Ok(sequence)), Some(Ok(pkid))) => (sequence, PacketIdentifier(pkid)),
+            _ => {
+                remove_file(&path)?;
+                continue;
+            }
+        };
+
+        // a crash between writing a replacement and removing the older record leaves both
+        match records.get(&pkid) {
+            Some((existing, _)) if *existing > sequence => remove_file(&path)?,
+            _ => {
+                if let Some((_, older)) = records.insert(pkid, (sequence, path)) {
+                    remove_file(&older)?;
+                }
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        out => out,
+    }
+}
+
+pub(crate) fn encode(packet: &Packet) -> io::Result<Vec<u8>> {
+    codec::encode(packet)
+}
+
+pub(crate) fn decode(record: &[u8]) -> io::Result<Packet> {
+    match codec::decode(record)? {
+        Some((packet, _)) => Ok(packet),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated record")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileStore, Store};
+    use mqtt311::{PacketIdentifier, Publish, QoS, SubscribeTopic};
+    use std::{env, fs, sync::Arc};
+
+    fn publish(pkid: u16, payload: u8) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(pkid)),
+            payload: Arc::new(vec![payload; 3]),
+        }
+    }
+
+    #[test]
+    fn file_store_should_survive_a_restart_in_saving_order() {
+        let dir = env::temp_dir().join(format!("rumqtt-store-{}", uuid::Uuid::new_v4()));
+        let mut store = FileStore::new(&dir).unwrap();
+        store.put_outgoing(&publish(65535, 1)).unwrap();
+        store.put_outgoing(&publish(1, 2)).unwrap();
+        store.put_outgoing(&publish(2, 3)).unwrap();
+        store.remove_outgoing(PacketIdentifier(2)).unwrap();
+        store.put_incoming(&publish(7, 4)).unwrap();
+        store.put_incoming(&publish(7, 5)).unwrap();
+        store.put_received(PacketIdentifier(9)).unwrap();
+        store.put_released(PacketIdentifier(1)).unwrap();
+        store.put_released(PacketIdentifier(5)).unwrap();
+        store.remove_released(PacketIdentifier(5)).unwrap();
+        let subscription = SubscribeTopic { topic_path: "a/#".to_owned(), qos: QoS::ExactlyOnce };
+        store.put_subscriptions(std::slice::from_ref(&subscription)).unwrap();
+        drop(store);
+
+        // packet ids roll over. sequence keeps the order
+        let mut store = FileStore::new(&dir).unwrap();
+        let outgoing: Vec<u8> = store.outgoing().unwrap().iter().map(|publish| publish.payload[0]).collect();
+        assert_eq!(outgoing, vec![1, 2]);
+        assert_eq!(store.incoming().unwrap(), vec![publish(7, 5)]);
+        assert_eq!(store.received().unwrap(), vec![PacketIdentifier(9)]);
+        assert_eq!(store.released().unwrap(), vec![PacketIdentifier(1)]);
+        assert_eq!(store.subscriptions().unwrap(), vec![subscription]);
+
+        store.put_outgoing(&publish(3, 6)).unwrap();
+        let outgoing: Vec<u8> = store.outgoing().unwrap().iter().map(|publish| publish.payload[0]).collect();
+        assert_eq!(outgoing, vec![1, 2, 6]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+//! Packet id allocation. Clients which multiplex logical sub clients over one
+//! session (bridges, gateways) can give each of them a range of packet ids or a
+//! random start so that their ids don't collide
+use mqtt311::PacketIdentifier;
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+};
+
+/// Number of packet ids in the mqtt packet id space (0 isn't a valid id)
+pub const PKID_SPACE: usize = 65_535;
+
+/// Proposes packet ids. Ids which are still in flight are skipped by the client
+/// by asking for the next one
+pub trait PkidAllocator: Send {
+    /// Next packet id after `last`, the id handed out before. `last` is 0 before
+    /// the first id
+    fn next(&mut self, last: PacketIdentifier) -> PacketIdentifier;
+
+    /// Number of distinct ids handed out. Publishes, subscribes and unsubscribes
+    /// wait for an ack once these many ids are in use. The allocator should
+    /// propose every one of them within these many calls
+    fn capacity(&self) -> usize {
+        PKID_SPACE
+    }
+}
+
+/// 1, 2, .. 65535 and rolls over to 1. Default allocator
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PkidAllocator for Sequential {
+    fn next(&mut self, PacketIdentifier(last): PacketIdentifier) -> PacketIdentifier {
+        match last {
+            65_535 => PacketIdentifier(1),
+            last => PacketIdentifier(last + 1),
+        }
+    }
+}
+
+/// Starts at a random id and continues sequentially. Avoids reusing the ids of a
+/// previous process on the same session right after a restart
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomStart {
+    started: bool,
+}
+
+impl PkidAllocator for RandomStart {
+    fn next(&mut self, last: PacketIdentifier) -> PacketIdentifier {
+        if self.started {
+            return Sequential.next(last);
+        }
+
+        self.started = true;
+        let random = RandomState::new().build_hasher().finish();
+        PacketIdentifier((random % PKID_SPACE as u64) as u16 + 1)
+    }
+}
+
+/// Hands out ids from `first` to `last` (both inclusive) and rolls over to `first`
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    first: u16,
+    last: u16,
+}
+
+impl Partition {
+    pub fn new(first: u16, last: u16) -> Partition {
+        if first == 0 || first > last {
+            panic!("Partition should be a non empty range of non zero ids");
+        }
+
+        Partition { first, last }
+    }
+}
+
+impl PkidAllocator for Partition {
+    fn next(&mut self, PacketIdentifier(last): PacketIdentifier) -> PacketIdentifier {
+        if last >= self.first && last < self.last {
+            PacketIdentifier(last + 1)
+        } else {
+            PacketIdentifier(self.first)
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        usize::from(self.last - self.first) + 1
+    }
+}
+
+/// Cloneable handle to a packet id [allocator]
+///
+/// [allocator]: trait.PkidAllocator.html
+#[derive(Clone)]
+pub struct PkidAllocatorHandle(Arc<Mutex<dyn PkidAllocator>>);
+
+impl PkidAllocatorHandle {
+    pub(crate) fn new<A: PkidAllocator + 'static>(allocator: A) -> PkidAllocatorHandle {
+        PkidAllocatorHandle(Arc::new(Mutex::new(allocator)))
+    }
+
+    pub(crate) fn next(&self, last: PacketIdentifier) -> PacketIdentifier {
+        self.0.lock().unwrap().next(last)
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.0.lock().unwrap().capacity()
+    }
+}
+
+impl Default for PkidAllocatorHandle {
+    fn default() -> Self {
+        PkidAllocatorHandle::new(Sequential)
+    }
+}
+
+impl fmt::Debug for PkidAllocatorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PkidAllocatorHandle")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Partition, PkidAllocator, RandomStart};
+    use mqtt311::PacketIdentifier;
+
+    #[test]
+    fn allocators_should_stay_in_their_range() {
+        let mut partition = Partition::new(100, 102);
+        let pkids: Vec<u16> = (0..5)
+            .scan(PacketIdentifier(0), |last, _| {
+                *last = partition.next(*last);
+                Some(last.0)
+            })
+            .collect();
+
+        assert_eq!(pkids, vec![100, 101, 102, 100, 101]);
+        assert_eq!(partition.capacity(), 3);
+
+        let mut random = RandomStart::default();
+        let PacketIdentifier(first) = random.next(PacketIdentifier(0));
+        assert_ne!(first, 0);
+        let PacketIdentifier(second) = random.next(PacketIdentifier(first));
+        assert_eq!(second, if first == 65_535 { 1 } else { first + 1 });
+    }
+}
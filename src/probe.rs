@@ -0,0 +1,62 @@
+//! Timestamping hooks on the path of a publish. Called with the time a publish
+//! is queued by the client, written for the network by the eventloop and
+//! acknowledged by the broker (puback for qos 1, pubcomp for qos 2)
+use mqtt311::PacketIdentifier;
+use std::{fmt, sync::Arc, time::Instant};
+
+/// Receives the timestamps of publishes. Hooks run on the publishing thread
+/// (`enqueued`) and the eventloop thread (`sent`, `acked`) and should be cheap.
+/// Nothing is done by default
+pub trait Probe: Send + Sync {
+    /// Publish is handed over to the eventloop. Packet id isn't assigned yet
+    fn enqueued(&self, _topic: &str, _size: usize, _at: Instant) {}
+    /// Qos 1 or 2 publish is written for the network. Called again for replays
+    /// and retransmissions
+    fn sent(&self, _pkid: PacketIdentifier, _at: Instant) {}
+    /// Qos 1 or 2 publish is acknowledged by the broker
+    fn acked(&self, _pkid: PacketIdentifier, _at: Instant) {}
+}
+
+impl<P: Probe + ?Sized> Probe for Arc<P> {
+    fn enqueued(&self, topic: &str, size: usize, at: Instant) {
+        (**self).enqueued(topic, size, at)
+    }
+
+    fn sent(&self, pkid: PacketIdentifier, at: Instant) {
+        (**self).sent(pkid, at)
+    }
+
+    fn acked(&self, pkid: PacketIdentifier, at: Instant) {
+        (**self).acked(pkid, at)
+    }
+}
+
+/// Cloneable handle to a user supplied [probe]
+///
+/// [probe]: trait.Probe.html
+#[derive(Clone)]
+pub struct ProbeHandle(Arc<dyn Probe>);
+
+impl ProbeHandle {
+    pub(crate) fn new<P: Probe + 'static>(probe: P) -> ProbeHandle {
+        ProbeHandle(Arc::new(probe))
+    }
+
+    pub(crate) fn enqueued(&self, topic: &str, size: usize, at: Instant) {
+        self.0.enqueued(topic, size, at)
+    }
+
+    pub(crate) fn sent(&self, pkid: PacketIdentifier, at: Instant) {
+        self.0.sent(pkid, at)
+    }
+
+    pub(crate) fn acked(&self, pkid: PacketIdentifier, at: Instant) {
+        self.0.acked(pkid, at)
+    }
+}
+
+impl fmt::Debug for ProbeHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProbeHandle")
+    }
+}
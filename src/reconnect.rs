@@ -0,0 +1,74 @@
+//! User defined reconnection decisions. Replaces the fixed [ReconnectOptions]
+//! when set with [set_reconnect_policy]
+//!
+//! [ReconnectOptions]: ../mqttoptions/enum.ReconnectOptions.html
+//! [set_reconnect_policy]: ../mqttoptions/struct.MqttOptions.html#method.set_reconnect_policy
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Context of the next connection attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attempt {
+    /// attempts since the last successful connection. Starts at 1
+    pub attempt: u32,
+    /// a connection succeeded earlier in the lifetime of the client
+    pub connected_before: bool,
+}
+
+/// Decides if and when the eventloop connects again after a failed connection
+/// attempt or a lost connection. Runs on the eventloop thread, which is a good
+/// place to refresh credentials before the next attempt
+pub trait ReconnectPolicy: Send + Sync {
+    /// Time to wait before the attempt or `None` to stop the eventloop
+    fn next_attempt(&self, attempt: &Attempt) -> Option<Duration>;
+}
+
+impl<F> ReconnectPolicy for F
+where
+    F: Fn(&Attempt) -> Option<Duration> + Send + Sync,
+{
+    fn next_attempt(&self, attempt: &Attempt) -> Option<Duration> {
+        self(attempt)
+    }
+}
+
+/// Shareable reconnect policy
+#[derive(Clone)]
+pub struct ReconnectPolicyHandle(Arc<dyn ReconnectPolicy>);
+
+impl ReconnectPolicyHandle {
+    pub(crate) fn new<P: ReconnectPolicy + 'static>(policy: P) -> ReconnectPolicyHandle {
+        ReconnectPolicyHandle(Arc::new(policy))
+    }
+
+    pub fn next_attempt(&self, attempt: &Attempt) -> Option<Duration> {
+        self.0.next_attempt(attempt)
+    }
+}
+
+impl fmt::Debug for ReconnectPolicyHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReconnectPolicyHandle")
+    }
+}
+
+/// User step of the connection lifecycle, e.g to toggle a cellular modem before
+/// reconnecting or to ask for a resync after connecting. Runs on the eventloop
+/// thread, which is blocked till it returns
+#[derive(Clone)]
+pub struct ReconnectHook(Arc<dyn Fn(&Attempt) + Send + Sync>);
+
+impl ReconnectHook {
+    pub(crate) fn new<F: Fn(&Attempt) + Send + Sync + 'static>(hook: F) -> ReconnectHook {
+        ReconnectHook(Arc::new(hook))
+    }
+
+    pub fn run(&self, attempt: &Attempt) {
+        (self.0)(attempt)
+    }
+}
+
+impl fmt::Debug for ReconnectHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReconnectHook")
+    }
+}
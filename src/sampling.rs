@@ -0,0 +1,126 @@
+//! Payload sampling for observability. Every Nth publish on topics matching a
+//! filter is logged or sent to a side channel with a truncated payload preview
+use crate::validation::matches;
+use crossbeam_channel::Sender;
+use mqtt311::Publish;
+
+/// Sampled publish
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub topic: String,
+    /// `true` for publishes from the broker
+    pub incoming: bool,
+    /// full payload size in bytes
+    pub len: usize,
+    /// first few bytes of the payload
+    pub preview: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    filter: String,
+    every: usize,
+    preview_len: usize,
+    count: usize,
+}
+
+/// Sampling rules per topic filter. Samples are logged at info level unless a
+/// channel is set. Each rule counts the publishes matching it separately
+#[derive(Debug, Clone, Default)]
+pub struct Sampling {
+    rules: Vec<Rule>,
+    tx: Option<Sender<Sample>>,
+}
+
+impl Sampling {
+    pub(crate) fn add_rule(&mut self, filter: String, every: usize, preview_len: usize) {
+        if every == 0 {
+            panic!("zero sampling interval is not allowed");
+        }
+
+        self.rules.push(Rule {
+            filter,
+            every,
+            preview_len,
+            count: 0,
+        });
+    }
+
+    pub(crate) fn set_channel(&mut self, tx: Sender<Sample>) {
+        self.tx = Some(tx);
+    }
+
+    /// Counts the publish against the first matching rule and returns a sample
+    /// if it's the Nth one
+    pub fn sample(&mut self, publish: &Publish, incoming: bool) -> Option<Sample> {
+        let rule = self.rules.iter_mut().find(|rule| matches(&publish.topic_name, &rule.filter))?;
+
+        rule.count += 1;
+        if rule.count < rule.every {
+            return None;
+        }
+
+        rule.count = 0;
+        let preview_len = rule.preview_len.min(publish.payload.len());
+        Some(Sample {
+            topic: publish.topic_name.clone(),
+            incoming,
+            len: publish.payload.len(),
+            preview: publish.payload[..preview_len].to_vec(),
+        })
+    }
+
+    /// Samples the publish and hands the sample over to the channel or the log
+    pub(crate) fn observe(&mut self, publish: &Publish, incoming: bool) {
+        let sample = match self.sample(publish, incoming) {
+            Some(sample) => sample,
+            None => return,
+        };
+
+        match self.tx {
+            Some(ref tx) => {
+                if tx.try_send(sample).is_err() {
+                    debug!("Sample channel full or closed");
+                }
+            }
+            None => info!(
+                "Sample. Topic = {}, Incoming = {}, Size = {}, Preview = {:?}",
+                sample.topic,
+                sample.incoming,
+                sample.len,
+                String::from_utf8_lossy(&sample.preview)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sampling;
+    use mqtt311::{Publish, QoS};
+    use std::sync::Arc;
+
+    fn publish(topic: &str) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: topic.to_owned(),
+            pkid: None,
+            payload: Arc::new(b"hello world".to_vec()),
+        }
+    }
+
+    #[test]
+    fn every_nth_matching_publish_should_be_sampled_with_preview() {
+        let mut sampling = Sampling::default();
+        sampling.add_rule("telemetry/#".to_owned(), 3, 5);
+
+        let samples: Vec<_> = (0..6).filter_map(|_| sampling.sample(&publish("telemetry/d1"), true)).collect();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].preview, b"hello");
+        assert_eq!(samples[0].len, 11);
+
+        assert!(sampling.sample(&publish("cmd/d1"), false).is_none());
+    }
+}
@@ -0,0 +1,107 @@
+//! Continuity checks of sequence numbers carried in payloads. Sequence numbers
+//! are extracted from incoming publishes on topics matching a filter and every
+//! topic is tracked on its own. A jump forward is reported as a gap, which
+//! shows loss from qos 0 drops or broker issues as soon as it happens
+use crate::validation::matches;
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// Reads the sequence number of a payload. `None` skips the publish
+pub trait SequenceExtractor: Send + Sync {
+    fn sequence(&self, topic: &str, payload: &[u8]) -> Option<u64>;
+}
+
+impl<F> SequenceExtractor for F
+where
+    F: Fn(&str, &[u8]) -> Option<u64> + Send + Sync,
+{
+    fn sequence(&self, topic: &str, payload: &[u8]) -> Option<u64> {
+        self(topic, payload)
+    }
+}
+
+/// Missing sequence numbers on a topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gap {
+    pub topic: String,
+    /// sequence number which was due
+    pub expected: u64,
+    /// sequence number which arrived instead
+    pub received: u64,
+}
+
+impl Gap {
+    /// Number of publishes which never arrived
+    pub fn missing(&self) -> u64 {
+        self.received - self.expected
+    }
+}
+
+/// Extractors per topic filter along with the last sequence number of every
+/// topic. A sequence number which doesn't move forward (publisher restart,
+/// duplicate qos 1 delivery) restarts tracking without a gap
+#[derive(Clone, Default)]
+pub struct SequenceTracking {
+    rules: Vec<(String, Arc<dyn SequenceExtractor>)>,
+    last: HashMap<String, u64>,
+}
+
+impl SequenceTracking {
+    pub(crate) fn add<E: SequenceExtractor + 'static>(&mut self, filter: String, extractor: E) {
+        self.rules.push((filter, Arc::new(extractor)));
+    }
+
+    /// Tracks the publish with the first matching extractor. Returns the gap
+    /// in front of it, if any
+    pub fn observe(&mut self, topic: &str, payload: &[u8]) -> Option<Gap> {
+        let (_, extractor) = self.rules.iter().find(|(filter, _)| matches(topic, filter))?;
+        let sequence = extractor.sequence(topic, payload)?;
+
+        let last = self.last.insert(topic.to_owned(), sequence)?;
+        let expected = last.checked_add(1)?;
+        if sequence > expected {
+            Some(Gap {
+                topic: topic.to_owned(),
+                expected,
+                received: sequence,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl fmt::Debug for SequenceTracking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filters: Vec<&String> = self.rules.iter().map(|(filter, _)| filter).collect();
+        write!(f, "SequenceTracking({:?})", filters)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Gap, SequenceTracking};
+
+    #[test]
+    fn forward_jumps_should_be_gaps_per_topic() {
+        let mut tracking = SequenceTracking::default();
+        tracking.add("telemetry/#".to_owned(), |_: &str, payload: &[u8]| std::str::from_utf8(payload).ok()?.parse().ok());
+
+        assert_eq!(tracking.observe("telemetry/d1", b"1"), None);
+        assert_eq!(tracking.observe("telemetry/d2", b"7"), None);
+        assert_eq!(tracking.observe("telemetry/d1", b"2"), None);
+        let gap = tracking.observe("telemetry/d1", b"5").unwrap();
+        assert_eq!(gap, Gap { topic: "telemetry/d1".to_owned(), expected: 3, received: 5 });
+        assert_eq!(gap.missing(), 2);
+
+        // duplicates, restarts and payloads without a sequence number aren't gaps
+        assert_eq!(tracking.observe("telemetry/d1", b"5"), None);
+        assert_eq!(tracking.observe("telemetry/d2", b"0"), None);
+        assert_eq!(tracking.observe("telemetry/d2", b"1"), None);
+        assert_eq!(tracking.observe("telemetry/d2", b"garbage"), None);
+        assert_eq!(tracking.observe("commands/d1", b"9"), None);
+    }
+}
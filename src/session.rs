@@ -0,0 +1,137 @@
+//! Session export and import. Moves the state which backs the qos guarantees
+//! (subscriptions, messages in flight and packet ids) from one client to a new
+//! client instance, e.g to migrate a bridge process to another host
+use mqtt311::{MqttRead, MqttWrite, Packet, PacketIdentifier, Publish, Subscribe, SubscribeTopic};
+use std::io::{self, Cursor, ErrorKind};
+
+const MAGIC: &[u8] = b"RMQS";
+const VERSION: u8 = 1;
+
+// every packet in the blob is prefixed with the section it belongs to
+const SUBSCRIPTIONS: u8 = 1;
+const OUTGOING_PUBLISH: u8 = 2;
+const OUTGOING_PUBREL: u8 = 3;
+const INCOMING_PUBLISH: u8 = 4;
+const INCOMING_PUBREC: u8 = 5;
+
+/// Snapshot of the client session
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// Last packet id handed out
+    pub last_pkid: PacketIdentifier,
+    /// Subscriptions of the client
+    pub subscriptions: Vec<SubscribeTopic>,
+    /// Qos 1 and 2 publishes waiting for puback or pubrec
+    pub outgoing_pub: Vec<Publish>,
+    /// Qos 2 publishes waiting for pubcomp
+    pub outgoing_rel: Vec<PacketIdentifier>,
+    /// Incoming qos 2 publishes held till pubrel
+    pub incoming_pub: Vec<Publish>,
+    /// Incoming qos 2 publishes which are delivered and waiting for pubrel
+    pub incoming_rec: Vec<PacketIdentifier>,
+}
+
+impl Session {
+    /// Serializes the session as a header followed by mqtt packets
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packets = Vec::new();
+        if !self.subscriptions.is_empty() {
+            let subscribe = Subscribe {
+                pkid: PacketIdentifier::zero(),
+                topics: self.subscriptions.clone(),
+            };
+
+            packets.push((SUBSCRIPTIONS, Packet::Subscribe(subscribe)));
+        }
+
+        packets.extend(self.outgoing_pub.iter().map(|p| (OUTGOING_PUBLISH, Packet::Publish(p.clone()))));
+        packets.extend(self.outgoing_rel.iter().map(|p| (OUTGOING_PUBREL, Packet::Pubrel(*p))));
+        packets.extend(self.incoming_pub.iter().map(|p| (INCOMING_PUBLISH, Packet::Publish(p.clone()))));
+        packets.extend(self.incoming_rec.iter().map(|p| (INCOMING_PUBREC, Packet::Pubrec(*p))));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.last_pkid.0.to_be_bytes());
+        for (section, packet) in packets {
+            let mut cursor = Cursor::new(Vec::new());
+            cursor.write_packet(&packet).expect("Writing to a vector can't fail");
+            bytes.push(section);
+            bytes.extend(cursor.into_inner());
+        }
+
+        bytes
+    }
+
+    /// Parses a session serialized with [to_bytes]
+    ///
+    /// [to_bytes]: struct.Session.html#method.to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Session> {
+        if bytes.len() < 7 || &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return Err(invalid("not a session or unsupported version"));
+        }
+
+        let mut session = Session {
+            last_pkid: PacketIdentifier(u16::from_be_bytes([bytes[5], bytes[6]])),
+            subscriptions: Vec::new(),
+            outgoing_pub: Vec::new(),
+            outgoing_rel: Vec::new(),
+            incoming_pub: Vec::new(),
+            incoming_rec: Vec::new(),
+        };
+
+        let mut rest = &bytes[7..];
+        while let Some((section, packets)) = rest.split_first() {
+            rest = packets;
+            let packet = rest.read_packet().map_err(|e| invalid(&format!("{:?}", e)))?;
+            match (*section, packet) {
+                (SUBSCRIPTIONS, Packet::Subscribe(subscribe)) => session.subscriptions.extend(subscribe.topics),
+                (OUTGOING_PUBLISH, Packet::Publish(publish)) => session.outgoing_pub.push(publish),
+                (OUTGOING_PUBREL, Packet::Pubrel(pkid)) => session.outgoing_rel.push(pkid),
+                (INCOMING_PUBLISH, Packet::Publish(publish)) => session.incoming_pub.push(publish),
+                (INCOMING_PUBREC, Packet::Pubrec(pkid)) => session.incoming_rec.push(pkid),
+                (section, packet) => return Err(invalid(&format!("unexpected packet {:?} in section {}", packet, section))),
+            }
+        }
+
+        Ok(session)
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("Invalid session. {}", reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Session;
+    use mqtt311::{PacketIdentifier, Publish, QoS, SubscribeTopic};
+    use std::sync::Arc;
+
+    #[test]
+    fn exported_session_should_import_as_is() {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::ExactlyOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pkid: Some(PacketIdentifier(9)),
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        let session = Session {
+            last_pkid: PacketIdentifier(10),
+            subscriptions: vec![SubscribeTopic {
+                topic_path: "c/#".to_owned(),
+                qos: QoS::AtLeastOnce,
+            }],
+            outgoing_pub: vec![publish.clone()],
+            outgoing_rel: vec![PacketIdentifier(8)],
+            incoming_pub: vec![publish],
+            incoming_rec: vec![PacketIdentifier(3)],
+        };
+
+        let bytes = session.to_bytes();
+        assert_eq!(Session::from_bytes(&bytes).unwrap(), session);
+        assert!(Session::from_bytes(&bytes[1..]).is_err());
+    }
+}
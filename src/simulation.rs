@@ -0,0 +1,339 @@
+//! Deterministic simulation of the mqtt session state machine. A [Scenario] plays
+//! the network and the clock so that protocol edge cases can be tested without a
+//! broker or sleeps
+//!
+//! ```ignore
+//! Scenario::new(MqttOptions::new("sim", "localhost", 1883).set_keep_alive(10))
+//!     .connect(false)
+//!     .expect_network_connect()
+//!     .advance(Duration::from_secs(11))
+//!     .tick()
+//!     .expect_network(Packet::Pingreq);
+//! ```
+//!
+//! Bytes written to the network can be checked against golden files with
+//! [assert_golden] so that protocol affecting changes are reviewable byte by byte
+//!
+//! Only `MqttState` is driven. The eventloop (network io, timers, reconnection
+//! and backoff) isn't part of the simulation. E.g [reconnect] mimics the
+//! requests which the eventloop replays on a reconnection but none of its
+//! connection handling
+//!
+//! [Scenario]: struct.Scenario.html
+//! [assert_golden]: struct.Scenario.html#method.assert_golden
+//! [reconnect]: struct.Scenario.html#method.reconnect
+use crate::client::{mqttstate::{Clock, MqttState}, Notification, Request};
+use crate::error::{ConnectError, NetworkError};
+use crate::mqttoptions::MqttOptions;
+use mqtt311::{Connack, ConnectReturnCode, MqttWrite, Packet};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    env, fs,
+    io::Cursor,
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Set this environment variable to (re)write golden files instead of comparing
+pub const UPDATE_GOLDEN_ENV: &str = "RUMQTT_UPDATE_GOLDEN";
+
+/// Everything the state machine emits during a scenario
+#[derive(Debug)]
+pub enum Output {
+    /// Packet written to the network
+    Network(Packet),
+    /// Notification to the user
+    User(Notification),
+    /// Error which would end the eventloop
+    Error(NetworkError),
+    /// Error while connecting
+    Connect(ConnectError),
+}
+
+/// Scripted run of the session state machine with a manual clock
+pub struct Scenario {
+    state: MqttState,
+    now: Rc<Cell<Instant>>,
+    outputs: VecDeque<Output>,
+    wire: Vec<String>,
+}
+
+impl Scenario {
+    pub fn new(opts: MqttOptions) -> Scenario {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let state = MqttState::with_clock(opts, Clock::Manual(now.clone()));
+
+        Scenario {
+            state,
+            now,
+            outputs: VecDeque::new(),
+            wire: Vec::new(),
+        }
+    }
+
+    /// Sends connect and receives an accepting connack
+    pub fn connect(&mut self, session_present: bool) -> &mut Self {
+        self.connect_with(session_present, ConnectReturnCode::Accepted)
+    }
+
+    /// Sends connect and receives a connack with this code
+    pub fn connect_with(&mut self, session_present: bool, code: ConnectReturnCode) -> &mut Self {
+        match self.state.handle_outgoing_connect() {
+            Ok(connect) => self.push_packet(Packet::Connect(connect)),
+            Err(e) => self.outputs.push_back(Output::Connect(e)),
+        }
+
+        if let Err(e) = self.state.handle_incoming_connack(Connack { session_present, code }) {
+            self.outputs.push_back(Output::Connect(e));
+        }
+
+        self
+    }
+
+    /// Simulates a reconnection. Unacked packets of the session are resent
+    pub fn reconnect(&mut self, session_present: bool) -> &mut Self {
+        let requests = self.state.handle_reconnection();
+        self.connect(session_present);
+
+        for request in requests {
+            match self.state.handle_outgoing_mqtt_packet(request.into()) {
+                Ok(request) => self.push_request(request),
+                Err(e) => self.outputs.push_back(Output::Error(e)),
+            }
+        }
+
+        self
+    }
+
+    /// User sends a packet to the network
+    pub fn send(&mut self, packet: Packet) -> &mut Self {
+        match self.state.handle_outgoing_mqtt_packet(packet) {
+            Ok(request) => self.push_request(request),
+            Err(e) => self.outputs.push_back(Output::Error(e)),
+        }
+
+        self
+    }
+
+    /// Broker sends a packet to the client
+    pub fn receive(&mut self, packet: Packet) -> &mut Self {
+        match self.state.handle_incoming_mqtt_packet(packet) {
+            Ok((notification, request)) => {
+                if let Notification::None = notification {
+                } else {
+                    self.outputs.push_back(Output::User(notification));
+                }
+
+                self.push_request(request);
+            }
+            Err(e) => self.outputs.push_back(Output::Error(e)),
+        }
+
+        self
+    }
+
+    /// Moves the clock forward
+    pub fn advance(&mut self, duration: Duration) -> &mut Self {
+        self.now.set(self.now.get() + duration);
+        self
+    }
+
+    /// Keep alive timer fires
+    pub fn tick(&mut self) -> &mut Self {
+        match self.state.handle_outgoing_ping() {
+            Ok(request) => self.push_request(request),
+            Err(e) => self.outputs.push_back(Output::Error(e)),
+        }
+
+        self
+    }
+
+    /// Next output should be this packet on the network
+    pub fn expect_network(&mut self, packet: Packet) -> &mut Self {
+        match self.outputs.pop_front() {
+            Some(Output::Network(p)) => assert_eq!(p, packet),
+            output => panic!("Expecting network packet {:?}. Received = {:?}", packet, output),
+        }
+
+        self
+    }
+
+    /// Next output should be a connect packet on the network
+    pub fn expect_network_connect(&mut self) -> &mut Self {
+        match self.outputs.pop_front() {
+            Some(Output::Network(Packet::Connect(_))) => self,
+            output => panic!("Expecting connect packet. Received = {:?}", output),
+        }
+    }
+
+    /// Next output should be a notification satisfying the check
+    pub fn expect_user<F: FnOnce(&Notification) -> bool>(&mut self, check: F) -> &mut Self {
+        match self.outputs.pop_front() {
+            Some(Output::User(ref n)) if check(n) => self,
+            output => panic!("Unexpected user notification. Received = {:?}", output),
+        }
+    }
+
+    /// Next output should be an eventloop error satisfying the check
+    pub fn expect_error<F: FnOnce(&NetworkError) -> bool>(&mut self, check: F) -> &mut Self {
+        match self.outputs.pop_front() {
+            Some(Output::Error(ref e)) if check(e) => self,
+            output => panic!("Unexpected error. Received = {:?}", output),
+        }
+    }
+
+    /// Nothing more should be emitted
+    pub fn expect_nothing(&mut self) -> &mut Self {
+        if let Some(output) = self.outputs.pop_front() {
+            panic!("Expecting nothing. Received = {:?}", output);
+        }
+
+        self
+    }
+
+    /// Drains all the outputs so far
+    pub fn outputs(&mut self) -> Vec<Output> {
+        self.outputs.drain(..).collect()
+    }
+
+    /// All the packets written to the network so far. One hex encoded packet per line
+    pub fn wire(&self) -> String {
+        let mut wire = self.wire.join("\n");
+        wire.push('\n');
+        wire
+    }
+
+    /// Compares the bytes written to the network with the golden file. The file is
+    /// written instead when it doesn't exist or when `RUMQTT_UPDATE_GOLDEN` is set
+    pub fn assert_golden<P: AsRef<Path>>(&self, path: P) -> &Self {
+        let path = path.as_ref();
+        let wire = self.wire();
+
+        if env::var_os(UPDATE_GOLDEN_ENV).is_some() || !path.exists() {
+            fs::write(path, &wire).unwrap_or_else(|e| panic!("Failed to write {:?}. Error = {:?}", path, e));
+            return self;
+        }
+
+        let golden = fs::read_to_string(path).unwrap_or_else(|e| panic!("Failed to read {:?}. Error = {:?}", path, e));
+        for (i, (expected, actual)) in golden.lines().zip(wire.lines()).enumerate() {
+            if expected != actual {
+                panic!("Packet {} differs from {:?}\nexpected = {}\nactual   = {}", i, path, expected, actual);
+            }
+        }
+
+        assert_eq!(golden.lines().count(), wire.lines().count(), "Packet count differs from {:?}", path);
+        self
+    }
+
+    fn push_request(&mut self, request: Request) {
+        match request {
+            Request::None => (),
+            request => self.push_packet(request.into()),
+        }
+    }
+
+    fn push_packet(&mut self, packet: Packet) {
+        let mut bytes = Cursor::new(Vec::new());
+        bytes.write_packet(&packet).expect("Packet encode failed");
+
+        let hex: Vec<String> = bytes.get_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        self.wire.push(hex.join(" "));
+        self.outputs.push_back(Output::Network(packet));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Scenario;
+    use crate::client::Notification;
+    use crate::error::NetworkError;
+    use crate::mqttoptions::MqttOptions;
+    use mqtt311::{Packet, PacketIdentifier, Publish, QoS};
+    use std::{sync::Arc, time::Duration};
+
+    fn publish(pkid: Option<PacketIdentifier>) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid,
+            payload: Arc::new(vec![1, 2, 3]),
+        }
+    }
+
+    /// Puback is notified to the user with acknotify
+    fn expect_puback(scenario: &mut Scenario, pkid: u16) -> &mut Scenario {
+        if cfg!(feature = "acknotify") {
+            scenario.expect_user(|notification| matches!(notification, Notification::PubAck(p) if p.0 == pkid));
+        }
+
+        scenario
+    }
+
+    #[test]
+    fn missing_pingresp_should_fail_after_one_and_a_half_keep_alives() {
+        Scenario::new(MqttOptions::new("sim", "localhost", 1883).set_keep_alive(10))
+            .connect(false)
+            .expect_network_connect()
+            .advance(Duration::from_secs(5))
+            .tick()
+            .expect_nothing()
+            .advance(Duration::from_secs(6))
+            .tick()
+            .expect_network(Packet::Pingreq)
+            .advance(Duration::from_secs(11))
+            .tick()
+            .expect_nothing()
+            .advance(Duration::from_secs(4))
+            .tick()
+            .expect_error(|e| matches!(e, NetworkError::AwaitPingResp));
+    }
+
+    #[test]
+    fn only_outgoing_packets_should_defer_the_ping() {
+        let mut scenario = Scenario::new(MqttOptions::new("sim", "localhost", 1883).set_keep_alive(10));
+        scenario
+            .connect(false)
+            .expect_network_connect()
+            .advance(Duration::from_secs(6))
+            .send(Packet::Publish(publish(None)))
+            .expect_network(Packet::Publish(publish(Some(PacketIdentifier(1)))))
+            .advance(Duration::from_secs(6))
+            .tick()
+            .expect_nothing()
+            .receive(Packet::Puback(PacketIdentifier(1)));
+
+        expect_puback(&mut scenario, 1)
+            .advance(Duration::from_secs(4))
+            .tick()
+            .expect_network(Packet::Pingreq)
+            .receive(Packet::Pingresp)
+            .advance(Duration::from_secs(10))
+            .tick()
+            .expect_network(Packet::Pingreq);
+    }
+
+    #[test]
+    fn unacked_publish_should_be_resent_after_reconnection() {
+        let mut scenario = Scenario::new(MqttOptions::new("sim", "localhost", 1883).set_clean_session(false));
+        scenario
+            .connect(false)
+            .expect_network_connect()
+            .send(Packet::Publish(publish(None)))
+            .expect_network(Packet::Publish(publish(Some(PacketIdentifier(1)))))
+            .reconnect(true)
+            .expect_network_connect()
+            .expect_network(Packet::Publish(Publish { dup: true, ..publish(Some(PacketIdentifier(1))) }))
+            .receive(Packet::Puback(PacketIdentifier(1)));
+
+        expect_puback(&mut scenario, 1)
+            .expect_nothing()
+            .reconnect(true)
+            .expect_network_connect()
+            .expect_nothing()
+            .assert_golden(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/qos1_resend.txt"));
+    }
+}
@@ -0,0 +1,513 @@
+//! Sparkplug B edge node sessions on top of the client. Covers the topic
+//! namespace, the protobuf payload, NBIRTH/NDEATH pairing with the bdSeq
+//! metric, message sequence numbers and rebirth requests from host applications
+//!
+//! ```text
+//! spBv1.0/<group id>/<message type>/<edge node id>[/<device id>]
+//! ```
+//!
+//! Only the payload fields needed for scalar metrics are encoded. Unknown
+//! fields are skipped while decoding and metrics of unsupported datatypes
+//! come out without a value
+use crate::client::MqttClient;
+use crate::error::{ClientError, SparkplugError};
+use crate::mqttoptions::MqttOptions;
+use mqtt311::{Publish, QoS};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Topic namespace of Sparkplug B
+pub const NAMESPACE: &str = "spBv1.0";
+
+/// Metric of the death certificate which pairs it with the birth certificate
+pub const BD_SEQ: &str = "bdSeq";
+
+/// Command metric with which host applications ask for new birth certificates
+pub const REBIRTH: &str = "Node Control/Rebirth";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    NBirth,
+    NDeath,
+    DBirth,
+    DDeath,
+    NData,
+    DData,
+    NCmd,
+    DCmd,
+}
+
+impl MessageType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageType::NBirth => "NBIRTH",
+            MessageType::NDeath => "NDEATH",
+            MessageType::DBirth => "DBIRTH",
+            MessageType::DDeath => "DDEATH",
+            MessageType::NData => "NDATA",
+            MessageType::DData => "DDATA",
+            MessageType::NCmd => "NCMD",
+            MessageType::DCmd => "DCMD",
+        }
+    }
+}
+
+/// Topic of a node message or, with a device id, of a device message
+pub fn topic(group: &str, message_type: MessageType, node: &str, device: Option<&str>) -> String {
+    match device {
+        Some(device) => format!("{}/{}/{}/{}/{}", NAMESPACE, group, message_type.as_str(), node, device),
+        None => format!("{}/{}/{}/{}", NAMESPACE, group, message_type.as_str(), node),
+    }
+}
+
+/// Metric value along with its Sparkplug datatype
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int32(i32),
+    Int64(i64),
+    UInt64(u64),
+    Float(f32),
+    Double(f64),
+    Boolean(bool),
+    String(String),
+}
+
+impl Value {
+    fn datatype(&self) -> u32 {
+        match self {
+            Value::Int32(_) => 3,
+            Value::Int64(_) => 4,
+            Value::UInt64(_) => 8,
+            Value::Float(_) => 9,
+            Value::Double(_) => 10,
+            Value::Boolean(_) => 11,
+            Value::String(_) => 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    /// milliseconds since epoch
+    pub timestamp: Option<u64>,
+    /// `None` for null values and unsupported datatypes
+    pub value: Option<Value>,
+}
+
+impl Metric {
+    pub fn new<S: Into<String>>(name: S, value: Value) -> Metric {
+        Metric {
+            name: name.into(),
+            timestamp: None,
+            value: Some(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Payload {
+    /// milliseconds since epoch
+    pub timestamp: Option<u64>,
+    pub metrics: Vec<Metric>,
+    pub seq: Option<u64>,
+}
+
+impl Payload {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(timestamp) = self.timestamp {
+            put_varint_field(&mut out, 1, timestamp);
+        }
+
+        for metric in self.metrics.iter() {
+            put_bytes_field(&mut out, 2, &encode_metric(metric));
+        }
+
+        if let Some(seq) = self.seq {
+            put_varint_field(&mut out, 3, seq);
+        }
+
+        out
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Payload, SparkplugError> {
+        let mut out = Payload::default();
+        let mut reader = Reader { buf: payload, pos: 0 };
+        while let Some((field, wire)) = reader.key()? {
+            match (field, wire) {
+                (1, VARINT) => out.timestamp = Some(reader.varint()?),
+                (2, BYTES) => out.metrics.push(decode_metric(reader.bytes()?)?),
+                (3, VARINT) => out.seq = Some(reader.varint()?),
+                _ => reader.skip(wire)?,
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Value of the first metric with this name
+    pub fn metric(&self, name: &str) -> Option<&Value> {
+        self.metrics.iter().find(|metric| metric.name == name).and_then(|metric| metric.value.as_ref())
+    }
+}
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const BYTES: u8 = 2;
+const FIXED32: u8 = 5;
+
+fn encode_metric(metric: &Metric) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_bytes_field(&mut out, 1, metric.name.as_bytes());
+    if let Some(timestamp) = metric.timestamp {
+        put_varint_field(&mut out, 3, timestamp);
+    }
+
+    let value = match &metric.value {
+        Some(value) => value,
+        None => {
+            put_varint_field(&mut out, 7, 1);
+            return out;
+        }
+    };
+
+    put_varint_field(&mut out, 4, u64::from(value.datatype()));
+    match value {
+        Value::Int32(v) => put_varint_field(&mut out, 10, u64::from(*v as u32)),
+        Value::Int64(v) => put_varint_field(&mut out, 11, *v as u64),
+        Value::UInt64(v) => put_varint_field(&mut out, 11, *v),
+        Value::Float(v) => {
+            put_key(&mut out, 12, FIXED32);
+            out.extend_from_slice(&v.to_bits().to_le_bytes());
+        }
+        Value::Double(v) => {
+            put_key(&mut out, 13, FIXED64);
+            out.extend_from_slice(&v.to_bits().to_le_bytes());
+        }
+        Value::Boolean(v) => put_varint_field(&mut out, 14, u64::from(*v)),
+        Value::String(v) => put_bytes_field(&mut out, 15, v.as_bytes()),
+    }
+
+    out
+}
+
+fn decode_metric(buf: &[u8]) -> Result<Metric, SparkplugError> {
+    let mut name = String::new();
+    let mut timestamp = None;
+    let mut datatype = 0;
+    let mut raw = None;
+    let mut reader = Reader { buf, pos: 0 };
+    while let Some((field, wire)) = reader.key()? {
+        match (field, wire) {
+            (1, BYTES) => name = String::from_utf8(reader.bytes()?.to_vec()).map_err(|_| SparkplugError::Malformed)?,
+            (3, VARINT) => timestamp = Some(reader.varint()?),
+            (4, VARINT) => datatype = reader.varint()?,
+            (10, VARINT) | (11, VARINT) | (14, VARINT) => raw = Some(Raw::Varint(reader.varint()?)),
+            (12, FIXED32) => raw = Some(Raw::Fixed32(reader.fixed::<4>()?)),
+            (13, FIXED64) => raw = Some(Raw::Fixed64(reader.fixed::<8>()?)),
+            (15, BYTES) => raw = Some(Raw::Bytes(reader.bytes()?.to_vec())),
+            _ => reader.skip(wire)?,
+        }
+    }
+
+    let value = match (datatype, raw) {
+        (3, Some(Raw::Varint(v))) => Some(Value::Int32(v as u32 as i32)),
+        (4, Some(Raw::Varint(v))) => Some(Value::Int64(v as i64)),
+        (8, Some(Raw::Varint(v))) => Some(Value::UInt64(v)),
+        (9, Some(Raw::Fixed32(v))) => Some(Value::Float(f32::from_le_bytes(v))),
+        (10, Some(Raw::Fixed64(v))) => Some(Value::Double(f64::from_le_bytes(v))),
+        (11, Some(Raw::Varint(v))) => Some(Value::Boolean(v != 0)),
+        (12, Some(Raw::Bytes(v))) => Some(Value::String(String::from_utf8(v).map_err(|_| SparkplugError::Malformed)?)),
+        _ => None,
+    };
+
+    Ok(Metric { name, timestamp, value })
+}
+
+enum Raw {
+    Varint(u64),
+    Fixed32([u8; 4]),
+    Fixed64([u8; 8]),
+    Bytes(Vec<u8>),
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire: u8) {
+    put_varint(out, u64::from(field << 3 | u32::from(wire)));
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+
+    out.push(v as u8);
+}
+
+fn put_varint_field(out: &mut Vec<u8>, field: u32, v: u64) {
+    put_key(out, field, VARINT);
+    put_varint(out, v);
+}
+
+fn put_bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(out, field, BYTES);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn key(&mut self) -> Result<Option<(u64, u8)>, SparkplugError> {
+        if self.pos == self.buf.len() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        Ok(Some((key >> 3, (key & 0x07) as u8)))
+    }
+
+    fn varint(&mut self) -> Result<u64, SparkplugError> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or(SparkplugError::Malformed)?;
+            self.pos += 1;
+            v |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+
+        Err(SparkplugError::Malformed)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SparkplugError> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.buf.len()).ok_or(SparkplugError::Malformed)?;
+        let out = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], SparkplugError> {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N], SparkplugError> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn skip(&mut self, wire: u8) -> Result<(), SparkplugError> {
+        match wire {
+            VARINT => self.varint().map(drop),
+            FIXED64 => self.take(8).map(drop),
+            BYTES => self.bytes().map(drop),
+            FIXED32 => self.take(4).map(drop),
+            wire => Err(SparkplugError::UnsupportedWireType(wire)),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Command from a host application which isn't handled by the edge node itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// `None` for node commands (NCMD)
+    pub device: Option<String>,
+    pub metrics: Vec<Metric>,
+}
+
+/// Sparkplug session of an edge node and its devices. Register the death
+/// certificate with `configure` before connecting and publish the birth
+/// certificate once connected. Births are to be published again after every
+/// reconnection as the broker publishes the death certificate when the
+/// connection is lost. Birth metrics are kept so that rebirth requests are
+/// answered without the user's involvement
+///
+/// bdSeq stays the same for the whole life of the client as the will is
+/// registered again on every reconnection. Persist it and bump it on restart
+pub struct EdgeNode {
+    group: String,
+    node: String,
+    bd_seq: u64,
+    seq: u64,
+    birth: Vec<Metric>,
+    devices: BTreeMap<String, Vec<Metric>>,
+}
+
+impl EdgeNode {
+    /// Edge node with the bdSeq of this session. bdSeq is kept in 0..=255 and
+    /// larger values wrap (e.g 300 is used as 44). Bump the persisted value and
+    /// let it wrap, or persist `bd_seq()` instead
+    pub fn new<S: Into<String>>(group: S, node: S, bd_seq: u64) -> EdgeNode {
+        EdgeNode {
+            group: group.into(),
+            node: node.into(),
+            bd_seq: bd_seq % 256,
+            seq: 0,
+            birth: Vec::new(),
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// bdSeq in use, always in 0..=255
+    pub fn bd_seq(&self) -> u64 {
+        self.bd_seq
+    }
+
+    /// Registers NDEATH as the last will. The death certificate carries the
+    /// bdSeq metric and the time at which the will was registered
+    pub fn configure(&self, mqttoptions: MqttOptions) -> MqttOptions {
+        let payload = Payload {
+            timestamp: Some(now()),
+            metrics: vec![Metric::new(BD_SEQ, Value::Int64(self.bd_seq as i64))],
+            seq: None,
+        };
+
+        let topic = self.topic(MessageType::NDeath, None);
+        mqttoptions.set_last_will_bytes(topic, payload.encode(), QoS::AtLeastOnce, false)
+    }
+
+    fn topic(&self, message_type: MessageType, device: Option<&str>) -> String {
+        topic(&self.group, message_type, &self.node, device)
+    }
+
+    /// Sequence number of the next message. Wraps after 255
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+
+    fn publish(&mut self, client: &mut MqttClient, message_type: MessageType, device: Option<&str>, metrics: Vec<Metric>) -> Result<(), ClientError> {
+        let payload = Payload {
+            timestamp: Some(now()),
+            metrics,
+            seq: Some(self.next_seq()),
+        };
+
+        client.publish(self.topic(message_type, device), QoS::AtMostOnce, false, payload.encode())
+    }
+
+    /// Subscribes to node commands and commands of all the devices of the node
+    pub fn subscribe_commands(&self, client: &mut MqttClient) -> Result<(), ClientError> {
+        client.subscribe(self.topic(MessageType::NCmd, None), QoS::AtLeastOnce)?;
+        client.subscribe(self.topic(MessageType::DCmd, Some("+")), QoS::AtLeastOnce)
+    }
+
+    /// Publishes NBIRTH with the bdSeq metric appended and DBIRTH of every
+    /// device born so far. Sequence numbers restart from 0
+    pub fn birth(&mut self, client: &mut MqttClient, metrics: Vec<Metric>) -> Result<(), ClientError> {
+        self.birth = metrics;
+        self.rebirth(client)
+    }
+
+    fn rebirth(&mut self, client: &mut MqttClient) -> Result<(), ClientError> {
+        self.seq = 0;
+        let mut metrics = self.birth.clone();
+        metrics.push(Metric::new(BD_SEQ, Value::Int64(self.bd_seq as i64)));
+        self.publish(client, MessageType::NBirth, None, metrics)?;
+
+        let devices: Vec<(String, Vec<Metric>)> = self.devices.iter().map(|(device, metrics)| (device.clone(), metrics.clone())).collect();
+        for (device, metrics) in devices {
+            self.publish(client, MessageType::DBirth, Some(&device), metrics)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn publish_data(&mut self, client: &mut MqttClient, metrics: Vec<Metric>) -> Result<(), ClientError> {
+        self.publish(client, MessageType::NData, None, metrics)
+    }
+
+    /// Publishes DBIRTH and keeps the metrics for rebirths
+    pub fn device_birth(&mut self, client: &mut MqttClient, device: &str, metrics: Vec<Metric>) -> Result<(), ClientError> {
+        self.devices.insert(device.to_owned(), metrics.clone());
+        self.publish(client, MessageType::DBirth, Some(device), metrics)
+    }
+
+    pub fn device_data(&mut self, client: &mut MqttClient, device: &str, metrics: Vec<Metric>) -> Result<(), ClientError> {
+        self.publish(client, MessageType::DData, Some(device), metrics)
+    }
+
+    pub fn device_death(&mut self, client: &mut MqttClient, device: &str) -> Result<(), ClientError> {
+        self.devices.remove(device);
+        self.publish(client, MessageType::DDeath, Some(device), Vec::new())
+    }
+
+    /// Handles an incoming publish. Rebirth requests are answered here. Other
+    /// commands addressed to this node are returned. Returns `None` for
+    /// everything else
+    pub fn handle(&mut self, client: &mut MqttClient, publish: &Publish) -> Result<Option<Command>, ClientError> {
+        let ncmd = self.topic(MessageType::NCmd, None);
+        let dcmd = self.topic(MessageType::DCmd, None);
+        let device = if publish.topic_name == ncmd {
+            None
+        } else {
+            match publish.topic_name.strip_prefix(&dcmd).and_then(|device| device.strip_prefix('/')) {
+                Some(device) => Some(device.to_owned()),
+                None => return Ok(None),
+            }
+        };
+
+        let payload = Payload::decode(&publish.payload)
+            .map_err(|e| ClientError::InvalidPayload(publish.topic_name.clone(), e.to_string()))?;
+
+        if device.is_none() && payload.metric(REBIRTH) == Some(&Value::Boolean(true)) {
+            self.rebirth(client)?;
+            return Ok(None);
+        }
+
+        Ok(Some(Command {
+            device,
+            metrics: payload.metrics,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EdgeNode, Metric, Payload, Value, BD_SEQ};
+    use crate::mqttoptions::MqttOptions;
+
+    #[test]
+    fn payloads_should_survive_an_encode_decode_round_trip() {
+        let payload = Payload {
+            timestamp: Some(1_560_000_000_000),
+            metrics: vec![
+                Metric::new("temperature", Value::Double(21.5)),
+                Metric::new("offset", Value::Int32(-3)),
+                Metric::new("counter", Value::Int64(-1)),
+                Metric::new("ratio", Value::Float(0.25)),
+                Metric::new("on", Value::Boolean(true)),
+                Metric::new("label", Value::String("boiler".to_owned())),
+                Metric {
+                    name: "missing".to_owned(),
+                    timestamp: Some(10),
+                    value: None,
+                },
+            ],
+            seq: Some(255),
+        };
+
+        assert_eq!(Payload::decode(&payload.encode()).unwrap(), payload);
+
+        // death certificate goes out as a binary will
+        let node = EdgeNode::new("group", "node", 400);
+        assert_eq!(node.bd_seq(), 144);
+        let mqttoptions = node.configure(MqttOptions::new("id", "localhost", 1883));
+        assert_eq!(mqttoptions.last_will().unwrap().topic, "spBv1.0/group/NDEATH/node");
+        let death = Payload::decode(&mqttoptions.last_will_bytes().unwrap()).unwrap();
+        assert_eq!(death.metric(BD_SEQ), Some(&Value::Int64(144)));
+        assert!(death.timestamp.is_some());
+    }
+}
@@ -0,0 +1,144 @@
+//! Message and byte counters per topic filter. Snapshots of the counters can be
+//! diffed, or the counters reset while taking a snapshot, to report per interval
+//! rates without keeping a copy of every counter
+use crate::validation::matches;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Publishes and payload bytes on topics matching a filter
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counters {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+impl Counters {
+    /// Counts since the earlier counters
+    pub fn diff(&self, earlier: &Counters) -> Counters {
+        Counters {
+            messages_in: self.messages_in.saturating_sub(earlier.messages_in),
+            bytes_in: self.bytes_in.saturating_sub(earlier.bytes_in),
+            messages_out: self.messages_out.saturating_sub(earlier.messages_out),
+            bytes_out: self.bytes_out.saturating_sub(earlier.bytes_out),
+        }
+    }
+}
+
+/// Counters of every filter at an instant
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub at: Instant,
+    pub filters: BTreeMap<String, Counters>,
+}
+
+impl Snapshot {
+    /// Counts between the earlier snapshot and this one. Filters missing in the
+    /// earlier snapshot count from zero
+    pub fn diff(&self, earlier: &Snapshot) -> Delta {
+        let filters = self
+            .filters
+            .iter()
+            .map(|(filter, counters)| {
+                let earlier = earlier.filters.get(filter).cloned().unwrap_or_default();
+                (filter.clone(), counters.diff(&earlier))
+            })
+            .collect();
+
+        Delta {
+            elapsed: self.at.saturating_duration_since(earlier.at),
+            filters,
+        }
+    }
+}
+
+/// Counts over an interval
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub elapsed: Duration,
+    pub filters: BTreeMap<String, Counters>,
+}
+
+/// Counters shared by the eventloop and the clients. A publish counts against
+/// every filter it matches
+#[derive(Debug, Clone, Default)]
+pub struct TopicStats {
+    filters: Arc<Mutex<Vec<(String, Counters)>>>,
+}
+
+impl TopicStats {
+    pub(crate) fn add_filter(&self, filter: String) {
+        self.filters.lock().unwrap().push((filter, Counters::default()));
+    }
+
+    /// Counts an incoming or outgoing publish
+    pub(crate) fn observe(&self, topic: &str, len: usize, incoming: bool) {
+        let mut filters = self.filters.lock().unwrap();
+        for (_, counters) in filters.iter_mut().filter(|(filter, _)| matches(topic, filter)) {
+            if incoming {
+                counters.messages_in += 1;
+                counters.bytes_in += len as u64;
+            } else {
+                counters.messages_out += 1;
+                counters.bytes_out += len as u64;
+            }
+        }
+    }
+
+    /// Current counters
+    pub fn snapshot(&self) -> Snapshot {
+        let filters = self.filters.lock().unwrap();
+        Snapshot {
+            at: Instant::now(),
+            filters: filters.iter().cloned().collect(),
+        }
+    }
+
+    /// Current counters. Counters are zeroed in the same step so that no publish
+    /// is lost between the snapshot and the reset
+    pub fn reset(&self) -> Snapshot {
+        let mut filters = self.filters.lock().unwrap();
+        let snapshot = Snapshot {
+            at: Instant::now(),
+            filters: filters.iter().cloned().collect(),
+        };
+
+        for (_, counters) in filters.iter_mut() {
+            *counters = Counters::default();
+        }
+
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Counters, TopicStats};
+
+    #[test]
+    fn snapshot_diffs_and_resets_should_give_per_interval_counts() {
+        let stats = TopicStats::default();
+        stats.add_filter("sensors/#".to_owned());
+        stats.add_filter("#".to_owned());
+
+        stats.observe("sensors/temperature", 10, false);
+        stats.observe("commands/reboot", 5, true);
+        let first = stats.snapshot();
+
+        stats.observe("sensors/humidity", 20, false);
+        let second = stats.snapshot();
+        let delta = second.diff(&first);
+        let sensors = Counters { messages_out: 1, bytes_out: 20, ..Counters::default() };
+        assert_eq!(delta.filters["sensors/#"], sensors);
+        assert_eq!(delta.filters["#"], sensors);
+
+        let total = stats.reset();
+        assert_eq!(total.filters["#"].messages_out, 2);
+        assert_eq!(total.filters["#"].bytes_in, 5);
+        assert_eq!(stats.snapshot().filters["#"], Counters::default());
+    }
+}
@@ -0,0 +1,185 @@
+//! Topic tree. Stores values against topic filters in a trie with a node per
+//! topic level so that all the filters matching a topic are found by walking
+//! the levels of the topic once instead of checking every filter
+use std::{collections::HashMap, fmt};
+
+/// Values keyed by topic filters (with '+' and '#' wildcards)
+pub struct TopicTree<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+struct Node<T> {
+    value: Option<T>,
+    children: HashMap<String, Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            value: None,
+            children: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    fn collect<'a>(&'a self, levels: &[&str], out: &mut Vec<&'a T>) {
+        // multi level wildcard also matches the parent level
+        if let Some(value) = self.children.get("#").and_then(|node| node.value.as_ref()) {
+            out.push(value);
+        }
+
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                out.extend(self.value.as_ref());
+                return;
+            }
+        };
+
+        if let Some(node) = self.children.get(*level) {
+            node.collect(rest, out);
+        }
+
+        if let Some(node) = self.children.get("+") {
+            node.collect(rest, out);
+        }
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<T> {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => return self.value.take(),
+        };
+
+        let node = self.children.get_mut(*level)?;
+        let value = node.remove(rest);
+        if node.is_empty() {
+            self.children.remove(*level);
+        }
+
+        value
+    }
+}
+
+impl<T> Default for TopicTree<T> {
+    fn default() -> Self {
+        TopicTree { root: Node::new(), len: 0 }
+    }
+}
+
+impl<T> TopicTree<T> {
+    pub fn new() -> TopicTree<T> {
+        TopicTree::default()
+    }
+
+    /// Stores the value against the filter. Returns the previous value of the filter
+    pub fn insert<S: AsRef<str>>(&mut self, filter: S, value: T) -> Option<T> {
+        let node = filter
+            .as_ref()
+            .split('/')
+            .fold(&mut self.root, |node, level| node.children.entry(level.to_owned()).or_insert_with(Node::new));
+
+        let previous = node.value.replace(value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+
+        previous
+    }
+
+    /// Value stored against the filter. Filters are compared as is, without wildcard matching
+    pub fn get(&self, filter: &str) -> Option<&T> {
+        filter
+            .split('/')
+            .try_fold(&self.root, |node, level| node.children.get(level))
+            .and_then(|node| node.value.as_ref())
+    }
+
+    /// Removes the filter and returns its value
+    pub fn remove(&mut self, filter: &str) -> Option<T> {
+        let levels: Vec<&str> = filter.split('/').collect();
+        let value = self.root.remove(&levels);
+        if value.is_some() {
+            self.len -= 1;
+        }
+
+        value
+    }
+
+    /// Values of all the filters matching the topic. Wildcards at the first level
+    /// don't match topics starting with '$'
+    pub fn matches(&self, topic: &str) -> Vec<&T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut out = Vec::new();
+
+        if topic.starts_with('$') {
+            if let Some(node) = self.root.children.get(levels[0]) {
+                node.collect(&levels[1..], &mut out);
+            }
+        } else {
+            self.root.collect(&levels, &mut out);
+        }
+
+        out
+    }
+
+    /// Number of filters in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T> fmt::Debug for TopicTree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TopicTree(len = {})", self.len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicTree;
+
+    #[test]
+    fn all_matching_filters_should_be_found() {
+        let mut tree = TopicTree::new();
+        tree.insert("a/b/c", 1);
+        tree.insert("a/+/c", 2);
+        tree.insert("a/#", 3);
+        tree.insert("#", 4);
+        tree.insert("a/b", 5);
+        tree.insert("$SYS/#", 6);
+
+        let mut matched: Vec<i32> = tree.matches("a/b/c").into_iter().cloned().collect();
+        matched.sort();
+        assert_eq!(matched, vec![1, 2, 3, 4]);
+
+        let mut matched: Vec<i32> = tree.matches("a").into_iter().cloned().collect();
+        matched.sort();
+        assert_eq!(matched, vec![3, 4]);
+
+        let matched: Vec<i32> = tree.matches("$SYS/uptime").into_iter().cloned().collect();
+        assert_eq!(matched, vec![6]);
+    }
+
+    #[test]
+    fn removed_filters_should_not_match() {
+        let mut tree = TopicTree::new();
+        tree.insert("a/+/c", 1);
+        assert_eq!(tree.insert("a/+/c", 2), Some(1));
+        assert_eq!(tree.len(), 1);
+
+        assert_eq!(tree.remove("a/+"), None);
+        assert_eq!(tree.remove("a/+/c"), Some(2));
+        assert!(tree.matches("a/b/c").is_empty());
+        assert!(tree.is_empty());
+        assert!(tree.root.is_empty());
+    }
+}
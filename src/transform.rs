@@ -0,0 +1,109 @@
+//! Payload transformation pipeline (envelopes, encryption, signatures etc).
+//! Transformers are registered per topic prefix. Outgoing payloads go through
+//! the matching transformers in the order they are added and incoming payloads
+//! through their inverses in the reverse order
+use std::{fmt, sync::Arc};
+
+/// Reversible payload transformation. Returns the reason of the failure as error
+pub trait Transformer: Send + Sync {
+    /// Transforms an outgoing payload
+    fn encode(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String>;
+    /// Reverts `encode` on an incoming payload
+    fn decode(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+/// Transformers along with the topic prefixes they apply to
+#[derive(Clone, Default)]
+pub struct Transformers(Vec<(String, Arc<dyn Transformer>)>);
+
+impl Transformers {
+    pub(crate) fn add<T: Transformer + 'static>(&mut self, prefix: String, transformer: T) {
+        self.0.push((prefix, Arc::new(transformer)));
+    }
+
+    /// Runs the outgoing chain of the topic. Stops at the first failure
+    pub fn encode(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        self.0
+            .iter()
+            .filter(|(prefix, _)| topic.starts_with(prefix.as_str()))
+            .try_fold(payload, |payload, (_, transformer)| transformer.encode(topic, payload))
+    }
+
+    /// Runs the inverse chain of the topic. Stops at the first failure
+    pub fn decode(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        self.0
+            .iter()
+            .rev()
+            .filter(|(prefix, _)| topic.starts_with(prefix.as_str()))
+            .try_fold(payload, |payload, (_, transformer)| transformer.decode(topic, payload))
+    }
+
+    /// Checks if any transformer applies to the topic
+    pub fn applies(&self, topic: &str) -> bool {
+        self.0.iter().any(|(prefix, _)| topic.starts_with(prefix.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Transformers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefixes: Vec<&String> = self.0.iter().map(|(prefix, _)| prefix).collect();
+        write!(f, "Transformers({:?})", prefixes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Transformer, Transformers};
+
+    struct Envelope;
+
+    impl Transformer for Envelope {
+        fn encode(&self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            let mut out = b"env:".to_vec();
+            out.extend(payload);
+            Ok(out)
+        }
+
+        fn decode(&self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            match payload.strip_prefix(b"env:") {
+                Some(inner) => Ok(inner.to_vec()),
+                None => Err("missing envelope".to_owned()),
+            }
+        }
+    }
+
+    struct Xor(u8);
+
+    impl Transformer for Xor {
+        fn encode(&self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            Ok(payload.into_iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decode(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            self.encode(topic, payload)
+        }
+    }
+
+    #[test]
+    fn incoming_chain_should_invert_the_outgoing_chain() {
+        let mut transformers = Transformers::default();
+        transformers.add("secure/".to_owned(), Envelope);
+        transformers.add("secure/keys/".to_owned(), Xor(0x5a));
+
+        // envelope is applied first, so the xor covers it
+        let encoded = transformers.encode("secure/keys/1", b"hello".to_vec()).unwrap();
+        assert!(!encoded.starts_with(b"env:"));
+        assert_eq!(transformers.decode("secure/keys/1", encoded).unwrap(), b"hello");
+
+        let encoded = transformers.encode("secure/data", b"hello".to_vec()).unwrap();
+        assert_eq!(encoded, b"env:hello");
+        assert!(transformers.decode("secure/data", b"hello".to_vec()).is_err());
+
+        assert!(!transformers.applies("public/data"));
+        assert_eq!(transformers.encode("public/data", b"hello".to_vec()).unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,138 @@
+//! Payload validation at the client boundary. Validators are registered per topic
+//! filter and run on outgoing publishes before they are queued and on incoming
+//! publishes before they are delivered. Incoming publishes can additionally be
+//! checked against a local authorization policy
+use mqtt311::QoS;
+use std::{fmt, sync::Arc};
+
+/// Contract check for payloads (json schema, protobuf decode etc). Returns the
+/// reason of the violation as error
+pub trait Validator: Send + Sync {
+    fn validate(&self, topic: &str, payload: &[u8]) -> Result<(), String>;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&str, &[u8]) -> Result<(), String> + Send + Sync,
+{
+    fn validate(&self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        self(topic, payload)
+    }
+}
+
+/// Validators along with the topic filters they apply to
+#[derive(Clone, Default)]
+pub struct Validators(Vec<(String, Arc<dyn Validator>)>);
+
+impl Validators {
+    pub(crate) fn add<V: Validator + 'static>(&mut self, filter: String, validator: V) {
+        self.0.push((filter, Arc::new(validator)));
+    }
+
+    /// Runs all the validators whose filter matches the topic. Stops at the first violation
+    pub fn validate(&self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        self.0
+            .iter()
+            .filter(|(filter, _)| matches(topic, filter))
+            .try_for_each(|(_, validator)| validator.validate(topic, payload))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filters: Vec<&String> = self.0.iter().map(|(filter, _)| filter).collect();
+        write!(f, "Validators({:?})", filters)
+    }
+}
+
+/// Device local policy on incoming publishes. Consulted before a publish is
+/// delivered. Returns the reason of the rejection as error
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, topic: &str, size: usize, qos: QoS) -> Result<(), String>;
+}
+
+impl<F> Authorizer for F
+where
+    F: Fn(&str, usize, QoS) -> Result<(), String> + Send + Sync,
+{
+    fn authorize(&self, topic: &str, size: usize, qos: QoS) -> Result<(), String> {
+        self(topic, size, qos)
+    }
+}
+
+/// Shareable authorizer of incoming publishes
+#[derive(Clone)]
+pub struct Authorization(Arc<dyn Authorizer>);
+
+impl Authorization {
+    pub(crate) fn new<A: Authorizer + 'static>(authorizer: A) -> Authorization {
+        Authorization(Arc::new(authorizer))
+    }
+
+    pub fn authorize(&self, topic: &str, size: usize, qos: QoS) -> Result<(), String> {
+        self.0.authorize(topic, size, qos)
+    }
+}
+
+impl fmt::Debug for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Authorization")
+    }
+}
+
+/// Checks if the topic matches the filter (with '+' and '#' wildcards)
+pub fn matches(topic: &str, filter: &str) -> bool {
+    // wildcards at the first level don't match topics starting with '$'
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topics = topic.split('/');
+    let mut filters = filter.split('/');
+
+    loop {
+        match (filters.next(), topics.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, Validators};
+
+    #[test]
+    fn wildcard_filters_should_match_topics() {
+        assert!(matches("a/b/c", "a/b/c"));
+        assert!(matches("a/b/c", "a/+/c"));
+        assert!(matches("a/b/c", "a/#"));
+        assert!(matches("a", "a/#"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(!matches("a/b/c", "a/+"));
+        assert!(!matches("$SYS/uptime", "#"));
+    }
+
+    #[test]
+    fn only_validators_with_matching_filters_should_run() {
+        let mut validators = Validators::default();
+        validators.add("json/#".to_owned(), |_: &str, payload: &[u8]| {
+            if payload.starts_with(b"{") {
+                Ok(())
+            } else {
+                Err("not a json object".to_owned())
+            }
+        });
+
+        assert!(validators.validate("json/a", b"{}").is_ok());
+        assert!(validators.validate("json/a", b"hello").is_err());
+        assert!(validators.validate("raw/a", b"hello").is_ok());
+    }
+}